@@ -0,0 +1,45 @@
+/* kv_store.h - C bindings for the distributed key-value store */
+
+#ifndef KV_STORE_H
+#define KV_STORE_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Return codes. Negative values are errors; kv_last_error has the details. */
+#define KV_OK 0
+#define KV_NOT_FOUND 1
+#define KV_INVALID_ARGUMENT (-1)
+#define KV_IO_ERROR (-2)
+#define KV_LOCKED (-3)
+#define KV_LIMIT_EXCEEDED (-4)
+#define KV_UNAVAILABLE (-5)
+#define KV_ERROR (-6)
+
+typedef struct KvHandle KvHandle;
+
+/* Open a database file in-process, locking it until kv_close */
+int kv_open(const char *path, KvHandle **out);
+
+/* Use the server at "host:port", e.g. the primary of a cluster */
+int kv_connect(const char *address, KvHandle **out);
+
+/* On KV_OK, *value must be released with kv_free_string */
+int kv_get(const KvHandle *handle, const char *key, char **value);
+int kv_put(const KvHandle *handle, const char *key, const char *value);
+int kv_delete(const KvHandle *handle, const char *key);
+
+/* Saves a database opened with kv_open; the handle is released either way */
+int kv_close(KvHandle *handle);
+
+void kv_free_string(char *value);
+
+/* Message for the last error on this thread, or NULL */
+const char *kv_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KV_STORE_H */
@@ -0,0 +1,335 @@
+// ffi/src/lib.rs
+
+// C bindings, so C and C++ services can use the store. A handle either
+// opens a database file in-process or connects to a node of a running
+// cluster, and the same calls work on both. include/kv_store.h declares
+// everything exported here.
+
+use distributed_kv_store::client::Client;
+use distributed_kv_store::{EmbeddedKv, StoreError};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char, c_int};
+use std::ptr;
+use tokio::runtime::Runtime;
+
+// Return codes. Negative values are errors; kv_last_error has the details.
+pub const KV_OK: c_int = 0;
+pub const KV_NOT_FOUND: c_int = 1;
+pub const KV_INVALID_ARGUMENT: c_int = -1;
+pub const KV_IO_ERROR: c_int = -2;
+pub const KV_LOCKED: c_int = -3;
+pub const KV_LIMIT_EXCEEDED: c_int = -4;
+pub const KV_UNAVAILABLE: c_int = -5;
+pub const KV_ERROR: c_int = -6;
+
+pub struct KvHandle {
+    backend: Backend,
+}
+
+enum Backend {
+    Embedded(EmbeddedKv),
+    // Calls block on a runtime owned by the handle
+    Remote { client: Client, runtime: Runtime },
+}
+
+thread_local! {
+    // Message for the last error returned on this thread
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn fail(code: c_int, message: impl ToString) -> c_int {
+    let message = CString::new(message.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    code
+}
+
+fn store_error(error: StoreError) -> c_int {
+    let code = match error {
+        StoreError::IoError(_) => KV_IO_ERROR,
+        StoreError::LockError(_) => KV_LOCKED,
+        StoreError::LimitError(_) | StoreError::QuotaError(_) => KV_LIMIT_EXCEEDED,
+        StoreError::UnavailableError(_) => KV_UNAVAILABLE,
+        _ => KV_ERROR,
+    };
+    fail(code, error)
+}
+
+// Borrow a NUL-terminated UTF-8 argument
+unsafe fn str_arg<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, c_int> {
+    if ptr.is_null() {
+        return Err(fail(KV_INVALID_ARGUMENT, format!("{} is null", name)));
+    }
+    unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .map_err(|_| fail(KV_INVALID_ARGUMENT, format!("{} is not UTF-8", name)))
+}
+
+unsafe fn handle_arg<'a>(handle: *const KvHandle) -> Result<&'a KvHandle, c_int> {
+    unsafe { handle.as_ref() }.ok_or_else(|| fail(KV_INVALID_ARGUMENT, "handle is null"))
+}
+
+unsafe fn set_handle(out: *mut *mut KvHandle, backend: Backend) -> c_int {
+    unsafe { *out = Box::into_raw(Box::new(KvHandle { backend })) };
+    KV_OK
+}
+
+/// Open the database file at `path` in-process, storing the new handle in
+/// `*out`. Like a server, this locks the file until kv_close.
+///
+/// # Safety
+/// `path` must be a NUL-terminated string and `out` a valid pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_open(path: *const c_char, out: *mut *mut KvHandle) -> c_int {
+    let path = match unsafe { str_arg(path, "path") } {
+        Ok(path) => path,
+        Err(code) => return code,
+    };
+    if out.is_null() {
+        return fail(KV_INVALID_ARGUMENT, "out is null");
+    }
+    match EmbeddedKv::open(path) {
+        Ok(kv) => unsafe { set_handle(out, Backend::Embedded(kv)) },
+        Err(e) => store_error(e),
+    }
+}
+
+/// Connect to the server at `address` ("host:port"), storing the new handle
+/// in `*out`. No connection is made until the first call.
+///
+/// # Safety
+/// `address` must be a NUL-terminated string and `out` a valid pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_connect(address: *const c_char, out: *mut *mut KvHandle) -> c_int {
+    let address = match unsafe { str_arg(address, "address") } {
+        Ok(address) => address,
+        Err(code) => return code,
+    };
+    if out.is_null() {
+        return fail(KV_INVALID_ARGUMENT, "out is null");
+    }
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => return fail(KV_IO_ERROR, e),
+    };
+    let client = Client::new(address.to_string());
+    unsafe { set_handle(out, Backend::Remote { client, runtime }) }
+}
+
+/// Look up `key`. On KV_OK, `*value` holds a string to release with
+/// kv_free_string; on KV_NOT_FOUND it is set to NULL.
+///
+/// # Safety
+/// `handle` must come from kv_open or kv_connect, `key` must be a
+/// NUL-terminated string and `value` a valid pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_get(
+    handle: *const KvHandle,
+    key: *const c_char,
+    value: *mut *mut c_char,
+) -> c_int {
+    let (handle, key) = match unsafe { (handle_arg(handle), str_arg(key, "key")) } {
+        (Ok(handle), Ok(key)) => (handle, key),
+        (Err(code), _) | (_, Err(code)) => return code,
+    };
+    if value.is_null() {
+        return fail(KV_INVALID_ARGUMENT, "value is null");
+    }
+    unsafe { *value = ptr::null_mut() };
+
+    let found = match &handle.backend {
+        Backend::Embedded(kv) => kv.get(key),
+        Backend::Remote { client, runtime } => match runtime.block_on(client.get(key)) {
+            Ok(found) => found,
+            Err(e) => return store_error(e),
+        },
+    };
+    match found.map(CString::new) {
+        Some(Ok(found)) => {
+            unsafe { *value = found.into_raw() };
+            KV_OK
+        }
+        Some(Err(_)) => fail(KV_ERROR, "value contains a NUL byte"),
+        None => KV_NOT_FOUND,
+    }
+}
+
+/// Set `key` to `value`.
+///
+/// # Safety
+/// `handle` must come from kv_open or kv_connect, and `key` and `value`
+/// must be NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_put(
+    handle: *const KvHandle,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    let (handle, key, value) = match unsafe {
+        (
+            handle_arg(handle),
+            str_arg(key, "key"),
+            str_arg(value, "value"),
+        )
+    } {
+        (Ok(handle), Ok(key), Ok(value)) => (handle, key, value),
+        (Err(code), _, _) | (_, Err(code), _) | (_, _, Err(code)) => return code,
+    };
+
+    let result = match &handle.backend {
+        Backend::Embedded(kv) => kv.put(key, value),
+        Backend::Remote { client, runtime } => runtime.block_on(client.put(key, value)),
+    };
+    match result {
+        Ok(()) => KV_OK,
+        Err(e) => store_error(e),
+    }
+}
+
+/// Delete `key`, returning KV_NOT_FOUND if it wasn't set.
+///
+/// # Safety
+/// `handle` must come from kv_open or kv_connect, and `key` must be a
+/// NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_delete(handle: *const KvHandle, key: *const c_char) -> c_int {
+    let (handle, key) = match unsafe { (handle_arg(handle), str_arg(key, "key")) } {
+        (Ok(handle), Ok(key)) => (handle, key),
+        (Err(code), _) | (_, Err(code)) => return code,
+    };
+
+    let deleted = match &handle.backend {
+        Backend::Embedded(kv) => match kv.delete(key) {
+            Ok(deleted) => deleted,
+            Err(e) => return store_error(e),
+        },
+        Backend::Remote { client, runtime } => match runtime.block_on(client.delete(key)) {
+            Ok(deleted) => deleted,
+            Err(e) => return store_error(e),
+        },
+    };
+    if deleted { KV_OK } else { KV_NOT_FOUND }
+}
+
+/// Release a handle. A database opened with kv_open is saved and unlocked
+/// first; the handle is released even if saving fails.
+///
+/// # Safety
+/// `handle` must come from kv_open or kv_connect and not be used again.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_close(handle: *mut KvHandle) -> c_int {
+    if handle.is_null() {
+        return KV_OK;
+    }
+    let handle = unsafe { Box::from_raw(handle) };
+    match handle.backend {
+        Backend::Embedded(kv) => match kv.close() {
+            Ok(()) => KV_OK,
+            Err(e) => store_error(e),
+        },
+        Backend::Remote { .. } => KV_OK,
+    }
+}
+
+/// Release a string returned by kv_get.
+///
+/// # Safety
+/// `value` must come from kv_get, or be NULL.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_free_string(value: *mut c_char) {
+    if !value.is_null() {
+        drop(unsafe { CString::from_raw(value) });
+    }
+}
+
+/// The message for the last error returned on this thread, or NULL. It is
+/// valid until the next failing call on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn kv_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    unsafe fn get(handle: *const KvHandle, key: &str) -> Option<String> {
+        let mut value = ptr::null_mut();
+        match unsafe { kv_get(handle, c(key).as_ptr(), &mut value) } {
+            KV_OK => {
+                let found = unsafe { CStr::from_ptr(value) }
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+                unsafe { kv_free_string(value) };
+                Some(found)
+            }
+            code => {
+                assert_eq!(code, KV_NOT_FOUND);
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn test_c_api() {
+        let dir = tempdir().unwrap();
+        let path = c(dir.path().join("ffi-db.json").to_str().unwrap());
+
+        unsafe {
+            let mut handle = ptr::null_mut();
+            assert_eq!(kv_open(path.as_ptr(), &mut handle), KV_OK);
+            let mut second = ptr::null_mut();
+            assert_eq!(kv_open(path.as_ptr(), &mut second), KV_LOCKED);
+            assert!(!kv_last_error().is_null());
+
+            assert_eq!(
+                kv_put(handle, c("key").as_ptr(), c("value").as_ptr()),
+                KV_OK
+            );
+            assert_eq!(get(handle, "key"), Some("value".to_string()));
+            assert_eq!(
+                kv_put(handle, ptr::null(), c("v").as_ptr()),
+                KV_INVALID_ARGUMENT
+            );
+            assert_eq!(kv_delete(handle, c("missing").as_ptr()), KV_NOT_FOUND);
+            assert_eq!(kv_close(handle), KV_OK);
+
+            // The data was saved on close
+            assert_eq!(kv_open(path.as_ptr(), &mut handle), KV_OK);
+            assert_eq!(get(handle, "key"), Some("value".to_string()));
+            assert_eq!(kv_close(handle), KV_OK);
+        }
+    }
+
+    #[test]
+    fn test_c_api_remote() {
+        let dir = tempdir().unwrap();
+        let server_runtime = Runtime::new().unwrap();
+        let kv = EmbeddedKv::open(dir.path().join("served-db.json")).unwrap();
+        let server = server_runtime.block_on(kv.serve("127.0.0.1:0")).unwrap();
+
+        unsafe {
+            let mut handle = ptr::null_mut();
+            let address = c(&server.address().to_string());
+            assert_eq!(kv_connect(address.as_ptr(), &mut handle), KV_OK);
+            assert_eq!(
+                kv_put(handle, c("key").as_ptr(), c("value").as_ptr()),
+                KV_OK
+            );
+            assert_eq!(kv.get("key"), Some("value".to_string()));
+            assert_eq!(get(handle, "key"), Some("value".to_string()));
+            assert_eq!(kv_delete(handle, c("key").as_ptr()), KV_OK);
+            assert_eq!(get(handle, "key"), None);
+            assert_eq!(kv_close(handle), KV_OK);
+        }
+        server.shutdown();
+    }
+}
@@ -0,0 +1,350 @@
+// src/backup.rs
+
+// Shipping snapshots off the machine and fetching them back, so a node on an
+// ephemeral disk can be rebuilt, and rolling a database back in time from its
+// local snapshots and write-ahead log. s3:// targets go through the `aws` CLI,
+// which also talks to S3-compatible stores via `--endpoint-url`; anything
+// else can be handled by a user-supplied shell command.
+
+use crate::changelog::Change;
+use crate::error::{Result, StoreError};
+use crate::snapshot;
+use crate::store::KeyValueStore;
+use crate::wal;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackupSink {
+    // Upload to s3://bucket/prefix/<name>, optionally on a non-AWS endpoint
+    S3 {
+        url: String,
+        endpoint: Option<String>,
+    },
+    // Run a shell command with {file} and {name} filled in
+    Command(String),
+}
+
+impl BackupSink {
+    // Build a sink from config settings; a command wins over an S3 target
+    pub fn from_settings(
+        target: Option<&str>,
+        endpoint: Option<&str>,
+        command: Option<&str>,
+    ) -> Result<Option<Self>> {
+        if let Some(command) = command {
+            return Ok(Some(BackupSink::Command(command.to_string())));
+        }
+
+        match target {
+            Some(url) if url.starts_with("s3://") => Ok(Some(BackupSink::S3 {
+                url: url.trim_end_matches('/').to_string(),
+                endpoint: endpoint.map(str::to_string),
+            })),
+            Some(url) => Err(StoreError::ConfigError(format!(
+                "Unsupported backup target '{}', expected s3://bucket/prefix",
+                url
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    // Upload `file` under `name`
+    pub async fn upload(&self, file: &Path, name: &str) -> Result<()> {
+        match self {
+            BackupSink::S3 { url, endpoint } => {
+                let mut cmd = aws_command(endpoint.as_deref());
+                cmd.arg("cp").arg(file).arg(format!("{}/{}", url, name));
+                run(cmd).await
+            }
+            BackupSink::Command(template) => {
+                let command = template
+                    .replace("{file}", &file.display().to_string())
+                    .replace("{name}", name);
+                run(shell(&command)).await
+            }
+        }
+    }
+}
+
+// Replace the database file with a backup fetched from `source`, which can be
+// an s3:// URL, an http(s):// URL, a local path, or anything `command` knows
+// how to fetch. Returns the number of keys restored.
+pub async fn restore(
+    source: &str,
+    endpoint: Option<&str>,
+    command: Option<&str>,
+    db_path: &Path,
+) -> Result<usize> {
+    let staging = with_suffix(db_path, "restore");
+
+    if let Some(template) = command {
+        let command = template
+            .replace("{source}", source)
+            .replace("{file}", &staging.display().to_string());
+        run(shell(&command)).await?;
+    } else if source.starts_with("s3://") {
+        let mut cmd = aws_command(endpoint);
+        cmd.arg("cp").arg(source).arg(&staging);
+        run(cmd).await?;
+    } else if source.starts_with("http://") || source.starts_with("https://") {
+        let mut cmd = Command::new("curl");
+        cmd.args(["--fail", "--silent", "--show-error", "--location"]);
+        cmd.arg("--output").arg(&staging).arg(source);
+        run(cmd).await?;
+    } else {
+        fs::copy(source, &staging)?;
+    }
+
+    // Make sure we fetched a readable database before replacing ours
+    if !staging.exists() {
+        return Err(StoreError::PersistenceError(format!(
+            "Nothing was fetched from {}",
+            source
+        )));
+    }
+    let keys = match KeyValueStore::load(&staging) {
+        Ok(store) => store.keys().len(),
+        Err(e) => {
+            let _ = fs::remove_file(&staging);
+            return Err(e);
+        }
+    };
+
+    fs::rename(&staging, db_path)?;
+    Ok(keys)
+}
+
+// Roll the database back to how it was at `unix_secs`: load the newest
+// snapshot taken by then and replay the write-ahead log on top of it up to
+// the end of that second. The database file and log it replaces are kept
+// with a `.before-restore` suffix. Returns the number of keys and of writes
+// replayed.
+pub fn restore_to_time(db_path: &Path, unix_secs: u64) -> Result<(usize, usize)> {
+    let base = snapshot::list_snapshots(db_path)?
+        .into_iter()
+        .rfind(|path| snapshot::snapshot_time(path).is_some_and(|time| time <= unix_secs));
+    let store = match &base {
+        Some(path) => KeyValueStore::load_file(path)?,
+        None => KeyValueStore::new(),
+    };
+    let base_seq = store.snapshot().seq();
+
+    // Every write since the snapshot has to be in the log, or the
+    // database file would be the only place left that has it
+    let log = wal::wal_path(db_path);
+    let changes: Vec<Change> = wal::read(&log)?
+        .into_iter()
+        .filter(|change| change.seq > base_seq)
+        .collect();
+    let saved_seq = KeyValueStore::load_file(db_path)?.snapshot().seq();
+    let first = changes.first().map_or(saved_seq + 1, |change| change.seq);
+    if first > base_seq + 1 {
+        let start = match &base {
+            Some(path) => format!("{} ends at write {}", path.display(), base_seq),
+            None => format!("there is no snapshot from before {}", unix_secs),
+        };
+        return Err(StoreError::PersistenceError(format!(
+            "The write-ahead log only goes back to write {}, but {}; \
+             raise wal_segment_retention to keep more of it",
+            first, start
+        )));
+    }
+
+    let until = unix_secs.saturating_mul(1000) + 999;
+    let replayed = store.replay(
+        changes
+            .into_iter()
+            .take_while(|change| change.timestamp <= until)
+            .collect(),
+    )?;
+
+    let staging = with_suffix(db_path, "restore");
+    store.save(&staging)?;
+    if db_path.exists() {
+        fs::copy(db_path, with_suffix(db_path, "before-restore"))?;
+    }
+    wal::set_aside(&log, "before-restore")?;
+    fs::rename(&staging, db_path)?;
+    Ok((store.keys().len(), replayed))
+}
+
+// `path` with `.<suffix>` appended
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// Restore from `source` only if there is no database file yet, so a new node
+// starts from a snapshot while restarts keep their own data. Returns the
+// number of keys restored, None if the file was already there.
+pub async fn bootstrap(
+    source: &str,
+    endpoint: Option<&str>,
+    command: Option<&str>,
+    db_path: &Path,
+) -> Result<Option<usize>> {
+    if db_path.exists() {
+        return Ok(None);
+    }
+    restore(source, endpoint, command, db_path).await.map(Some)
+}
+
+fn aws_command(endpoint: Option<&str>) -> Command {
+    let mut cmd = Command::new("aws");
+    if let Some(endpoint) = endpoint {
+        cmd.arg("--endpoint-url").arg(endpoint);
+    }
+    cmd.arg("s3");
+    cmd
+}
+
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+// Run a command to completion, turning a non-zero exit into an error
+async fn run(mut cmd: Command) -> Result<()> {
+    let output = cmd.output().await?;
+    if output.status.success() {
+        return Ok(());
+    }
+
+    Err(StoreError::PersistenceError(format!(
+        "{:?} failed ({}): {}",
+        cmd.as_std().get_program(),
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_sink_from_settings() {
+        assert_eq!(BackupSink::from_settings(None, None, None).unwrap(), None);
+        assert_eq!(
+            BackupSink::from_settings(Some("s3://bucket/kv/"), Some("http://minio:9000"), None)
+                .unwrap(),
+            Some(BackupSink::S3 {
+                url: "s3://bucket/kv".to_string(),
+                endpoint: Some("http://minio:9000".to_string()),
+            })
+        );
+        assert!(BackupSink::from_settings(Some("ftp://host/kv"), None, None).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_upload_and_restore() -> Result<()> {
+        let dir = tempdir()?;
+        let remote = dir.path().join("remote");
+        fs::create_dir(&remote)?;
+
+        let db_path = dir.path().join("backup-db.json");
+        let store = KeyValueStore::new();
+        store.put("key1".to_string(), "value1".to_string());
+        store.save(&db_path)?;
+
+        // Ship the database into a stand-in for remote storage
+        let sink = BackupSink::Command(format!("cp {{file}} {}/{{name}}", remote.display()));
+        sink.upload(&db_path, "snapshot-1.json").await?;
+
+        // Lose the local file, then restore it from the uploaded copy
+        fs::remove_file(&db_path)?;
+        let source = remote.join("snapshot-1.json");
+        let keys = restore(source.to_str().unwrap(), None, None, &db_path).await?;
+        assert_eq!(keys, 1);
+        assert_eq!(
+            KeyValueStore::load(&db_path)?.get("key1"),
+            Some("value1".to_string())
+        );
+
+        // A failing command is reported instead of clobbering the database
+        assert!(
+            restore("anything", None, Some("exit 1"), &db_path)
+                .await
+                .is_err()
+        );
+        assert!(db_path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_to_time() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("pitr-db.json");
+        let log = wal::wal_path(&db_path);
+        let store = KeyValueStore::new();
+        store.open_wal(&log)?;
+        store.wal().unwrap().set_segment_retention(10);
+        store.put("key1".to_string(), "value1".to_string());
+        store.save(&db_path)?;
+        fs::copy(&db_path, with_suffix(&db_path, "19700101-000001"))?;
+        store.put("key2".to_string(), "value2".to_string());
+        store.delete("key1");
+        drop(store);
+
+        // Long before the later writes, only the snapshot applies
+        assert_eq!(restore_to_time(&db_path, 1000)?, (1, 0));
+        let restored = KeyValueStore::load(&db_path)?;
+        assert_eq!(restored.get("key1"), Some("value1".to_string()));
+        assert_eq!(restored.get("key2"), None);
+        assert!(!log.exists());
+        assert!(with_suffix(&db_path, "before-restore").exists());
+
+        // Put the log back and roll forward to now
+        for entry in fs::read_dir(dir.path())? {
+            let path = entry?.path();
+            let name = path.to_string_lossy().into_owned();
+            if let Some(original) = name.strip_suffix(".before-restore")
+                && original.starts_with(&*log.to_string_lossy())
+            {
+                fs::rename(&path, original)?;
+            }
+        }
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert_eq!(restore_to_time(&db_path, now)?, (1, 2));
+        let restored = KeyValueStore::load(&db_path)?;
+        assert_eq!(restored.get("key1"), None);
+        assert_eq!(restored.get("key2"), Some("value2".to_string()));
+
+        // With the log gone, the snapshot can't be brought up to date
+        assert!(restore_to_time(&db_path, 1000).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_only_on_first_start() -> Result<()> {
+        let dir = tempdir()?;
+        let snapshot = dir.path().join("snapshot.json");
+        let store = KeyValueStore::new();
+        store.put("key1".to_string(), "value1".to_string());
+        store.save(&snapshot)?;
+
+        let db_path = dir.path().join("new-node.json");
+        let source = snapshot.to_str().unwrap();
+        assert_eq!(bootstrap(source, None, None, &db_path).await?, Some(1));
+
+        // A restart keeps what the node has written since
+        let store = KeyValueStore::load(&db_path)?;
+        store.put("key2".to_string(), "value2".to_string());
+        store.save(&db_path)?;
+        assert_eq!(bootstrap(source, None, None, &db_path).await?, None);
+        assert_eq!(KeyValueStore::load(&db_path)?.keys().len(), 2);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+// src/changelog.rs
+
+// Ordered log of committed mutations for change data capture. Every write
+// gets the next sequence number; the most recent ones are kept in memory so
+// consumers can catch up from a sequence number and then follow live changes.
+
+use crate::error::{Result, StoreError};
+use crate::hlc::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+// Number of recent changes kept for consumers to catch up from
+const CHANGELOG_CAPACITY: usize = 10_000;
+
+// Live changes a slow consumer may fall behind by before it is dropped
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum ChangeOp {
+    Put { key: String, value: String },
+    Delete { key: String },
+    // The key expires at `at`, in unix milliseconds (0 = never)
+    Expire { key: String, at: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub seq: u64,
+    // Unix time in milliseconds
+    pub timestamp: u64,
+    // Hybrid logical clock time of the write, given by the node that took
+    // it, so changes from different nodes can be ordered
+    #[serde(default)]
+    pub hlc: Timestamp,
+    #[serde(flatten)]
+    pub op: ChangeOp,
+}
+
+pub struct Changelog {
+    inner: Mutex<ChangelogInner>,
+    sender: broadcast::Sender<Change>,
+}
+
+struct ChangelogInner {
+    entries: VecDeque<Change>,
+    last_seq: u64,
+}
+
+impl Default for Changelog {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+        Changelog {
+            inner: Mutex::new(ChangelogInner {
+                entries: VecDeque::new(),
+                last_seq: 0,
+            }),
+            sender,
+        }
+    }
+}
+
+impl Changelog {
+    // Append a committed mutation, returning its sequence number. Callers
+    // hold the store's write lock so sequence order matches commit order.
+    pub fn record(&self, op: ChangeOp, hlc: Timestamp) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_seq += 1;
+
+        let change = Change {
+            seq: inner.last_seq,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            hlc,
+            op,
+        };
+
+        if inner.entries.len() >= CHANGELOG_CAPACITY {
+            inner.entries.pop_front();
+        }
+        inner.entries.push_back(change.clone());
+
+        // No subscribers is not an error
+        let _ = self.sender.send(change);
+        inner.last_seq
+    }
+
+    // Sequence number of the latest change, 0 if none
+    pub fn last_seq(&self) -> u64 {
+        self.inner.lock().unwrap().last_seq
+    }
+
+    // When the change numbered `seq` was committed. For changes that are no
+    // longer buffered this is when the oldest buffered one was, so it never
+    // overstates how recent they are. None if `seq` hasn't happened yet.
+    pub fn timestamp(&self, seq: u64) -> Option<u64> {
+        let inner = self.inner.lock().unwrap();
+        let oldest = inner.entries.front()?.seq;
+        inner
+            .entries
+            .get(seq.saturating_sub(oldest) as usize)
+            .map(|change| change.timestamp)
+    }
+
+    // Continue numbering from a sequence restored from disk
+    pub fn set_last_seq(&self, seq: u64) {
+        self.inner.lock().unwrap().last_seq = seq;
+    }
+
+    // A receiver for every change committed from now on
+    pub fn follow(&self) -> broadcast::Receiver<Change> {
+        self.sender.subscribe()
+    }
+
+    // Changes from `from_seq` onwards that are still buffered, plus a receiver
+    // for everything committed after them
+    pub fn subscribe(&self, from_seq: u64) -> Result<(Vec<Change>, broadcast::Receiver<Change>)> {
+        let inner = self.inner.lock().unwrap();
+
+        // Anything older than the buffer is gone; the consumer must resync
+        let oldest = inner
+            .entries
+            .front()
+            .map_or(inner.last_seq + 1, |change| change.seq);
+        if from_seq.max(1) < oldest {
+            return Err(StoreError::ReplicationError(format!(
+                "Sequence {} is no longer available, oldest is {}",
+                from_seq, oldest
+            )));
+        }
+
+        let backlog = inner
+            .entries
+            .iter()
+            .filter(|change| change.seq >= from_seq)
+            .cloned()
+            .collect();
+        Ok((backlog, self.sender.subscribe()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(key: &str) -> ChangeOp {
+        ChangeOp::Put {
+            key: key.to_string(),
+            value: "value".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_subscribe_from_seq() {
+        let changelog = Changelog::default();
+        assert_eq!(changelog.record(put("a"), Timestamp::default()), 1);
+        assert_eq!(changelog.record(put("b"), Timestamp::default()), 2);
+
+        // Catch up from the middle, then follow new changes
+        let (backlog, mut receiver) = changelog.subscribe(2).unwrap();
+        assert_eq!(backlog.len(), 1);
+        assert_eq!(backlog[0].op, put("b"));
+
+        let deleted = Timestamp {
+            wall: 1,
+            logical: 2,
+        };
+        changelog.record(
+            ChangeOp::Delete {
+                key: "a".to_string(),
+            },
+            deleted,
+        );
+        let live = receiver.try_recv().unwrap();
+        assert_eq!(live.seq, 3);
+        assert_eq!(live.hlc, deleted);
+        assert_eq!(
+            serde_json::to_string(&live.op).unwrap(),
+            r#"{"op":"delete","key":"a"}"#
+        );
+
+        // Sequences that were never buffered can't be replayed
+        assert!(Changelog::default().subscribe(0).is_ok());
+        changelog.set_last_seq(100);
+        assert!(changelog.subscribe(101).is_ok());
+        let restarted = Changelog::default();
+        restarted.set_last_seq(100);
+        assert!(restarted.subscribe(50).is_err());
+    }
+}
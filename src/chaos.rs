@@ -0,0 +1,127 @@
+// src/chaos.rs
+
+// Fault injection for trying out failover settings and client retry logic
+// before production. Only servers started with --chaos accept CHAOS
+// commands; everything is off until one of them turns it on.
+
+use crate::eviction::random_index;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+pub const CHAOS_USAGE: &str =
+    "ERROR: Usage: CHAOS [LATENCY <ms> | DROP <percent> | REFUSE <percent> | OFF]";
+
+#[derive(Default)]
+pub struct Chaos {
+    enabled: AtomicBool,
+
+    // Delay added before every command is answered
+    latency_ms: AtomicU64,
+
+    // Chance, in percent, of ignoring a REPLICATE or HEARTBEAT
+    drop_percent: AtomicUsize,
+
+    // Chance, in percent, of closing a new connection straight away
+    refuse_percent: AtomicUsize,
+}
+
+impl Chaos {
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn latency(&self) -> Option<Duration> {
+        match self.latency_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    pub fn drop_replication(&self) -> bool {
+        roll(&self.drop_percent)
+    }
+
+    pub fn refuse_connection(&self) -> bool {
+        roll(&self.refuse_percent)
+    }
+
+    // Apply a CHAOS subcommand such as `LATENCY 200` or `OFF`
+    pub fn apply(&self, args: &[&str]) -> Result<(), String> {
+        let percent = |value: &str| match value.parse::<usize>() {
+            Ok(percent) if percent <= 100 => Ok(percent),
+            _ => Err(format!("ERROR: '{}' is not a percentage", value)),
+        };
+
+        match args {
+            [setting, value] if setting.eq_ignore_ascii_case("LATENCY") => {
+                let ms = value
+                    .parse()
+                    .map_err(|_| format!("ERROR: '{}' is not a number of milliseconds", value))?;
+                self.latency_ms.store(ms, Ordering::Relaxed);
+            }
+            [setting, value] if setting.eq_ignore_ascii_case("DROP") => {
+                self.drop_percent.store(percent(value)?, Ordering::Relaxed);
+            }
+            [setting, value] if setting.eq_ignore_ascii_case("REFUSE") => {
+                self.refuse_percent
+                    .store(percent(value)?, Ordering::Relaxed);
+            }
+            [setting] if setting.eq_ignore_ascii_case("OFF") => {
+                self.latency_ms.store(0, Ordering::Relaxed);
+                self.drop_percent.store(0, Ordering::Relaxed);
+                self.refuse_percent.store(0, Ordering::Relaxed);
+            }
+            _ => return Err(CHAOS_USAGE.to_string()),
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Chaos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "latency_ms={}, drop_percent={}, refuse_percent={}",
+            self.latency_ms.load(Ordering::Relaxed),
+            self.drop_percent.load(Ordering::Relaxed),
+            self.refuse_percent.load(Ordering::Relaxed)
+        )
+    }
+}
+
+fn roll(percent: &AtomicUsize) -> bool {
+    random_index(100) < percent.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chaos_settings() {
+        let chaos = Chaos::default();
+        assert!(!chaos.is_enabled());
+        assert!(!chaos.refuse_connection());
+
+        chaos.apply(&["LATENCY", "250"]).unwrap();
+        chaos.apply(&["drop", "100"]).unwrap();
+        assert_eq!(chaos.latency(), Some(Duration::from_millis(250)));
+        assert!(chaos.drop_replication());
+        assert_eq!(
+            chaos.to_string(),
+            "latency_ms=250, drop_percent=100, refuse_percent=0"
+        );
+
+        assert!(chaos.apply(&["REFUSE", "101"]).is_err());
+        assert!(chaos.apply(&["LATENCY"]).is_err());
+
+        chaos.apply(&["OFF"]).unwrap();
+        assert_eq!(chaos.latency(), None);
+        assert!(!chaos.drop_replication());
+    }
+}
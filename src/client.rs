@@ -3,48 +3,240 @@
 // a client to connect to our server
 
 use crate::error::{Result, StoreError};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
+#[cfg(target_arch = "wasm32")]
+use crate::websocket::WebSocketTransport;
+use std::collections::HashMap;
+#[cfg(not(target_arch = "wasm32"))]
+use {
+    crate::changelog::Change,
+    crate::notifications::Notification,
+    crate::socket::SocketOptions,
+    crate::tls::{Stream, Tls},
+    std::sync::Arc,
+    tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+};
 
-pub struct Client {
+// Prefix of responses the server expects clients to retry later
+pub const TRY_AGAIN: &str = "ERROR: TRYAGAIN";
+
+// How a client reaches a server: one command line in, one response line
+// back. The typed methods below work over any transport, such as TCP, the
+// WebSocket gateway or an in-process one.
+pub trait Transport {
+    fn send_command(&self, command: &str) -> impl Future<Output = Result<String>>;
+}
+
+// A connection to a server that answers each command sent over it with a
+// line, in order
+pub(crate) trait Connection {
+    fn exchange(&mut self, command: &str) -> impl Future<Output = Result<String>>;
+}
+
+// Start a new connection by authenticating, if we have credentials, and
+// selecting our namespace
+pub(crate) async fn open_session(
+    connection: &mut impl Connection,
+    auth_token: Option<&str>,
+    namespace: Option<&str>,
+) -> Result<()> {
+    if let Some(token) = auth_token {
+        let response = connection.exchange(&format!("AUTH {}", token)).await?;
+        if response != "OK" {
+            return Err(StoreError::AuthError(response));
+        }
+    }
+    if let Some(namespace) = namespace {
+        let response = connection
+            .exchange(&format!("SELECT {}", namespace))
+            .await?;
+        if response != "OK" {
+            return Err(StoreError::ConfigError(response));
+        }
+    }
+    Ok(())
+}
+
+// A TCP connection per command, over TLS if we have a CA to check the
+// server against
+#[cfg(not(target_arch = "wasm32"))]
+pub struct TcpTransport {
     address: String,
+    socket_options: SocketOptions,
+    tls: Option<Arc<Tls>>,
+    auth_token: Option<String>,
+    namespace: Option<String>,
 }
 
-impl Client {
+#[cfg(not(target_arch = "wasm32"))]
+impl TcpTransport {
+    // Connect to the server, authenticating first if we have credentials and
+    // selecting our namespace
+    async fn connect(&self) -> Result<BufReader<Stream>> {
+        let stream = self.socket_options.connect(&self.address).await?;
+        let stream = match &self.tls {
+            Some(tls) => tls.connect(&self.address, stream).await?,
+            None => Stream::Plain(stream),
+        };
+        let mut stream = BufReader::new(stream);
+        open_session(
+            &mut stream,
+            self.auth_token.as_deref(),
+            self.namespace.as_deref(),
+        )
+        .await?;
+        Ok(stream)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Transport for TcpTransport {
+    async fn send_command(&self, command: &str) -> Result<String> {
+        let mut stream = self.connect().await?;
+        stream.exchange(command).await
+    }
+}
+
+// Natively a client speaks TCP unless given another transport; in a
+// browser it goes through the WebSocket gateway
+#[cfg(not(target_arch = "wasm32"))]
+pub struct Client<T = TcpTransport> {
+    transport: T,
+}
 
+#[cfg(target_arch = "wasm32")]
+pub struct Client<T = WebSocketTransport> {
+    transport: T,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Client {
     pub fn new(address: String) -> Self {
-        Client { address }
+        Client {
+            transport: TcpTransport {
+                address,
+                socket_options: SocketOptions::default(),
+                tls: None,
+                auth_token: None,
+                namespace: None,
+            },
+        }
     }
 
-    pub async fn send_command(&self, command: &str) -> Result<String> {
-        // Connect to server
-        let mut stream = TcpStream::connect(&self.address)
-            .await
-            .map_err(|e| StoreError::IoError(e))?;
+    // Use custom TCP options for connections to the server
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.transport.socket_options = socket_options;
+        self
+    }
 
-        // Send command
+    // Connect over TLS, checking the server's certificate against the CA
+    pub fn with_tls(mut self, tls: Arc<Tls>) -> Self {
+        self.transport.tls = Some(tls);
+        self
+    }
+
+    // Authenticate every connection with the server's admin token
+    pub fn with_auth_token(mut self, token: String) -> Self {
+        self.transport.auth_token = Some(token);
+        self
+    }
+
+    // Work inside a namespace, as if every connection started with SELECT
+    pub fn with_namespace(mut self, namespace: String) -> Self {
+        self.transport.namespace = Some(namespace);
+        self
+    }
+
+    // Follow the server's changes from `from_seq`, calling `on_change` for
+    // each one until the server closes the stream
+    pub async fn sync(&self, from_seq: u64, mut on_change: impl FnMut(Change)) -> Result<()> {
+        let mut stream = self.transport.connect().await?;
         stream
-            .write_all(command.as_bytes())
-            .await
-            .map_err(|e| StoreError::IoError(e))?;
+            .write_all(format!("SYNC {}\n", from_seq).as_bytes())
+            .await?;
+        stream.flush().await?;
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if stream.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+
+            let line = line.trim();
+            if line.starts_with("ERROR") {
+                return Err(StoreError::ReplicationError(line.to_string()));
+            }
+            let change = serde_json::from_str(line)
+                .map_err(|e| StoreError::SerializationError(e.to_string()))?;
+            on_change(change);
+        }
+    }
+
+    // Follow keyspace notifications for keys starting with `prefix` (all
+    // keys if empty), calling `on_notification` for each one until the
+    // server closes the stream. Nothing that happened before the server
+    // acknowledges the subscription is seen.
+    pub async fn subscribe(
+        &self,
+        prefix: &str,
+        mut on_notification: impl FnMut(Notification),
+    ) -> Result<()> {
+        let mut stream = self.transport.connect().await?;
         stream
-            .write_all(b"\n")
+            .write_all(format!("SUBSCRIBE {}\n", prefix).as_bytes())
+            .await?;
+        stream.flush().await?;
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if stream.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+
+            let line = line.trim();
+            if line.starts_with("ERROR") {
+                return Err(StoreError::UnavailableError(line.to_string()));
+            }
+            if line != "OK" {
+                on_notification(line.parse()?);
+            }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Connection for BufReader<Stream> {
+    // Send a single command line and read back its single-line response
+    async fn exchange(&mut self, command: &str) -> Result<String> {
+        // Send command
+        self.write_all(command.as_bytes())
             .await
-            .map_err(|e| StoreError::IoError(e))?;
-        stream.flush().await.map_err(|e| StoreError::IoError(e))?;
+            .map_err(StoreError::IoError)?;
+        self.write_all(b"\n").await.map_err(StoreError::IoError)?;
+        self.flush().await.map_err(StoreError::IoError)?;
 
         // Read response
-        let mut reader = BufReader::new(stream);
         let mut response = String::new();
-        reader
-            .read_line(&mut response)
+        self.read_line(&mut response)
             .await
-            .map_err(|e| StoreError::IoError(e))?;
+            .map_err(StoreError::IoError)?;
 
         Ok(response.trim().to_string())
     }
+}
+
+// Convience methods
+impl<T: Transport> Client<T> {
+    // A client over some other transport
+    pub fn with_transport(transport: T) -> Self {
+        Client { transport }
+    }
+
+    pub async fn send_command(&self, command: &str) -> Result<String> {
+        self.transport.send_command(command).await
+    }
 
-    // Convience methods
     pub async fn get(&self, key: &str) -> Result<Option<String>> {
         let response = self.send_command(&format!("GET {}", key)).await?;
 
@@ -55,22 +247,292 @@ impl Client {
         }
     }
 
+    // Values of several keys in one round trip, None for those that don't
+    // exist
+    pub async fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        let response = self
+            .send_command(&format!("MGET {}", keys.join(" ")))
+            .await?;
+        serde_json::from_str(&response).map_err(|_| StoreError::SerializationError(response))
+    }
+
+    // Store several values in one round trip, all at once. Values can't
+    // contain whitespace.
+    pub async fn put_many(&self, entries: &[(&str, &str)]) -> Result<()> {
+        let mut command = "MSET".to_string();
+        for (key, value) in entries {
+            if value.is_empty() || value.contains(char::is_whitespace) {
+                return Err(StoreError::ValueError(format!(
+                    "the value for '{}' can't be sent with MSET",
+                    key
+                )));
+            }
+            command += &format!(" {} {}", key, value);
+        }
+        let response = self.send_command(&command).await?;
+
+        if response == "OK" {
+            Ok(())
+        } else if response.starts_with(TRY_AGAIN) {
+            Err(StoreError::UnavailableError(response))
+        } else {
+            Err(StoreError::SerializationError(response))
+        }
+    }
+
+    // A key's value with its version, to pass to `cas`
+    pub async fn get_versioned(&self, key: &str) -> Result<Option<(String, u64)>> {
+        let response = self
+            .send_command(&format!("GET {} WITHVERSION", key))
+            .await?;
+        if response == "Key not found" {
+            return Ok(None);
+        }
+        response
+            .split_once(' ')
+            .and_then(|(version, value)| Some((value.to_string(), version.parse().ok()?)))
+            .map(Some)
+            .ok_or(StoreError::SerializationError(response))
+    }
+
+    // Store a value only if the key is still at `version` (0 meaning it
+    // doesn't exist). Returns whether it was stored.
+    pub async fn cas(&self, key: &str, version: u64, value: &str) -> Result<bool> {
+        let response = self
+            .send_command(&format!("CAS {} {} {}", key, version, value))
+            .await?;
+        applied_response(response)
+    }
+
+    // Read through the primary once it has confirmed it is still the
+    // primary, so the value can't be stale after an unnoticed failover
+    pub async fn get_linearizable(&self, key: &str) -> Result<Option<String>> {
+        let response = self
+            .send_command(&format!("GET {} LINEARIZABLE", key))
+            .await?;
+
+        if response.starts_with(TRY_AGAIN) {
+            Err(StoreError::UnavailableError(response))
+        } else if response.starts_with("ERROR") {
+            Err(StoreError::ReplicationError(response))
+        } else if response == "Key not found" {
+            Ok(None)
+        } else {
+            Ok(Some(response))
+        }
+    }
+
     pub async fn put(&self, key: &str, value: &str) -> Result<()> {
         let response = self.send_command(&format!("PUT {} {}", key, value)).await?;
 
         if response == "OK" {
             Ok(())
+        } else if response.starts_with(TRY_AGAIN) {
+            Err(StoreError::UnavailableError(response))
         } else {
             Err(StoreError::SerializationError(response))
         }
     }
 
+    // Store a value only if the key doesn't exist. Returns whether it was
+    // stored.
+    pub async fn put_if_absent(&self, key: &str, value: &str) -> Result<bool> {
+        let response = self
+            .send_command(&format!("SETNX {} {}", key, value))
+            .await?;
+        applied_response(response)
+    }
+
+    // Store a value that expires after `seconds`
+    pub async fn put_with_ttl(&self, key: &str, value: &str, seconds: u64) -> Result<()> {
+        self.put(key, &format!("{} EX {}", value, seconds)).await
+    }
+
+    // Make an existing key expire after `seconds`. False if it doesn't exist.
+    pub async fn expire(&self, key: &str, seconds: u64) -> Result<bool> {
+        let response = self
+            .send_command(&format!("EXPIRE {} {}", key, seconds))
+            .await?;
+        applied_response(response)
+    }
+
+    // Make an existing key expire at a unix time in seconds. False if it
+    // doesn't exist.
+    pub async fn expire_at(&self, key: &str, unix_secs: u64) -> Result<bool> {
+        let response = self
+            .send_command(&format!("EXPIREAT {} {}", key, unix_secs))
+            .await?;
+        applied_response(response)
+    }
+
+    // Remove a key's expiry time. False if it doesn't exist or has none.
+    pub async fn persist(&self, key: &str) -> Result<bool> {
+        let response = self.send_command(&format!("PERSIST {}", key)).await?;
+        applied_response(response)
+    }
+
+    // Count a key as just used and, if it has an expiry time, push it back to
+    // `seconds` from now. False if the key doesn't exist.
+    pub async fn touch(&self, key: &str, seconds: Option<u64>) -> Result<bool> {
+        let command = match seconds {
+            Some(seconds) => format!("TOUCH {} {}", key, seconds),
+            None => format!("TOUCH {}", key),
+        };
+        let response = self.send_command(&command).await?;
+        applied_response(response)
+    }
+
+    // Seconds until a key expires: -1 if it never does, -2 if it doesn't exist
+    pub async fn ttl(&self, key: &str) -> Result<i64> {
+        let response = self.send_command(&format!("TTL {}", key)).await?;
+        response
+            .parse()
+            .map_err(|_| StoreError::SerializationError(response))
+    }
+
+    // Add `delta` to the integer a key holds, a missing key counting as 0,
+    // and return the result
+    pub async fn increment(&self, key: &str, delta: i64) -> Result<i64> {
+        let response = self
+            .send_command(&format!("INCRBY {} {}", key, delta))
+            .await?;
+        if response.starts_with(TRY_AGAIN) {
+            return Err(StoreError::UnavailableError(response));
+        }
+        response
+            .parse()
+            .map_err(|_| StoreError::SerializationError(response))
+    }
+
+    // Add `suffix` to the end of a key's value, a missing key counting as
+    // empty, and return the new length in bytes
+    pub async fn append(&self, key: &str, suffix: &str) -> Result<usize> {
+        let response = self
+            .send_command(&format!("APPEND {} {}", key, suffix))
+            .await?;
+        length_response(response)
+    }
+
+    // Length in bytes of a key's value, 0 if it doesn't exist
+    pub async fn strlen(&self, key: &str) -> Result<usize> {
+        let response = self.send_command(&format!("STRLEN {}", key)).await?;
+        length_response(response)
+    }
+
+    // Store a value and return the one it replaced, None if the key didn't
+    // exist
+    pub async fn get_set(&self, key: &str, value: &str) -> Result<Option<String>> {
+        let response = self
+            .send_command(&format!("GETSET {} {}", key, value))
+            .await?;
+        taken_response(response)
+    }
+
+    // Return a key's value and delete it, None if it didn't exist
+    pub async fn get_del(&self, key: &str) -> Result<Option<String>> {
+        let response = self.send_command(&format!("GETDEL {}", key)).await?;
+        taken_response(response)
+    }
+
+    // Move a key's value and expiry time to a new name, replacing whatever
+    // that held. False if the key doesn't exist.
+    pub async fn rename(&self, src: &str, dst: &str) -> Result<bool> {
+        let response = self
+            .send_command(&format!("RENAME {} {}", src, dst))
+            .await?;
+        applied_response(response)
+    }
+
+    // Copy a key's value and expiry time to another key, which is only
+    // overwritten if `replace` is set. Returns whether it was copied.
+    pub async fn copy(&self, src: &str, dst: &str, replace: bool) -> Result<bool> {
+        let mut command = format!("COPY {} {}", src, dst);
+        if replace {
+            command += " REPLACE";
+        }
+        let response = self.send_command(&command).await?;
+        applied_response(response)
+    }
+
+    // Delete several keys at once, returning how many there were
+    pub async fn delete_many(&self, keys: &[&str]) -> Result<usize> {
+        if let [key] = keys {
+            return Ok(usize::from(self.delete(key).await?));
+        }
+        let response = self
+            .send_command(&format!("DELETE {}", keys.join(" ")))
+            .await?;
+        length_response(response)
+    }
+
     pub async fn delete(&self, key: &str) -> Result<bool> {
         let response = self.send_command(&format!("DELETE {}", key)).await?;
 
+        if response.starts_with(TRY_AGAIN) {
+            return Err(StoreError::UnavailableError(response));
+        }
         Ok(response == "OK")
     }
 
+    // Fetch the given entries, or every entry if `keys` is empty
+    pub async fn dump(&self, keys: &[String]) -> Result<HashMap<String, String>> {
+        let command = if keys.is_empty() {
+            "DUMP".to_string()
+        } else {
+            format!("DUMP {}", keys.join(" "))
+        };
+        let response = self.send_command(&command).await?;
+
+        if response.starts_with("ERROR") {
+            return Err(StoreError::ReplicationError(response));
+        }
+        serde_json::from_str(&response).map_err(|e| StoreError::SerializationError(e.to_string()))
+    }
+
+    // Number of keys, counted on the server
+    pub async fn db_size(&self) -> Result<usize> {
+        let response = self.send_command("DBSIZE").await?;
+        length_response(response)
+    }
+
+    // Number of keys and the bytes their values take up
+    pub async fn db_size_bytes(&self) -> Result<(usize, usize)> {
+        let response = self.send_command("DBSIZE BYTES").await?;
+        if response.starts_with(TRY_AGAIN) {
+            return Err(StoreError::UnavailableError(response));
+        }
+        response
+            .split_once(' ')
+            .and_then(|(keys, bytes)| Some((keys.parse().ok()?, bytes.parse().ok()?)))
+            .ok_or(StoreError::SerializationError(response))
+    }
+
+    // One page of keys matching the glob `pattern`, starting from `cursor`
+    // (0 for the first page), and the cursor for the next page, 0 once every
+    // key has been seen. Pages can be short or empty before the end.
+    pub async fn scan(
+        &self,
+        cursor: u64,
+        pattern: Option<&str>,
+        count: Option<usize>,
+    ) -> Result<(u64, Vec<String>)> {
+        let mut command = format!("SCAN {}", cursor);
+        if let Some(pattern) = pattern {
+            command += &format!(" MATCH {}", pattern);
+        }
+        if let Some(count) = count {
+            command += &format!(" COUNT {}", count);
+        }
+        let response = self.send_command(&command).await?;
+        if response.starts_with(TRY_AGAIN) {
+            return Err(StoreError::UnavailableError(response));
+        }
+        response
+            .split_once(' ')
+            .and_then(|(next, keys)| Some((next.parse().ok()?, serde_json::from_str(keys).ok()?)))
+            .ok_or(StoreError::SerializationError(response))
+    }
+
     pub async fn keys(&self) -> Result<Vec<String>> {
         let response = self.send_command("KEYS").await?;
 
@@ -82,10 +544,44 @@ impl Client {
     }
 }
 
+// Whether a write that only goes ahead under some condition did, replied
+// as OK or NULL
+fn applied_response(response: String) -> Result<bool> {
+    match response.as_str() {
+        "OK" => Ok(true),
+        "NULL" => Ok(false),
+        _ if response.starts_with(TRY_AGAIN) => Err(StoreError::UnavailableError(response)),
+        _ => Err(StoreError::SerializationError(response)),
+    }
+}
+
+// The value a write replaced or removed
+fn taken_response(response: String) -> Result<Option<String>> {
+    if response == "Key not found" {
+        Ok(None)
+    } else if response.starts_with(TRY_AGAIN) {
+        Err(StoreError::UnavailableError(response))
+    } else if response.starts_with("ERROR") {
+        Err(StoreError::SerializationError(response))
+    } else {
+        Ok(Some(response))
+    }
+}
+
+fn length_response(response: String) -> Result<usize> {
+    if response.starts_with(TRY_AGAIN) {
+        return Err(StoreError::UnavailableError(response));
+    }
+    response
+        .parse()
+        .map_err(|_| StoreError::SerializationError(response))
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
 
+    use crate::network::Server;
     use crate::store::KeyValueStore;
 
     use super::*;
@@ -105,7 +601,7 @@ mod tests {
             let server = Server::new(Arc::clone(&store), server_addr.clone());
 
             let _server_handle = tokio::spawn(async move {
-                let _ = server.run().await.unwrap();
+                server.run().await.unwrap();
             });
 
             // Give the server a moement to start
@@ -124,11 +620,87 @@ mod tests {
             let keys = client.keys().await.unwrap();
             assert!(keys.contains(&"network_key".to_string()));
 
-            assert_eq!(client.delete("network_key").await.unwrap(), true);
+            assert!(client.delete("network_key").await.unwrap());
             assert_eq!(client.get("network_key").await.unwrap(), None);
 
+            assert_eq!(client.append("log", "a b").await.unwrap(), 3);
+            assert_eq!(client.append("log", "c").await.unwrap(), 4);
+            assert_eq!(client.strlen("log").await.unwrap(), 4);
+            assert_eq!(client.strlen("missing").await.unwrap(), 0);
+            assert_eq!(client.increment("hits", 3).await.unwrap(), 3);
+            assert_eq!(
+                client.get_set("hits", "0").await.unwrap().as_deref(),
+                Some("3")
+            );
+            assert_eq!(client.get_del("hits").await.unwrap().as_deref(), Some("0"));
+            assert_eq!(client.get_del("hits").await.unwrap(), None);
+            assert!(client.put_if_absent("lock", "a").await.unwrap());
+            assert!(!client.put_if_absent("lock", "b").await.unwrap());
+            client
+                .put_many(&[("batch:a", "1"), ("batch:b", "2")])
+                .await
+                .unwrap();
+            assert_eq!(
+                client
+                    .get_many(&["batch:b", "batch:c", "batch:a"])
+                    .await
+                    .unwrap(),
+                [Some("2".to_string()), None, Some("1".to_string())]
+            );
+            assert!(client.put_many(&[("batch:c", "x y")]).await.is_err());
+            assert_eq!(
+                client
+                    .delete_many(&["batch:a", "batch:c", "batch:b"])
+                    .await
+                    .unwrap(),
+                2
+            );
+            let (value, version) = client.get_versioned("lock").await.unwrap().unwrap();
+            assert_eq!((value.as_str(), version), ("a", 1));
+            assert!(client.cas("lock", version, "b c").await.unwrap());
+            assert!(!client.cas("lock", version, "d").await.unwrap());
+            assert_eq!(
+                client.get_versioned("lock").await.unwrap(),
+                Some(("b c".to_string(), 2))
+            );
+            assert!(client.increment("log", 1).await.is_err());
+            assert_eq!(client.db_size().await.unwrap(), 2);
+            assert_eq!(client.db_size_bytes().await.unwrap(), (2, 7));
+            let (next, keys) = client.scan(0, Some("lo?"), Some(10)).await.unwrap();
+            assert_eq!((next, keys), (0, vec!["log".to_string()]));
+
             // Server is not stopped in this test. It will run until the test completes
             // server_handle.abort();
         })
     }
+
+    // Answers each command from a script and records what was sent
+    struct ScriptedTransport {
+        sent: std::sync::Mutex<Vec<String>>,
+        responses: std::sync::Mutex<Vec<&'static str>>,
+    }
+
+    impl Transport for ScriptedTransport {
+        async fn send_command(&self, command: &str) -> Result<String> {
+            self.sent.lock().unwrap().push(command.to_string());
+            Ok(self.responses.lock().unwrap().remove(0).to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_transport() {
+        let client = Client::with_transport(ScriptedTransport {
+            sent: Default::default(),
+            responses: std::sync::Mutex::new(vec!["OK", "value", "Key not found", "a b"]),
+        });
+
+        client.put("key", "value").await.unwrap();
+        assert_eq!(client.get("key").await.unwrap(), Some("value".to_string()));
+        assert_eq!(client.get("missing").await.unwrap(), None);
+        assert_eq!(client.keys().await.unwrap(), ["a", "b"]);
+        assert_eq!(
+            *client.transport.sent.lock().unwrap(),
+            ["PUT key value", "GET key", "GET missing", "KEYS"]
+        );
+    }
 }
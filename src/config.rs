@@ -0,0 +1,399 @@
+// src/config.rs
+
+// Server configuration file. Everything in here can be reloaded at runtime
+// (SIGHUP or CONFIG RELOAD) without restarting the server.
+
+use crate::backup::BackupSink;
+use crate::error::{Result, StoreError};
+use crate::eviction::EvictionPolicy;
+use crate::logging::{LogLevel, LogOutput};
+use crate::quota::QuotaLimits;
+use crate::snapshot::SavePolicy;
+use crate::statsd::{self, StatsdSettings};
+use crate::tenant::{self, TenantConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Minimum level of log messages to print
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<LogLevel>,
+
+    // Where log messages go: console, syslog or journald
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_output: Option<LogOutput>,
+
+    // How often a primary sends heartbeats to its backups
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heartbeat_interval_ms: Option<u64>,
+
+    // Phi suspicion level at which a backup declares the primary dead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phi_threshold: Option<f64>,
+
+    // Silence past the usual heartbeat interval a backup tolerates before it
+    // starts suspecting the primary
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acceptable_pause_ms: Option<u64>,
+
+    // Cap on memory used by keys and values, in bytes (0 = unlimited)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maxmemory: Option<u64>,
+
+    // Caps on the number of keys and on key length in bytes (0 = unlimited)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_keys: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_key_length: Option<usize>,
+
+    // Client commands executing at once (0 = unlimited), and how many more
+    // may wait for a turn before the server answers busy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_commands: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_queued_commands: Option<usize>,
+
+    // Values at least this many bytes long are stored compressed (0 = never)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression_threshold: Option<u64>,
+
+    // Values at least this many bytes long are kept in files next to the
+    // database instead of in memory (0 = never)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spill_threshold: Option<u64>,
+
+    // Memory values may use before the least read ones are moved to files
+    // next to the database (0 = no tiering)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_budget: Option<u64>,
+
+    // Track a vector clock per key, so writes made concurrently on
+    // different nodes are kept as siblings instead of one replacing the other
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vector_clocks: Option<bool>,
+
+    // What to evict when maxmemory is reached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maxmemory_policy: Option<EvictionPolicy>,
+
+    // Limits on key prefixes, e.g. "tenantA:*": {"max_memory": 104857600}
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quotas: Option<BTreeMap<String, QuotaLimits>>,
+
+    // Tenants by name, each with its own token, keyspace and limits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenants: Option<BTreeMap<String, TenantConfig>>,
+
+    // Automatic save rules, e.g. "900 1 60 1000"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub save: Option<SavePolicy>,
+
+    // Number of timestamped snapshot files to keep
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot_retention: Option<usize>,
+
+    // Also keep the newest snapshot from each of this many days
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot_retention_days: Option<u64>,
+
+    // Save once the write-ahead log grows past this many bytes, which
+    // compacts it (0 = never)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wal_rewrite_size: Option<u64>,
+
+    // How long a write-ahead log fsync waits for more writes to share it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wal_commit_delay_us: Option<u64>,
+
+    // Seal write-ahead log segments at this many bytes (0 = only on saves),
+    // and keep this many of them once a save covers them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wal_segment_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wal_segment_retention: Option<usize>,
+
+    // Upload each snapshot to s3://bucket/prefix, on `backup_endpoint` for
+    // S3-compatible stores, or run `backup_command` with {file} and {name}
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup_target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup_endpoint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup_command: Option<String>,
+
+    // Token required to run admin commands
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<String>,
+
+    // Push metrics to a StatsD daemon at host:port, named `statsd_prefix`.*
+    // and sent every `statsd_flush_interval_ms`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statsd_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statsd_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statsd_flush_interval_ms: Option<u64>,
+}
+
+// Parameters that can be read and changed with CONFIG GET/SET
+pub const RUNTIME_PARAMS: &[&str] = &[
+    "log_level",
+    "log_output",
+    "heartbeat_interval_ms",
+    "phi_threshold",
+    "acceptable_pause_ms",
+    "maxmemory",
+    "maxmemory_policy",
+    "max_keys",
+    "max_key_length",
+    "max_concurrent_commands",
+    "max_queued_commands",
+    "compression_threshold",
+    "spill_threshold",
+    "memory_budget",
+    "vector_clocks",
+    "save",
+    "snapshot_retention",
+    "snapshot_retention_days",
+    "wal_rewrite_size",
+    "wal_commit_delay_us",
+    "wal_segment_size",
+    "wal_segment_retention",
+];
+
+impl Config {
+    // Read a JSON config file
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let config: Config = serde_json::from_str(&contents).map_err(|e| {
+            StoreError::ConfigError(format!("Invalid config file {}: {}", path.display(), e))
+        })?;
+        config.backup_sink()?;
+        if let Some(tenants) = &config.tenants {
+            tenant::validate(tenants, config.admin_token.as_deref())?;
+        }
+        Ok(config)
+    }
+
+    // Where to push metrics, None if no StatsD address is given
+    pub fn statsd_settings(&self) -> Option<StatsdSettings> {
+        Some(StatsdSettings {
+            address: self.statsd_address.clone()?,
+            prefix: self
+                .statsd_prefix
+                .clone()
+                .unwrap_or_else(|| statsd::DEFAULT_PREFIX.to_string()),
+            flush_interval: self
+                .statsd_flush_interval_ms
+                .map_or(statsd::DEFAULT_FLUSH_INTERVAL, Duration::from_millis),
+        })
+    }
+
+    // Where snapshots should be uploaded, None if no backup settings are given
+    pub fn backup_sink(&self) -> Result<Option<BackupSink>> {
+        BackupSink::from_settings(
+            self.backup_target.as_deref(),
+            self.backup_endpoint.as_deref(),
+            self.backup_command.as_deref(),
+        )
+    }
+
+    // Write the config back to a JSON file
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self)
+            .map_err(|e| StoreError::SerializationError(e.to_string()))?;
+        fs::write(path, contents + "\n")?;
+        Ok(())
+    }
+
+    // Value of a runtime parameter, None if it is unknown or unset
+    pub fn get(&self, param: &str) -> Option<String> {
+        match param {
+            "log_level" => self.log_level.map(|level| level.to_string()),
+            "log_output" => self.log_output.map(|output| output.to_string()),
+            "heartbeat_interval_ms" => self.heartbeat_interval_ms.map(|ms| ms.to_string()),
+            "phi_threshold" => self.phi_threshold.map(|phi| phi.to_string()),
+            "acceptable_pause_ms" => self.acceptable_pause_ms.map(|ms| ms.to_string()),
+            "maxmemory" => self.maxmemory.map(|bytes| bytes.to_string()),
+            "maxmemory_policy" => self.maxmemory_policy.map(|policy| policy.to_string()),
+            "max_keys" => self.max_keys.map(|count| count.to_string()),
+            "max_key_length" => self.max_key_length.map(|bytes| bytes.to_string()),
+            "max_concurrent_commands" => self.max_concurrent_commands.map(|n| n.to_string()),
+            "max_queued_commands" => self.max_queued_commands.map(|n| n.to_string()),
+            "compression_threshold" => self.compression_threshold.map(|bytes| bytes.to_string()),
+            "spill_threshold" => self.spill_threshold.map(|bytes| bytes.to_string()),
+            "memory_budget" => self.memory_budget.map(|bytes| bytes.to_string()),
+            "vector_clocks" => self.vector_clocks.map(|on| on.to_string()),
+            "save" => self.save.as_ref().map(|policy| policy.to_string()),
+            "snapshot_retention" => self.snapshot_retention.map(|count| count.to_string()),
+            "snapshot_retention_days" => self.snapshot_retention_days.map(|days| days.to_string()),
+            "wal_rewrite_size" => self.wal_rewrite_size.map(|bytes| bytes.to_string()),
+            "wal_commit_delay_us" => self.wal_commit_delay_us.map(|us| us.to_string()),
+            "wal_segment_size" => self.wal_segment_size.map(|bytes| bytes.to_string()),
+            "wal_segment_retention" => self.wal_segment_retention.map(|count| count.to_string()),
+            _ => None,
+        }
+    }
+
+    // Parse and set a runtime parameter
+    pub fn set(&mut self, param: &str, value: &str) -> Result<()> {
+        let invalid = |e: String| {
+            StoreError::ConfigError(format!("Invalid value '{}' for {}: {}", value, param, e))
+        };
+
+        match param {
+            "log_level" => self.log_level = Some(value.parse().map_err(invalid)?),
+            "log_output" => self.log_output = Some(value.parse().map_err(invalid)?),
+            "heartbeat_interval_ms" => {
+                let ms: u64 = value.parse().map_err(|e| invalid(format!("{}", e)))?;
+                if ms == 0 {
+                    return Err(invalid("must be positive".to_string()));
+                }
+                self.heartbeat_interval_ms = Some(ms);
+            }
+            "phi_threshold" => {
+                let phi: f64 = value.parse().map_err(|e| invalid(format!("{}", e)))?;
+                if phi <= 0.0 {
+                    return Err(invalid("must be positive".to_string()));
+                }
+                self.phi_threshold = Some(phi);
+            }
+            "acceptable_pause_ms" => {
+                self.acceptable_pause_ms =
+                    Some(value.parse().map_err(|e| invalid(format!("{}", e)))?)
+            }
+            "maxmemory" => self.maxmemory = Some(parse_bytes(value).map_err(invalid)?),
+            "maxmemory_policy" => self.maxmemory_policy = Some(value.parse().map_err(invalid)?),
+            "compression_threshold" => {
+                self.compression_threshold = Some(parse_bytes(value).map_err(invalid)?)
+            }
+            "spill_threshold" => self.spill_threshold = Some(parse_bytes(value).map_err(invalid)?),
+            "memory_budget" => self.memory_budget = Some(parse_bytes(value).map_err(invalid)?),
+            "vector_clocks" => {
+                self.vector_clocks = Some(value.parse().map_err(|e| invalid(format!("{}", e)))?)
+            }
+            "save" => self.save = Some(value.parse().map_err(invalid)?),
+            "max_keys" => {
+                self.max_keys = Some(value.parse().map_err(|e| invalid(format!("{}", e)))?)
+            }
+            "max_key_length" => {
+                self.max_key_length = Some(value.parse().map_err(|e| invalid(format!("{}", e)))?)
+            }
+            "max_concurrent_commands" => {
+                self.max_concurrent_commands =
+                    Some(value.parse().map_err(|e| invalid(format!("{}", e)))?)
+            }
+            "max_queued_commands" => {
+                self.max_queued_commands =
+                    Some(value.parse().map_err(|e| invalid(format!("{}", e)))?)
+            }
+            "snapshot_retention" => {
+                self.snapshot_retention =
+                    Some(value.parse().map_err(|e| invalid(format!("{}", e)))?)
+            }
+            "snapshot_retention_days" => {
+                self.snapshot_retention_days =
+                    Some(value.parse().map_err(|e| invalid(format!("{}", e)))?)
+            }
+            "wal_rewrite_size" => {
+                self.wal_rewrite_size = Some(parse_bytes(value).map_err(invalid)?)
+            }
+            "wal_commit_delay_us" => {
+                self.wal_commit_delay_us =
+                    Some(value.parse().map_err(|e| invalid(format!("{}", e)))?)
+            }
+            "wal_segment_size" => {
+                self.wal_segment_size = Some(parse_bytes(value).map_err(invalid)?)
+            }
+            "wal_segment_retention" => {
+                self.wal_segment_retention =
+                    Some(value.parse().map_err(|e| invalid(format!("{}", e)))?)
+            }
+            _ => {
+                return Err(StoreError::ConfigError(format!(
+                    "Unknown parameter '{}'",
+                    param
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Parse a byte size such as "1048576", "512kb", "100mb" or "2gb"
+pub fn parse_bytes(value: &str) -> std::result::Result<u64, String> {
+    let lower = value.trim().to_lowercase();
+    let (digits, multiplier) = if let Some(n) = lower.strip_suffix("gb") {
+        (n, 1024 * 1024 * 1024)
+    } else if let Some(n) = lower.strip_suffix("mb") {
+        (n, 1024 * 1024)
+    } else if let Some(n) = lower.strip_suffix("kb") {
+        (n, 1024)
+    } else if let Some(n) = lower.strip_suffix('b') {
+        (n, 1)
+    } else {
+        (lower.as_str(), 1)
+    };
+
+    digits
+        .trim()
+        .parse::<u64>()
+        .map(|n| n * multiplier)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_load_config() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("config.json");
+
+        fs::write(&path, r#"{"log_level": "debug", "phi_threshold": 12.0}"#)?;
+        let config = Config::load(&path)?;
+        assert_eq!(config.log_level, Some(LogLevel::Debug));
+        assert_eq!(config.phi_threshold, Some(12.0));
+        assert_eq!(config.heartbeat_interval_ms, None);
+
+        // Typos are reported instead of being silently ignored
+        fs::write(&path, r#"{"phi_treshold": 12.0}"#)?;
+        assert!(matches!(
+            Config::load(&path),
+            Err(StoreError::ConfigError(_))
+        ));
+        fs::write(&path, r#"{"backup_target": "/mnt/backups"}"#)?;
+        assert!(matches!(
+            Config::load(&path),
+            Err(StoreError::ConfigError(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_runtime_params() {
+        let mut config = Config::default();
+
+        config.set("maxmemory", "100mb").unwrap();
+        config.set("log_level", "warn").unwrap();
+        assert_eq!(config.get("maxmemory"), Some("104857600".to_string()));
+        assert_eq!(config.get("log_level"), Some("warn".to_string()));
+        assert_eq!(config.get("phi_threshold"), None);
+
+        assert!(config.set("phi_threshold", "-1").is_err());
+        assert!(config.set("heartbeat_interval_ms", "soon").is_err());
+        assert!(config.set("no_such_param", "1").is_err());
+
+        config.set("save", "900 1 60 1000").unwrap();
+        assert_eq!(config.get("save"), Some("900 1 60 1000".to_string()));
+        assert!(config.set("save", "900").is_err());
+    }
+}
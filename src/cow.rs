@@ -0,0 +1,234 @@
+// src/cow.rs
+
+// A hash map split into shards that are shared with snapshots. Taking a
+// snapshot only clones a reference to each shard, so it is cheap however
+// large the map is. The first write to a shard that a snapshot still shares
+// copies that shard alone, leaving the snapshot unchanged, so a save or full
+// sync reading a snapshot never holds up writes for longer than one shard
+// copy.
+
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::Arc;
+
+const SHARDS: usize = 64;
+const SHARD_BITS: u32 = SHARDS.trailing_zeros();
+
+type Shard<V> = Arc<HashMap<String, V>>;
+
+pub struct CowMap<V> {
+    shards: Box<[Shard<V>]>,
+    hasher: RandomState,
+    len: usize,
+}
+
+// The contents of a CowMap at the moment it was taken
+pub struct Snapshot<V> {
+    shards: Box<[Shard<V>]>,
+    hasher: RandomState,
+    len: usize,
+}
+
+impl<V> Default for CowMap<V> {
+    fn default() -> Self {
+        CowMap {
+            shards: (0..SHARDS).map(|_| Arc::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<V: Clone> CowMap<V> {
+    fn shard(&self, key: &str) -> usize {
+        self.hasher.hash_one(key) as usize % SHARDS
+    }
+
+    // The shard holding `key`, copied first if a snapshot shares it
+    fn shard_mut(&mut self, key: &str) -> &mut HashMap<String, V> {
+        let shard = self.shard(key);
+        Arc::make_mut(&mut self.shards[shard])
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.shards[self.shard(key)].get(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        if !self.contains_key(key) {
+            return None;
+        }
+        self.shard_mut(key).get_mut(key)
+    }
+
+    pub fn insert(&mut self, key: String, value: V) -> Option<V> {
+        let old = self.shard_mut(&key).insert(key, value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        if !self.contains_key(key) {
+            return None;
+        }
+        let old = self.shard_mut(key).remove(key);
+        self.len -= usize::from(old.is_some());
+        old
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    // Copies every shard a snapshot still shares
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&String, &mut V)> {
+        self.shards
+            .iter_mut()
+            .flat_map(|shard| Arc::make_mut(shard).iter_mut())
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    pub fn snapshot(&self) -> Snapshot<V> {
+        Snapshot {
+            shards: self.shards.clone(),
+            hasher: self.hasher.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<V> Snapshot<V> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.shards[self.hasher.hash_one(key) as usize % SHARDS].get(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    // Where a key comes in a scan: ordered by shard, then by the rest of its
+    // hash. Stays put for as long as the map lives.
+    fn position(&self, key: &str) -> u64 {
+        self.hasher.hash_one(key).rotate_right(SHARD_BITS)
+    }
+
+    // Up to `count` entries from `cursor` on (0 to start), and the cursor to
+    // carry on from, 0 once the end is reached. Scanning snapshots taken one
+    // after another returns every key present throughout at least once.
+    pub fn scan(&self, cursor: u64, count: usize) -> (Vec<(&String, &V)>, u64) {
+        let mut found = Vec::new();
+        let mut shard = (cursor >> (u64::BITS - SHARD_BITS)) as usize;
+        let mut from = cursor;
+        while shard < SHARDS {
+            let mut entries: Vec<(u64, &String, &V)> = self.shards[shard]
+                .iter()
+                .map(|(key, value)| (self.position(key), key, value))
+                .filter(|(position, ..)| *position >= from)
+                .collect();
+            entries.sort_unstable_by_key(|(position, ..)| *position);
+
+            let wanted = count - found.len();
+            if entries.len() > wanted {
+                let next = entries[wanted].0;
+                found.extend(
+                    entries[..wanted]
+                        .iter()
+                        .map(|(_, key, value)| (*key, *value)),
+                );
+                return (found, next);
+            }
+            found.extend(entries.into_iter().map(|(_, key, value)| (key, value)));
+            shard += 1;
+            from = (shard as u64) << (u64::BITS - SHARD_BITS);
+        }
+        (found, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan() {
+        let mut map = CowMap::default();
+        for i in 0..1000 {
+            map.insert(i.to_string(), i);
+        }
+
+        // Pages pick up where the last one stopped, whatever was written in
+        // between
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let snapshot = map.snapshot();
+            let (page, next) = snapshot.scan(cursor, 7);
+            assert!(page.len() <= 7);
+            seen.extend(page.into_iter().map(|(_, value)| *value));
+            map.insert(format!("new{}", seen.len()), -1);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        seen.retain(|value| *value >= 0);
+        seen.sort_unstable();
+        assert_eq!(seen, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_snapshots_are_unaffected_by_writes() {
+        let mut map = CowMap::default();
+        for i in 0..1000 {
+            map.insert(i.to_string(), i);
+        }
+        let snapshot = map.snapshot();
+
+        map.insert("0".to_string(), -1);
+        map.insert("new".to_string(), 0);
+        map.remove("1");
+        *map.get_mut("2").unwrap() = -2;
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.get("0"), Some(&-1));
+        assert_eq!(map.get("1"), None);
+        assert_eq!(map.remove("1"), None);
+
+        assert_eq!(snapshot.len(), 1000);
+        assert_eq!(snapshot.iter().count(), 1000);
+        for i in 0..3 {
+            assert_eq!(snapshot.get(&i.to_string()), Some(&i));
+        }
+        assert_eq!(snapshot.get("new"), None);
+
+        // Only the shards written to were copied
+        let shared = map
+            .shards
+            .iter()
+            .zip(snapshot.shards.iter())
+            .filter(|(live, old)| Arc::ptr_eq(live, old))
+            .count();
+        assert!(shared >= SHARDS - 4, "{} shards shared", shared);
+    }
+}
@@ -0,0 +1,394 @@
+// src/crdt.rs
+
+// Conflict-free replicated data types. Each node changes its own copy and
+// copies are merged by taking in everything either has seen, so nodes that
+// took writes to the same key independently end up with the same value in
+// whatever order they exchange them, and no write is lost or turned into a
+// sibling.
+//
+// The state of a CRDT is kept as the key's value, as `crdt:` followed by
+// JSON, so snapshots, the write-ahead log and replication carry it like any
+// other value. Reads show what the state adds up to instead.
+
+use crate::hlc::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+// Marks a value holding CRDT state
+pub const PREFIX: &str = "crdt:";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Crdt {
+    GCounter(GCounter),
+    PnCounter(PnCounter),
+    Register(LwwRegister),
+    OrSet(OrSet),
+}
+
+// A change a client makes to a CRDT
+#[derive(Debug, Clone, PartialEq)]
+pub enum Update {
+    // Add to a grow-only counter
+    Grow(u64),
+    // Add a signed amount to a PN counter
+    Count(i64),
+    // Store a value in a register
+    Assign(String),
+    // Add members to, or remove them from, an OR-set
+    Add(Vec<String>),
+    Remove(Vec<String>),
+}
+
+impl Update {
+    // Name of the type the update applies to
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Update::Grow(_) => "gcounter",
+            Update::Count(_) => "pncounter",
+            Update::Assign(_) => "register",
+            Update::Add(_) | Update::Remove(_) => "orset",
+        }
+    }
+}
+
+impl Crdt {
+    // The state stored in a value, None if it doesn't hold one
+    pub fn parse(value: &str) -> Option<Crdt> {
+        serde_json::from_str(value.strip_prefix(PREFIX)?).ok()
+    }
+
+    // An empty CRDT of the type `update` applies to
+    pub fn empty_for(update: &Update) -> Crdt {
+        match update {
+            Update::Grow(_) => Crdt::GCounter(GCounter::default()),
+            Update::Count(_) => Crdt::PnCounter(PnCounter::default()),
+            Update::Assign(_) => Crdt::Register(LwwRegister::default()),
+            Update::Add(_) | Update::Remove(_) => Crdt::OrSet(OrSet::default()),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Crdt::GCounter(_) => "gcounter",
+            Crdt::PnCounter(_) => "pncounter",
+            Crdt::Register(_) => "register",
+            Crdt::OrSet(_) => "orset",
+        }
+    }
+
+    // Make a change on `node` at `at`, a timestamp no other change on this
+    // node has. Fails if the update is for another type.
+    pub fn apply(&mut self, update: Update, node: &str, at: Timestamp) -> Result<(), String> {
+        match (self, update) {
+            (Crdt::GCounter(counter), Update::Grow(n)) => counter.add(node, n),
+            (Crdt::PnCounter(counter), Update::Count(delta)) => counter.add(node, delta),
+            (Crdt::Register(register), Update::Assign(value)) => register.assign(value, node, at),
+            (Crdt::OrSet(set), Update::Add(members)) => {
+                for member in members {
+                    set.add(member, node, at);
+                }
+            }
+            (Crdt::OrSet(set), Update::Remove(members)) => {
+                for member in &members {
+                    set.remove(member);
+                }
+            }
+            (crdt, update) => {
+                return Err(format!("holds a {}, not a {}", crdt.kind(), update.kind()));
+            }
+        }
+        Ok(())
+    }
+
+    // Take in everything `other` has seen. Fails, changing nothing, if it is
+    // of another type.
+    pub fn merge(&mut self, other: &Crdt) -> Result<(), String> {
+        match (self, other) {
+            (Crdt::GCounter(ours), Crdt::GCounter(theirs)) => ours.merge(theirs),
+            (Crdt::PnCounter(ours), Crdt::PnCounter(theirs)) => ours.merge(theirs),
+            (Crdt::Register(ours), Crdt::Register(theirs)) => ours.merge(theirs),
+            (Crdt::OrSet(ours), Crdt::OrSet(theirs)) => ours.merge(theirs),
+            (ours, theirs) => {
+                return Err(format!("holds a {}, not a {}", ours.kind(), theirs.kind()));
+            }
+        }
+        Ok(())
+    }
+
+    // What clients read: a counter's total, a register's value or an
+    // OR-set's members as a JSON array
+    pub fn value(&self) -> String {
+        match self {
+            Crdt::GCounter(counter) => counter.value().to_string(),
+            Crdt::PnCounter(counter) => counter.value().to_string(),
+            Crdt::Register(register) => register.value.clone(),
+            Crdt::OrSet(set) => serde_json::to_string(&set.members()).unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Display for Crdt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}{}", PREFIX, state)
+    }
+}
+
+// What a client reads for a stored value: what CRDT state adds up to, or
+// the value itself
+pub fn shown(value: String) -> String {
+    match Crdt::parse(&value) {
+        Some(crdt) => crdt.value(),
+        None => value,
+    }
+}
+
+// A count per node, each only ever growing, so merging takes the larger
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GCounter {
+    counts: BTreeMap<String, u64>,
+}
+
+impl GCounter {
+    fn add(&mut self, node: &str, n: u64) {
+        let count = self.counts.entry(node.to_string()).or_default();
+        *count = count.saturating_add(n);
+    }
+
+    fn merge(&mut self, other: &GCounter) {
+        for (node, &count) in &other.counts {
+            let ours = self.counts.entry(node.clone()).or_default();
+            *ours = (*ours).max(count);
+        }
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts
+            .values()
+            .fold(0, |total, &count| total.saturating_add(count))
+    }
+}
+
+// A grow-only counter of increments and another of decrements
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PnCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+impl PnCounter {
+    fn add(&mut self, node: &str, delta: i64) {
+        if delta >= 0 {
+            self.increments.add(node, delta.unsigned_abs());
+        } else {
+            self.decrements.add(node, delta.unsigned_abs());
+        }
+    }
+
+    fn merge(&mut self, other: &PnCounter) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+
+    pub fn value(&self) -> i64 {
+        let total = self.increments.value() as i128 - self.decrements.value() as i128;
+        total.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+}
+
+// A value and the hybrid logical clock time it was assigned at. The later
+// assignment wins a merge, the node name breaking ties.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LwwRegister {
+    value: String,
+    at: Timestamp,
+    node: String,
+}
+
+impl LwwRegister {
+    fn assign(&mut self, value: String, node: &str, at: Timestamp) {
+        let assigned = LwwRegister {
+            value,
+            at,
+            node: node.to_string(),
+        };
+        self.merge(&assigned);
+    }
+
+    fn merge(&mut self, other: &LwwRegister) {
+        if (other.at, &other.node) > (self.at, &self.node) {
+            *self = other.clone();
+        }
+    }
+}
+
+// An observed-remove set. Every add is tagged with the node and time it was
+// made at, and a remove only takes out the tags it has seen, so a member
+// added on one node while another removes it stays in the set.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OrSet {
+    // Tags of the adds not yet removed, per member
+    added: BTreeMap<String, BTreeSet<String>>,
+    // Tags of removed adds, per member, so merging doesn't bring them back
+    removed: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl OrSet {
+    fn add(&mut self, member: String, node: &str, at: Timestamp) {
+        let tag = format!("{}@{}", node, at);
+        self.added.entry(member).or_default().insert(tag);
+    }
+
+    fn remove(&mut self, member: &str) {
+        if let Some(tags) = self.added.remove(member) {
+            self.removed
+                .entry(member.to_string())
+                .or_default()
+                .extend(tags);
+        }
+    }
+
+    fn merge(&mut self, other: &OrSet) {
+        for (member, tags) in &other.removed {
+            self.removed
+                .entry(member.clone())
+                .or_default()
+                .extend(tags.iter().cloned());
+        }
+        for (member, tags) in &other.added {
+            self.added
+                .entry(member.clone())
+                .or_default()
+                .extend(tags.iter().cloned());
+        }
+        let removed = &self.removed;
+        self.added.retain(|member, tags| {
+            if let Some(removed) = removed.get(member) {
+                tags.retain(|tag| !removed.contains(tag));
+            }
+            !tags.is_empty()
+        });
+    }
+
+    // Members in sorted order
+    pub fn members(&self) -> Vec<&str> {
+        self.added.keys().map(String::as_str).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(wall: u64) -> Timestamp {
+        Timestamp { wall, logical: 0 }
+    }
+
+    // Two copies changed independently, merged both ways
+    fn merged(a: &Crdt, b: &Crdt) -> (Crdt, Crdt) {
+        let (mut ab, mut ba) = (a.clone(), b.clone());
+        ab.merge(b).unwrap();
+        ba.merge(a).unwrap();
+        (ab, ba)
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut a = Crdt::empty_for(&Update::Grow(0));
+        let mut b = a.clone();
+        a.apply(Update::Grow(3), "a", at(1)).unwrap();
+        b.apply(Update::Grow(2), "b", at(1)).unwrap();
+        let (ab, ba) = merged(&a, &b);
+        assert_eq!(ab, ba);
+        assert_eq!(ab.value(), "5");
+
+        // Merging again, or merging what was already seen, changes nothing
+        let mut again = ab.clone();
+        again.merge(&a).unwrap();
+        again.merge(&ab).unwrap();
+        assert_eq!(again, ab);
+
+        let mut a = Crdt::empty_for(&Update::Count(0));
+        let mut b = a.clone();
+        a.apply(Update::Count(10), "a", at(1)).unwrap();
+        b.apply(Update::Count(-4), "b", at(1)).unwrap();
+        a.apply(Update::Count(-1), "a", at(2)).unwrap();
+        let (ab, ba) = merged(&a, &b);
+        assert_eq!(ab, ba);
+        assert_eq!(ab.value(), "5");
+
+        assert!(a.apply(Update::Grow(1), "a", at(3)).is_err());
+        assert!(a.merge(&Crdt::empty_for(&Update::Grow(0))).is_err());
+        assert_eq!(a.value(), "9");
+    }
+
+    #[test]
+    fn test_lww_register() {
+        let mut a = Crdt::empty_for(&Update::Assign(String::new()));
+        let mut b = a.clone();
+        a.apply(Update::Assign("first".to_string()), "a", at(1))
+            .unwrap();
+        b.apply(Update::Assign("second".to_string()), "b", at(2))
+            .unwrap();
+        let (ab, ba) = merged(&a, &b);
+        assert_eq!(ab, ba);
+        assert_eq!(ab.value(), "second");
+
+        // The same time on two nodes is settled by the node name
+        let mut c = Crdt::empty_for(&Update::Assign(String::new()));
+        c.apply(Update::Assign("third".to_string()), "c", at(2))
+            .unwrap();
+        let (bc, cb) = merged(&b, &c);
+        assert_eq!(bc, cb);
+        assert_eq!(bc.value(), "third");
+
+        // An assignment older than what the register holds loses
+        let mut late = bc.clone();
+        late.apply(Update::Assign("stale".to_string()), "a", at(1))
+            .unwrap();
+        assert_eq!(late.value(), "third");
+    }
+
+    #[test]
+    fn test_or_set() {
+        let members = |members: &[&str]| members.iter().map(|m| m.to_string()).collect();
+        let mut a = Crdt::empty_for(&Update::Add(vec![]));
+        a.apply(Update::Add(members(&["x", "y", "z"])), "a", at(1))
+            .unwrap();
+        let mut b = a.clone();
+
+        // A remove only takes out the adds it has seen
+        b.apply(Update::Remove(members(&["x"])), "b", at(2))
+            .unwrap();
+        a.apply(Update::Add(members(&["x"])), "a", at(3)).unwrap();
+        b.apply(Update::Remove(members(&["y"])), "b", at(3))
+            .unwrap();
+        let (ab, ba) = merged(&a, &b);
+        assert_eq!(ab, ba);
+        assert_eq!(ab.value(), r#"["x","z"]"#);
+
+        let mut removed = ab.clone();
+        removed
+            .apply(Update::Remove(members(&["x"])), "a", at(4))
+            .unwrap();
+        let (both, _) = merged(&removed, &ab);
+        assert_eq!(both.value(), r#"["z"]"#);
+    }
+
+    #[test]
+    fn test_stored_state() {
+        let mut counter = Crdt::empty_for(&Update::Count(0));
+        counter.apply(Update::Count(-2), "a", at(1)).unwrap();
+        let value = counter.to_string();
+        assert!(value.starts_with(PREFIX));
+        assert!(!value.contains(char::is_whitespace));
+        assert_eq!(Crdt::parse(&value), Some(counter));
+        assert_eq!(shown(value), "-2");
+
+        assert_eq!(Crdt::parse("plain"), None);
+        assert_eq!(Crdt::parse("crdt:{\"type\":\"tree\"}"), None);
+        assert_eq!(shown("crdt:oops".to_string()), "crdt:oops");
+    }
+}
@@ -0,0 +1,134 @@
+// src/dev_cluster.rs
+
+// A primary and its backups running in one process on consecutive local
+// ports, for trying out replication without juggling terminals. Nodes keep
+// everything in memory and are gone when the cluster stops.
+
+use crate::error::{Result, StoreError};
+use crate::logging::log_error;
+use crate::network::Server;
+use crate::replication::ReplicationMode;
+use crate::socket::SocketOptions;
+use crate::store::KeyValueStore;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+// How long nodes get to bind their ports before we check on them
+const STARTUP_GRACE: Duration = Duration::from_millis(100);
+
+pub struct DevCluster {
+    // The primary first, then the backups
+    addresses: Vec<String>,
+    nodes: Vec<JoinHandle<()>>,
+}
+
+impl DevCluster {
+    // Start `nodes` servers on 127.0.0.1 from `base_port` up: a primary with
+    // the rest already added as its backups
+    pub async fn start(
+        nodes: usize,
+        base_port: u16,
+        mode: ReplicationMode,
+        socket_options: SocketOptions,
+    ) -> Result<Self> {
+        if nodes == 0 || base_port as usize + nodes > u16::MAX as usize + 1 {
+            return Err(StoreError::ConfigError(format!(
+                "Can't fit {} nodes from port {}",
+                nodes, base_port
+            )));
+        }
+
+        let addresses: Vec<String> = (0..nodes)
+            .map(|i| format!("127.0.0.1:{}", base_port as usize + i))
+            .collect();
+        let mut servers = Vec::new();
+        for (i, address) in addresses.iter().enumerate() {
+            let server = Server::with_replication(Arc::new(KeyValueStore::new()), address.clone())
+                .with_socket_options(socket_options.clone())
+                .with_replication_mode(mode);
+            if i == 0 {
+                server.start_as_primary().await?;
+            } else {
+                server.start_as_backup(addresses[0].clone()).await?;
+            }
+            servers.push(server);
+        }
+        for backup in &addresses[1..] {
+            servers[0].add_backup(backup.clone()).await?;
+        }
+
+        let nodes = servers
+            .into_iter()
+            .map(|server| {
+                tokio::spawn(async move {
+                    if let Err(e) = server.run().await {
+                        log_error!("Dev cluster node failed: {}", e);
+                    }
+                })
+            })
+            .collect();
+        let cluster = DevCluster { addresses, nodes };
+
+        // A node that couldn't bind its port has already stopped
+        tokio::time::sleep(STARTUP_GRACE).await;
+        if let Some(i) = cluster.nodes.iter().position(|node| node.is_finished()) {
+            let address = cluster.addresses[i].clone();
+            cluster.stop();
+            return Err(StoreError::ConfigError(format!(
+                "Node {} failed to start, is the port in use?",
+                address
+            )));
+        }
+        Ok(cluster)
+    }
+
+    pub fn primary(&self) -> &str {
+        &self.addresses[0]
+    }
+
+    pub fn backups(&self) -> &[String] {
+        &self.addresses[1..]
+    }
+
+    pub fn stop(self) {
+        for node in self.nodes {
+            node.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+
+    #[tokio::test]
+    async fn test_dev_cluster() -> Result<()> {
+        let cluster =
+            DevCluster::start(3, 7905, ReplicationMode::Push, SocketOptions::default()).await?;
+        assert_eq!(cluster.primary(), "127.0.0.1:7905");
+        assert_eq!(cluster.backups(), ["127.0.0.1:7906", "127.0.0.1:7907"]);
+
+        // Writes to the primary reach every backup without any setup
+        Client::new(cluster.primary().to_string())
+            .put("key", "value")
+            .await?;
+        for backup in cluster.backups() {
+            assert_eq!(
+                Client::new(backup.clone()).get("key").await?,
+                Some("value".to_string())
+            );
+        }
+
+        // The ports are taken now
+        assert!(
+            DevCluster::start(1, 7905, ReplicationMode::Push, SocketOptions::default())
+                .await
+                .is_err()
+        );
+
+        cluster.stop();
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+// src/discovery.rs
+
+// Where a node finds a peer, for environments where addresses change: a
+// fixed address, a DNS SRV name, or a file that something else rewrites.
+// Sources are resolved again whenever the peer stops answering.
+
+use crate::error::{Result, StoreError};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use trust_dns_resolver::TokioAsyncResolver;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerSource {
+    // host:port, used as is
+    Address(String),
+    // `srv:_kv._tcp.example.com`, resolved to its preferred target
+    Srv(String),
+    // `file:/etc/kv/primary`, whose first line that isn't blank or a
+    // comment is the address
+    File(PathBuf),
+}
+
+impl PeerSource {
+    pub async fn resolve(&self) -> Result<String> {
+        match self {
+            PeerSource::Address(address) => Ok(address.clone()),
+            PeerSource::Srv(name) => resolve_srv(name).await,
+            PeerSource::File(path) => {
+                let contents = tokio::fs::read_to_string(path).await?;
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .find(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_string)
+                    .ok_or_else(|| {
+                        StoreError::ConfigError(format!("No address in {}", path.display()))
+                    })
+            }
+        }
+    }
+}
+
+// The target with the lowest priority, then the highest weight
+async fn resolve_srv(name: &str) -> Result<String> {
+    let lookup_error = |e: trust_dns_resolver::error::ResolveError| {
+        StoreError::ConfigError(format!("SRV lookup for {} failed: {}", name, e))
+    };
+    let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(lookup_error)?;
+    let records = resolver.srv_lookup(name).await.map_err(lookup_error)?;
+
+    records
+        .iter()
+        .min_by_key(|srv| (srv.priority(), std::cmp::Reverse(srv.weight())))
+        .map(|srv| {
+            let target = srv.target().to_utf8();
+            format!("{}:{}", target.trim_end_matches('.'), srv.port())
+        })
+        .ok_or_else(|| StoreError::ConfigError(format!("No SRV records for {}", name)))
+}
+
+impl FromStr for PeerSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let source = if let Some(name) = s.strip_prefix("srv:") {
+            PeerSource::Srv(name.to_string())
+        } else if let Some(path) = s.strip_prefix("file:") {
+            PeerSource::File(PathBuf::from(path))
+        } else {
+            PeerSource::Address(s.to_string())
+        };
+
+        match &source {
+            PeerSource::Srv(name) if name.is_empty() => Err("Missing SRV name".to_string()),
+            PeerSource::File(path) if path.as_os_str().is_empty() => {
+                Err("Missing file path".to_string())
+            }
+            _ => Ok(source),
+        }
+    }
+}
+
+impl fmt::Display for PeerSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerSource::Address(address) => write!(f, "{}", address),
+            PeerSource::Srv(name) => write!(f, "srv:{}", name),
+            PeerSource::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_peer_sources() -> Result<()> {
+        let address: PeerSource = "127.0.0.1:7001".parse().unwrap();
+        assert_eq!(address.resolve().await?, "127.0.0.1:7001");
+        assert_eq!(
+            "srv:_kv._tcp.example.com".parse::<PeerSource>().unwrap(),
+            PeerSource::Srv("_kv._tcp.example.com".to_string())
+        );
+        assert!("srv:".parse::<PeerSource>().is_err());
+
+        // A file is read again on every resolve
+        let dir = tempdir()?;
+        let path = dir.path().join("primary");
+        let source: PeerSource = format!("file:{}", path.display()).parse().unwrap();
+        assert_eq!(source.to_string(), format!("file:{}", path.display()));
+        std::fs::write(&path, "# current primary\n\n10.0.0.5:7001\n")?;
+        assert_eq!(source.resolve().await?, "10.0.0.5:7001");
+        std::fs::write(&path, "10.0.0.6:7001\n")?;
+        assert_eq!(source.resolve().await?, "10.0.0.6:7001");
+        std::fs::write(&path, "# nothing yet\n")?;
+        assert!(source.resolve().await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+// src/embedded.rs
+
+// The store opened in-process, for Rust services that embed it instead of
+// running the kv-store binary. It can also serve the network protocol so
+// other processes can reach the same data.
+
+use crate::error::Result;
+use crate::network::{Server, ServerHandle};
+use crate::store::{self, DatabaseLock, KeyValueStore};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub struct EmbeddedKv {
+    store: Arc<KeyValueStore>,
+    db_path: PathBuf,
+    _lock: DatabaseLock,
+}
+
+impl EmbeddedKv {
+    // Open the database file at `path`, which is created on the first save.
+    // Like a server, this locks the file and spills large values next to it.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let db_path = path.as_ref().to_path_buf();
+        let lock = DatabaseLock::acquire(&db_path)?;
+        let store = Arc::new(KeyValueStore::load(&db_path)?);
+        store.set_spill_dir(&store::spill_dir(&db_path))?;
+
+        Ok(EmbeddedKv {
+            store,
+            db_path,
+            _lock: lock,
+        })
+    }
+
+    // The store itself, for everything beyond get/put/delete
+    pub fn store(&self) -> &Arc<KeyValueStore> {
+        &self.store
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.store.get(key)
+    }
+
+    // Set a value, subject to the store's limits
+    pub fn put(&self, key: &str, value: &str) -> Result<()> {
+        self.store.try_put(key.to_string(), value.to_string())
+    }
+
+    // Delete a key, failing if the delete can't be logged. Returns whether
+    // it existed.
+    pub fn delete(&self, key: &str) -> Result<bool> {
+        self.store.try_delete(key)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        self.store.save(&self.db_path)
+    }
+
+    // Serve the network protocol on `address` from this store. SAVE and
+    // BGSAVE write to the same database file.
+    pub async fn serve(&self, address: &str) -> Result<ServerHandle> {
+        Server::new(Arc::clone(&self.store), address.to_string())
+            .with_db_path(self.db_path.clone())
+            .spawn()
+            .await
+    }
+
+    // Save and release the database file
+    pub fn close(self) -> Result<()> {
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_embedded_store() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("embedded-db.json");
+
+        let kv = EmbeddedKv::open(&path)?;
+        assert!(EmbeddedKv::open(&path).is_err());
+        kv.put("local", "1")?;
+
+        // The same data is reachable over the network
+        let server = kv.serve("127.0.0.1:0").await?;
+        let client = Client::new(server.address().to_string());
+        assert_eq!(client.get("local").await?, Some("1".to_string()));
+        client.put("remote", "2").await?;
+        assert_eq!(kv.get("remote"), Some("2".to_string()));
+        server.shutdown();
+
+        kv.close()?;
+        let reopened = EmbeddedKv::open(&path)?;
+        assert_eq!(reopened.get("remote"), Some("2".to_string()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,502 @@
+// src/encryption.rs
+
+// Encryption of the database file and its snapshots at rest, with
+// ChaCha20-Poly1305. Keys come from a keyring file or environment variable
+// of `<key-id>:<64 hex digits>` lines. The last key encrypts new files and
+// every key can decrypt, so a key can be rotated while older snapshots stay
+// readable. An encrypted file starts with a `kv-store encrypted <key-id>
+// <nonce>` line, then chunks sealed one at a time so files can be streamed.
+// The last chunk is marked, so a truncated file is caught.
+
+use crate::error::{Result, StoreError};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use clap::Args;
+use std::fmt;
+use std::io::{self, Cursor, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock, RwLock};
+
+const MAGIC: &[u8] = b"kv-store encrypted ";
+// Plaintext bytes per chunk
+const CHUNK_SIZE: usize = 64 * 1024;
+// Random per file; the chunk counter makes up the rest of the nonce
+const NONCE_PREFIX_LEN: usize = 8;
+const LAST_CHUNK: u8 = 1;
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct EncryptionOptions {
+    // Keyring file of `<key-id>:<hex key>` lines; the last key encrypts
+    #[clap(long, conflicts_with = "encryption_key_env")]
+    pub encryption_key_file: Option<PathBuf>,
+
+    // Environment variable holding the keyring, instead of a file
+    #[clap(long)]
+    pub encryption_key_env: Option<String>,
+}
+
+impl EncryptionOptions {
+    pub fn source(&self) -> Option<KeySource> {
+        match (&self.encryption_key_file, &self.encryption_key_env) {
+            (Some(path), _) => Some(KeySource::File(path.clone())),
+            (None, Some(name)) => Some(KeySource::Env(name.clone())),
+            (None, None) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    File(PathBuf),
+    Env(String),
+}
+
+impl fmt::Display for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeySource::File(path) => write!(f, "{}", path.display()),
+            KeySource::Env(name) => write!(f, "${}", name),
+        }
+    }
+}
+
+impl KeySource {
+    fn read(&self) -> Result<Keyring> {
+        let text = match self {
+            KeySource::File(path) => std::fs::read_to_string(path)?,
+            KeySource::Env(name) => std::env::var(name).map_err(|_| {
+                StoreError::ConfigError(format!("Environment variable {} is not set", name))
+            })?,
+        };
+        Keyring::parse(&text).map_err(|e| StoreError::ConfigError(format!("{}: {}", self, e)))
+    }
+}
+
+pub struct Keyring {
+    // In file order; the last one is active
+    keys: Vec<(String, ChaCha20Poly1305)>,
+}
+
+impl Keyring {
+    pub fn parse(text: &str) -> std::result::Result<Self, String> {
+        let mut keys = Vec::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, hex) = line
+                .split_once(':')
+                .ok_or_else(|| "expected <key-id>:<hex key>".to_string())?;
+            if id.is_empty() || id.contains(char::is_whitespace) {
+                return Err(format!("invalid key id '{}'", id));
+            }
+            let key = decode_key(hex.trim())
+                .ok_or_else(|| format!("key '{}' is not 64 hex digits", id))?;
+            keys.push((id.to_string(), ChaCha20Poly1305::new(&key.into())));
+        }
+        if keys.is_empty() {
+            return Err("no keys".to_string());
+        }
+        Ok(Keyring { keys })
+    }
+
+    // The key new files are encrypted with
+    pub fn active_id(&self) -> &str {
+        &self.keys.last().unwrap().0
+    }
+
+    fn cipher(&self, id: &str) -> Option<&ChaCha20Poly1305> {
+        self.keys
+            .iter()
+            .rev()
+            .find(|(key_id, _)| key_id == id)
+            .map(|(_, cipher)| cipher)
+    }
+}
+
+fn decode_key(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+// Where keys come from and the keyring last read from there
+pub struct Encryption {
+    source: KeySource,
+    keyring: RwLock<Arc<Keyring>>,
+}
+
+impl Encryption {
+    pub fn load(source: KeySource) -> Result<Self> {
+        let keyring = source.read()?;
+        Ok(Encryption {
+            source,
+            keyring: RwLock::new(Arc::new(keyring)),
+        })
+    }
+
+    pub fn keyring(&self) -> Arc<Keyring> {
+        Arc::clone(&self.keyring.read().unwrap())
+    }
+
+    // Read the keyring again so a key added since becomes the active one,
+    // returning its id. Files already written keep their key.
+    pub fn rotate(&self) -> Result<String> {
+        let keyring = self.source.read()?;
+        let id = keyring.active_id().to_string();
+        if id == self.keyring().active_id() {
+            return Err(StoreError::ConfigError(format!(
+                "The active key is still '{}'; add a new key to {} first",
+                id, self.source
+            )));
+        }
+        *self.keyring.write().unwrap() = Arc::new(keyring);
+        Ok(id)
+    }
+}
+
+// Set once at startup, before any database file is read
+static ENCRYPTION: OnceLock<Encryption> = OnceLock::new();
+
+// Encrypt database files written from now on with keys from `source`,
+// returning the active key id
+pub fn enable(source: KeySource) -> Result<String> {
+    let encryption = Encryption::load(source)?;
+    let id = encryption.keyring().active_id().to_string();
+    ENCRYPTION
+        .set(encryption)
+        .map_err(|_| StoreError::ConfigError("Encryption is already enabled".to_string()))?;
+    Ok(id)
+}
+
+pub fn current() -> Option<&'static Encryption> {
+    ENCRYPTION.get()
+}
+
+fn keyring() -> Option<Arc<Keyring>> {
+    current().map(Encryption::keyring)
+}
+
+fn nonce(prefix: &[u8; NONCE_PREFIX_LEN], counter: u32) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..].copy_from_slice(&counter.to_be_bytes());
+    nonce.into()
+}
+
+// The header line and the chunk's flag are authenticated with each chunk
+fn aad(header: &[u8], flag: u8) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.push(flag);
+    aad
+}
+
+fn corrupt(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+// Writes a database file, encrypted when a keyring is configured.
+// `finish` must be called to write the last chunk.
+pub struct Writer<W: Write> {
+    inner: W,
+    sealer: Option<Sealer>,
+}
+
+struct Sealer {
+    cipher: ChaCha20Poly1305,
+    header: Vec<u8>,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> Writer<W> {
+    // Encrypt with the configured keyring, if there is one
+    pub fn new(inner: W) -> io::Result<Self> {
+        Self::with_keyring(inner, keyring().as_deref())
+    }
+
+    pub fn with_keyring(mut inner: W, keyring: Option<&Keyring>) -> io::Result<Self> {
+        let Some(keyring) = keyring else {
+            return Ok(Writer {
+                inner,
+                sealer: None,
+            });
+        };
+
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut prefix);
+        let id = keyring.active_id();
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(id.as_bytes());
+        header.push(b' ');
+        for byte in prefix {
+            header.extend_from_slice(format!("{:02x}", byte).as_bytes());
+        }
+        header.push(b'\n');
+        inner.write_all(&header)?;
+
+        let sealer = Sealer {
+            cipher: keyring.cipher(id).unwrap().clone(),
+            header,
+            prefix,
+            counter: 0,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        };
+        Ok(Writer {
+            inner,
+            sealer: Some(sealer),
+        })
+    }
+
+    fn seal(&mut self, flag: u8) -> io::Result<()> {
+        let sealer = self.sealer.as_mut().unwrap();
+        let payload = Payload {
+            msg: &sealer.buffer,
+            aad: &aad(&sealer.header, flag),
+        };
+        let sealed = sealer
+            .cipher
+            .encrypt(&nonce(&sealer.prefix, sealer.counter), payload)
+            .map_err(|_| io::Error::other("Encryption failed"))?;
+        sealer.counter = sealer
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| io::Error::other("File too large to encrypt"))?;
+        sealer.buffer.clear();
+
+        self.inner.write_all(&[flag])?;
+        self.inner.write_all(&(sealed.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&sealed)
+    }
+
+    // Write the last chunk and flush, returning the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.sealer.is_some() {
+            self.seal(LAST_CHUNK)?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(sealer) = self.sealer.as_mut() else {
+            return self.inner.write(buf);
+        };
+
+        let len = buf.len().min(CHUNK_SIZE - sealer.buffer.len());
+        sealer.buffer.extend_from_slice(&buf[..len]);
+        if sealer.buffer.len() == CHUNK_SIZE {
+            self.seal(0)?;
+        }
+        Ok(len)
+    }
+
+    // Only whole chunks are written, so this doesn't seal a partial one
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Whether a file starting with `start` is encrypted
+pub fn is_encrypted(start: &[u8]) -> bool {
+    start.starts_with(MAGIC)
+}
+
+// Reads a database file, decrypting it if it is encrypted
+pub enum Reader<R: Read> {
+    Plain(io::Chain<Cursor<Vec<u8>>, R>),
+    Encrypted(Box<Opener<R>>),
+}
+
+pub struct Opener<R: Read> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    header: Vec<u8>,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u32,
+    plain: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> Reader<R> {
+    // Decrypt with the configured keyring, if needed
+    pub fn new(inner: R) -> io::Result<Self> {
+        Self::with_keyring(inner, keyring().as_deref())
+    }
+
+    pub fn with_keyring(mut inner: R, keyring: Option<&Keyring>) -> io::Result<Self> {
+        let mut start = Vec::new();
+        (&mut inner)
+            .take(MAGIC.len() as u64)
+            .read_to_end(&mut start)?;
+        if start != MAGIC {
+            return Ok(Reader::Plain(Cursor::new(start).chain(inner)));
+        }
+
+        // The rest of the header line
+        let mut header = start;
+        let mut byte = [0u8];
+        while header.last() != Some(&b'\n') {
+            if header.len() > 256 || inner.read(&mut byte)? == 0 {
+                return Err(corrupt("Invalid encryption header"));
+            }
+            header.push(byte[0]);
+        }
+        let line = std::str::from_utf8(&header[MAGIC.len()..header.len() - 1])
+            .map_err(|_| corrupt("Invalid encryption header"))?;
+        let (id, hex) = line
+            .split_once(' ')
+            .ok_or_else(|| corrupt("Invalid encryption header"))?;
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        if hex.len() != NONCE_PREFIX_LEN * 2 {
+            return Err(corrupt("Invalid encryption header"));
+        }
+        for (i, byte) in prefix.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| corrupt("Invalid encryption header"))?;
+        }
+
+        let Some(keyring) = keyring else {
+            return Err(io::Error::other(format!(
+                "File is encrypted with key '{}', but no encryption key is configured",
+                id
+            )));
+        };
+        let cipher = keyring.cipher(id).ok_or_else(|| {
+            io::Error::other(format!(
+                "File is encrypted with key '{}', which isn't in the keyring",
+                id
+            ))
+        })?;
+
+        Ok(Reader::Encrypted(Box::new(Opener {
+            inner,
+            cipher: cipher.clone(),
+            header,
+            prefix,
+            counter: 0,
+            plain: Vec::new(),
+            pos: 0,
+            done: false,
+        })))
+    }
+}
+
+impl<R: Read> Opener<R> {
+    fn open_chunk(&mut self) -> io::Result<()> {
+        let truncated = |e: io::Error| match e.kind() {
+            io::ErrorKind::UnexpectedEof => corrupt("Encrypted file is truncated"),
+            _ => e,
+        };
+        let mut flag = [0u8];
+        self.inner.read_exact(&mut flag).map_err(truncated)?;
+        let mut len = [0u8; 4];
+        self.inner.read_exact(&mut len).map_err(truncated)?;
+        let mut sealed = vec![0u8; u32::from_be_bytes(len) as usize];
+        self.inner.read_exact(&mut sealed).map_err(truncated)?;
+
+        let payload = Payload {
+            msg: &sealed,
+            aad: &aad(&self.header, flag[0]),
+        };
+        self.plain = self
+            .cipher
+            .decrypt(&nonce(&self.prefix, self.counter), payload)
+            .map_err(|_| corrupt("Decryption failed: wrong key or corrupted file"))?;
+        self.pos = 0;
+        self.counter = self.counter.wrapping_add(1);
+        self.done = flag[0] == LAST_CHUNK;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let opener = match self {
+            Reader::Plain(inner) => return inner.read(buf),
+            Reader::Encrypted(opener) => opener,
+        };
+
+        while opener.pos == opener.plain.len() {
+            if opener.done {
+                return Ok(0);
+            }
+            opener.open_chunk()?;
+        }
+        let len = buf.len().min(opener.plain.len() - opener.pos);
+        buf[..len].copy_from_slice(&opener.plain[opener.pos..opener.pos + len]);
+        opener.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn read_all(file: &[u8], keyring: Option<&Keyring>) -> io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        Reader::with_keyring(file, keyring)?.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    #[test]
+    fn test_encryption_and_rotation() -> Result<()> {
+        let dir = tempdir()?;
+        let keys = dir.path().join("keys");
+        std::fs::write(&keys, format!("# keyring\nk1:{}\n", "11".repeat(32)))?;
+        let encryption = Encryption::load(KeySource::File(keys.clone()))?;
+        assert!(encryption.rotate().is_err());
+
+        // Spans several chunks
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut writer = Writer::with_keyring(Vec::new(), Some(&encryption.keyring()))?;
+        writer.write_all(&data)?;
+        let file = writer.finish()?;
+        assert!(file.starts_with(b"kv-store encrypted k1 "));
+        assert!(!file.windows(64).any(|w| w == &data[1000..1064]));
+        assert_eq!(read_all(&file, Some(&encryption.keyring()))?, data);
+
+        // Rotating makes the new key active; old files stay readable
+        std::fs::write(
+            &keys,
+            format!("k1:{}\nk2:{}\n", "11".repeat(32), "22".repeat(32)),
+        )?;
+        assert_eq!(encryption.rotate()?, "k2");
+        let mut writer = Writer::with_keyring(Vec::new(), Some(&encryption.keyring()))?;
+        writer.write_all(b"after rotation")?;
+        let rotated = writer.finish()?;
+        assert!(rotated.starts_with(b"kv-store encrypted k2 "));
+        assert_eq!(read_all(&file, Some(&encryption.keyring()))?, data);
+
+        // Missing keys, truncation and tampering are caught
+        let only_k2 = Keyring::parse(&format!("k2:{}", "22".repeat(32))).unwrap();
+        assert!(read_all(&file, Some(&only_k2)).is_err());
+        assert!(read_all(&file, None).is_err());
+        assert!(read_all(&file[..file.len() - 100], Some(&encryption.keyring())).is_err());
+        let mut tampered = rotated.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(read_all(&tampered, Some(&encryption.keyring())).is_err());
+
+        // Unencrypted files pass through, with or without a keyring
+        assert_eq!(
+            read_all(b"kv-store format 3\n{}", None)?,
+            b"kv-store format 3\n{}"
+        );
+        assert_eq!(read_all(b"{}", Some(&only_k2))?, b"{}");
+        assert!(Keyring::parse("k1:abcd").is_err());
+
+        Ok(())
+    }
+}
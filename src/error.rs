@@ -2,6 +2,7 @@
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum StoreError {
     // #[error("Key not found: {0}")]
     // KeyNotFound(String),
@@ -12,8 +13,40 @@ pub enum StoreError {
     #[error("Serilization error: {0}")]
     SerializationError(String),
 
+    #[error("Persistence error: {0}")]
+    PersistenceError(String),
+
+    // The database file was damaged or cut short after it was written
+    #[error("Corrupt database file: {0}")]
+    Corruption(String),
+
     #[error("Replication error: {0}")]
     ReplicationError(String),
+
+    #[error("Config error: {0}")]
+    ConfigError(String),
+
+    #[error("Limit exceeded: {0}")]
+    LimitError(String),
+
+    #[error("Quota exceeded: {0}")]
+    QuotaError(String),
+
+    #[error("Conflict: {0}")]
+    ConflictError(String),
+
+    #[error("Invalid value: {0}")]
+    ValueError(String),
+
+    #[error("Lock error: {0}")]
+    LockError(String),
+
+    #[error("Authentication error: {0}")]
+    AuthError(String),
+
+    // Retryable: the server is temporarily refusing the request
+    #[error("Service unavailable: {0}")]
+    UnavailableError(String),
 }
 
 pub type Result<T> = std::result::Result<T, StoreError>;
\ No newline at end of file
@@ -0,0 +1,83 @@
+// src/eviction.rs
+
+// What to do when a write would take the store past maxmemory
+
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EvictionPolicy {
+    // Refuse the write
+    #[default]
+    #[serde(rename = "noeviction")]
+    NoEviction,
+    // Evict the least recently used keys
+    #[serde(rename = "allkeys-lru")]
+    AllKeysLru,
+    // Evict keys at random
+    #[serde(rename = "allkeys-random")]
+    AllKeysRandom,
+    // Evict the keys closest to expiring, among those with a TTL
+    #[serde(rename = "volatile-ttl")]
+    VolatileTtl,
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::AllKeysRandom => "allkeys-random",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "allkeys-random" => Ok(EvictionPolicy::AllKeysRandom),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            _ => Err(format!("Unknown eviction policy '{}'", s)),
+        }
+    }
+}
+
+// A random index below `len`, good enough for picking eviction victims.
+// Each RandomState is seeded differently, so hashing nothing gives a fresh
+// random number without pulling in a dependency.
+pub fn random_index(len: usize) -> usize {
+    (RandomState::new().hash_one(()) % len.max(1) as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_names() {
+        for name in [
+            "noeviction",
+            "allkeys-lru",
+            "allkeys-random",
+            "volatile-ttl",
+        ] {
+            let policy: EvictionPolicy = name.parse().unwrap();
+            assert_eq!(policy.to_string(), name);
+            assert_eq!(
+                serde_json::to_string(&policy).unwrap(),
+                format!("\"{}\"", name)
+            );
+        }
+        assert!("allkeys-lfu".parse::<EvictionPolicy>().is_err());
+        assert!(random_index(3) < 3);
+    }
+}
@@ -0,0 +1,204 @@
+// src/failure_detector.rs
+
+// Phi accrual failure detector (Hayashibara et al.)
+//
+// Instead of a fixed timeout, the detector learns the distribution of
+// heartbeat inter-arrival times and reports a suspicion level (phi) that
+// grows the longer we go without a heartbeat. phi = 1 means roughly a 10%
+// chance the peer is still alive, phi = 2 a 1% chance, and so on.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Lower bound for the standard deviation so a very regular peer doesn't make
+// us hypersensitive to a little jitter, in milliseconds
+const MIN_STD_DEV: f64 = 100.0;
+
+// Silence tolerated on top of the usual heartbeat interval before suspicion
+// starts to build, so one stalled heartbeat (a busy runtime during a save, a
+// GC pause on the other end) doesn't fail the primary over
+pub const DEFAULT_ACCEPTABLE_PAUSE: Duration = Duration::from_secs(3);
+
+pub struct PhiAccrualDetector {
+    // Recent heartbeat inter-arrival times, in milliseconds
+    intervals: VecDeque<f64>,
+    max_samples: usize,
+    // Extra slack added to the mean, to tolerate e.g. GC pauses
+    acceptable_pause: f64,
+    last_heartbeat: Instant,
+}
+
+impl PhiAccrualDetector {
+    // Create a detector seeded with the expected heartbeat interval, that
+    // tolerates `acceptable_pause` of extra silence
+    pub fn new(expected_interval: Duration, acceptable_pause: Duration) -> Self {
+        let mean = expected_interval.as_secs_f64() * 1000.0;
+        let std_dev = mean / 4.0;
+
+        // Seed with two samples around the expected interval so we have
+        // a sensible distribution before the first real heartbeat arrives
+        let mut intervals = VecDeque::new();
+        intervals.push_back(mean - std_dev);
+        intervals.push_back(mean + std_dev);
+
+        PhiAccrualDetector {
+            intervals,
+            max_samples: 200,
+            acceptable_pause: acceptable_pause.as_secs_f64() * 1000.0,
+            last_heartbeat: Instant::now(),
+        }
+    }
+
+    // Record a heartbeat arriving at `now`
+    pub fn heartbeat(&mut self, now: Instant) {
+        let interval = now.duration_since(self.last_heartbeat).as_secs_f64() * 1000.0;
+        self.last_heartbeat = now;
+
+        if self.intervals.len() >= self.max_samples {
+            self.intervals.pop_front();
+        }
+        self.intervals.push_back(interval);
+    }
+
+    // Current suspicion level that the peer has failed
+    pub fn phi(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.last_heartbeat).as_secs_f64() * 1000.0;
+        let mean = self.mean() + self.acceptable_pause;
+        let std_dev = self.std_dev().max(MIN_STD_DEV);
+
+        // Logistic approximation of the normal CDF, written so that a
+        // heartbeat far ahead of the mean can't turn it into inf / inf
+        let y = (elapsed - mean) / std_dev;
+        let p_later = 1.0 / (1.0 + logistic_exponent(y).exp());
+
+        -p_later.max(f64::MIN_POSITIVE).log10()
+    }
+
+    // The shortest silence beyond the mean interval and acceptable pause
+    // after which any detector reaches `phi_threshold`, which is when its
+    // distribution is as tight as MIN_STD_DEV allows
+    pub fn min_suspicion_delay(phi_threshold: f64) -> Duration {
+        // phi = -log10(e / (1 + e)), solved for the exponent of e
+        let p = 10f64.powf(-phi_threshold);
+        let target = ((1.0 - p) / p).ln();
+        if target <= 0.0 {
+            return Duration::ZERO;
+        }
+
+        // The exponent grows with y, so bisect for it
+        let (mut low, mut high) = (0.0, target);
+        for _ in 0..64 {
+            let mid = (low + high) / 2.0;
+            if logistic_exponent(mid) < target {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        Duration::from_secs_f64(low * MIN_STD_DEV / 1000.0)
+    }
+
+    // Time since the last heartbeat
+    pub fn elapsed(&self) -> Duration {
+        self.last_heartbeat.elapsed()
+    }
+
+    fn mean(&self) -> f64 {
+        self.intervals.iter().sum::<f64>() / self.intervals.len() as f64
+    }
+
+    fn std_dev(&self) -> f64 {
+        let mean = self.mean();
+        let variance = self
+            .intervals
+            .iter()
+            .map(|i| (i - mean) * (i - mean))
+            .sum::<f64>()
+            / self.intervals.len() as f64;
+        variance.sqrt()
+    }
+}
+
+fn logistic_exponent(y: f64) -> f64 {
+    y * (1.5976 + 0.070566 * y * y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phi_grows_without_heartbeats() {
+        let mut detector = PhiAccrualDetector::new(Duration::from_secs(1), Duration::ZERO);
+        let start = Instant::now();
+
+        // Feed a steady one-second heartbeat
+        for i in 1..=20 {
+            detector.heartbeat(start + Duration::from_secs(i));
+        }
+        let last = start + Duration::from_secs(20);
+
+        // Right on schedule we shouldn't suspect anything
+        assert!(detector.phi(last + Duration::from_millis(500)) < 1.0);
+
+        // Suspicion keeps increasing as heartbeats go missing
+        let phi_2s = detector.phi(last + Duration::from_secs(2));
+        let phi_5s = detector.phi(last + Duration::from_secs(5));
+        assert!(phi_2s > 1.0);
+        assert!(phi_5s > phi_2s);
+        assert!(phi_5s > 8.0);
+
+        // Not before the earliest point any detector could get there
+        let delay = PhiAccrualDetector::min_suspicion_delay(8.0);
+        let due = last + Duration::from_secs(1) + delay;
+        assert!(detector.phi(due - Duration::from_millis(10)) < 8.0);
+        assert!(detector.phi(due + Duration::from_millis(10)) > 8.0);
+    }
+
+    #[test]
+    fn test_jittery_heartbeats_raise_tolerance() {
+        let start = Instant::now();
+
+        // A perfectly regular peer
+        let mut steady = PhiAccrualDetector::new(Duration::from_secs(1), Duration::ZERO);
+        let mut t = start;
+        for _ in 0..50 {
+            t += Duration::from_millis(1000);
+            steady.heartbeat(t);
+        }
+        let steady_phi = steady.phi(t + Duration::from_millis(2500));
+
+        // A peer whose heartbeats arrive anywhere between 0.5s and 2s apart
+        let mut jittery = PhiAccrualDetector::new(Duration::from_secs(1), Duration::ZERO);
+        let mut t = start;
+        for i in 0..50 {
+            t += Duration::from_millis(if i % 2 == 0 { 500 } else { 2000 });
+            jittery.heartbeat(t);
+        }
+        let jittery_phi = jittery.phi(t + Duration::from_millis(2500));
+
+        // The same silence is far less suspicious on the jittery link
+        assert!(jittery_phi < steady_phi);
+    }
+
+    #[test]
+    fn test_acceptable_pause_delays_suspicion() {
+        let start = Instant::now();
+        let mut strict = PhiAccrualDetector::new(Duration::from_secs(1), Duration::ZERO);
+        let mut patient = PhiAccrualDetector::new(Duration::from_secs(1), DEFAULT_ACCEPTABLE_PAUSE);
+        let mut t = start;
+        for _ in 0..20 {
+            t += Duration::from_secs(1);
+            strict.heartbeat(t);
+            patient.heartbeat(t);
+        }
+
+        // One missed heartbeat is enough for the strict detector, but not
+        // for one that tolerates a pause
+        let silence = t + Duration::from_millis(2500);
+        assert!(strict.phi(silence) > 8.0);
+        assert!(patient.phi(silence) < 1.0);
+        assert!(patient.phi(t) < 1.0);
+        assert!(patient.phi(t + Duration::from_secs(6)) > 8.0);
+    }
+}
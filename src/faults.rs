@@ -0,0 +1,193 @@
+// src/faults.rs
+
+// Simulated disk failures, so tests can check how saving and loading cope
+// with a disk that misbehaves. Faults can only be injected in builds with
+// the `fault-injection` feature; in every other build the wrappers below
+// pass reads, writes and syncs straight through.
+
+#[cfg(feature = "fault-injection")]
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+#[cfg(feature = "fault-injection")]
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskFault {
+    // Writes to the file fail once this many bytes have been written, as if
+    // the disk filled up or the machine lost power mid-save
+    ShortWrite(usize),
+    // Syncing the file reports an error even though the data was written
+    SyncFailure,
+    // The byte at this offset reads back with its bits flipped
+    CorruptRead(usize),
+}
+
+// Faults waiting for the next matching access to their file
+#[cfg(feature = "fault-injection")]
+static FAULTS: std::sync::LazyLock<std::sync::Mutex<HashMap<PathBuf, Vec<DiskFault>>>> =
+    std::sync::LazyLock::new(Default::default);
+
+// Make the next matching access to `path` fail. Each fault fires once.
+#[cfg(feature = "fault-injection")]
+pub fn inject(path: &Path, fault: DiskFault) {
+    FAULTS
+        .lock()
+        .unwrap()
+        .entry(path.to_path_buf())
+        .or_default()
+        .push(fault);
+}
+
+#[cfg(feature = "fault-injection")]
+fn take(path: &Path, wanted: fn(&DiskFault) -> bool) -> Option<DiskFault> {
+    let mut faults = FAULTS.lock().unwrap();
+    let pending = faults.get_mut(path)?;
+    let index = pending.iter().position(wanted)?;
+    Some(pending.remove(index))
+}
+
+#[cfg(not(feature = "fault-injection"))]
+fn take(_path: &Path, _wanted: fn(&DiskFault) -> bool) -> Option<DiskFault> {
+    None
+}
+
+// A file being read or written, with any fault injected for its path
+pub struct Faulty<T> {
+    inner: T,
+    position: usize,
+    fault: Option<DiskFault>,
+}
+
+impl<T: Write> Faulty<T> {
+    pub fn writer(path: &Path, inner: T) -> Self {
+        let fault = take(path, |fault| matches!(fault, DiskFault::ShortWrite(_)));
+        Faulty {
+            inner,
+            position: 0,
+            fault,
+        }
+    }
+}
+
+impl<T: Read> Faulty<T> {
+    pub fn reader(path: &Path, inner: T) -> Self {
+        let fault = take(path, |fault| matches!(fault, DiskFault::CorruptRead(_)));
+        Faulty {
+            inner,
+            position: 0,
+            fault,
+        }
+    }
+}
+
+impl<T: Write> Write for Faulty<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut len = buf.len();
+        if let Some(DiskFault::ShortWrite(limit)) = self.fault {
+            if self.position >= limit {
+                return Err(io::Error::other("Injected short write"));
+            }
+            len = len.min(limit - self.position);
+        }
+
+        let written = self.inner.write(&buf[..len])?;
+        self.position += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Read> Read for Faulty<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(DiskFault::CorruptRead(offset)) = self.fault
+            && (self.position..self.position + read).contains(&offset)
+        {
+            buf[offset - self.position] ^= 0xff;
+        }
+        self.position += read;
+        Ok(read)
+    }
+}
+
+// Flush a file to disk
+pub fn sync(path: &Path, file: &File) -> io::Result<()> {
+    if take(path, |fault| *fault == DiskFault::SyncFailure).is_some() {
+        return Err(io::Error::other("Injected fsync failure"));
+    }
+    file.sync_all()
+}
+
+#[cfg(all(test, feature = "fault-injection"))]
+mod tests {
+    use super::*;
+    use crate::store::KeyValueStore;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_disk_faults() -> crate::error::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("faulty-db.json");
+        let store = KeyValueStore::new();
+        store.put("key1".to_string(), "value1".to_string());
+        store.save(&path)?;
+
+        // A save cut short fails but leaves the previous file in place
+        store.put("key2".to_string(), "value2".to_string());
+        inject(&path, DiskFault::ShortWrite(30));
+        assert!(store.save(&path).is_err());
+        let previous = KeyValueStore::load(&path)?;
+        assert_eq!(previous.get("key1"), Some("value1".to_string()));
+        assert_eq!(previous.get("key2"), None);
+        assert!(!dir.path().join("faulty-db.json.tmp").exists());
+
+        // A failed fsync is reported even though the data made it out
+        inject(&path, DiskFault::SyncFailure);
+        assert!(store.save(&path).is_err());
+        assert_eq!(
+            KeyValueStore::load(&path)?.get("key1"),
+            Some("value1".to_string())
+        );
+
+        // Corruption is caught on load, and each fault only fires once
+        inject(&path, DiskFault::CorruptRead(0));
+        assert!(KeyValueStore::load(&path).is_err());
+        assert!(KeyValueStore::load(&path).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_delete_is_reported() -> crate::error::Result<()> {
+        let dir = tempdir()?;
+        let wal_path = dir.path().join("faulty.wal");
+        let store = KeyValueStore::new();
+        store.open_wal(&wal_path)?;
+        for key in ["a", "b", "c"] {
+            store.try_put(key.to_string(), "1".to_string())?;
+        }
+
+        // A client's delete that doesn't reach the disk fails
+        inject(&wal_path, DiskFault::SyncFailure);
+        assert!(store.try_delete("a").is_err());
+        inject(&wal_path, DiskFault::SyncFailure);
+        assert!(store.try_take("b").is_err());
+        inject(&wal_path, DiskFault::SyncFailure);
+        let (done, result) = store.try_delete_many(&["c", "missing"]);
+        assert_eq!(done, 2);
+        assert!(result.is_err());
+
+        // Deletes that must go ahead regardless, such as replicated ones,
+        // only log it
+        store.put("d".to_string(), "1".to_string());
+        inject(&wal_path, DiskFault::SyncFailure);
+        assert!(store.delete("d"));
+        assert_eq!(store.get("d"), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,451 @@
+// src/format.rs
+
+// Versioning of the database file. Files start with a header line,
+// `kv-store format N version=X node=ID epoch=E`, followed by the store
+// itself. The fields after the format say which release wrote the file, the
+// node it belongs to and the replication epoch that node had reached; headers
+// from before they existed have only the format. A store encoded as
+// MessagePack rather than JSON adds `encoding=msgpack`. Files from before the header
+// existed are plain JSON and count as version 1. Older versions are upgraded
+// in memory on load, one migration at a time, and written back in the
+// current format on the next save. Newer versions are refused.
+//
+// From version 4 on, files end with a trailer line,
+// `kv-store end records=N crc32=C`, giving the number of keys and a CRC32 of
+// everything before it. A file that doesn't match its trailer, or has lost
+// it, was damaged or cut short after it was written.
+
+use crate::error::{Result, StoreError};
+use crate::logging::log_info;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use serde_json::{Map, Value, json};
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::str::FromStr;
+
+// Bump this and append a migration whenever the layout changes
+pub const FORMAT_VERSION: u32 = 4;
+
+// First version whose files end with a trailer
+const TRAILER_VERSION: u32 = 4;
+
+// Release of kv-store this is, recorded in the files it writes
+pub const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const HEADER_PREFIX: &str = "kv-store format ";
+const TRAILER_PREFIX: &str = "kv-store end ";
+
+// MIGRATIONS[i] upgrades a version i + 1 document to version i + 2
+const MIGRATIONS: &[fn(Value) -> Result<Value>] = &[v1_to_v2, v2_to_v3, v3_to_v4];
+
+// How the store after the header is encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Json,
+    // Smaller and faster to read and write, but not human-readable
+    MessagePack,
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+        })
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Encoding::Json),
+            "msgpack" | "messagepack" => Ok(Encoding::MessagePack),
+            _ => Err(format!(
+                "Unknown encoding '{}', expected json or msgpack",
+                s
+            )),
+        }
+    }
+}
+
+// What a database file says about itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub format: u32,
+    // Release that wrote the file, if it recorded one
+    pub written_by: Option<String>,
+    pub node_id: Option<String>,
+    pub epoch: u64,
+    pub encoding: Encoding,
+}
+
+impl Header {
+    // The header this build writes
+    pub fn current(node_id: &str, epoch: u64, encoding: Encoding) -> Self {
+        Header {
+            format: FORMAT_VERSION,
+            written_by: Some(CRATE_VERSION.to_string()),
+            node_id: Some(node_id.to_string()),
+            epoch,
+            encoding,
+        }
+    }
+
+    fn legacy(format: u32) -> Self {
+        Header {
+            format,
+            written_by: None,
+            node_id: None,
+            epoch: 0,
+            encoding: Encoding::Json,
+        }
+    }
+
+    fn written_by(&self) -> String {
+        match &self.written_by {
+            Some(version) => format!("kv-store {}", version),
+            None => "an older kv-store".to_string(),
+        }
+    }
+}
+
+// A random ID for a node whose database file doesn't have one yet
+pub fn new_node_id() -> String {
+    let mut id = [0u8; 8];
+    OsRng.fill_bytes(&mut id);
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn write_header(writer: &mut impl Write, header: &Header) -> io::Result<()> {
+    write!(writer, "{}{}", HEADER_PREFIX, header.format)?;
+    if let Some(version) = &header.written_by {
+        write!(writer, " version={}", version)?;
+    }
+    if let Some(node_id) = &header.node_id {
+        write!(writer, " node={}", node_id)?;
+    }
+    write!(writer, " epoch={}", header.epoch)?;
+    // Left out for JSON, so releases that only know JSON can read the file
+    if header.encoding != Encoding::Json {
+        write!(writer, " encoding={}", header.encoding)?;
+    }
+    writeln!(writer)
+}
+
+// Read the header if there is one, leaving the reader at the start of the
+// body. Files in a newer format than this build understands are refused,
+// older ones are upgraded by `migrate`.
+pub fn read_header(reader: &mut impl BufRead) -> Result<Header> {
+    if !reader.fill_buf()?.starts_with(HEADER_PREFIX.as_bytes()) {
+        return Ok(Header::legacy(1));
+    }
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let invalid = || StoreError::PersistenceError(format!("Invalid header: {}", line.trim()));
+    let mut fields = line[HEADER_PREFIX.len()..].split_whitespace();
+    let format = fields
+        .next()
+        .and_then(|format| format.parse().ok())
+        .filter(|&format| format > 0)
+        .ok_or_else(invalid)?;
+
+    // Fields a later release added are skipped
+    let mut header = Header::legacy(format);
+    for field in fields {
+        match field.split_once('=') {
+            Some(("version", version)) => header.written_by = Some(version.to_string()),
+            Some(("node", node_id)) => header.node_id = Some(node_id.to_string()),
+            Some(("epoch", epoch)) => header.epoch = epoch.parse().map_err(|_| invalid())?,
+            Some(("encoding", encoding)) => {
+                header.encoding = encoding.parse().map_err(|_| invalid())?
+            }
+            Some(_) => {}
+            None => return Err(invalid()),
+        }
+    }
+
+    if header.format > FORMAT_VERSION {
+        return Err(StoreError::PersistenceError(format!(
+            "Database file was written by {} in format {}, but kv-store {} only reads formats up to {}. \
+             Upgrade this node, or start it from a file written by an older release",
+            header.written_by(),
+            header.format,
+            CRATE_VERSION,
+            FORMAT_VERSION
+        )));
+    }
+    if header.format < FORMAT_VERSION {
+        log_info!(
+            "Database file was written by {} in format {}, upgrading it to format {}. \
+             Once saved, releases before kv-store {} can't read it",
+            header.written_by(),
+            header.format,
+            FORMAT_VERSION,
+            CRATE_VERSION
+        );
+    } else if header
+        .written_by
+        .as_deref()
+        .is_some_and(|version| version != CRATE_VERSION)
+    {
+        log_info!(
+            "Database file was written by {}, now running kv-store {}",
+            header.written_by(),
+            CRATE_VERSION
+        );
+    }
+    Ok(header)
+}
+
+// Computes the checksum of what is written or read through it, for the
+// trailer
+pub struct Checksummed<T> {
+    inner: T,
+    hasher: crc32fast::Hasher,
+}
+
+impl<T> Checksummed<T> {
+    pub fn new(inner: T) -> Self {
+        Checksummed {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    // The wrapped writer or reader, and the checksum so far
+    pub fn into_parts(self) -> (T, u32) {
+        (self.inner, self.hasher.finalize())
+    }
+
+    fn checksum(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: BufRead> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<R: BufRead> BufRead for Checksummed<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        // Already buffered, so this doesn't read anything
+        if let Ok(buf) = self.inner.fill_buf() {
+            self.hasher.update(&buf[..amount]);
+        }
+        self.inner.consume(amount);
+    }
+}
+
+// End a file holding `records` keys, whose contents so far add up to `checksum`
+pub fn write_trailer(writer: &mut impl Write, records: usize, checksum: u32) -> io::Result<()> {
+    writeln!(
+        writer,
+        "{}records={} crc32={:08x}",
+        TRAILER_PREFIX, records, checksum
+    )
+}
+
+// Where the trailer starts in a whole file held in memory, if it has one
+pub fn trailer_start(file: &[u8]) -> Option<usize> {
+    let last_line = file.strip_suffix(b"\n")?;
+    let start = last_line
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |i| i + 1);
+    last_line[start..]
+        .starts_with(TRAILER_PREFIX.as_bytes())
+        .then_some(start)
+}
+
+// Check what follows the body of a file just read against its trailer,
+// given the number of keys the body held
+pub fn check_trailer<R: BufRead>(
+    reader: &mut Checksummed<R>,
+    header: &Header,
+    records: usize,
+) -> Result<()> {
+    // The line break before the trailer counts towards the checksum
+    loop {
+        let buf = reader.fill_buf()?;
+        let blank = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+        if blank == 0 {
+            break;
+        }
+        reader.consume(blank);
+    }
+    let checksum = reader.checksum();
+
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest)?;
+    if rest.is_empty() {
+        if header.format < TRAILER_VERSION {
+            return Ok(());
+        }
+        return Err(StoreError::Corruption(
+            "the file is truncated, its trailer is missing".to_string(),
+        ));
+    }
+
+    let line = String::from_utf8_lossy(&rest);
+    let invalid =
+        || StoreError::Corruption(format!("unexpected data after the store: {:.40}", line));
+    let fields = line
+        .strip_suffix('\n')
+        .and_then(|line| line.strip_prefix(TRAILER_PREFIX))
+        .ok_or_else(invalid)?;
+    let (mut expected_records, mut expected_checksum) = (None, None);
+    for field in fields.split_whitespace() {
+        match field.split_once('=') {
+            Some(("records", n)) => expected_records = n.parse::<usize>().ok(),
+            Some(("crc32", crc)) => expected_checksum = u32::from_str_radix(crc, 16).ok(),
+            _ => {}
+        }
+    }
+    let (Some(expected_records), Some(expected_checksum)) = (expected_records, expected_checksum)
+    else {
+        return Err(invalid());
+    };
+
+    if records != expected_records {
+        return Err(StoreError::Corruption(format!(
+            "read {} keys, but the file was written with {}",
+            records, expected_records
+        )));
+    }
+    if checksum != expected_checksum {
+        return Err(StoreError::Corruption(format!(
+            "checksum is {:08x}, but the file was written with {:08x}",
+            checksum, expected_checksum
+        )));
+    }
+    Ok(())
+}
+
+// Upgrade a document read as `version` to the current format
+pub fn migrate(mut doc: Value, version: u32) -> Result<Value> {
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize - 1) {
+        log_info!("Migrating database from format {} to {}", i + 1, i + 2);
+        doc = migration(doc)?;
+    }
+    Ok(doc)
+}
+
+// Version 1 serialized the store's lock alongside the data, as `data_lock`.
+// Fold anything in it into `data` and drop it.
+fn v1_to_v2(doc: Value) -> Result<Value> {
+    let Value::Object(mut doc) = doc else {
+        return Err(StoreError::PersistenceError(
+            "Expected a JSON object".to_string(),
+        ));
+    };
+
+    let mut data = match doc.remove("data") {
+        Some(Value::Object(data)) => data,
+        _ => Map::new(),
+    };
+    if let Some(Value::Object(locked)) = doc.remove("data_lock") {
+        for (key, value) in locked {
+            data.entry(key).or_insert(value);
+        }
+    }
+
+    doc.insert("data".to_string(), Value::Object(data));
+    Ok(Value::Object(doc))
+}
+
+// Version 2 stored bare values. Version 3 stores an object per entry so
+// per-key metadata such as the version can live next to the value.
+fn v2_to_v3(doc: Value) -> Result<Value> {
+    let Value::Object(mut doc) = doc else {
+        return Err(StoreError::PersistenceError(
+            "Expected a JSON object".to_string(),
+        ));
+    };
+
+    if let Some(Value::Object(data)) = doc.get_mut("data") {
+        for value in data.values_mut() {
+            *value = json!({"value": value.take(), "version": 1});
+        }
+    }
+    Ok(Value::Object(doc))
+}
+
+// Version 4 added the trailer, the store itself is laid out as before
+fn v3_to_v4(doc: Value) -> Result<Value> {
+    Ok(doc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_header_and_migration() -> Result<()> {
+        let header = Header::current("node1", 4, Encoding::Json);
+        let mut file = Vec::new();
+        write_header(&mut file, &header)?;
+        file.extend_from_slice(b"{}");
+        let mut reader = Cursor::new(file);
+        assert_eq!(read_header(&mut reader)?, header);
+        assert_eq!(reader.fill_buf()?, b"{}");
+
+        let header = Header::current("node1", 4, Encoding::MessagePack);
+        let mut file = Vec::new();
+        write_header(&mut file, &header)?;
+        assert!(String::from_utf8_lossy(&file).ends_with(" encoding=msgpack\n"));
+        assert_eq!(read_header(&mut Cursor::new(file))?, header);
+
+        // Headers from before the release, node and epoch were recorded
+        let mut bare = Cursor::new(b"kv-store format 3\n{}".to_vec());
+        assert_eq!(read_header(&mut bare)?, Header::legacy(3));
+
+        // Plain JSON from before the header is version 1
+        let mut legacy = Cursor::new(br#"{"data_lock": {"a": "1"}, "data": {"b": "2"}}"#);
+        assert_eq!(read_header(&mut legacy)?.format, 1);
+        let doc = migrate(serde_json::from_reader(legacy).unwrap(), 1)?;
+        assert_eq!(
+            doc,
+            json!({"data": {
+                "a": {"value": "1", "version": 1},
+                "b": {"value": "2", "version": 1}
+            }})
+        );
+
+        let mut future = Cursor::new(b"kv-store format 99 version=9.0.0 shards=4\n{}".to_vec());
+        let err = read_header(&mut future).unwrap_err().to_string();
+        assert!(
+            err.contains("written by kv-store 9.0.0 in format 99"),
+            "{}",
+            err
+        );
+        assert!(read_header(&mut Cursor::new(b"kv-store format 3 epoch\n")).is_err());
+
+        Ok(())
+    }
+}
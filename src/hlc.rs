@@ -0,0 +1,165 @@
+// src/hlc.rs
+
+// Hybrid logical clock. Timestamps follow wall-clock time in milliseconds,
+// plus a logical counter that orders events within the same millisecond.
+// Observing a timestamp from another node moves the local clock past it, so
+// anything that happens here after a replicated write is stamped later than
+// that write, even if the two nodes' clocks disagree.
+
+use crate::logging::log_warn;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Remote timestamps further ahead of our wall clock than this are not
+// adopted, so one node with a broken clock can't drag the others along
+const MAX_OFFSET_MS: u64 = 60_000;
+
+// Written as `<wall ms>.<logical>`, e.g. `1718000000000.2`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    pub wall: u64,
+    pub logical: u32,
+}
+
+impl Timestamp {
+    // Timestamps are never zero once assigned; zero stands for "unknown"
+    pub fn is_zero(&self) -> bool {
+        *self == Timestamp::default()
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.wall, self.logical)
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid timestamp '{}', expected <wall>.<logical>", s);
+        let (wall, logical) = s.split_once('.').ok_or_else(invalid)?;
+        Ok(Timestamp {
+            wall: wall.parse().map_err(|_| invalid())?,
+            logical: logical.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Default)]
+pub struct Clock {
+    last: Mutex<Timestamp>,
+}
+
+impl Clock {
+    // A timestamp later than any this clock has given out or observed
+    pub fn now(&self) -> Timestamp {
+        let mut last = self.last.lock().unwrap();
+        *last = tick(*last, wall_millis());
+        *last
+    }
+
+    // Latest timestamp given out or observed, without advancing the clock
+    pub fn current(&self) -> Timestamp {
+        *self.last.lock().unwrap()
+    }
+
+    // Move the clock past a timestamp from another node
+    pub fn observe(&self, remote: Timestamp) {
+        let wall = wall_millis();
+        if remote.wall > wall + MAX_OFFSET_MS {
+            log_warn!(
+                "Ignoring timestamp {} from a clock {}ms ahead of ours",
+                remote,
+                remote.wall - wall
+            );
+            return;
+        }
+
+        let mut last = self.last.lock().unwrap();
+        if remote > *last {
+            *last = remote;
+        }
+    }
+}
+
+// The next timestamp after `last` at wall-clock time `wall`
+fn tick(last: Timestamp, wall: u64) -> Timestamp {
+    if wall > last.wall {
+        Timestamp { wall, logical: 0 }
+    } else {
+        Timestamp {
+            wall: last.wall,
+            logical: last.logical + 1,
+        }
+    }
+}
+
+fn wall_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hybrid_logical_clock() {
+        let clock = Clock::default();
+        let first = clock.now();
+        let second = clock.now();
+        assert!(second > first);
+        assert!(first.wall > 0);
+
+        // A node whose wall clock lags still orders events after what it saw
+        let ahead = Timestamp {
+            wall: second.wall + 5_000,
+            logical: 7,
+        };
+        clock.observe(ahead);
+        assert_eq!(clock.current(), ahead);
+        assert_eq!(
+            clock.now(),
+            Timestamp {
+                wall: ahead.wall,
+                logical: 8
+            }
+        );
+
+        // Older timestamps and wildly wrong clocks leave it alone
+        clock.observe(first);
+        clock.observe(Timestamp {
+            wall: u64::MAX / 2,
+            logical: 0,
+        });
+        assert_eq!(clock.current().wall, ahead.wall);
+
+        assert_eq!(ahead.to_string().parse(), Ok(ahead));
+        assert!("12".parse::<Timestamp>().is_err());
+        assert_eq!(
+            serde_json::to_string(&ahead).unwrap(),
+            format!("\"{}\"", ahead)
+        );
+    }
+}
@@ -0,0 +1,163 @@
+// src/latency.rs
+
+// Latency histograms for the commands that touch data, so slow persistence
+// or stalled replication shows up in INFO LATENCY and StatsD. Buckets are
+// laid out like an HDR histogram: exact below 16µs, then 16 linear
+// sub-buckets per power of two, which keeps every percentile within about
+// 6% of the true value in a fixed amount of memory.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const SUB_BUCKETS: u64 = 16;
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+// Enough buckets for any u64 number of microseconds
+const BUCKETS: usize = ((64 - SUB_BUCKET_BITS + 1) * SUB_BUCKETS as u32) as usize;
+
+// Percentiles reported for each command
+pub const PERCENTILES: [u8; 3] = [50, 95, 99];
+
+// Commands that get a histogram
+pub const COMMANDS: [&str; 4] = ["GET", "PUT", "DELETE", "REPLICATE"];
+
+pub struct Histogram {
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, elapsed: Duration) {
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[bucket(micros)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    // The value below which `percentile`% of recordings fall, in
+    // microseconds; 0 if nothing was recorded
+    pub fn percentile(&self, percentile: u8) -> u64 {
+        let count = self.count();
+        if count == 0 {
+            return 0;
+        }
+
+        let target = (count * percentile as u64).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= target {
+                return highest_in_bucket(index);
+            }
+        }
+        // Recordings made while we were reading
+        highest_in_bucket(BUCKETS - 1)
+    }
+}
+
+fn bucket(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exponent = 63 - micros.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let sub_bucket = (micros >> shift) & (SUB_BUCKETS - 1);
+    ((shift + 1) as u64 * SUB_BUCKETS + sub_bucket) as usize
+}
+
+fn highest_in_bucket(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let lowest = (SUB_BUCKETS + index % SUB_BUCKETS) << shift;
+    lowest + ((1u64 << shift) - 1)
+}
+
+// One histogram per command in COMMANDS
+#[derive(Default)]
+pub struct Latencies {
+    histograms: [Histogram; COMMANDS.len()],
+}
+
+impl Latencies {
+    // Record how long `command` took, if it is one we track
+    pub fn record(&self, command: &str, elapsed: Duration) {
+        if let Some(index) = COMMANDS
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(command))
+        {
+            self.histograms[index].record(elapsed);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &Histogram)> {
+        COMMANDS.into_iter().zip(self.histograms.iter())
+    }
+
+    // `get_calls:10, get_p50_us:85, ...` for INFO LATENCY
+    pub fn info(&self) -> String {
+        let mut info = Vec::new();
+        for (command, histogram) in self.iter() {
+            let name = command.to_lowercase();
+            info.push(format!("{}_calls:{}", name, histogram.count()));
+            for p in PERCENTILES {
+                info.push(format!("{}_p{}_us:{}", name, p, histogram.percentile(p)));
+            }
+        }
+        info.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_percentiles() {
+        let latencies = Latencies::default();
+        assert!(latencies.info().contains("get_p99_us:0"));
+
+        for micros in 1..=1000 {
+            latencies.record("get", Duration::from_micros(micros));
+        }
+        latencies.record("PUT", Duration::from_millis(250));
+        latencies.record("KEYS", Duration::from_secs(1));
+
+        let (_, get) = latencies.iter().next().unwrap();
+        assert_eq!(get.count(), 1000);
+        // Within a bucket's width of the exact value
+        for (p, exact) in [(50, 500.0), (95, 950.0), (99, 990.0)] {
+            let reported = get.percentile(p) as f64;
+            assert!(
+                (reported - exact).abs() / exact < 0.07,
+                "p{} = {}",
+                p,
+                reported
+            );
+        }
+
+        let info = latencies.info();
+        assert!(info.contains("put_calls:1"), "{}", info);
+        assert!(info.contains("delete_calls:0"), "{}", info);
+        let put_p50 = latencies.iter().nth(1).unwrap().1.percentile(50);
+        assert!((250_000..265_000).contains(&put_p50), "{}", put_p50);
+
+        // Bucket bounds line up
+        for micros in [0, 15, 16, 31, 32, 1000, 1 << 40, u64::MAX] {
+            assert!(highest_in_bucket(bucket(micros)) >= micros);
+        }
+    }
+}
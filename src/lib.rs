@@ -0,0 +1,86 @@
+// src/lib.rs
+
+// The key-value store as a library, so Rust services can embed it with
+// EmbeddedKv or run a Server in-process. The kv-store binary is a CLI over
+// the same modules. Built for wasm32, only the client and what it needs are
+// included, so browsers can reach a server through its WebSocket gateway.
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod backup;
+pub mod changelog;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod chaos;
+pub mod client;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod config;
+#[cfg(not(target_arch = "wasm32"))]
+mod cow;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod crdt;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod dev_cluster;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod discovery;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod embedded;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod encryption;
+pub mod error;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod eviction;
+#[cfg(not(target_arch = "wasm32"))]
+mod failure_detector;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod faults;
+#[cfg(not(target_arch = "wasm32"))]
+mod format;
+pub mod hlc;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod latency;
+// Only the clock logs when built for wasm32
+#[cfg_attr(target_arch = "wasm32", allow(unused_macros, unused_imports))]
+pub mod logging;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod lsm;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod network;
+pub mod notifications;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod queue;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod quota;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod rdb;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod replication;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod snapshot;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod socket;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod statsd;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod store;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tenant;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tls;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod transfer;
+#[cfg(not(target_arch = "wasm32"))]
+mod value;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod vclock;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod wal;
+pub mod websocket;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod writer;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use embedded::EmbeddedKv;
+pub use error::{Result, StoreError};
+#[cfg(not(target_arch = "wasm32"))]
+pub use network::{Server, ServerHandle};
+#[cfg(not(target_arch = "wasm32"))]
+pub use store::KeyValueStore;
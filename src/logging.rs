@@ -0,0 +1,270 @@
+// src/logging.rs
+
+// Minimal leveled logging. The level and output are globals that can be
+// changed at runtime, e.g. when the config file is reloaded.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("Unknown log level '{}'", s)),
+        }
+    }
+}
+
+// Where log lines go
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    // stdout, with errors and warnings on stderr
+    #[default]
+    Console = 0,
+    // The local syslog daemon, through /dev/log
+    Syslog = 1,
+    // The systemd journal, with the level and module as fields
+    Journald = 2,
+}
+
+impl fmt::Display for LogOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LogOutput::Console => "console",
+            LogOutput::Syslog => "syslog",
+            LogOutput::Journald => "journald",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for LogOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "console" => Ok(LogOutput::Console),
+            "syslog" => Ok(LogOutput::Syslog),
+            "journald" => Ok(LogOutput::Journald),
+            _ => Err(format!("Unknown log output '{}'", s)),
+        }
+    }
+}
+
+// Name logs are tagged with in syslog and the journal
+const IDENTIFIER: &str = "kv-store";
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static OUTPUT: AtomicU8 = AtomicU8::new(LogOutput::Console as u8);
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> LogLevel {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Error,
+        1 => LogLevel::Warn,
+        2 => LogLevel::Info,
+        _ => LogLevel::Debug,
+    }
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn set_output(output: LogOutput) {
+    OUTPUT.store(output as u8, Ordering::Relaxed);
+}
+
+pub fn output() -> LogOutput {
+    match OUTPUT.load(Ordering::Relaxed) {
+        1 => LogOutput::Syslog,
+        2 => LogOutput::Journald,
+        _ => LogOutput::Console,
+    }
+}
+
+// Write a log line from `module`. Lines that can't reach syslog or the
+// journal are printed instead, so they aren't lost.
+pub fn log(level: LogLevel, module: &str, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+
+    let sent = match output() {
+        LogOutput::Console => false,
+        LogOutput::Syslog => send(SYSLOG_SOCKET, syslog_message(level, &args.to_string())),
+        LogOutput::Journald => send(
+            JOURNALD_SOCKET,
+            journald_message(level, module, &args.to_string()),
+        ),
+    };
+    if sent {
+        return;
+    }
+
+    match level {
+        LogLevel::Error | LogLevel::Warn => eprintln!("{}", args),
+        LogLevel::Info | LogLevel::Debug => println!("{}", args),
+    }
+}
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+// Syslog severity of a level
+fn severity(level: LogLevel) -> u8 {
+    match level {
+        LogLevel::Error => 3,
+        LogLevel::Warn => 4,
+        LogLevel::Info => 6,
+        LogLevel::Debug => 7,
+    }
+}
+
+// An RFC 3164 message from the daemon facility
+fn syslog_message(level: LogLevel, message: &str) -> Vec<u8> {
+    const DAEMON: u8 = 3;
+    format!(
+        "<{}>{}[{}]: {}",
+        DAEMON * 8 + severity(level),
+        IDENTIFIER,
+        std::process::id(),
+        message
+    )
+    .into_bytes()
+}
+
+// A message in the journal's native protocol. Fields are NAME=value lines;
+// values with newlines are sent length-prefixed instead.
+fn journald_message(level: LogLevel, module: &str, message: &str) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    let fields = [
+        ("MESSAGE", message),
+        ("PRIORITY", &severity(level).to_string()),
+        ("SYSLOG_IDENTIFIER", IDENTIFIER),
+        ("KV_LEVEL", &level.to_string()),
+        ("CODE_MODULE", module),
+    ];
+    for (name, value) in fields {
+        datagram.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            datagram.push(b'\n');
+            datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            datagram.push(b'=');
+        }
+        datagram.extend_from_slice(value.as_bytes());
+        datagram.push(b'\n');
+    }
+    datagram
+}
+
+#[cfg(unix)]
+fn send(path: &str, datagram: Vec<u8>) -> bool {
+    use std::os::unix::net::UnixDatagram;
+
+    UnixDatagram::unbound()
+        .and_then(|socket| socket.send_to(&datagram, path))
+        .is_ok()
+}
+
+#[cfg(not(unix))]
+fn send(_path: &str, _datagram: Vec<u8>) -> bool {
+    false
+}
+
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        $crate::logging::log(
+            $crate::logging::LogLevel::Error,
+            module_path!(),
+            format_args!($($arg)*),
+        )
+    };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        $crate::logging::log(
+            $crate::logging::LogLevel::Warn,
+            module_path!(),
+            format_args!($($arg)*),
+        )
+    };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        $crate::logging::log(
+            $crate::logging::LogLevel::Info,
+            module_path!(),
+            format_args!($($arg)*),
+        )
+    };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        $crate::logging::log(
+            $crate::logging::LogLevel::Debug,
+            module_path!(),
+            format_args!($($arg)*),
+        )
+    };
+}
+
+pub(crate) use {log_debug, log_error, log_info, log_warn};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_outputs() {
+        assert_eq!("Journald".parse::<LogOutput>(), Ok(LogOutput::Journald));
+        assert!("file".parse::<LogOutput>().is_err());
+
+        let syslog = String::from_utf8(syslog_message(LogLevel::Warn, "disk full")).unwrap();
+        assert!(syslog.starts_with("<28>kv-store["), "{}", syslog);
+        assert!(syslog.ends_with("]: disk full"), "{}", syslog);
+
+        let journald = journald_message(LogLevel::Error, "kv::network", "two\nlines");
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\nPRIORITY=3\nSYSLOG_IDENTIFIER=kv-store\n");
+        expected.extend_from_slice(b"KV_LEVEL=error\nCODE_MODULE=kv::network\n");
+        assert_eq!(journald, expected);
+    }
+}
@@ -0,0 +1,1162 @@
+// src/lsm.rs
+
+// Log-structured merge tree behind --engine lsm, which keeps the store's
+// entries on disk so a dataset larger than memory fits. Writes go into a
+// memtable; a full memtable is frozen and written out as a sorted table by a
+// background thread, and every four tables of a similar size are merged into
+// one so reads check few tables. Each table ends with its block index and a
+// bloom filter, which are kept in memory, so a lookup reads at most one block
+// from each table that may hold the key.
+//
+// A manifest names the live tables and is replaced whenever a flush or a
+// merge changes them, so a restart reopens the tables it names and removes
+// any others a crash left behind. Writes still in a memtable are lost then;
+// the store's write-ahead log covers them.
+
+use crate::error::{Result, StoreError};
+use crate::eviction::random_index;
+use crate::logging::log_error;
+use crate::store::sync_parent;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
+use std::thread;
+
+// Memtable size at which it is frozen and written out
+pub const DEFAULT_MEMTABLE_SIZE: usize = 4 * 1024 * 1024;
+
+// Frozen memtables that may wait for the background thread before writers
+// write them out themselves
+const MAX_FROZEN: usize = 2;
+
+// Tables of the same size tier merged at once
+const FANOUT: usize = 4;
+
+// Records are grouped into blocks of about this size, the unit of a read
+const BLOCK_SIZE: usize = 4096;
+
+// Bloom filter bits per key and probes per lookup, for about 1% false positives
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_PROBES: u64 = 7;
+
+// Length recorded for a deleted key's value
+const TOMBSTONE: u32 = u32::MAX;
+
+// Bookkeeping counted towards a memtable's size for each of its records
+const ENTRY_OVERHEAD: usize = 32;
+
+// Records an iterator copies out of a memtable at a time
+const MEMTABLE_CHUNK: usize = 256;
+
+// Ends every table: where its index and bloom filter start, the index's
+// length, the number of records, a checksum of index and filter, and MAGIC
+const FOOTER_SIZE: usize = 28;
+const MAGIC: u32 = 0x4c53_4d31;
+
+// Table numbers, newest first, one per line
+const MANIFEST: &str = "MANIFEST";
+
+// A key and its value, None for a deleted key
+type Record = (Vec<u8>, Option<Vec<u8>>);
+
+pub struct Lsm {
+    dir: PathBuf,
+    memtable_size: usize,
+    state: RwLock<State>,
+    // Held while writing out frozen memtables, so each is written once,
+    // while merging tables, and while replacing the manifest
+    flushing: Mutex<()>,
+    compacting: Mutex<()>,
+    manifest: Mutex<()>,
+    // Wakes the background thread when a memtable is frozen
+    wake: Sender<()>,
+    next_table: AtomicU64,
+    flushes: AtomicU64,
+    compactions: AtomicU64,
+}
+
+#[derive(Default)]
+struct State {
+    // Shared with views, and copied by the next write if one still uses it
+    memtable: Arc<Memtable>,
+    // Both newest first
+    frozen: Vec<Arc<Memtable>>,
+    tables: Vec<Arc<Table>>,
+}
+
+#[derive(Default, Clone)]
+struct Memtable {
+    records: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    bytes: usize,
+}
+
+impl Memtable {
+    fn insert(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        let size = |key: &[u8], value: &Option<Vec<u8>>| {
+            key.len() + value.as_ref().map_or(0, Vec::len) + ENTRY_OVERHEAD
+        };
+        self.bytes += size(&key, &value);
+        if let Some(old) = self.records.get(&key) {
+            self.bytes -= size(&key, old);
+        }
+        self.records.insert(key, value);
+    }
+}
+
+impl Lsm {
+    // Open the tree in `dir`, creating it if needed and reopening the tables
+    // its manifest names
+    pub fn open(dir: &Path, memtable_size: usize) -> Result<Arc<Self>> {
+        fs::create_dir_all(dir)?;
+        let numbers = read_manifest(dir)?;
+        let tables = numbers
+            .iter()
+            .map(|number| Table::open(dir, *number).map(Arc::new))
+            .collect::<Result<Vec<_>>>()?;
+        // Tables written out or merged away just before a crash
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "sst")
+                && table_number(&path).is_none_or(|number| !numbers.contains(&number))
+            {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let (wake, woken) = mpsc::channel();
+        let lsm = Arc::new(Lsm {
+            dir: dir.to_path_buf(),
+            memtable_size: memtable_size.max(1),
+            state: RwLock::new(State {
+                tables,
+                ..State::default()
+            }),
+            flushing: Mutex::new(()),
+            compacting: Mutex::new(()),
+            manifest: Mutex::new(()),
+            wake,
+            next_table: AtomicU64::new(numbers.iter().max().map_or(0, |number| number + 1)),
+            flushes: AtomicU64::new(0),
+            compactions: AtomicU64::new(0),
+        });
+
+        // Stops once the tree is dropped, which drops the sender
+        let tree = Arc::downgrade(&lsm);
+        thread::Builder::new()
+            .name("lsm".to_string())
+            .spawn(move || {
+                while woken.recv().is_ok() {
+                    let Some(lsm) = tree.upgrade() else {
+                        break;
+                    };
+                    if let Err(e) = lsm.flush_frozen().and_then(|()| lsm.compact()) {
+                        log_error!("LSM maintenance in {} failed: {}", lsm.dir.display(), e);
+                    }
+                }
+            })?;
+        Ok(lsm)
+    }
+
+    // Apply a batch of writes, a None value deleting its key. A batch never
+    // straddles two memtables, so it is written out as a whole or not at all.
+    pub fn write(&self, batch: impl IntoIterator<Item = Record>) {
+        let mut state = self.state.write().unwrap();
+        let memtable = Arc::make_mut(&mut state.memtable);
+        for (key, value) in batch {
+            memtable.insert(key, value);
+        }
+        self.freeze_if_full(state);
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.view().get(key)
+    }
+
+    // The tree as of now, unaffected by later writes
+    pub fn view(&self) -> View {
+        let state = self.state.read().unwrap();
+        View {
+            memtables: std::iter::once(&state.memtable)
+                .chain(&state.frozen)
+                .cloned()
+                .collect(),
+            tables: state.tables.clone(),
+        }
+    }
+
+    // Write everything in memory out to tables, then merge them as needed
+    pub fn flush(&self) -> Result<()> {
+        {
+            let mut state = self.state.write().unwrap();
+            if !state.memtable.records.is_empty() {
+                let memtable = mem::take(&mut state.memtable);
+                state.frozen.insert(0, memtable);
+            }
+        }
+        self.flush_frozen()?;
+        self.compact()
+    }
+
+    // Directory the tree is kept in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Statistics for INFO
+    pub fn info(&self) -> String {
+        let state = self.state.read().unwrap();
+        let memtable_bytes = state.memtable.bytes
+            + state
+                .frozen
+                .iter()
+                .map(|memtable| memtable.bytes)
+                .sum::<usize>();
+        format!(
+            "lsm_tables:{}, lsm_memtable_bytes:{}, lsm_flushes:{}, lsm_compactions:{}",
+            state.tables.len(),
+            memtable_bytes,
+            self.flushes.load(Ordering::Relaxed),
+            self.compactions.load(Ordering::Relaxed)
+        )
+    }
+
+    // Freeze the memtable once it is full and have it written out. Writers
+    // write it out themselves when the background thread is falling behind.
+    fn freeze_if_full(&self, mut state: RwLockWriteGuard<'_, State>) {
+        if state.memtable.bytes < self.memtable_size {
+            return;
+        }
+        let memtable = mem::take(&mut state.memtable);
+        state.frozen.insert(0, memtable);
+        let backlog = state.frozen.len();
+        drop(state);
+
+        if backlog > MAX_FROZEN {
+            if let Err(e) = self.flush_frozen() {
+                log_error!("Could not flush memtable to {}: {}", self.dir.display(), e);
+            }
+        } else {
+            let _ = self.wake.send(());
+        }
+    }
+
+    // Write frozen memtables out as tables, oldest first
+    fn flush_frozen(&self) -> Result<()> {
+        let _flushing = self.flushing.lock().unwrap();
+        loop {
+            let Some(memtable) = self.state.read().unwrap().frozen.last().cloned() else {
+                return Ok(());
+            };
+            let mut table = self.new_table(memtable.records.len())?;
+            for (key, value) in &memtable.records {
+                table.add(key, value.as_deref())?;
+            }
+            let table = table.finish()?;
+
+            // Only we remove frozen memtables, and new ones go in front
+            {
+                let mut state = self.state.write().unwrap();
+                state.frozen.pop();
+                if let Some(table) = table {
+                    state.tables.insert(0, Arc::new(table));
+                }
+            }
+            self.write_manifest()?;
+            self.flushes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Merge runs of tables in the same size tier until there are none.
+    // Tables are only ever added in front while we merge, so a run stays
+    // together.
+    fn compact(&self) -> Result<()> {
+        let _compacting = self.compacting.lock().unwrap();
+        loop {
+            let tables = self.state.read().unwrap().tables.clone();
+            let tiers: Vec<u32> = tables.iter().map(|table| self.tier(table)).collect();
+            let Some(start) = tiers
+                .windows(FANOUT)
+                .position(|run| run.iter().all(|tier| *tier == run[0]))
+            else {
+                return Ok(());
+            };
+            let run = &tables[start..start + FANOUT];
+            // Nothing older is left for a tombstone to hide
+            let oldest = start + FANOUT == tables.len();
+            let merged = self.merge(run, oldest)?;
+
+            let replaced: Vec<_> = {
+                let mut state = self.state.write().unwrap();
+                let at = state
+                    .tables
+                    .iter()
+                    .position(|table| Arc::ptr_eq(table, &run[0]))
+                    .unwrap_or(state.tables.len());
+                let end = (at + FANOUT).min(state.tables.len());
+                state.tables.splice(at..end, merged.map(Arc::new)).collect()
+            };
+            // Each file goes once the manifest no longer names it and no
+            // read is using it any more
+            self.write_manifest()?;
+            for table in replaced {
+                table.obsolete.store(true, Ordering::Relaxed);
+            }
+            self.compactions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Size tier of a table: how many times over a memtable has been merged
+    // into it, roughly
+    fn tier(&self, table: &Table) -> u32 {
+        let mut size = table.size / self.memtable_size as u64;
+        let mut tier = 0;
+        while size >= FANOUT as u64 {
+            size /= FANOUT as u64;
+            tier += 1;
+        }
+        tier
+    }
+
+    // Merge `run`, newest first, into one table, keeping the newest record
+    // of each key. Tombstones are dropped when nothing older is left.
+    fn merge(&self, run: &[Arc<Table>], drop_tombstones: bool) -> Result<Option<Table>> {
+        let sources = run
+            .iter()
+            .map(|table| Box::new(TableReader::new(Arc::clone(table), &[])) as Source)
+            .collect();
+        let records = run.iter().map(|table| table.records).sum();
+        let mut merged = self.new_table(records)?;
+        for record in MergeIter::new(sources) {
+            match record? {
+                (_, None) if drop_tombstones => {}
+                (key, value) => merged.add(&key, value.as_deref())?,
+            }
+        }
+        merged.finish()
+    }
+
+    // Start a table for up to `records` records, to be added in key order
+    fn new_table(&self, records: usize) -> Result<TableWriter> {
+        let number = self.next_table.fetch_add(1, Ordering::Relaxed);
+        TableWriter::create(number, table_path(&self.dir, number), records)
+    }
+
+    // Replace the manifest with one naming the current tables
+    fn write_manifest(&self) -> Result<()> {
+        let _manifest = self.manifest.lock().unwrap();
+        let numbers: String = self
+            .state
+            .read()
+            .unwrap()
+            .tables
+            .iter()
+            .map(|table| format!("{}\n", table.number))
+            .collect();
+        let path = self.dir.join(MANIFEST);
+        let temp = self.dir.join(format!("{}.tmp", MANIFEST));
+        let mut file = File::create(&temp)?;
+        file.write_all(numbers.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, &path)?;
+        sync_parent(&path)
+    }
+}
+
+// Table numbers in the manifest in `dir`, none if there is no manifest yet
+fn read_manifest(dir: &Path) -> Result<Vec<u64>> {
+    let path = dir.join(MANIFEST);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    text.lines()
+        .map(|line| {
+            line.parse()
+                .map_err(|_| StoreError::Corruption(format!("Bad LSM manifest {}", path.display())))
+        })
+        .collect()
+}
+
+fn table_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:06}.sst", number))
+}
+
+fn table_number(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+// The tree at one point in time: its memtables and tables, newest first
+#[derive(Clone)]
+pub struct View {
+    memtables: Vec<Arc<Memtable>>,
+    tables: Vec<Arc<Table>>,
+}
+
+impl View {
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        for memtable in &self.memtables {
+            if let Some(value) = memtable.records.get(key) {
+                return Ok(value.clone());
+            }
+        }
+        for table in &self.tables {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    // Keys and values in key order, starting at `from`
+    pub fn range(&self, from: &[u8]) -> Range {
+        let memtables = self.memtables.iter().map(|memtable| {
+            Box::new(MemtableReader {
+                memtable: Arc::clone(memtable),
+                from: Bound::Included(from.to_vec()),
+                records: Vec::new().into_iter(),
+            }) as Source
+        });
+        let tables = self
+            .tables
+            .iter()
+            .map(|table| Box::new(TableReader::new(Arc::clone(table), from)) as Source);
+        Range(MergeIter::new(memtables.chain(tables).collect()))
+    }
+
+    // A key picked at random, each memtable and table weighted by how many
+    // records it holds. The key may have been deleted or written since.
+    pub fn random_key(&self) -> Result<Option<Vec<u8>>> {
+        let memtables = self.memtables.iter().map(|memtable| memtable.records.len());
+        let tables = self.tables.iter().map(|table| table.records);
+        let total: usize = memtables.clone().chain(tables).sum();
+        if total == 0 {
+            return Ok(None);
+        }
+
+        let mut pick = random_index(total);
+        for memtable in &self.memtables {
+            if pick < memtable.records.len() {
+                return Ok(memtable.records.keys().nth(pick).cloned());
+            }
+            pick -= memtable.records.len();
+        }
+        for table in &self.tables {
+            if pick < table.records {
+                let records = table.read_block(random_index(table.index.len()))?;
+                return Ok(Some(records[random_index(records.len())].0.clone()));
+            }
+            pick -= table.records;
+        }
+        Ok(None)
+    }
+}
+
+// Live keys and values of a view in key order. Ends after the first error.
+pub struct Range(MergeIter);
+
+impl Iterator for Range {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.0.next()? {
+                Ok((key, Some(value))) => return Some(Ok((key, value))),
+                Ok((_, None)) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+type Source = Box<dyn Iterator<Item = Result<Record>> + Send>;
+
+// A source's next record, ordered by key and then by the source's age
+type Head = Reverse<(Vec<u8>, usize, Option<Vec<u8>>)>;
+
+// Merges sources, each in key order and newest first, into one stream that
+// holds the newest record of each key
+struct MergeIter {
+    sources: Vec<Source>,
+    // The next record of every source not in `pending`
+    heap: BinaryHeap<Head>,
+    // Sources to read a record from before the next merge
+    pending: Vec<usize>,
+}
+
+impl MergeIter {
+    fn new(sources: Vec<Source>) -> Self {
+        MergeIter {
+            pending: (0..sources.len()).collect(),
+            sources,
+            heap: BinaryHeap::new(),
+        }
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(source) = self.pending.pop() {
+            match self.sources[source].next() {
+                Some(Ok((key, value))) => self.heap.push(Reverse((key, source, value))),
+                Some(Err(e)) => {
+                    self.heap.clear();
+                    self.pending.clear();
+                    return Some(Err(e));
+                }
+                None => {}
+            }
+        }
+
+        let Reverse((key, source, value)) = self.heap.pop()?;
+        self.pending.push(source);
+        // Older records of the key are hidden by this one
+        while let Some(Reverse((other, source, _))) = self.heap.peek()
+            && *other == key
+        {
+            self.pending.push(*source);
+            self.heap.pop();
+        }
+        Some(Ok((key, value)))
+    }
+}
+
+// Reads a memtable's records in order, copying out a chunk at a time
+struct MemtableReader {
+    memtable: Arc<Memtable>,
+    from: Bound<Vec<u8>>,
+    records: std::vec::IntoIter<Record>,
+}
+
+impl Iterator for MemtableReader {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(record) = self.records.next() {
+            return Some(Ok(record));
+        }
+        let from = self.from.as_ref().map(Vec::as_slice);
+        let chunk: Vec<Record> = self
+            .memtable
+            .records
+            .range::<[u8], _>((from, Bound::Unbounded))
+            .take(MEMTABLE_CHUNK)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        self.from = Bound::Excluded(chunk.last()?.0.clone());
+        self.records = chunk.into_iter();
+        self.records.next().map(Ok)
+    }
+}
+
+// Where a block is in its table
+struct BlockHandle {
+    first_key: Vec<u8>,
+    offset: u64,
+    len: u32,
+    checksum: u32,
+}
+
+// A table on disk, with its block index and bloom filter
+struct Table {
+    number: u64,
+    path: PathBuf,
+    file: Mutex<File>,
+    index: Vec<BlockHandle>,
+    bloom: Bloom,
+    records: usize,
+    // Bytes of records
+    size: u64,
+    // Set once merged into another table
+    obsolete: AtomicBool,
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::Relaxed)
+            && let Err(e) = fs::remove_file(&self.path)
+        {
+            log_error!("Could not remove LSM table {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl Table {
+    // Reopen a table, reading only its footer, index and bloom filter
+    fn open(dir: &Path, number: u64) -> Result<Self> {
+        let path = table_path(dir, number);
+        let corrupt = || StoreError::Corruption(format!("Bad LSM table {}", path.display()));
+        let mut file = File::open(&path)?;
+        let len = file.metadata()?.len();
+        let meta_end = len.checked_sub(FOOTER_SIZE as u64).ok_or_else(corrupt)?;
+        let mut footer = [0; FOOTER_SIZE];
+        file.seek(SeekFrom::Start(meta_end))?;
+        file.read_exact(&mut footer)?;
+
+        let mut rest = &footer[..];
+        let meta_offset = take_u64(&mut rest).ok_or_else(corrupt)?;
+        let index_len = take_u32(&mut rest).ok_or_else(corrupt)? as u64;
+        let records = take_u64(&mut rest).ok_or_else(corrupt)?;
+        let checksum = take_u32(&mut rest).ok_or_else(corrupt)?;
+        let magic = take_u32(&mut rest).ok_or_else(corrupt)?;
+        if magic != MAGIC || meta_offset > meta_end || index_len > meta_end - meta_offset {
+            return Err(corrupt());
+        }
+
+        let mut meta = vec![0; (meta_end - meta_offset) as usize];
+        file.seek(SeekFrom::Start(meta_offset))?;
+        file.read_exact(&mut meta)?;
+        if crc32fast::hash(&meta) != checksum {
+            return Err(corrupt());
+        }
+        let (mut index_data, bloom) = meta.split_at(index_len as usize);
+        let mut index = Vec::new();
+        while !index_data.is_empty() {
+            let key_len = take_u32(&mut index_data).ok_or_else(corrupt)?;
+            let first_key = take(&mut index_data, key_len as usize).ok_or_else(corrupt)?;
+            index.push(BlockHandle {
+                first_key: first_key.to_vec(),
+                offset: take_u64(&mut index_data).ok_or_else(corrupt)?,
+                len: take_u32(&mut index_data).ok_or_else(corrupt)?,
+                checksum: take_u32(&mut index_data).ok_or_else(corrupt)?,
+            });
+        }
+
+        Ok(Table {
+            number,
+            file: Mutex::new(file),
+            index,
+            bloom: Bloom::decode(bloom).ok_or_else(corrupt)?,
+            records: records as usize,
+            size: meta_offset,
+            obsolete: AtomicBool::new(false),
+            path,
+        })
+    }
+
+    // The value of `key` if this table has it, None inside if it's deleted
+    fn get(&self, key: &[u8]) -> Result<Option<Option<Vec<u8>>>> {
+        if !self.bloom.contains(key) {
+            return Ok(None);
+        }
+        let block = self
+            .index
+            .partition_point(|block| block.first_key.as_slice() <= key);
+        if block == 0 {
+            return Ok(None);
+        }
+        let records = self.read_block(block - 1)?;
+        Ok(records
+            .into_iter()
+            .find(|(record, _)| record == key)
+            .map(|(_, value)| value))
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<Record>> {
+        let handle = &self.index[block];
+        let mut data = vec![0; handle.len as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(handle.offset))?;
+            file.read_exact(&mut data)?;
+        }
+        if crc32fast::hash(&data) != handle.checksum {
+            return Err(self.corrupt());
+        }
+
+        let mut records = Vec::new();
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let key_len = take_u32(&mut rest).ok_or_else(|| self.corrupt())?;
+            let key = take(&mut rest, key_len as usize).ok_or_else(|| self.corrupt())?;
+            let value = match take_u32(&mut rest).ok_or_else(|| self.corrupt())? {
+                TOMBSTONE => None,
+                len => Some(take(&mut rest, len as usize).ok_or_else(|| self.corrupt())?),
+            };
+            records.push((key.to_vec(), value.map(<[u8]>::to_vec)));
+        }
+        Ok(records)
+    }
+
+    fn corrupt(&self) -> StoreError {
+        StoreError::Corruption(format!("Bad block in LSM table {}", self.path.display()))
+    }
+}
+
+// Reads a table's records in order from a key on, a block at a time
+struct TableReader {
+    table: Arc<Table>,
+    from: Vec<u8>,
+    next_block: usize,
+    records: std::vec::IntoIter<Record>,
+}
+
+impl TableReader {
+    fn new(table: Arc<Table>, from: &[u8]) -> Self {
+        // The block before the first one starting after `from` may hold it
+        let next_block = table
+            .index
+            .partition_point(|block| block.first_key.as_slice() <= from)
+            .saturating_sub(1);
+        TableReader {
+            table,
+            from: from.to_vec(),
+            next_block,
+            records: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Iterator for TableReader {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.records.next() {
+                if record.0 < self.from {
+                    continue;
+                }
+                return Some(Ok(record));
+            }
+            if self.next_block == self.table.index.len() {
+                return None;
+            }
+            let block = self.table.read_block(self.next_block);
+            self.next_block += 1;
+            match block {
+                Ok(records) => self.records = records.into_iter(),
+                Err(e) => {
+                    self.next_block = self.table.index.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+struct TableWriter {
+    number: u64,
+    path: PathBuf,
+    file: BufWriter<File>,
+    block: Vec<u8>,
+    block_first: Vec<u8>,
+    offset: u64,
+    index: Vec<BlockHandle>,
+    bloom: Bloom,
+    records: usize,
+}
+
+impl TableWriter {
+    // Start a table that will hold up to `records` records
+    fn create(number: u64, path: PathBuf, records: usize) -> Result<Self> {
+        Ok(TableWriter {
+            number,
+            file: BufWriter::new(File::create(&path)?),
+            path,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            block_first: Vec::new(),
+            offset: 0,
+            index: Vec::new(),
+            bloom: Bloom::new(records),
+            records: 0,
+        })
+    }
+
+    fn add(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        let too_large = || StoreError::ValueError("Key or value too large".to_string());
+        let key_len = u32::try_from(key.len()).map_err(|_| too_large())?;
+        if self.block.is_empty() {
+            self.block_first = key.to_vec();
+        }
+        self.block.extend_from_slice(&key_len.to_le_bytes());
+        self.block.extend_from_slice(key);
+        match value {
+            Some(value) => {
+                let len = u32::try_from(value.len())
+                    .ok()
+                    .filter(|len| *len != TOMBSTONE)
+                    .ok_or_else(too_large)?;
+                self.block.extend_from_slice(&len.to_le_bytes());
+                self.block.extend_from_slice(value);
+            }
+            None => self.block.extend_from_slice(&TOMBSTONE.to_le_bytes()),
+        }
+        self.bloom.insert(key);
+        self.records += 1;
+        if self.block.len() >= BLOCK_SIZE {
+            self.end_block()?;
+        }
+        Ok(())
+    }
+
+    fn end_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            first_key: mem::take(&mut self.block_first),
+            offset: self.offset,
+            len: self.block.len() as u32,
+            checksum: crc32fast::hash(&self.block),
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    // Finish the table, or remove it and return None if it holds nothing
+    fn finish(mut self) -> Result<Option<Table>> {
+        self.end_block()?;
+        if self.records == 0 {
+            drop(self.file);
+            fs::remove_file(&self.path)?;
+            return Ok(None);
+        }
+
+        let mut meta = Vec::new();
+        for block in &self.index {
+            meta.extend_from_slice(&(block.first_key.len() as u32).to_le_bytes());
+            meta.extend_from_slice(&block.first_key);
+            meta.extend_from_slice(&block.offset.to_le_bytes());
+            meta.extend_from_slice(&block.len.to_le_bytes());
+            meta.extend_from_slice(&block.checksum.to_le_bytes());
+        }
+        let index_len = meta.len() as u32;
+        meta.extend(self.bloom.bits.iter().flat_map(|word| word.to_le_bytes()));
+        self.file.write_all(&meta)?;
+        self.file.write_all(&self.offset.to_le_bytes())?;
+        self.file.write_all(&index_len.to_le_bytes())?;
+        self.file.write_all(&(self.records as u64).to_le_bytes())?;
+        self.file.write_all(&crc32fast::hash(&meta).to_le_bytes())?;
+        self.file.write_all(&MAGIC.to_le_bytes())?;
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_data()?;
+
+        Ok(Some(Table {
+            number: self.number,
+            file: Mutex::new(File::open(&self.path)?),
+            path: self.path,
+            index: self.index,
+            bloom: self.bloom,
+            records: self.records,
+            size: self.offset,
+            obsolete: AtomicBool::new(false),
+        }))
+    }
+}
+
+// Split `n` bytes off the front of `data`
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    let (front, rest) = data.split_at_checked(n)?;
+    *data = rest;
+    Some(front)
+}
+
+fn take_u32(data: &mut &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(take(data, 4)?.try_into().ok()?))
+}
+
+fn take_u64(data: &mut &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(take(data, 8)?.try_into().ok()?))
+}
+
+// Which keys a table may hold
+struct Bloom {
+    bits: Vec<u64>,
+}
+
+impl Bloom {
+    fn new(keys: usize) -> Self {
+        Bloom {
+            bits: vec![0; (keys * BLOOM_BITS_PER_KEY).div_ceil(64).max(1)],
+        }
+    }
+
+    // A filter as written at the end of a table
+    fn decode(data: &[u8]) -> Option<Self> {
+        if data.is_empty() || !data.len().is_multiple_of(8) {
+            return None;
+        }
+        let bits = data
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        Some(Bloom { bits })
+    }
+
+    // Bits for `key`, by double hashing
+    fn probes(&self, key: &[u8]) -> impl Iterator<Item = usize> + use<> {
+        let hash = hash(key);
+        let step = hash.rotate_left(32) | 1;
+        let len = self.bits.len() as u64 * 64;
+        (0..BLOOM_PROBES).map(move |i| (hash.wrapping_add(i.wrapping_mul(step)) % len) as usize)
+    }
+
+    fn insert(&mut self, key: &[u8]) {
+        for bit in self.probes(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.probes(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+// FNV-1a, spread over all 64 bits with splitmix64's finalizer. Filters are
+// written to disk, so this must not change between runs.
+fn hash(key: &[u8]) -> u64 {
+    let mut z = key.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table_files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| {
+                let path = entry.as_ref().unwrap().path();
+                path.extension().is_some_and(|ext| ext == "sst")
+            })
+            .count()
+    }
+
+    fn key(i: u64) -> Vec<u8> {
+        format!("key{:04}", i).into_bytes()
+    }
+
+    fn put(lsm: &Lsm, i: u64) {
+        lsm.write([(key(i), Some(format!("value {}", i).into_bytes()))]);
+    }
+
+    fn delete(lsm: &Lsm, i: u64) {
+        lsm.write([(key(i), None)]);
+    }
+
+    fn value(lsm: &Lsm, i: u64) -> Result<Option<String>> {
+        Ok(lsm
+            .get(&key(i))?
+            .map(|value| String::from_utf8(value).unwrap()))
+    }
+
+    #[test]
+    fn test_flush_and_read() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let lsm = Lsm::open(dir.path(), 1024)?;
+        for i in 0..100 {
+            put(&lsm, i);
+        }
+        delete(&lsm, 7);
+        lsm.flush()?;
+
+        // Values are read back from tables, through their bloom filters
+        assert_eq!(value(&lsm, 42)?.as_deref(), Some("value 42"));
+        assert_eq!(value(&lsm, 7)?, None);
+        assert_eq!(value(&lsm, 1000)?, None);
+        assert!(lsm.state.read().unwrap().memtable.records.is_empty());
+        assert!(lsm.flushes.load(Ordering::Relaxed) > 0);
+
+        // A key deleted after it was written out is hidden by a tombstone
+        delete(&lsm, 42);
+        assert_eq!(value(&lsm, 42)?, None);
+        lsm.flush()?;
+        assert_eq!(value(&lsm, 42)?, None);
+        assert_eq!(value(&lsm, 43)?.as_deref(), Some("value 43"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compaction() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let lsm = Lsm::open(dir.path(), 1 << 20)?;
+        let round = |keys: std::ops::Range<u64>, deleting: bool| {
+            for i in keys {
+                if deleting {
+                    delete(&lsm, i);
+                } else {
+                    put(&lsm, i);
+                }
+            }
+            lsm.flush()
+        };
+        round(0..100, false)?;
+        round(100..200, false)?;
+        round(0..100, true)?;
+        assert_eq!(lsm.state.read().unwrap().tables.len(), 3);
+
+        // The fourth table of the tier merges all of them, and the deleted
+        // keys go along with their tombstones
+        round(200..300, false)?;
+        assert_eq!(lsm.compactions.load(Ordering::Relaxed), 1);
+        {
+            let state = lsm.state.read().unwrap();
+            assert_eq!(state.tables.len(), 1);
+            assert_eq!(state.tables[0].records, 200);
+        }
+        assert_eq!(table_files(dir.path()), 1);
+        assert_eq!(value(&lsm, 50)?, None);
+        assert_eq!(value(&lsm, 150)?.as_deref(), Some("value 150"));
+        assert_eq!(value(&lsm, 250)?.as_deref(), Some("value 250"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_background_flush() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let lsm = Lsm::open(dir.path(), 1024)?;
+        for i in 0..2000 {
+            put(&lsm, i);
+            if i % 3 == 0 {
+                delete(&lsm, i);
+            }
+        }
+        for i in 0..2000 {
+            let expected = (i % 3 != 0).then(|| format!("value {}", i));
+            assert_eq!(value(&lsm, i)?, expected);
+        }
+        assert!(lsm.flushes.load(Ordering::Relaxed) > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_range() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let lsm = Lsm::open(dir.path(), 1 << 20)?;
+        for i in 0..50 {
+            put(&lsm, i);
+        }
+        lsm.flush()?;
+        for i in 40..60 {
+            put(&lsm, i * 2);
+        }
+        delete(&lsm, 45);
+        delete(&lsm, 80);
+
+        // Newest records win across the memtable and the table, and a view
+        // doesn't see writes made after it
+        let view = lsm.view();
+        put(&lsm, 46);
+        delete(&lsm, 47);
+        let keys: Vec<Vec<u8>> = view
+            .range(&key(44))
+            .map(|record| record.map(|(key, _)| key))
+            .collect::<Result<_>>()?;
+        let mut expected: Vec<u64> = (44..50).filter(|i| *i != 45).collect();
+        expected.extend((40..60).map(|i| i * 2).filter(|i| *i != 80));
+        assert_eq!(keys, expected.into_iter().map(key).collect::<Vec<_>>());
+        assert_eq!(view.get(&key(47))?, Some(b"value 47".to_vec()));
+        assert_eq!(lsm.get(&key(47))?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reopen() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        {
+            let lsm = Lsm::open(dir.path(), 1024)?;
+            for i in 0..500 {
+                put(&lsm, i);
+            }
+            delete(&lsm, 10);
+            lsm.flush()?;
+            // Lost with the memtable, as after a crash
+            put(&lsm, 1000);
+        }
+        // A table written out but never named in the manifest
+        fs::write(table_path(dir.path(), 999), b"partial")?;
+
+        let lsm = Lsm::open(dir.path(), 1024)?;
+        assert_eq!(value(&lsm, 250)?.as_deref(), Some("value 250"));
+        assert_eq!(value(&lsm, 10)?, None);
+        assert_eq!(value(&lsm, 1000)?, None);
+        assert!(!table_path(dir.path(), 999).exists());
+        assert_eq!(lsm.view().range(&[]).count(), 499);
+
+        // New tables don't reuse the numbers of the reopened ones
+        put(&lsm, 1000);
+        lsm.flush()?;
+        assert_eq!(value(&lsm, 1000)?.as_deref(), Some("value 1000"));
+        drop(lsm);
+        let lsm = Lsm::open(dir.path(), 1024)?;
+        assert_eq!(value(&lsm, 1000)?.as_deref(), Some("value 1000"));
+        assert_eq!(value(&lsm, 499)?.as_deref(), Some("value 499"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_random_key() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let lsm = Lsm::open(dir.path(), 1024)?;
+        assert_eq!(lsm.view().random_key()?, None);
+        for i in 0..150 {
+            put(&lsm, i);
+        }
+        lsm.flush()?;
+        for i in 150..200 {
+            put(&lsm, i);
+        }
+        let view = lsm.view();
+        assert!(!view.tables.is_empty());
+        for _ in 0..50 {
+            let picked = view.random_key()?.unwrap();
+            assert!((0..200).any(|i| key(i) == picked));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut bloom = Bloom::new(1000);
+        for i in 0..1000 {
+            bloom.insert(&key(i * 2));
+        }
+        assert!((0..1000).all(|i| bloom.contains(&key(i * 2))));
+        let false_positives = (0..1000)
+            .filter(|i| bloom.contains(&key(i * 2 + 1)))
+            .count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn test_corrupt_block() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let lsm = Lsm::open(dir.path(), 1 << 20)?;
+        put(&lsm, 1);
+        lsm.flush()?;
+
+        let path = lsm.state.read().unwrap().tables[0].path.clone();
+        let mut data = fs::read(&path)?;
+        data[0] ^= 0xff;
+        fs::write(&path, &data)?;
+        assert!(matches!(lsm.get(&key(1)), Err(StoreError::Corruption(_))));
+
+        // A damaged index or filter is found when the table is reopened
+        drop(lsm);
+        let last = data.len() - FOOTER_SIZE - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, &data)?;
+        assert!(matches!(
+            Lsm::open(dir.path(), 1 << 20),
+            Err(StoreError::Corruption(_))
+        ));
+        Ok(())
+    }
+}
@@ -1,20 +1,28 @@
 // src/main.rs
 
 use clap::{Parser, Subcommand};
-use client::Client;
+use distributed_kv_store::client::Client;
+use distributed_kv_store::dev_cluster::DevCluster;
+use distributed_kv_store::encryption::{self, EncryptionOptions};
+use distributed_kv_store::discovery::PeerSource;
+use distributed_kv_store::backup;
+use distributed_kv_store::rdb;
 use std::path::PathBuf;
 use std::process;
 use std::sync::Arc;
 
-mod client;
-mod error;
-mod network;
-mod replication;
-mod store;
-
-use error::{Result, StoreError};
-use network::Server;
-use store::KeyValueStore;
+use distributed_kv_store::error::{Result, StoreError};
+use distributed_kv_store::network::Server;
+use distributed_kv_store::replication::ReplicationMode;
+use distributed_kv_store::snapshot::{self, SavePolicy, SaveRule, SnapshotManager};
+use distributed_kv_store::socket::SocketOptions;
+use distributed_kv_store::tls::{Tls, TlsOptions};
+use distributed_kv_store::store::{self, DatabaseLock, Encoding, Engine, KeyValueStore};
+use distributed_kv_store::transfer::{self, CsvOptions, Format};
+use distributed_kv_store::wal::wal_path;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
 
 #[derive(Parser)]
 #[clap(
@@ -28,10 +36,34 @@ struct Cli {
     #[clap(short, long, default_value = "kv-store.json")]
     db_path: PathBuf,
 
+    // Encoding of the database file from the next save on: json or msgpack.
+    // Existing files are read in whichever encoding they use.
+    #[clap(long)]
+    db_format: Option<Encoding>,
+
+    // Load what can still be read from a damaged database file instead of
+    // refusing to start
+    #[clap(long)]
+    salvage: bool,
+
+    // TCP tuning for server and client connections
+    #[clap(flatten)]
+    socket: SocketOptions,
+
+    // Keys for encrypting the database file and snapshots at rest
+    #[clap(flatten)]
+    encryption: EncryptionOptions,
+
+    // Certificates for TLS on server, replication and client connections
+    #[clap(flatten)]
+    tls: TlsOptions,
+
     #[clap(subcommand)]
     command: Command,
 }
 
+// Parsed once at startup, so the size of the server options doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Command {
     // Server mode
@@ -39,13 +71,104 @@ enum Command {
         #[clap(short, long, default_value = "127.0.0.1:7000")]
         address: String,
 
+        // Also accept WebSocket connections on this address, for browsers
+        #[clap(long)]
+        websocket: Option<String>,
+
         // Replication role
         #[clap(long)]
         role: Option<String>, // "primary" or "backup"
 
-        // Primary address (for backup nodes)
+        // Primary address (for backup nodes), or where to discover it:
+        // srv:<dns name> or file:<path>
+        #[clap(long)]
+        primary: Option<PeerSource>,
+
+        // Join the cluster of this node as a backup, finding its primary.
+        // Takes an address, srv:<dns name> or file:<path>
+        #[clap(long, conflicts_with_all = ["role", "primary"])]
+        join: Option<PeerSource>,
+
+        // "push" sends each write to backups, "log" has backups tail the primary's changelog
+        #[clap(long, default_value = "push")]
+        replication_mode: ReplicationMode,
+
+        // Start serving while the database file is still loading
+        #[clap(long)]
+        lazy_load: bool,
+
+        // Where values are kept: "memory", "lsm" for an LSM tree in
+        // <db-path>.lsm that holds keys and values alike and is reopened on
+        // restart, or "mmap" to read them in place from the memory-mapped
+        // database file
+        #[clap(long, default_value = "memory", conflicts_with = "ephemeral")]
+        engine: Engine,
+
+        // Keep everything in memory: never read, write or lock the database file
+        #[clap(long, conflicts_with_all = ["lazy_load", "save", "save_interval", "snapshot_retention", "snapshot_retention_days"])]
+        ephemeral: bool,
+
+        // Log every write to <db-path>.wal before applying it, so a crash
+        // between saves loses nothing
+        #[clap(long, conflicts_with = "ephemeral")]
+        wal: bool,
+
+        // Allow CHAOS commands to inject latency, dropped replication and refused connections
+        #[clap(long)]
+        chaos: bool,
+
+        // Token clients must AUTH with before running admin commands
+        #[clap(long)]
+        admin_token: Option<String>,
+
+        // JSON config file, reloaded on SIGHUP or CONFIG RELOAD
+        #[clap(long)]
+        config: Option<PathBuf>,
+
+        // Automatic save rules as "<seconds> <changes>" pairs, e.g. "900 1 60 1000"
+        #[clap(long)]
+        save: Option<SavePolicy>,
+
+        // Save every this many seconds if anything changed, on top of --save
+        #[clap(long)]
+        save_interval: Option<u64>,
+
+        // Keep this many timestamped copies of the database file
+        #[clap(long)]
+        snapshot_retention: Option<usize>,
+
+        // Also keep the newest snapshot from each of this many days
+        #[clap(long)]
+        snapshot_retention_days: Option<u64>,
+
+        // Snapshot to start from when there is no database file yet: an
+        // s3:// or http(s):// URL, or a local path
+        #[clap(long, conflicts_with = "ephemeral")]
+        bootstrap_from: Option<String>,
+
+        // Endpoint of an S3-compatible store for --bootstrap-from
+        #[clap(long, requires = "bootstrap_from")]
+        bootstrap_endpoint: Option<String>,
+
+        // JSON lines file of {"key": ..., "value": ...} loaded on first boot
         #[clap(long)]
-        primary: Option<String>,
+        seed: Option<PathBuf>,
+
+        // Apply the seed file even if the store already has data
+        #[clap(long, requires = "seed")]
+        seed_overwrite: bool,
+    },
+    // Run a primary and backups in this process, in memory, until Ctrl-C
+    DevCluster {
+        #[clap(long, default_value = "3")]
+        nodes: usize,
+
+        // The primary listens here, backups on the following ports
+        #[clap(long, default_value = "7000")]
+        base_port: u16,
+
+        #[clap(long, default_value = "push")]
+        replication_mode: ReplicationMode,
     },
     // Add backup to primary
     AddBackup {
@@ -56,6 +179,81 @@ enum Command {
         backup: String,
     },
 
+    // Replace the database file with a backup, e.g. one uploaded by a server,
+    // or with --to-timestamp, roll it back to an earlier time
+    Restore {
+        // s3://bucket/key, a local path, or anything --command can fetch
+        #[clap(long, required_unless_present = "to_timestamp", conflicts_with = "to_timestamp")]
+        from: Option<String>,
+
+        // Unix seconds or YYYYMMDD-HHMMSS (UTC) to rebuild the database as of,
+        // from its local snapshots and write-ahead log
+        #[clap(long, value_parser = snapshot::parse_time)]
+        to_timestamp: Option<u64>,
+
+        // Endpoint of an S3-compatible store
+        #[clap(long)]
+        endpoint: Option<String>,
+
+        // Shell command that fetches {source} into {file}
+        #[clap(long)]
+        command: Option<String>,
+    },
+
+    // Follow a server's changes as JSON lines, starting at a sequence number
+    Sync {
+        #[clap(long)]
+        address: String,
+
+        #[clap(long, default_value = "0")]
+        from: u64,
+    },
+
+    // Print a server's keyspace notifications as they happen, for keys
+    // starting with a prefix
+    Subscribe {
+        #[clap(long)]
+        address: String,
+
+        #[clap(default_value = "")]
+        prefix: String,
+    },
+
+    // Probe a running server, exiting non-zero unless it is alive, or with
+    // --ready, ready for traffic
+    Health {
+        #[clap(long)]
+        address: String,
+
+        #[clap(long)]
+        ready: bool,
+    },
+
+    // Toggle maintenance mode on a running server
+    Maintenance {
+        #[clap(long)]
+        address: String,
+
+        #[clap(long)]
+        token: Option<String>,
+
+        // "on" or "off"
+        state: String,
+    },
+
+    // Move the primary role to one of its backups without losing writes
+    Handoff {
+        // The current primary
+        #[clap(long)]
+        address: String,
+
+        #[clap(long)]
+        token: Option<String>,
+
+        // The backup to take over
+        to: String,
+    },
+
     // Client commands
     Get {
         key: String,
@@ -67,7 +265,80 @@ enum Command {
     Delete {
         key: String,
     },
+    // Make a key expire after `seconds`
+    Expire {
+        key: String,
+        seconds: u64,
+    },
+    // Remove a key's expiry time
+    Persist {
+        key: String,
+    },
+    // Count a key as used and, if it expires, push that back to `seconds` from now
+    Touch {
+        key: String,
+        seconds: Option<u64>,
+    },
+    // Seconds until a key expires, -1 if it never does
+    Ttl {
+        key: String,
+    },
     Keys,
+    // Count keys, and with --bytes the bytes their values take up
+    Dbsize {
+        #[clap(long)]
+        bytes: bool,
+    },
+
+    // Fold the write-ahead log into the database file, dropping overwritten
+    // and deleted values, and report the bytes freed
+    Compact {
+        // Sealed log segments to keep for restore --to-timestamp
+        #[clap(long, default_value = "0")]
+        keep_segments: usize,
+    },
+
+    // Write every key and value as jsonl, csv or redis (for redis-cli --pipe)
+    Export {
+        #[clap(long, default_value = "jsonl")]
+        format: Format,
+
+        // File to write, stdout if not given
+        #[clap(long)]
+        output: Option<PathBuf>,
+
+        #[clap(long, default_value = ",")]
+        delimiter: char,
+
+        // Quote every CSV field, not just those that need it
+        #[clap(long)]
+        quote_all: bool,
+    },
+
+    // Load keys and values from a jsonl, csv or redis file, overwriting
+    // existing keys
+    Import {
+        file: PathBuf,
+
+        #[clap(long, default_value = "jsonl")]
+        format: Format,
+
+        #[clap(long, default_value = ",")]
+        delimiter: char,
+    },
+
+    // Load the string keys of a Redis RDB snapshot or append-only file
+    ImportRedis {
+        #[clap(long, required_unless_present = "aof", conflicts_with = "aof")]
+        rdb: Option<PathBuf>,
+
+        #[clap(long)]
+        aof: Option<PathBuf>,
+
+        // Redis database number to import
+        #[clap(long, default_value = "0")]
+        db: u64,
+    },
 }
 
 #[tokio::main]
@@ -75,18 +346,185 @@ async fn main() -> Result<()> {
     // Parse the command-line arguments
     let cli = Cli::parse();
 
-    // Load the store
-    let store = KeyValueStore::load(&cli.db_path)?;
-    let store = Arc::new(store);
+    // Keys must be in place before any database file is read or written
+    if let Some(source) = cli.encryption.source() {
+        encryption::enable(source)?;
+    }
+    let tls = cli.tls.load()?;
+
+    // Make sure no other process is writing to the same database file.
+    // Read-only commands don't need the lock.
+    let _db_lock = match cli.command {
+        Command::Server { ephemeral: false, .. }
+        | Command::Restore { .. }
+        | Command::Put { .. }
+        | Command::Delete { .. }
+        | Command::Expire { .. }
+        | Command::Persist { .. }
+        | Command::Touch { .. }
+        | Command::Import { .. }
+        | Command::ImportRedis { .. }
+        | Command::Compact { .. } => {
+            Some(DatabaseLock::acquire(&cli.db_path)?)
+        }
+        _ => None,
+    };
+
+    // Restoring replaces the database file, so there is nothing to load first
+    if let Command::Restore { from, to_timestamp, endpoint, command } = &cli.command {
+        if let Some(time) = to_timestamp {
+            let (keys, replayed) = backup::restore_to_time(&cli.db_path, *time)?;
+            println!("Restored {} keys as of {}, replaying {} logged writes", keys, time, replayed);
+        } else if let Some(from) = from {
+            let keys = backup::restore(from, endpoint.as_deref(), command.as_deref(), &cli.db_path).await?;
+            println!("Restored {} keys from {}", keys, from);
+        }
+        return Ok(());
+    }
+
+    // A new node fetches its data before loading the store
+    if let Command::Server { bootstrap_from: Some(source), bootstrap_endpoint, .. } = &cli.command
+        && let Some(keys) =
+            backup::bootstrap(source, bootstrap_endpoint.as_deref(), None, &cli.db_path).await?
+    {
+        println!("Bootstrapped {} keys from {}", keys, source);
+    }
+
+    // Probes run often, so they don't load the store
+    if let Command::Health { address, ready } = &cli.command {
+        let probe = if *ready { "HEALTH READY" } else { "HEALTH LIVE" };
+        let client = client(address.clone(), &cli.socket, &tls);
+        let response = client.send_command(probe).await?;
+        println!("{}", response);
+        if response != "OK" {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Dev cluster nodes keep everything in memory
+    if let Command::DevCluster { nodes, base_port, replication_mode } = cli.command {
+        let cluster = DevCluster::start(nodes, base_port, replication_mode, cli.socket.clone()).await?;
+        println!("Primary: {}", cluster.primary());
+        for backup in cluster.backups() {
+            println!("Backup:  {}", backup);
+        }
+        println!("Press Ctrl-C to stop");
+        tokio::signal::ctrl_c().await?;
+        cluster.stop();
+        return Ok(());
+    }
+
+    // Load the store, streaming it in the background for lazy server starts
+    let store = match &cli.command {
+        Command::Server { ephemeral: true, .. } => Arc::new(KeyValueStore::new()),
+        _ if cli.salvage => Arc::new(KeyValueStore::salvage(&cli.db_path)?),
+        // Only keys are read at startup, so there is nothing to stream
+        Command::Server { engine: Engine::Mmap, .. } => Arc::new(KeyValueStore::load_mapped(&cli.db_path)?),
+        Command::Server { lazy_load, engine, .. } if *lazy_load || *engine == Engine::Lsm => {
+            // Entries go into the LSM tree as they're read, so the file
+            // never has to fit in memory. A tree in step with the file is
+            // reopened instead.
+            let store = match engine {
+                Engine::Lsm => KeyValueStore::load_streaming_lsm(&cli.db_path, &store::lsm_dir(&cli.db_path))?,
+                Engine::Memory | Engine::Mmap => KeyValueStore::load_streaming(&cli.db_path)?,
+            };
+            if !lazy_load {
+                store.wait_until_loaded()?;
+                store
+            } else {
+                let loader = Arc::clone(&store);
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = loader.wait_until_loaded() {
+                        eprintln!("Could not load database: {}", e);
+                        process::exit(1);
+                    }
+                });
+                store
+            }
+        }
+        _ => Arc::new(KeyValueStore::load(&cli.db_path)?),
+    };
+    if let Some(encoding) = cli.db_format {
+        store.set_encoding(encoding);
+    }
 
     match cli.command {
-        Command::Server { address, role, primary } => {
+        Command::Server {
+            address,
+            websocket,
+            role,
+            primary,
+            join,
+            replication_mode,
+            admin_token,
+            chaos,
+            config,
+            save,
+            save_interval,
+            snapshot_retention,
+            snapshot_retention_days,
+            seed,
+            seed_overwrite,
+            ephemeral,
+            wal,
+            engine,
+            ..
+        } => {
+            // Ship initial data with the deployment, saving it right away so
+            // the next boot sees a non-empty store
+            if let Some(path) = seed {
+                let count = store.seed(&path, seed_overwrite)?;
+                if count > 0 {
+                    if !ephemeral {
+                        store.save(&cli.db_path)?;
+                    }
+                    println!("Seeded {} keys from {}", count, path.display());
+                }
+            }
+
             // Create server with or without replication
-            let server = if role.is_some() {
+            let mut server = if role.is_some() || join.is_some() {
                 Server::with_replication(Arc::clone(&store), address.clone())
+                    .with_replication_mode(replication_mode)
             } else {
                 Server::new(Arc::clone(&store), address.clone())
-            };
+            }
+            .with_socket_options(cli.socket.clone())
+            .with_websocket(websocket)
+            .with_tls(tls);
+            if !ephemeral {
+                // Large values spill to files next to the database
+                store.set_spill_dir(&store::spill_dir(&cli.db_path))?;
+                server = server.with_db_path(cli.db_path.clone());
+            }
+            // A salvaged store was loaded into memory first
+            if engine == Engine::Lsm && store.lsm().is_none() {
+                store.set_lsm_dir(&store::lsm_dir(&cli.db_path))?;
+            }
+            if wal {
+                store.open_wal(&wal_path(&cli.db_path))?;
+            }
+
+            // Command-line flags take precedence over the config file
+            if let Some(path) = config {
+                server = server.with_config(&path)?;
+            }
+            let mut server = server.with_admin_token(admin_token).with_chaos(chaos);
+            let mut save = save;
+            if let Some(seconds) = save_interval {
+                let rule = SaveRule { seconds, changes: 1 };
+                save.get_or_insert_with(SavePolicy::default).rules.push(rule);
+            }
+            if let Some(policy) = save {
+                server = server.with_save_policy(policy);
+            }
+            if let Some(count) = snapshot_retention {
+                server = server.with_snapshot_retention(count);
+            }
+            if let Some(days) = snapshot_retention_days {
+                server = server.with_snapshot_retention_days(days);
+            }
             
             // Configure replication if requested
             if let Some(role_str) = role {
@@ -95,31 +533,69 @@ async fn main() -> Result<()> {
                         server.start_as_primary().await?;
                     },
                     "backup" => {
-                        if let Some(primary_addr) = primary {
-                            server.start_as_backup(primary_addr).await?;
+                        if let Some(source) = primary {
+                            server.start_as_backup_from(source).await?;
                         } else {
                             return Err(StoreError::ReplicationError(
-                                "Backup nodes require --primary".to_string()).into());
+                                "Backup nodes require --primary".to_string()));
                         }
                     },
                     _ => {
                         return Err(StoreError::ReplicationError(
-                            "Role must be 'primary' or 'backup'".to_string()).into());
+                            "Role must be 'primary' or 'backup'".to_string()));
                     }
                 }
             }
             
+            if let Some(source) = join {
+                let primary_addr = server.join(&source.resolve().await?).await?;
+                println!("Joined as a backup of {}", primary_addr);
+            }
+
             // Run the server
             server.run().await?;
         },
         Command::AddBackup { primary, backup } => {
             // Connect to primary
-            let client = Client::new(primary);
+            let client = client(primary, &cli.socket, &tls);
             
             // Send add_backup command
             let response = client.send_command(&format!("ADD_BACKUP {}", backup)).await?;
             println!("Response: {}", response);
         },
+        Command::Sync { address, from } => {
+            let client = client(address, &cli.socket, &tls);
+            client
+                .sync(from, |change| match serde_json::to_string(&change) {
+                    Ok(line) => println!("{}", line),
+                    Err(e) => eprintln!("Could not print change {}: {}", change.seq, e),
+                })
+                .await?;
+        },
+        Command::Subscribe { address, prefix } => {
+            let client = client(address, &cli.socket, &tls);
+            client
+                .subscribe(&prefix, |notification| println!("{}", notification))
+                .await?;
+        },
+        Command::Maintenance { address, token, state } => {
+            let mut client = client(address, &cli.socket, &tls);
+            if let Some(token) = token {
+                client = client.with_auth_token(token);
+            }
+
+            let response = client.send_command(&format!("MAINTENANCE {}", state)).await?;
+            println!("Response: {}", response);
+        },
+        Command::Handoff { address, token, to } => {
+            let mut client = client(address, &cli.socket, &tls);
+            if let Some(token) = token {
+                client = client.with_auth_token(token);
+            }
+
+            let response = client.send_command(&format!("HANDOFF {}", to)).await?;
+            println!("Response: {}", response);
+        },
 
         
         // Client mode commands
@@ -146,6 +622,99 @@ async fn main() -> Result<()> {
                 process::exit(1);
             }
         }
+        Command::Expire { key, seconds } => {
+            if store.expire(&key, store::expires_in(seconds))? {
+                println!("Expiry set");
+                store.save(&cli.db_path)?;
+            } else {
+                eprint!("Key not found: {}", key);
+                process::exit(1);
+            }
+        }
+        Command::Persist { key } => {
+            if store.persist(&key)? {
+                println!("Expiry removed");
+                store.save(&cli.db_path)?;
+            } else {
+                eprint!("Key not found or has no expiry: {}", key);
+                process::exit(1);
+            }
+        }
+        Command::Touch { key, seconds } => {
+            if store.touch_key(&key, seconds.map(store::expires_in))?.is_none() {
+                eprint!("Key not found: {}", key);
+                process::exit(1);
+            }
+            println!("Key touched");
+            store.save(&cli.db_path)?;
+        }
+        Command::Ttl { key } => match store.ttl(&key) {
+            -2 => {
+                eprint!("Key not found: {}", key);
+                process::exit(1);
+            }
+            ttl => println!("{}", ttl),
+        },
+        Command::Export { format, output, delimiter, quote_all } => {
+            let entries: BTreeMap<String, String> = store.entries().into_iter().collect();
+            let pairs = entries.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+            let csv = CsvOptions { delimiter, quote_all };
+            let count = match &output {
+                Some(path) => transfer::export(pairs, format, csv, &mut BufWriter::new(File::create(path)?))?,
+                None => transfer::export(pairs, format, csv, &mut io::stdout().lock())?,
+            };
+            if let Some(path) = output {
+                println!("Exported {} keys to {}", count, path.display());
+            }
+        }
+        Command::Import { file, format, delimiter } => {
+            let csv = CsvOptions { delimiter, ..CsvOptions::default() };
+            let entries = transfer::import(&mut BufReader::new(File::open(&file)?), format, csv)
+                .map_err(|e| StoreError::SerializationError(format!("{}: {}", file.display(), e)))?;
+            let count = entries.len();
+            for (key, value) in entries {
+                store.put(key, value);
+            }
+            store.save(&cli.db_path)?;
+            println!("Imported {} keys from {}", count, file.display());
+        }
+        Command::ImportRedis { rdb, aof, db } => {
+            let data = match (rdb, aof) {
+                (Some(path), _) => rdb::read_rdb(&std::fs::read(&path)?, db),
+                (None, Some(path)) => rdb::read_aof(&std::fs::read(&path)?, db),
+                (None, None) => unreachable!("clap requires --rdb or --aof"),
+            }?;
+            let count = data.entries.len();
+            for (key, value) in data.entries {
+                store.put(key, value);
+            }
+            store.save(&cli.db_path)?;
+            println!("Imported {} keys from Redis database {}", count, db);
+            for (reason, skipped) in data.skipped {
+                println!("Skipped {} keys: {}", skipped, reason);
+            }
+        }
+        Command::Restore { .. } | Command::DevCluster { .. } | Command::Health { .. } => {
+            unreachable!("handled before loading the store")
+        }
+        Command::Compact { keep_segments } => {
+            // Opened so the save drops the segments it covers
+            store.open_wal(&wal_path(&cli.db_path))?;
+            if let Some(wal) = store.wal() {
+                wal.set_segment_retention(keep_segments);
+            }
+            let snapshots = Arc::new(SnapshotManager::new(Arc::clone(&store), cli.db_path.clone()));
+            let reclaimed = snapshots.compact().await?;
+            println!("Compacted {}, reclaimed {} bytes", cli.db_path.display(), reclaimed);
+        }
+        Command::Dbsize { bytes } => {
+            let (keys, value_bytes) = store.count("");
+            if bytes {
+                println!("{} keys, {} bytes", keys, value_bytes);
+            } else {
+                println!("{}", keys);
+            }
+        }
         Command::Keys => {
             let keys = store.keys();
             if keys.is_empty() {
@@ -163,3 +732,12 @@ async fn main() -> Result<()> {
     // store.save(&cli.db_path)?;
     Ok(())
 }
+
+// A client for another server, over TLS if we were given a CA
+fn client(address: String, socket: &SocketOptions, tls: &Option<Arc<Tls>>) -> Client {
+    let client = Client::new(address).with_socket_options(socket.clone());
+    match tls {
+        Some(tls) => client.with_tls(Arc::clone(tls)),
+        None => client,
+    }
+}
@@ -1,17 +1,161 @@
 // src/network.rs
 
+use crate::changelog::Change;
+use crate::chaos::Chaos;
+use crate::client::TRY_AGAIN;
+use crate::config::{Config, RUNTIME_PARAMS};
+use crate::crdt::{self, Update};
+use crate::discovery::PeerSource;
+use crate::encryption;
 use crate::error::{Result, StoreError};
-use crate::store::KeyValueStore;
-use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use crate::logging::{self, log_debug, log_error, log_info, log_warn};
+use crate::queue::CommandQueue;
+use crate::snapshot::{SavePolicy, SnapshotManager};
+use crate::socket::SocketOptions;
+use crate::statsd::{self, Metrics, StatsdSettings};
+use crate::store::{Condition, KeyValueStore, expires_in};
+use crate::tenant::{Tenant, TenantConfig};
+use crate::tls::{Stream, Tls};
+use crate::wal::Wal;
+use crate::websocket;
+use crate::writer::{self, Write, Writers};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
 
-use crate::replication::{Operation, ReplicationManager, Role};
+use crate::replication::{ALSO_PRIMARY, ReplicationManager, ReplicationMode, Role, STALE_EPOCH};
+
+// How often values are moved between memory and disk for memory_budget
+const TIERING_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often keys past their expiry time are removed
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Bytes buffered each way between the WebSocket gateway and a connection
+const WEBSOCKET_BUFFER: usize = 64 * 1024;
 
 pub struct Server {
     store: Arc<KeyValueStore>,
     address: String,
     replication_manager: Option<Arc<ReplicationManager>>,
+    socket_options: SocketOptions,
+    // Where the WebSocket gateway listens, if it's enabled
+    websocket_address: Option<String>,
+    state: Arc<ServerState>,
+}
+
+// Runtime state shared by every connection
+pub struct ServerState {
+    // Token required by admin commands; None leaves them open
+    admin_token: RwLock<Option<String>>,
+
+    // While set, new writes are refused with a retryable error
+    maintenance: AtomicBool,
+
+    // Config file re-read on SIGHUP and CONFIG RELOAD
+    config_path: RwLock<Option<PathBuf>>,
+
+    // Certificates for TLS, also re-read on SIGHUP and CONFIG RELOAD
+    tls: OnceLock<Arc<Tls>>,
+
+    // Saves the store to its database file for SAVE/BGSAVE
+    snapshots: OnceLock<Arc<SnapshotManager>>,
+
+    // Tenants by name, each confined to its own keyspace
+    tenants: RwLock<BTreeMap<String, Arc<Tenant>>>,
+
+    // Faults injected with CHAOS on servers started with --chaos
+    chaos: Chaos,
+
+    // Counters for the StatsD exporter, and where it sends them
+    metrics: Metrics,
+    statsd: RwLock<Option<StatsdSettings>>,
+
+    // Numbers the request IDs given to commands that didn't bring one
+    request_ids: AtomicU64,
+
+    // Bounds how many commands execute and wait at once
+    commands: CommandQueue,
+
+    // Tasks that apply and replicate client writes, once the server runs
+    writers: OnceLock<Writers>,
+}
+
+// State a connection carries from one command to the next: what it has
+// authenticated as and the namespace it has selected
+#[derive(Default)]
+struct Session {
+    admin: bool,
+    tenant: Option<String>,
+    // Set by SELECT; None is the top-level keyspace
+    namespace: Option<String>,
+}
+
+impl Session {
+    // Prepended to every key the connection names, e.g. "tenantA:orders:"
+    fn key_prefix(&self) -> String {
+        [&self.tenant, &self.namespace]
+            .into_iter()
+            .flatten()
+            .map(|scope| format!("{}:", scope))
+            .collect()
+    }
+}
+
+impl ServerState {
+    fn new() -> Self {
+        ServerState {
+            admin_token: RwLock::new(None),
+            maintenance: AtomicBool::new(false),
+            config_path: RwLock::new(None),
+            tls: OnceLock::new(),
+            snapshots: OnceLock::new(),
+            tenants: RwLock::new(BTreeMap::new()),
+            chaos: Chaos::default(),
+            metrics: Metrics::default(),
+            statsd: RwLock::new(None),
+            request_ids: AtomicU64::new(0),
+            commands: CommandQueue::default(),
+            writers: OnceLock::new(),
+        }
+    }
+
+    // Whether a connection may run admin commands
+    fn is_admin(&self, authenticated: bool) -> bool {
+        authenticated || self.admin_token.read().unwrap().is_none()
+    }
+
+    fn tenant(&self, name: &str) -> Option<Arc<Tenant>> {
+        self.tenants.read().unwrap().get(name).cloned()
+    }
+
+    fn tenant_by_token(&self, token: &str) -> Option<Arc<Tenant>> {
+        self.tenants
+            .read()
+            .unwrap()
+            .values()
+            .find(|tenant| tenant.token() == token)
+            .cloned()
+    }
+
+    // Replace the tenants, keeping the stats of those that remain
+    fn set_tenants(&self, configs: &BTreeMap<String, TenantConfig>) {
+        let mut tenants = self.tenants.write().unwrap();
+        *tenants = configs
+            .iter()
+            .map(|(name, config)| {
+                let tenant = Tenant::new(name, config, tenants.get(name).map(Arc::as_ref));
+                (name.clone(), Arc::new(tenant))
+            })
+            .collect();
+    }
 }
 
 impl Server {
@@ -23,6 +167,9 @@ impl Server {
             store,
             address,
             replication_manager: Some(replication_manager),
+            socket_options: SocketOptions::default(),
+            websocket_address: None,
+            state: Arc::new(ServerState::new()),
         }
     }
 
@@ -37,15 +184,41 @@ impl Server {
             ))
         }
     }
-    // Start as backup
+    // Start as backup, registering with the primary in the background
     pub async fn start_as_backup(&self, primary_addr: String) -> Result<()> {
+        self.start_as_backup_from(PeerSource::Address(primary_addr))
+            .await
+    }
+
+    // Start as backup of the primary `source` points at
+    pub async fn start_as_backup_from(&self, source: PeerSource) -> Result<()> {
+        match &self.replication_manager {
+            Some(rm) => {
+                Arc::clone(rm)
+                    .start_backup_from(source, &self.address)
+                    .await
+            }
+            None => Err(StoreError::ReplicationError(
+                "Replication not enabled".to_string(),
+            )),
+        }
+    }
+
+    // How writes reach backups; must match on the primary and its backups
+    pub fn with_replication_mode(self, mode: ReplicationMode) -> Self {
         if let Some(rm) = &self.replication_manager {
-            rm.clone().start_backup(primary_addr).await?;
-            Ok(())
-        } else {
-            Err(StoreError::ReplicationError(
+            rm.set_mode(mode);
+        }
+        self
+    }
+
+    // Join an existing cluster through any of its members, as a backup
+    pub async fn join(&self, member: &str) -> Result<String> {
+        match &self.replication_manager {
+            Some(rm) => Arc::clone(rm).join(member, &self.address).await,
+            None => Err(StoreError::ReplicationError(
                 "Replication not enabled".to_string(),
-            ))
+            )),
         }
     }
 
@@ -66,51 +239,682 @@ impl Server {
             store,
             address,
             replication_manager: None,
+            socket_options: SocketOptions::default(),
+            websocket_address: None,
+            state: Arc::new(ServerState::new()),
+        }
+    }
+
+    // Use custom TCP options for the listener and accepted connections
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    // Also accept WebSocket connections on `address`, for browsers and other
+    // clients that can't open plain TCP connections
+    pub fn with_websocket(mut self, address: Option<String>) -> Self {
+        self.websocket_address = address;
+        self
+    }
+
+    // Serve TLS with the certificate `tls` holds, and reach other nodes over
+    // TLS if it has a CA
+    pub fn with_tls(self, tls: Option<Arc<Tls>>) -> Self {
+        if let Some(tls) = tls {
+            if let Some(rm) = &self.replication_manager {
+                rm.set_tls(Arc::clone(&tls));
+            }
+            let _ = self.state.tls.set(tls);
+        }
+        self
+    }
+
+    // Require AUTH with this token before admin commands are accepted
+    pub fn with_admin_token(self, token: Option<String>) -> Self {
+        if token.is_some() {
+            if let Some(rm) = &self.replication_manager {
+                rm.set_auth_token(token.clone());
+            }
+            *self.state.admin_token.write().unwrap() = token;
+        }
+        self
+    }
+
+    // Database file that SAVE and BGSAVE write to
+    pub fn with_db_path(self, db_path: PathBuf) -> Self {
+        let manager = SnapshotManager::new(Arc::clone(&self.store), db_path);
+        let _ = self.state.snapshots.set(Arc::new(manager));
+        self
+    }
+
+    // Save automatically in the background according to `policy`
+    pub fn with_save_policy(self, policy: SavePolicy) -> Self {
+        if let Some(snapshots) = self.state.snapshots.get() {
+            snapshots.set_policy(policy);
         }
+        self
     }
 
+    // Keep this many timestamped copies of the database file after each save
+    pub fn with_snapshot_retention(self, count: usize) -> Self {
+        if let Some(snapshots) = self.state.snapshots.get() {
+            snapshots.set_retention(count);
+        }
+        self
+    }
+
+    // Also keep the newest snapshot from each of the last `days` days
+    pub fn with_snapshot_retention_days(self, days: u64) -> Self {
+        if let Some(snapshots) = self.state.snapshots.get() {
+            snapshots.set_retention_days(days);
+        }
+        self
+    }
+
+    // Accept CHAOS commands for injecting faults
+    pub fn with_chaos(self, enabled: bool) -> Self {
+        if enabled {
+            log_warn!("Chaos mode is on, CHAOS commands can inject faults");
+            self.state.chaos.enable();
+        }
+        self
+    }
+
+    // Load settings from a config file, which is re-read on SIGHUP and CONFIG RELOAD
+    pub fn with_config(self, path: &Path) -> Result<Self> {
+        let config = Config::load(path)?;
+        apply_config(&config, &self.store, &self.state, &self.replication_manager);
+        *self.state.config_path.write().unwrap() = Some(path.to_path_buf());
+        Ok(self)
+    }
+
+    // Serve until the process is told to stop, then save what hasn't been
+    // saved yet
     pub async fn run(&self) -> Result<()> {
-        let listener = TcpListener::bind(&self.address)
-            .await
-            .map_err(|e| StoreError::IoError(e))?;
-        println!("Server listening on {}", self.address);
+        let listener = self.socket_options.bind(&self.address).await?;
+        log_info!("Server listening on {}", self.address);
+        if let Some(gateway) = self.bind_websocket().await? {
+            self.start_websocket_gateway(gateway);
+        }
+
+        self.start_background_tasks()?;
+        tokio::select! {
+            result = self.accept_connections(listener) => result,
+            result = shutdown_signal() => {
+                result?;
+                log_info!("Shutting down");
+                if let Some(snapshots) = self.state.snapshots.get() {
+                    snapshots.final_save().await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Serve in the background, returning a handle that stops the server
+    pub async fn spawn(self) -> Result<ServerHandle> {
+        let listener = self.socket_options.bind(&self.address).await?;
+        let address = listener.local_addr()?;
+        log_info!("Server listening on {}", address);
+
+        let mut tasks = self.start_background_tasks()?;
+        let gateway = self.bind_websocket().await?;
+        let websocket_address = match gateway {
+            Some(gateway) => {
+                let websocket_address = gateway.local_addr()?;
+                tasks.push(self.start_websocket_gateway(gateway));
+                Some(websocket_address)
+            }
+            None => None,
+        };
+        tasks.push(tokio::spawn(async move {
+            if let Err(e) = self.accept_connections(listener).await {
+                log_error!("Server on {} stopped: {}", address, e);
+            }
+        }));
+        Ok(ServerHandle {
+            address,
+            websocket_address,
+            tasks,
+        })
+    }
+
+    async fn bind_websocket(&self) -> Result<Option<TcpListener>> {
+        let Some(address) = &self.websocket_address else {
+            return Ok(None);
+        };
+        let listener = self.socket_options.bind(address).await?;
+        log_info!("WebSocket gateway listening on {}", listener.local_addr()?);
+        Ok(Some(listener))
+    }
+
+    fn start_background_tasks(&self) -> Result<Vec<JoinHandle<()>>> {
+        // Heartbeats can reach us from now on
+        if let Some(rm) = &self.replication_manager {
+            rm.start_monitoring();
+        }
+
+        // Writers outlive a shutdown, serving connections that are still open
+        if self.state.writers.get().is_none() {
+            let writers = Writers::start(Arc::clone(&self.store), self.replication_manager.clone());
+            let _ = self.state.writers.set(writers);
+        }
+
+        let mut tasks = vec![
+            self.start_tiering(),
+            self.start_expiry(),
+            self.start_statsd(),
+        ];
 
+        #[cfg(unix)]
+        tasks.extend(self.reload_on_sighup()?);
+        if let Some(tls) = self.state.tls.get() {
+            tasks.push(tls.watch());
+        }
+
+        if let Some(snapshots) = self.state.snapshots.get() {
+            tasks.push(snapshots.start_scheduler());
+        }
+        Ok(tasks)
+    }
+
+    async fn accept_connections(&self, listener: TcpListener) -> Result<()> {
         loop {
             match listener.accept().await {
                 Ok((socket, addr)) => {
-                    println!("New connection from: {}", addr);
+                    if self.state.chaos.refuse_connection() {
+                        log_debug!("Chaos: refusing connection from {}", addr);
+                        continue;
+                    }
+                    log_debug!("New connection from: {}", addr);
+
+                    if let Err(e) = self.socket_options.apply(&socket) {
+                        log_warn!("Failed to set socket options for {}: {}", addr, e);
+                    }
 
                     // Clone store and replication_manager for the new connection
                     let store = Arc::clone(&self.store);
                     let replication_manager = self.replication_manager.clone(); // Clone the Option<Arc<ReplicationManager>>
+                    let state = Arc::clone(&self.state);
 
                     // Spawn a new task to handle the connection
+                    state.metrics.connection_opened();
                     tokio::spawn(async move {
-                        if let Err(e) = handle_connection(socket, store, replication_manager).await
-                        {
-                            eprintln!("Error handling connection: {}", e);
+                        let result = match accept_tls(&state, socket).await {
+                            Ok(socket) => {
+                                handle_connection(
+                                    socket,
+                                    store,
+                                    replication_manager,
+                                    Arc::clone(&state),
+                                )
+                                .await
+                            }
+                            Err(e) => {
+                                log_debug!("TLS handshake with {} failed: {}", addr, e);
+                                Ok(())
+                            }
+                        };
+                        state.metrics.connection_closed();
+                        if let Err(e) = result {
+                            log_error!("Error handling connection: {}", e);
                         }
                     });
                 }
                 Err(e) => {
-                    eprintln!("Error accepting connection: {}", e);
+                    log_error!("Error accepting connection: {}", e);
+                }
+            }
+        }
+    }
+
+    // Accept WebSocket connections, serving each one like a TCP connection
+    // through an in-memory pipe that carries its commands and responses
+    fn start_websocket_gateway(&self, listener: TcpListener) -> JoinHandle<()> {
+        let store = Arc::clone(&self.store);
+        let replication_manager = self.replication_manager.clone();
+        let state = Arc::clone(&self.state);
+        let socket_options = self.socket_options.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (socket, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log_error!("Error accepting WebSocket connection: {}", e);
+                        continue;
+                    }
+                };
+                if state.chaos.refuse_connection() {
+                    log_debug!("Chaos: refusing WebSocket connection from {}", addr);
+                    continue;
+                }
+                log_debug!("New WebSocket connection from: {}", addr);
+
+                if let Err(e) = socket_options.apply(&socket) {
+                    log_warn!("Failed to set socket options for {}: {}", addr, e);
+                }
+
+                let store = Arc::clone(&store);
+                let replication_manager = replication_manager.clone();
+                let state = Arc::clone(&state);
+                state.metrics.connection_opened();
+                tokio::spawn(async move {
+                    let socket = match accept_tls(&state, socket).await {
+                        Ok(socket) => socket,
+                        Err(e) => {
+                            log_debug!("TLS handshake with {} failed: {}", addr, e);
+                            state.metrics.connection_closed();
+                            return;
+                        }
+                    };
+                    let (server_end, gateway_end) = tokio::io::duplex(WEBSOCKET_BUFFER);
+                    let (result, gateway_result) = tokio::join!(
+                        handle_connection(
+                            server_end,
+                            store,
+                            replication_manager,
+                            Arc::clone(&state),
+                        ),
+                        websocket::serve(socket, gateway_end),
+                    );
+                    state.metrics.connection_closed();
+                    if let Err(e) = gateway_result {
+                        log_debug!("WebSocket connection from {} failed: {}", addr, e);
+                    }
+                    if let Err(e) = result {
+                        log_error!("Error handling connection: {}", e);
+                    }
+                });
+            }
+        })
+    }
+
+    // Push metrics to StatsD whenever the config names a daemon
+    fn start_statsd(&self) -> JoinHandle<()> {
+        let store = Arc::clone(&self.store);
+        let state = Arc::clone(&self.state);
+
+        tokio::spawn(async move {
+            let mut reporter = statsd::Reporter::default();
+            loop {
+                let settings = state.statsd.read().unwrap().clone();
+                let Some(settings) = settings else {
+                    tokio::time::sleep(statsd::DEFAULT_FLUSH_INTERVAL).await;
+                    continue;
+                };
+
+                tokio::time::sleep(settings.flush_interval).await;
+                if let Err(e) = reporter.flush(&settings, &store, &state.metrics).await {
+                    log_warn!("Failed to send metrics to {}: {}", settings.address, e);
+                }
+            }
+        })
+    }
+
+    // Move values between memory and disk to stay within memory_budget
+    fn start_tiering(&self) -> JoinHandle<()> {
+        let store = Arc::clone(&self.store);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TIERING_INTERVAL);
+            loop {
+                interval.tick().await;
+                let store = Arc::clone(&store);
+                match tokio::task::spawn_blocking(move || store.rebalance_tiers()).await {
+                    Ok((0, 0)) => {}
+                    Ok((demoted, promoted)) => {
+                        log_debug!(
+                            "Tiering demoted {} and promoted {} values",
+                            demoted,
+                            promoted
+                        )
+                    }
+                    Err(e) => log_error!("Tiering pass failed: {}", e),
+                }
+            }
+        })
+    }
+
+    // Remove expired keys. Until then reads already treat them as gone.
+    // Only the primary does this, sending its deletes to the backups, so a
+    // backup whose clock runs ahead doesn't drop keys the primary still has.
+    fn start_expiry(&self) -> JoinHandle<()> {
+        let store = Arc::clone(&self.store);
+        let state = Arc::clone(&self.state);
+        let replication_manager = self.replication_manager.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Some(rm) = &replication_manager {
+                    // Writes wait out a handoff or safe mode, and so do we
+                    if matches!(rm.get_role().await, Role::Backup(_))
+                        || rm.handoff().is_some()
+                        || rm.safe_mode().is_some()
+                    {
+                        continue;
+                    }
+                }
+                let due = {
+                    let store = Arc::clone(&store);
+                    match tokio::task::spawn_blocking(move || store.due_keys()).await {
+                        Ok(due) => due,
+                        Err(e) => {
+                            log_error!("Expiry pass failed: {}", e);
+                            continue;
+                        }
+                    }
+                };
+                let mut removed = 0;
+                for key in due {
+                    let write = Write::RemoveExpired(key);
+                    match submit_write(write, "expiry", &store, &replication_manager, &state).await
+                    {
+                        Ok(response) if response == "OK" => removed += 1,
+                        Ok(_) => {}
+                        Err(e) => log_error!("Failed to replicate an expired key: {}", e),
+                    }
+                }
+                if removed > 0 {
+                    log_debug!("Removed {} expired keys", removed);
+                }
+            }
+        })
+    }
+
+    // Reload the config file and TLS certificates whenever we receive SIGHUP
+    #[cfg(unix)]
+    fn reload_on_sighup(&self) -> Result<Option<JoinHandle<()>>> {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        if self.state.config_path.read().unwrap().is_none() && self.state.tls.get().is_none() {
+            return Ok(None);
+        }
+
+        let mut hangups = signal(SignalKind::hangup())?;
+        let store = Arc::clone(&self.store);
+        let state = Arc::clone(&self.state);
+        let replication_manager = self.replication_manager.clone();
+        let task = tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                if let Err(e) = reload_config(&store, &state, &replication_manager) {
+                    log_error!("Failed to reload config: {}", e);
                 }
             }
+        });
+        Ok(Some(task))
+    }
+}
+
+// Complete the TLS handshake on an accepted connection, if the server has a
+// certificate
+async fn accept_tls(state: &ServerState, socket: TcpStream) -> Result<Stream> {
+    match state.tls.get() {
+        Some(tls) => tls.accept(socket).await,
+        None => Ok(Stream::Plain(socket)),
+    }
+}
+
+// Resolves on Ctrl-C, or on SIGTERM on Unix
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+// A server started with Server::spawn
+pub struct ServerHandle {
+    address: SocketAddr,
+    websocket_address: Option<SocketAddr>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    // Where the server is listening, with the actual port if it was given 0
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    // Where the WebSocket gateway is listening, if it's enabled
+    pub fn websocket_address(&self) -> Option<SocketAddr> {
+        self.websocket_address
+    }
+
+    // Stop accepting connections and stop background work such as automatic
+    // saves. Connections that are already open are served until they close.
+    pub fn shutdown(self) {
+        for task in self.tasks {
+            task.abort();
+        }
+    }
+}
+
+// Path of the config file the server was started with
+fn config_path(state: &ServerState) -> Result<PathBuf> {
+    state
+        .config_path
+        .read()
+        .unwrap()
+        .clone()
+        .ok_or_else(|| StoreError::ConfigError("Server was started without --config".to_string()))
+}
+
+// Re-read the TLS certificates and the config file, applying its settings.
+// Only a server started with --config needs one.
+fn reload_config(
+    store: &KeyValueStore,
+    state: &ServerState,
+    replication_manager: &Option<Arc<ReplicationManager>>,
+) -> Result<()> {
+    if let Some(tls) = state.tls.get() {
+        tls.reload()?;
+        if state.config_path.read().unwrap().is_none() {
+            return Ok(());
+        }
+    }
+    let path = config_path(state)?;
+    let config = Config::load(&path)?;
+    apply_config(&config, store, state, replication_manager);
+    log_info!("Reloaded config from {}", path.display());
+    Ok(())
+}
+
+// Persist the current runtime parameters into the config file, keeping
+// anything else it contains
+fn rewrite_config(
+    store: &KeyValueStore,
+    replication_manager: &Option<Arc<ReplicationManager>>,
+    state: &ServerState,
+) -> Result<()> {
+    let path = config_path(state)?;
+    let mut config = Config::load(&path)?;
+    let current = current_config(store, state, replication_manager);
+    for param in RUNTIME_PARAMS {
+        if let Some(value) = current.get(param) {
+            config.set(param, &value)?;
+        }
+    }
+    config.save(&path)?;
+    log_info!("Rewrote config file {}", path.display());
+    Ok(())
+}
+
+// The runtime parameters currently in effect
+fn current_config(
+    store: &KeyValueStore,
+    state: &ServerState,
+    replication_manager: &Option<Arc<ReplicationManager>>,
+) -> Config {
+    let snapshots = state.snapshots.get();
+    Config {
+        log_level: Some(logging::level()),
+        log_output: Some(logging::output()),
+        heartbeat_interval_ms: replication_manager
+            .as_ref()
+            .map(|rm| rm.heartbeat_interval().as_millis() as u64),
+        phi_threshold: replication_manager.as_ref().map(|rm| rm.phi_threshold()),
+        acceptable_pause_ms: replication_manager
+            .as_ref()
+            .map(|rm| rm.acceptable_pause().as_millis() as u64),
+        maxmemory: Some(store.max_memory() as u64),
+        maxmemory_policy: Some(store.eviction_policy()),
+        quotas: None,
+        tenants: None,
+        max_keys: Some(store.max_keys()),
+        max_key_length: Some(store.max_key_length()),
+        max_concurrent_commands: Some(state.commands.max_running()),
+        max_queued_commands: Some(state.commands.max_queued()),
+        compression_threshold: Some(store.compression_threshold() as u64),
+        spill_threshold: Some(store.spill_threshold() as u64),
+        memory_budget: Some(store.memory_budget() as u64),
+        vector_clocks: Some(store.tracks_vector_clocks()),
+        save: snapshots.map(|s| s.policy()),
+        snapshot_retention: snapshots.map(|s| s.retention()),
+        snapshot_retention_days: snapshots.map(|s| s.retention_days()),
+        wal_rewrite_size: snapshots.map(|s| s.wal_rewrite_size()),
+        wal_commit_delay_us: store.wal().map(|wal| wal.commit_delay().as_micros() as u64),
+        wal_segment_size: store.wal().map(Wal::segment_size),
+        wal_segment_retention: store.wal().map(Wal::segment_retention),
+        backup_target: None,
+        backup_endpoint: None,
+        backup_command: None,
+        admin_token: None,
+        statsd_address: None,
+        statsd_prefix: None,
+        statsd_flush_interval_ms: None,
+    }
+}
+
+// Apply the settings present in a config file; absent ones are left alone
+fn apply_config(
+    config: &Config,
+    store: &KeyValueStore,
+    state: &ServerState,
+    replication_manager: &Option<Arc<ReplicationManager>>,
+) {
+    if let Some(level) = config.log_level {
+        logging::set_level(level);
+    }
+    if let Some(output) = config.log_output {
+        logging::set_output(output);
+    }
+
+    if let Some(bytes) = config.maxmemory {
+        store.set_max_memory(bytes as usize);
+    }
+    if let Some(policy) = config.maxmemory_policy {
+        store.set_eviction_policy(policy);
+    }
+    if let Some(count) = config.max_keys {
+        store.set_max_keys(count);
+    }
+    if let Some(bytes) = config.max_key_length {
+        store.set_max_key_length(bytes);
+    }
+    if let Some(count) = config.max_concurrent_commands {
+        state.commands.set_max_running(count);
+    }
+    if let Some(count) = config.max_queued_commands {
+        state.commands.set_max_queued(count);
+    }
+    if let Some(bytes) = config.compression_threshold {
+        store.set_compression_threshold(bytes as usize);
+    }
+    if let Some(bytes) = config.spill_threshold {
+        store.set_spill_threshold(bytes as usize);
+    }
+    if let Some(bytes) = config.memory_budget {
+        store.set_memory_budget(bytes as usize);
+    }
+    if let Some(on) = config.vector_clocks {
+        store.set_vector_clocks(on);
+    }
+    if let Some(wal) = store.wal() {
+        if let Some(us) = config.wal_commit_delay_us {
+            wal.set_commit_delay(Duration::from_micros(us));
+        }
+        if let Some(bytes) = config.wal_segment_size {
+            wal.set_segment_size(bytes);
+        }
+        if let Some(count) = config.wal_segment_retention {
+            wal.set_segment_retention(count);
         }
     }
 
+    // Each tenant's limits are a quota on its keyspace
+    if let Some(tenants) = &config.tenants {
+        state.set_tenants(tenants);
+    }
+    if config.quotas.is_some() || config.tenants.is_some() {
+        let mut quotas = config.quotas.clone().unwrap_or_default();
+        for (name, tenant) in config.tenants.iter().flatten() {
+            quotas.insert(format!("{}:*", name), tenant.limits());
+        }
+        store.set_quotas(&quotas);
+    }
+
+    if let Some(snapshots) = state.snapshots.get() {
+        if let Some(policy) = &config.save {
+            snapshots.set_policy(policy.clone());
+        }
+        if let Some(count) = config.snapshot_retention {
+            snapshots.set_retention(count);
+        }
+        if let Some(days) = config.snapshot_retention_days {
+            snapshots.set_retention_days(days);
+        }
+        if let Some(bytes) = config.wal_rewrite_size {
+            snapshots.set_wal_rewrite_size(bytes);
+        }
+        if let Ok(Some(sink)) = config.backup_sink() {
+            snapshots.set_backup_sink(Some(sink));
+        }
+    }
 
+    if let Some(token) = &config.admin_token {
+        if let Some(rm) = replication_manager {
+            rm.set_auth_token(Some(token.clone()));
+        }
+        *state.admin_token.write().unwrap() = Some(token.clone());
+    }
+    if let Some(settings) = config.statsd_settings() {
+        *state.statsd.write().unwrap() = Some(settings);
+    }
 
+    if let Some(rm) = replication_manager {
+        if let Some(ms) = config.heartbeat_interval_ms {
+            rm.set_heartbeat_interval(Duration::from_millis(ms));
+        }
+        if let Some(threshold) = config.phi_threshold {
+            rm.set_phi_threshold(threshold);
+        }
+        if let Some(ms) = config.acceptable_pause_ms {
+            rm.set_acceptable_pause(Duration::from_millis(ms));
+        }
+    }
 }
 
-async fn handle_connection(
-    socket: TcpStream,
+async fn handle_connection<S: AsyncRead + AsyncWrite + Send>(
+    socket: S,
     store: Arc<KeyValueStore>,
     replication_manager: Option<Arc<ReplicationManager>>,
+    state: Arc<ServerState>,
 ) -> Result<()> {
     let (reader, mut writer) = tokio::io::split(socket);
     let mut reader = BufReader::new(reader);
     let mut line = String::new();
+    let mut session = Session::default();
 
     loop {
         // Read command
@@ -118,48 +922,871 @@ async fn handle_connection(
         if reader
             .read_line(&mut line)
             .await
-            .map_err(|e| StoreError::IoError(e))?
+            .map_err(StoreError::IoError)?
             == 0
         {
             break;
         }
 
+        // Commands can carry a request ID for tracing, otherwise we make one
+        let (request_id, command) = match split_request_id(line.trim()) {
+            (Some(request_id), command) => (request_id.to_string(), command),
+            (None, command) => {
+                let n = state.request_ids.fetch_add(1, Ordering::Relaxed) + 1;
+                (format!("{}-{}", store.node_id(), n), command)
+            }
+        };
+
+        // SYNC turns the connection into a one-way stream of changes
+        if session.tenant.is_none()
+            && let Some(from_seq) = sync_request(command)
+        {
+            match from_seq {
+                Some(from_seq) => return stream_changes(&mut writer, &store, from_seq).await,
+                None => {
+                    writer
+                        .write_all(b"ERROR: Usage: SYNC <from-seq>\n")
+                        .await
+                        .map_err(StoreError::IoError)?;
+                    continue;
+                }
+            }
+        }
+
+        // So does SUBSCRIBE, into one of keyspace notifications
+        if session.tenant.is_none()
+            && let Some(prefix) = subscribe_request(command)
+        {
+            match prefix {
+                Some(prefix) => return stream_notifications(&mut writer, &store, prefix).await,
+                None => {
+                    writer
+                        .write_all(b"ERROR: Usage: SUBSCRIBE [prefix]\n")
+                        .await
+                        .map_err(StoreError::IoError)?;
+                    continue;
+                }
+            }
+        }
+
+        if let Some(latency) = state.chaos.latency() {
+            tokio::time::sleep(latency).await;
+        }
+        if is_replication_message(command) && state.chaos.drop_replication() {
+            log_debug!("Chaos: dropping '{}'", command);
+            return Ok(());
+        }
+
+        // Wait for a turn to run. Replication messages skip the queue, so a
+        // busy backup isn't taken for a dead one.
+        let turn = if is_replication_message(command) {
+            None
+        } else {
+            Some(state.commands.enter().await)
+        };
+
         // Parse and execute command
-        let response = execute_command(line.trim(), &store, &replication_manager).await?;
+        state.metrics.record_command();
+        let started = Instant::now();
+        let response = if let Some(None) = turn {
+            log_debug!("Request {}: rejected, server busy", request_id);
+            format!("{} Server busy, retry later", TRY_AGAIN)
+        } else {
+            log_debug!("Request {}: {}", request_id, command);
+            execute_command(
+                command,
+                &request_id,
+                &store,
+                &replication_manager,
+                &state,
+                &mut session,
+            )
+            .await?
+        };
+        drop(turn);
+        if let Some(name) = command.split_whitespace().next() {
+            state.metrics.latencies().record(name, started.elapsed());
+        }
 
         // Send response
         writer
             .write_all(response.as_bytes())
             .await
-            .map_err(|e| StoreError::IoError(e))?;
-        writer
-            .write_all(b"\n")
-            .await
-            .map_err(|e| StoreError::IoError(e))?;
-        writer.flush().await.map_err(|e| StoreError::IoError(e))?;
+            .map_err(StoreError::IoError)?;
+        writer.write_all(b"\n").await.map_err(StoreError::IoError)?;
+        writer.flush().await.map_err(StoreError::IoError)?;
     }
 
     Ok(())
 }
 
-async fn execute_command(
-    command: &str,
+// Apply a client write through the writer that owns its key, or directly
+// if the server hasn't started them
+async fn submit_write(
+    write: Write,
+    request_id: &str,
     store: &KeyValueStore,
     replication_manager: &Option<Arc<ReplicationManager>>,
+    state: &ServerState,
 ) -> Result<String> {
-    let parts: Vec<&str> = command.split_whitespace().collect();
+    match state.writers.get() {
+        Some(writers) => writers.submit(write, request_id).await,
+        None => writer::apply(write, request_id, store, replication_manager).await,
+    }
+}
 
-    if parts.is_empty() {
-        return Ok("Error: Empty command".to_string());
+// Split a leading `#<request-id>` off a command
+fn split_request_id(line: &str) -> (Option<&str>, &str) {
+    match line.strip_prefix('#').and_then(|rest| rest.split_once(' ')) {
+        Some((request_id, command)) if !request_id.is_empty() => {
+            (Some(request_id), command.trim_start())
+        }
+        _ => (None, line),
     }
+}
+
+fn is_replication_message(command: &str) -> bool {
+    command.split_whitespace().next().is_some_and(|name| {
+        name.eq_ignore_ascii_case("REPLICATE") || name.eq_ignore_ascii_case("HEARTBEAT")
+    })
+}
+
+// Parse a `SYNC <from-seq>` command: None if it isn't one, Some(None) if the
+// sequence number is missing or invalid
+fn sync_request(command: &str) -> Option<Option<u64>> {
+    let parts: Vec<&str> = command.split_whitespace().collect();
+    if !parts.first()?.eq_ignore_ascii_case("SYNC") {
+        return None;
+    }
+
+    match parts.as_slice() {
+        [_, seq] => Some(seq.parse().ok()),
+        _ => Some(None),
+    }
+}
+
+// Send changes from `from_seq` onwards as JSON lines, from the changelog or
+// the write-ahead log, then keep following new ones until the consumer
+// disconnects or falls too far behind
+async fn stream_changes<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    store: &Arc<KeyValueStore>,
+    from_seq: u64,
+) -> Result<()> {
+    let subscription = {
+        let store = Arc::clone(store);
+        tokio::task::spawn_blocking(move || store.changes_from(from_seq))
+            .await
+            .map_err(|e| StoreError::ReplicationError(e.to_string()))?
+    };
+    let (backlog, mut receiver) = match subscription {
+        Ok(subscription) => subscription,
+        Err(e) => {
+            writer
+                .write_all(format!("ERROR: {}\n", e).as_bytes())
+                .await?;
+            return Ok(());
+        }
+    };
+    log_debug!("SYNC consumer attached at seq {}", from_seq);
+
+    let mut next_seq = from_seq;
+    for change in backlog {
+        next_seq = change.seq + 1;
+        write_change(writer, &change).await?;
+    }
+
+    loop {
+        match receiver.recv().await {
+            // Skip anything the backlog already covered
+            Ok(change) if change.seq < next_seq => continue,
+            Ok(change) => {
+                next_seq = change.seq + 1;
+                if write_change(writer, &change).await.is_err() {
+                    // Consumer went away
+                    return Ok(());
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                let message = format!(
+                    "ERROR: Consumer fell behind, resume with SYNC {}\n",
+                    next_seq
+                );
+                writer.write_all(message.as_bytes()).await?;
+                return Ok(());
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+// Parse a `SUBSCRIBE [prefix]` command: None if it isn't one, Some(None) if
+// it has too many arguments
+fn subscribe_request(command: &str) -> Option<Option<&str>> {
+    let parts: Vec<&str> = command.split_whitespace().collect();
+    if !parts.first()?.eq_ignore_ascii_case("SUBSCRIBE") {
+        return None;
+    }
+
+    match parts.as_slice() {
+        [_] => Some(Some("")),
+        [_, prefix] => Some(Some(prefix)),
+        _ => Some(None),
+    }
+}
+
+// Send keyspace notifications for keys starting with `prefix` as they
+// happen, until the subscriber disconnects or falls too far behind
+async fn stream_notifications<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    store: &KeyValueStore,
+    prefix: &str,
+) -> Result<()> {
+    let mut receiver = store.notifications();
+    writer.write_all(b"OK\n").await?;
+    writer.flush().await?;
+    log_debug!("Subscriber attached for '{}'", prefix);
+
+    loop {
+        match receiver.recv().await {
+            Ok(notification) if !notification.key.starts_with(prefix) => continue,
+            Ok(notification) => {
+                let line = format!("{}\n", notification);
+                if writer.write_all(line.as_bytes()).await.is_err() || writer.flush().await.is_err()
+                {
+                    // Subscriber went away
+                    return Ok(());
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                let message = format!(
+                    "ERROR: Subscriber fell behind, {} notifications lost\n",
+                    missed
+                );
+                writer.write_all(message.as_bytes()).await?;
+                return Ok(());
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+async fn write_change<W: AsyncWriteExt + Unpin>(writer: &mut W, change: &Change) -> Result<()> {
+    let mut line =
+        serde_json::to_vec(change).map_err(|e| StoreError::SerializationError(e.to_string()))?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn execute_command(
+    command: &str,
+    request_id: &str,
+    store: &KeyValueStore,
+    replication_manager: &Option<Arc<ReplicationManager>>,
+    state: &ServerState,
+    session: &mut Session,
+) -> Result<String> {
+    let scoped_keys: Vec<String>;
+    let mut parts: Vec<&str> = command.split_whitespace().collect();
+
+    if parts.is_empty() {
+        return Ok("Error: Empty command".to_string());
+    }
+
+    let name = parts[0].to_uppercase();
+
+    // While the store is still loading in the background, commands that
+    // need all of it wait here without holding up the runtime. Reads of
+    // keys that are already in, and commands about the server itself, go
+    // ahead.
+    if store.is_loading() {
+        let ready = match name.as_str() {
+            "AUTH" | "SELECT" | "INFO" | "HEALTH" | "SAFEMODE" | "MAINTENANCE" | "QUOTA"
+            | "TENANT" | "LASTSAVE" | "CHAOS" | "HEARTBEAT" => true,
+            "GET" => parts.get(1).is_some_and(|key| {
+                let key = format!("{}{}", session.key_prefix(), key);
+                store.get_loaded(&key).is_some()
+            }),
+            _ => false,
+        };
+        if !ready {
+            store.loaded().await;
+        }
+    }
+
+    // Tenants can't run anything that reaches across the keyspace or the
+    // server
+    if let Some(tenant_name) = &session.tenant
+        && name != "AUTH"
+    {
+        let Some(tenant) = state.tenant(tenant_name) else {
+            return Ok("ERROR: Tenant no longer exists".to_string());
+        };
+
+        match name.as_str() {
+            "GET" | "MGET" | "VERSION" | "OBJECT" | "KEYS" | "LIST" | "SCAN" | "DBSIZE" | "TTL"
+            | "STRLEN" => tenant.record_read(),
+            "PUT" | "MSET" | "SETNX" | "CAS" | "DELETE" | "RENAME" | "COPY" | "GETORSET"
+            | "GETDEL" | "GETSET" | "RESOLVE" | "EXPIRE" | "EXPIREAT" | "PERSIST" | "TOUCH"
+            | "INCR" | "DECR" | "INCRBY" | "APPEND" | "GCOUNTER" | "PNCOUNTER" | "LWW"
+            | "ORSET" => {
+                if tenant.is_frozen() {
+                    return Ok(format!("ERROR: Tenant {} is frozen", tenant.name()));
+                }
+                tenant.record_write();
+            }
+            "SELECT" => {}
+            "INFO" => return Ok(tenant_info(store, &tenant)),
+            _ => return Ok(format!("ERROR: {} is not available to tenants", name)),
+        }
+    }
+
+    // Connections with a tenant or namespace only see keys under its prefix
+    let prefix = session.key_prefix();
+    if !prefix.is_empty() {
+        let unscoped = |keys: Vec<String>| {
+            let keys: Vec<&str> = keys
+                .iter()
+                .filter_map(|key| key.strip_prefix(prefix.as_str()))
+                .collect();
+            if keys.is_empty() {
+                "No keys found".to_string()
+            } else {
+                keys.join(", ")
+            }
+        };
+
+        match name.as_str() {
+            "KEYS" => return Ok(unscoped(store.keys())),
+            "DBSIZE" => return Ok(db_size(store, &prefix, &parts)),
+            "SCAN" => return scan_page(store, &prefix, &parts),
+            "LIST" => {
+                let Some((after, limit)) = list_args(&parts[1..]) else {
+                    return Ok(LIST_USAGE.to_string());
+                };
+                let after = after.map(|after| format!("{}{}", prefix, after));
+                return Ok(unscoped(store.list(&prefix, after.as_deref(), limit)));
+            }
+            "RANDOMKEY" | "SAMPLE" => {
+                return Ok(format!("ERROR: {} is not available in a namespace", name));
+            }
+            _ => {}
+        }
+
+        // OBJECT and the CRDT commands take a subcommand before the key, MGET
+        // any number of keys and MSET keys and values in turn
+        let key_indexes: Vec<usize> = match name.as_str() {
+            "OBJECT" | "GCOUNTER" | "PNCOUNTER" | "LWW" | "ORSET" => vec![2],
+            "MGET" | "DELETE" => (1..parts.len()).collect(),
+            "RENAME" | "COPY" => vec![1, 2],
+            "MSET" => (1..parts.len()).step_by(2).collect(),
+            "GET" | "VERSION" | "PUT" | "SETNX" | "CAS" | "GETORSET" | "GETDEL" | "GETSET"
+            | "RESOLVE" | "EXPIRE" | "EXPIREAT" | "PERSIST" | "TOUCH" | "TTL" | "INCR" | "DECR"
+            | "INCRBY" | "APPEND" | "STRLEN" => vec![1],
+            _ => vec![],
+        };
+        let key_indexes: Vec<usize> = key_indexes
+            .into_iter()
+            .filter(|&index| index < parts.len())
+            .collect();
+        scoped_keys = key_indexes
+            .iter()
+            .map(|&index| format!("{}{}", prefix, parts[index]))
+            .collect();
+        for (&index, key) in key_indexes.iter().zip(&scoped_keys) {
+            parts[index] = key;
+        }
+    }
+
+    // Refuse new writes while in maintenance, but keep serving reads and
+    // replication traffic so backups can drain
+    let is_write = matches!(
+        name.as_str(),
+        "PUT"
+            | "MSET"
+            | "RENAME"
+            | "COPY"
+            | "SETNX"
+            | "CAS"
+            | "DELETE"
+            | "GETORSET"
+            | "GETDEL"
+            | "GETSET"
+            | "RESOLVE"
+            | "EXPIRE"
+            | "EXPIREAT"
+            | "PERSIST"
+            | "TOUCH"
+            | "INCR"
+            | "DECR"
+            | "INCRBY"
+            | "APPEND"
+            | "GCOUNTER"
+            | "PNCOUNTER"
+            | "LWW"
+            | "ORSET"
+            | "MERGE"
+    );
+    if is_write && state.maintenance.load(Ordering::SeqCst) {
+        return Ok(format!("{} Server in maintenance, retry later", TRY_AGAIN));
+    }
+
+    if is_write && let Some(rm) = replication_manager {
+        // Paused while handing off, and sent to the new primary afterwards
+        if let Some(target) = rm.handoff() {
+            return Ok(match rm.get_role().await {
+                Role::Primary => format!("{} Handing off to {}", TRY_AGAIN, target),
+                _ => format!("ERROR: MOVED {}", target),
+            });
+        }
+        // Another node claims to be primary too, so our writes would diverge
+        if let Some(reason) = rm.safe_mode() {
+            return Ok(format!("ERROR: Safe mode, writes are refused: {}", reason));
+        }
+        // A primary cut off from its backups may already have been replaced
+        if !rm.holds_lease().await {
+            return Ok(format!(
+                "{} Primary lease expired, can't reach a majority of backups",
+                TRY_AGAIN
+            ));
+        }
+    }
+
+    // Match the command
+    match name.as_str() {
+        // Admin commands
+        "AUTH" => {
+            if parts.len() != 2 {
+                return Ok("ERROR: Usage: AUTH <token>".to_string());
+            }
+
+            if let Some(tenant) = state.tenant_by_token(parts[1]) {
+                *session = Session {
+                    admin: false,
+                    tenant: Some(tenant.name().to_string()),
+                    namespace: None,
+                };
+                return Ok("OK".to_string());
+            }
+
+            match &*state.admin_token.read().unwrap() {
+                None => Ok("ERROR: No admin token configured".to_string()),
+                Some(token) if token == parts[1] => {
+                    *session = Session {
+                        admin: true,
+                        tenant: None,
+                        namespace: None,
+                    };
+                    Ok("OK".to_string())
+                }
+                Some(_) => Ok("ERROR: Invalid token".to_string()),
+            }
+        }
+        // Keys named from now on live under "<namespace>:"; 0 goes back to
+        // the top level, as in Redis
+        "SELECT" => match parts.as_slice() {
+            [_, "0"] => {
+                session.namespace = None;
+                Ok("OK".to_string())
+            }
+            [_, namespace] if namespace.contains(':') => {
+                Ok("ERROR: Namespace names can't contain ':'".to_string())
+            }
+            [_, namespace] => {
+                session.namespace = Some(namespace.to_string());
+                Ok("OK".to_string())
+            }
+            _ => Ok("ERROR: Usage: SELECT <namespace>".to_string()),
+        },
+        "CONFIG" => {
+            if !state.is_admin(session.admin) {
+                return Ok("ERROR: Admin authentication required".to_string());
+            }
+
+            match parts.get(1).map(|s| s.to_uppercase()).as_deref() {
+                Some("GET") if parts.len() == 3 => {
+                    let current = current_config(store, state, replication_manager);
+                    let param = parts[2].to_lowercase();
+
+                    if param == "*" {
+                        let values: Vec<String> = RUNTIME_PARAMS
+                            .iter()
+                            .filter_map(|p| current.get(p).map(|v| format!("{}={}", p, v)))
+                            .collect();
+                        return Ok(values.join(", "));
+                    }
+
+                    match current.get(&param) {
+                        Some(value) => Ok(value),
+                        None if param == "save"
+                            || param == "wal_rewrite_size"
+                            || param.starts_with("snapshot_retention") =>
+                        {
+                            Ok("ERROR: No database file configured".to_string())
+                        }
+                        None if param.starts_with("wal_") => {
+                            Ok("ERROR: No write-ahead log, start with --wal".to_string())
+                        }
+                        None if RUNTIME_PARAMS.contains(&param.as_str()) => Ok(format!(
+                            "ERROR: {} is not available without replication",
+                            param
+                        )),
+                        None => Ok(format!("ERROR: Unknown parameter '{}'", param)),
+                    }
+                }
+                Some("SET") if parts.len() >= 4 => {
+                    // Values such as save rules may contain spaces
+                    let param = parts[2].to_lowercase();
+                    let value = parts[3..].join(" ");
+                    let mut update = Config::default();
+                    if let Err(e) = update.set(&param, &value) {
+                        return Ok(format!("ERROR: {}", e));
+                    }
+                    if replication_manager.is_none()
+                        && (update.heartbeat_interval_ms.is_some()
+                            || update.phi_threshold.is_some()
+                            || update.acceptable_pause_ms.is_some())
+                    {
+                        return Ok(format!(
+                            "ERROR: {} is not available without replication",
+                            param
+                        ));
+                    }
+                    if state.snapshots.get().is_none()
+                        && (update.save.is_some()
+                            || update.snapshot_retention.is_some()
+                            || update.snapshot_retention_days.is_some()
+                            || update.wal_rewrite_size.is_some())
+                    {
+                        return Ok("ERROR: No database file configured".to_string());
+                    }
+                    if store.wal().is_none()
+                        && (update.wal_commit_delay_us.is_some()
+                            || update.wal_segment_size.is_some()
+                            || update.wal_segment_retention.is_some())
+                    {
+                        return Ok("ERROR: No write-ahead log, start with --wal".to_string());
+                    }
+
+                    apply_config(&update, store, state, replication_manager);
+                    log_info!("CONFIG SET {} {}", param, value);
+                    Ok("OK".to_string())
+                }
+                Some("REWRITE") => match rewrite_config(store, replication_manager, state) {
+                    Ok(()) => Ok("OK".to_string()),
+                    Err(e) => Ok(format!("ERROR: {}", e)),
+                },
+                Some("RELOAD") => match reload_config(store, state, replication_manager) {
+                    Ok(()) => Ok("OK".to_string()),
+                    Err(e) => Ok(format!("ERROR: {}", e)),
+                },
+                _ => Ok(
+                    "ERROR: Usage: CONFIG GET <param> | SET <param> <value> | REWRITE | RELOAD"
+                        .to_string(),
+                ),
+            }
+        }
+        "SAVE" | "BGSAVE" => {
+            if !state.is_admin(session.admin) {
+                return Ok("ERROR: Admin authentication required".to_string());
+            }
+            let Some(snapshots) = state.snapshots.get() else {
+                return Ok("ERROR: No database file configured".to_string());
+            };
+
+            if name == "SAVE" {
+                match snapshots.save().await {
+                    Ok(()) => Ok("OK".to_string()),
+                    Err(e) => Ok(format!("ERROR: {}", e)),
+                }
+            } else {
+                match snapshots.bgsave() {
+                    Ok(()) => Ok("Background saving started".to_string()),
+                    Err(e) => Ok(format!("ERROR: {}", e)),
+                }
+            }
+        }
+        "COMPACT" => {
+            if !state.is_admin(session.admin) {
+                return Ok("ERROR: Admin authentication required".to_string());
+            }
+            let Some(snapshots) = state.snapshots.get() else {
+                return Ok("ERROR: No database file configured".to_string());
+            };
+
+            match snapshots.compact().await {
+                Ok(reclaimed) => Ok(format!("OK reclaimed={}", reclaimed)),
+                Err(e) => Ok(format!("ERROR: {}", e)),
+            }
+        }
+        "ROTATE-KEY" => {
+            if !state.is_admin(session.admin) {
+                return Ok("ERROR: Admin authentication required".to_string());
+            }
+            let Some(encryption) = encryption::current() else {
+                return Ok("ERROR: Encryption at rest is not enabled".to_string());
+            };
+
+            let id = match encryption.rotate() {
+                Ok(id) => id,
+                Err(e) => return Ok(format!("ERROR: {}", e)),
+            };
+            log_info!("Encryption key rotated to '{}'", id);
+            // Rewrite the database file under the new key right away
+            if let Some(snapshots) = state.snapshots.get()
+                && let Err(e) = snapshots.save().await
+            {
+                return Ok(format!(
+                    "ERROR: Rotated to key '{}' but saving failed: {}",
+                    id, e
+                ));
+            }
+            Ok(format!("OK {}", id))
+        }
+        "VERIFY" => {
+            if !state.is_admin(session.admin) {
+                return Ok("ERROR: Admin authentication required".to_string());
+            }
+            let Some(snapshots) = state.snapshots.get() else {
+                return Ok("ERROR: No database file configured".to_string());
+            };
+
+            match snapshots.verify().await {
+                Ok(report) => {
+                    if report.status() != "ok" {
+                        let keys = report.differing_keys();
+                        log_warn!(
+                            "VERIFY: {} (first differing keys: {:?})",
+                            report,
+                            &keys[..keys.len().min(10)]
+                        );
+                    }
+                    Ok(report.to_string())
+                }
+                Err(e) => Ok(format!("ERROR: {}", e)),
+            }
+        }
+        "INFO"
+            if parts
+                .get(1)
+                .is_some_and(|s| s.eq_ignore_ascii_case("REPLICATION")) =>
+        {
+            let Some(rm) = replication_manager else {
+                return Ok("role:standalone".to_string());
+            };
+            Ok(replication_info(store, rm).await)
+        }
+        "INFO"
+            if parts
+                .get(1)
+                .is_some_and(|s| s.eq_ignore_ascii_case("LATENCY")) =>
+        {
+            Ok(state.metrics.latencies().info())
+        }
+        "INFO" => {
+            let mut info = vec![
+                // Counts the keys loaded so far while still loading
+                format!("keys:{}", store.loaded_len()),
+                format!("loading:{}", store.is_loading() as u8),
+                format!("max_keys:{}", store.max_keys()),
+                format!("used_memory:{}", store.used_memory()),
+                format!("maxmemory:{}", store.max_memory()),
+                format!("maxmemory_policy:{}", store.eviction_policy()),
+                format!("evicted_keys:{}", store.evicted_keys()),
+                format!("expired_keys:{}", store.expired_keys()),
+                format!("changelog_seq:{}", store.changelog().last_seq()),
+                format!("wal_size:{}", store.wal_size()),
+                format!("wal_syncs:{}", store.wal().map_or(0, Wal::syncs)),
+                format!("node_id:{}", store.node_id()),
+                format!("hlc:{}", store.hlc().current()),
+                state.commands.info(),
+            ];
+            if let Some(lsm) = store.lsm() {
+                info.push(lsm.info());
+            }
+            if let Some(snapshots) = state.snapshots.get() {
+                info.push(format!(
+                    "save_in_progress:{}",
+                    snapshots.in_progress() as u8
+                ));
+                let status = if snapshots.last_save_failed() {
+                    "err"
+                } else {
+                    "ok"
+                };
+                info.push(format!("last_save_status:{}", status));
+            }
+            Ok(info.join(", "))
+        }
+        "QUOTA" => {
+            let usage = store.quota_usage(parts.get(1).copied());
+            if usage.is_empty() {
+                return Ok("ERROR: No such quota".to_string());
+            }
+            Ok(usage.join(" | "))
+        }
+        "TENANT" => {
+            if !state.is_admin(session.admin) {
+                return Ok("ERROR: Admin authentication required".to_string());
+            }
+
+            let subcommand = parts.get(1).map(|s| s.to_uppercase());
+            if matches!(subcommand.as_deref(), None | Some("LIST")) {
+                let tenants = state.tenants.read().unwrap();
+                if tenants.is_empty() {
+                    return Ok("No tenants configured".to_string());
+                }
+                return Ok(tenants.keys().cloned().collect::<Vec<_>>().join(", "));
+            }
+
+            let Some(tenant) = parts.get(2).and_then(|name| state.tenant(name)) else {
+                return Ok("ERROR: Usage: TENANT LIST | INFO|FREEZE|UNFREEZE <tenant>".to_string());
+            };
+            match subcommand.as_deref() {
+                Some("INFO") => Ok(tenant_info(store, &tenant)),
+                Some("FREEZE") | Some("UNFREEZE") => {
+                    let frozen = subcommand.as_deref() == Some("FREEZE");
+                    tenant.set_frozen(frozen);
+                    log_info!("Tenant {} frozen: {}", tenant.name(), frozen);
+                    Ok("OK".to_string())
+                }
+                _ => Ok("ERROR: Usage: TENANT LIST | INFO|FREEZE|UNFREEZE <tenant>".to_string()),
+            }
+        }
+        "SNAPSHOTS" => {
+            if !state.is_admin(session.admin) {
+                return Ok("ERROR: Admin authentication required".to_string());
+            }
+            let Some(snapshots) = state.snapshots.get() else {
+                return Ok("ERROR: No database file configured".to_string());
+            };
+
+            match snapshots.snapshots() {
+                Ok(list) if list.is_empty() => Ok("No snapshots".to_string()),
+                Ok(list) => Ok(list
+                    .iter()
+                    .map(|snapshot| snapshot.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" | ")),
+                Err(e) => Ok(format!("ERROR: {}", e)),
+            }
+        }
+        "LASTSAVE" => match state.snapshots.get() {
+            Some(snapshots) => Ok(snapshots.last_save().to_string()),
+            None => Ok("ERROR: No database file configured".to_string()),
+        },
+        "CHAOS" => {
+            if !state.is_admin(session.admin) {
+                return Ok("ERROR: Admin authentication required".to_string());
+            }
+            if !state.chaos.is_enabled() {
+                return Ok("ERROR: Chaos mode is off, start the server with --chaos".to_string());
+            }
+            if parts.len() == 1 {
+                return Ok(state.chaos.to_string());
+            }
+
+            match state.chaos.apply(&parts[1..]) {
+                Ok(()) => {
+                    log_warn!("CHAOS {} ({})", parts[1..].join(" "), state.chaos);
+                    Ok("OK".to_string())
+                }
+                Err(e) => Ok(e),
+            }
+        }
+        // Probes for orchestrators: LIVE answers as long as the process
+        // serves connections, READY only once it should get traffic
+        "HEALTH" => match parts.get(1).map(|s| s.to_uppercase()).as_deref() {
+            Some("LIVE") => Ok("OK".to_string()),
+            Some("READY") => {
+                let mut reasons = Vec::new();
+                if state.maintenance.load(Ordering::SeqCst) {
+                    reasons.push("in maintenance");
+                }
+                if replication_manager
+                    .as_ref()
+                    .is_some_and(|rm| !rm.is_synced())
+                {
+                    reasons.push("catching up with the primary");
+                }
+                if store.is_loading() {
+                    reasons.push("loading");
+                }
+                Ok(if reasons.is_empty() {
+                    "OK".to_string()
+                } else {
+                    format!("NOT READY: {}", reasons.join(", "))
+                })
+            }
+            _ => Ok("ERROR: Usage: HEALTH LIVE|READY".to_string()),
+        },
+        "SAFEMODE" => {
+            let Some(rm) = replication_manager else {
+                return Ok("OFF".to_string());
+            };
+            match parts.get(1).map(|s| s.to_uppercase()).as_deref() {
+                None => Ok(match rm.safe_mode() {
+                    Some(reason) => format!("ON: {}", reason),
+                    None => "OFF".to_string(),
+                }),
+                Some("OFF") => {
+                    if !state.is_admin(session.admin) {
+                        return Ok("ERROR: Admin authentication required".to_string());
+                    }
+                    rm.leave_safe_mode();
+                    Ok("OK".to_string())
+                }
+                Some(_) => Ok("ERROR: Usage: SAFEMODE [OFF]".to_string()),
+            }
+        }
+        "MAINTENANCE" => {
+            if !state.is_admin(session.admin) {
+                return Ok("ERROR: Admin authentication required".to_string());
+            }
+
+            match parts.get(1).map(|s| s.to_uppercase()).as_deref() {
+                None => Ok(if state.maintenance.load(Ordering::SeqCst) {
+                    "ON".to_string()
+                } else {
+                    "OFF".to_string()
+                }),
+                Some("ON") => {
+                    state.maintenance.store(true, Ordering::SeqCst);
+                    log_info!("Entering maintenance mode, refusing new writes");
+                    Ok("OK".to_string())
+                }
+                Some("OFF") => {
+                    state.maintenance.store(false, Ordering::SeqCst);
+                    log_info!("Leaving maintenance mode");
+                    Ok("OK".to_string())
+                }
+                Some(_) => Ok("ERROR: Usage: MAINTENANCE [ON|OFF]".to_string()),
+            }
+        }
 
-    // Match the command
-    match parts[0].to_uppercase().as_str() {
         // Special replication commands
         "HEARTBEAT" => {
             if let Some(rm) = replication_manager {
-                rm.receive_heartbeat().await?;
-                Ok("OK".to_string())
+                // Heartbeats from a primary that has since been replaced
+                if let Some(epoch) = parts.get(2).and_then(|epoch| epoch.parse().ok())
+                    && let Err(known) = rm.observe_epoch(epoch)
+                {
+                    return Ok(format!("{} {}", STALE_EPOCH, known));
+                }
+                // A node that has been promoted no longer follows anyone
+                match rm.get_role().await {
+                    Role::Backup(_) => {}
+                    Role::Primary => {
+                        rm.enter_safe_mode("another node sent us heartbeats as its backup");
+                        return Ok(ALSO_PRIMARY.to_string());
+                    }
+                    Role::Standalone => return Ok("ERROR: Not a backup".to_string()),
+                }
+                let primary_seq = parts.get(1).and_then(|seq| seq.parse().ok());
+                rm.receive_heartbeat(primary_seq).await?;
+                Ok(format!("OK {}", rm.applied_seq()))
             } else {
                 Ok("ERROR: Replication not enabled".to_string())
             }
@@ -170,83 +1797,508 @@ async fn execute_command(
             }
 
             if let Some(rm) = replication_manager {
+                if rm.get_role().await == Role::Primary {
+                    rm.enter_safe_mode("another node replicated a write to us");
+                    return Ok(ALSO_PRIMARY.to_string());
+                }
                 let op_str = parts[1..].join(" ");
-                rm.apply_operation(&op_str).await?;
-                Ok("OK".to_string())
+                match rm.apply_operation(&op_str).await {
+                    Ok(()) => {
+                        log_debug!("Applied request {} from the primary", request_id);
+                        Ok("OK".to_string())
+                    }
+                    Err(e) => {
+                        log_warn!("Failed to apply request {}: {}", request_id, e);
+                        Ok(format!("ERROR: {}", e))
+                    }
+                }
             } else {
                 Ok("ERROR: Replication not enabled".to_string())
             }
         }
-        "ADD_BACKUP" => {
+        "DUMP" => {
+            // Entries as a single line of JSON, used by REPAIR on backups.
+            // Read from a snapshot so writes carry on meanwhile.
+            let snapshot = store.snapshot();
+            let selected: HashMap<String, String> = if parts.len() > 1 {
+                parts[1..]
+                    .iter()
+                    .filter_map(|key| Some((key.to_string(), snapshot.get(key)?)))
+                    .collect()
+            } else {
+                snapshot.entries()
+            };
+            serde_json::to_string(&selected)
+                .map_err(|e| StoreError::SerializationError(e.to_string()))
+        }
+        "REPAIR" => {
+            if !state.is_admin(session.admin) {
+                return Ok("ERROR: Admin authentication required".to_string());
+            }
+            let Some(rm) = replication_manager else {
+                return Ok("ERROR: Replication not enabled".to_string());
+            };
+
+            let dry_run = parts
+                .get(1)
+                .is_some_and(|arg| arg.eq_ignore_ascii_case("DRYRUN"));
+            let keys: Vec<String> = parts[if dry_run { 2 } else { 1 }..]
+                .iter()
+                .map(|key| key.to_string())
+                .collect();
+
+            let report = match rm.repair(&keys, dry_run).await {
+                Ok(report) => report,
+                Err(e) => return Ok(format!("ERROR: {}", e)),
+            };
+
+            // Rewrite the local file too, in case it was what went bad
+            let changed = !report.updated.is_empty() || !report.deleted.is_empty();
+            if !dry_run
+                && changed
+                && let Some(snapshots) = state.snapshots.get()
+                && let Err(e) = snapshots.save().await
+            {
+                log_warn!("Repaired store could not be saved: {}", e);
+            }
+            Ok(report.to_string())
+        }
+        "HANDOFF" => {
+            if !state.is_admin(session.admin) {
+                return Ok("ERROR: Admin authentication required".to_string());
+            }
+            if parts.len() != 2 {
+                return Ok("ERROR: Usage: HANDOFF <backup>".to_string());
+            }
+            let Some(rm) = replication_manager else {
+                return Ok("ERROR: Replication not enabled".to_string());
+            };
+            match Arc::clone(rm).hand_off(parts[1]).await {
+                Ok(()) => Ok("OK".to_string()),
+                Err(e) => Ok(format!("ERROR: {}", e)),
+            }
+        }
+        // Sent by a primary handing off to us, with its other backups
+        "TAKEOVER" => {
+            if !state.is_admin(session.admin) {
+                return Ok("ERROR: Admin authentication required".to_string());
+            }
+            let Some(epoch) = parts.get(1).and_then(|epoch| epoch.parse().ok()) else {
+                return Ok("ERROR: Usage: TAKEOVER <epoch> [backup...]".to_string());
+            };
+            let Some(rm) = replication_manager else {
+                return Ok("ERROR: Replication not enabled".to_string());
+            };
+            let backups = parts[2..].iter().map(|addr| addr.to_string()).collect();
+            match Arc::clone(rm).take_over(epoch, backups).await {
+                Ok(seq) => Ok(format!("OK {}", seq)),
+                Err(e) => Ok(format!("ERROR: {}", e)),
+            }
+        }
+        // Sent to the other backups once a handoff has completed
+        "FOLLOW" => {
+            if !state.is_admin(session.admin) {
+                return Ok("ERROR: Admin authentication required".to_string());
+            }
+            if parts.len() != 2 {
+                return Ok("ERROR: Usage: FOLLOW <primary>".to_string());
+            }
+            let Some(rm) = replication_manager else {
+                return Ok("ERROR: Replication not enabled".to_string());
+            };
+            match rm.follow(parts[1].to_string()).await {
+                Ok(()) => Ok("OK".to_string()),
+                Err(e) => Ok(format!("ERROR: {}", e)),
+            }
+        }
+        // REGISTER is sent by backups themselves when they start
+        "ADD_BACKUP" | "REGISTER" => {
             if parts.len() != 2 {
-                return Ok("ERROR: Usage: ADD_BACKUP <address>".to_string());
+                return Ok(format!("ERROR: Usage: {} <address>", name));
             }
 
             if let Some(rm) = replication_manager {
-                rm.add_backup(parts[1].to_string()).await?;
-                Ok("OK".to_string())
+                match rm.add_backup(parts[1].to_string()).await {
+                    Ok(()) => Ok("OK".to_string()),
+                    Err(e) => Ok(format!("ERROR: {}", e)),
+                }
             } else {
                 Ok("ERROR: Replication not enabled".to_string())
             }
         }
 
         "GET" => {
+            let flag = |name: &str| {
+                parts
+                    .get(2..)
+                    .unwrap_or_default()
+                    .iter()
+                    .any(|part| part.eq_ignore_ascii_case(name))
+            };
+            let (linearizable, with_version) = (flag("LINEARIZABLE"), flag("WITHVERSION"));
+            if parts.len() < 2 || parts.len() != 2 + linearizable as usize + with_version as usize {
+                return Ok("Error: GET <key> [LINEARIZABLE] [WITHVERSION]".to_string());
+            }
+
+            // Only read once the primary knows it hasn't been replaced
+            if linearizable && let Some(rm) = replication_manager {
+                match rm.get_role().await {
+                    Role::Primary => {
+                        if let Err(e) = rm.confirm_leadership().await {
+                            return Ok(format!("{} {}", TRY_AGAIN, e));
+                        }
+                    }
+                    Role::Backup(primary) => {
+                        return Ok(format!(
+                            "ERROR: Linearizable reads go to the primary at {}",
+                            primary
+                        ));
+                    }
+                    Role::Standalone => {}
+                }
+            }
+
+            // Values written concurrently on different nodes are all returned
+            if let Some(values) = store.siblings(parts[1]) {
+                let values = serde_json::to_string(&values)
+                    .map_err(|e| StoreError::SerializationError(e.to_string()))?;
+                return Ok(format!("SIBLINGS {}", values));
+            }
+            // CRDTs are read as what their state adds up to
+            match store.get_versioned(parts[1]) {
+                Some((value, version)) if with_version => {
+                    Ok(format!("{} {}", version, crdt::shown(value)))
+                }
+                Some((value, _)) => Ok(crdt::shown(value)),
+                None => Ok("Key not found".to_string()),
+            }
+        }
+
+        "MGET" => {
+            if parts.len() < 2 {
+                return Ok("Error: Usage: MGET <key> [key...]".to_string());
+            }
+            // A JSON array with null for missing keys
+            let values: Vec<Option<String>> = store
+                .get_many(&parts[1..])
+                .into_iter()
+                .map(|value| value.map(crdt::shown))
+                .collect();
+            serde_json::to_string(&values)
+                .map_err(|e| StoreError::SerializationError(e.to_string()))
+        }
+
+        "MSET" => {
+            if parts.len() < 3 || parts.len().is_multiple_of(2) {
+                return Ok("Error: Usage: MSET <key> <value> [key value...]".to_string());
+            }
+            let entries = parts[1..]
+                .chunks(2)
+                .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+                .collect();
+            let write = Write::PutMany(entries);
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "VERSION" => {
             if parts.len() != 2 {
-                return Ok("Error: GET <key>".to_string());
+                return Ok("Error: VERSION <key>".to_string());
             }
+            Ok(store.version(parts[1]).to_string())
+        }
 
-            match store.get(parts[1]) {
-                Some(value) => Ok(value),
+        "OBJECT" => {
+            if parts.len() != 3 || !parts[1].eq_ignore_ascii_case("INFO") {
+                return Ok("Error: OBJECT INFO <key>".to_string());
+            }
+            match store.metadata(parts[2]) {
+                Some(meta) => Ok(meta.to_string()),
                 None => Ok("Key not found".to_string()),
             }
         }
 
         "PUT" => {
+            const USAGE: &str = "Error: Usage: PUT <key> <value> \
+                [IF-ABSENT | IF-VALUE <old> | IF-VERSION <n>] [EX <seconds>]";
             if parts.len() < 3 {
-                return Ok("Error: Usage: PUT <key> <value>".to_string());
+                return Ok(USAGE.to_string());
             }
+            let Some((value, ttl)) = put_ttl(&parts[2..]) else {
+                return Ok(USAGE.to_string());
+            };
+            let Some((value, condition)) = put_condition(value) else {
+                return Ok(USAGE.to_string());
+            };
             // Join all remaining parts for value (to allow spaces)
             let key = parts[1].to_string();
-            let value = parts[2..].join(" ");
+            let value = value.join(" ");
+            let write = match ttl {
+                Some(seconds) => Write::PutExpiring(key, value, condition, expires_in(seconds)),
+                None => Write::Put(key, value, condition),
+            };
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
 
-            // Apply locally
-            store.put(key.clone(), value.clone());
+        // Shorthand for PUT ... IF-VERSION
+        "CAS" => {
+            const USAGE: &str = "Error: Usage: CAS <key> <expected-version> <value>";
+            let version = parts.get(2).and_then(|version| version.parse().ok());
+            let (Some(version), 4..) = (version, parts.len()) else {
+                return Ok(USAGE.to_string());
+            };
+            let write = Write::Put(
+                parts[1].to_string(),
+                parts[3..].join(" "),
+                Condition::Version(version),
+            );
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
 
-            // Replicate if we're primary
-            if let Some(rm) = replication_manager {
-                if let Role::Primary = rm.get_role().await {
-                    let op = Operation::Put(key, value);
-                    rm.replicate_operation(&op).await?;
-                }
+        // Shorthand for PUT ... IF-ABSENT
+        "SETNX" => {
+            if parts.len() < 3 {
+                return Ok("Error: Usage: SETNX <key> <value>".to_string());
             }
+            let write = Write::Put(
+                parts[1].to_string(),
+                parts[2..].join(" "),
+                Condition::Absent,
+            );
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
 
-            Ok("OK".to_string())
+        "RENAME" => {
+            let [_, src, dst] = parts[..] else {
+                return Ok("Error: Usage: RENAME <src> <dst>".to_string());
+            };
+            let write = Write::Rename(src.to_string(), dst.to_string());
+            submit_write(write, request_id, store, replication_manager, state).await
         }
 
-        "DELETE" => {
-            if parts.len() != 2 {
-                return Ok("Error: DELETE <key>".to_string());
+        "COPY" => {
+            let replace = match parts.get(3) {
+                None => false,
+                Some(flag) if flag.eq_ignore_ascii_case("REPLACE") => true,
+                Some(_) => return Ok("Error: Usage: COPY <src> <dst> [REPLACE]".to_string()),
+            };
+            if parts.len() < 3 || parts.len() > 4 {
+                return Ok("Error: Usage: COPY <src> <dst> [REPLACE]".to_string());
             }
-            let key = parts[1].to_string();
-            let deleted = store.delete(&key);
-
-            // Replicate if we're primary and it was deleted
-            if deleted {
-                if let Some(rm) = replication_manager {
-                    if let Role::Primary = rm.get_role().await {
-                        let op = Operation::Delete(key);
-                        rm.replicate_operation(&op).await?;
-                    }
-                }
+            let write = Write::Copy(parts[1].to_string(), parts[2].to_string(), replace);
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "EXPIRE" => {
+            const USAGE: &str = "Error: Usage: EXPIRE <key> <seconds>";
+            let seconds = parts.get(2).and_then(|seconds| seconds.parse().ok());
+            let (Some(seconds), 3) = (seconds, parts.len()) else {
+                return Ok(USAGE.to_string());
+            };
+            let write = Write::Expire(parts[1].to_string(), expires_in(seconds));
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "EXPIREAT" => {
+            const USAGE: &str = "Error: Usage: EXPIREAT <key> <unix-seconds>";
+            let at = parts.get(2).and_then(|at| at.parse::<u64>().ok());
+            let (Some(at), 3) = (at, parts.len()) else {
+                return Ok(USAGE.to_string());
+            };
+            // 0 would mean never, but a time in the past expires the key
+            let expires = at.saturating_mul(1000).max(1);
+            let write = Write::Expire(parts[1].to_string(), expires);
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "PERSIST" => {
+            if parts.len() != 2 {
+                return Ok("Error: PERSIST <key>".to_string());
             }
+            let write = Write::Persist(parts[1].to_string());
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
 
-            if deleted {
-                Ok("OK".to_string())
-            } else {
-                Ok("NULL".to_string())
+        "TOUCH" => {
+            const USAGE: &str = "Error: Usage: TOUCH <key> [seconds]";
+            let expires = match parts.len() {
+                2 => None,
+                3 => match parts[2].parse() {
+                    Ok(seconds) => Some(expires_in(seconds)),
+                    Err(_) => return Ok(USAGE.to_string()),
+                },
+                _ => return Ok(USAGE.to_string()),
+            };
+            let write = Write::Touch(parts[1].to_string(), expires);
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "TTL" => {
+            if parts.len() != 2 {
+                return Ok("Error: TTL <key>".to_string());
             }
+            Ok(store.ttl(parts[1]).to_string())
         }
 
+        "GETORSET" => {
+            if parts.len() < 3 {
+                return Ok("Error: Usage: GETORSET <key> <default>".to_string());
+            }
+            let write = Write::GetOrSet(parts[1].to_string(), parts[2..].join(" "));
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "RESOLVE" => {
+            if parts.len() < 3 {
+                return Ok("Error: Usage: RESOLVE <key> <value>".to_string());
+            }
+            let value = parts[2..].join(" ");
+            let write = Write::Put(parts[1].to_string(), value, Condition::Conflicted);
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "GETDEL" => {
+            if parts.len() != 2 {
+                return Ok("Error: GETDEL <key>".to_string());
+            }
+            let write = Write::GetDel(parts[1].to_string());
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "INCR" | "DECR" => {
+            if parts.len() != 2 {
+                return Ok(format!("Error: {} <key>", name));
+            }
+            let delta = if name == "INCR" { 1 } else { -1 };
+            let write = Write::Increment(parts[1].to_string(), delta);
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "INCRBY" => {
+            const USAGE: &str = "Error: Usage: INCRBY <key> <delta>";
+            let delta = parts.get(2).and_then(|delta| delta.parse().ok());
+            let (Some(delta), 3) = (delta, parts.len()) else {
+                return Ok(USAGE.to_string());
+            };
+            let write = Write::Increment(parts[1].to_string(), delta);
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "APPEND" => {
+            if parts.len() < 3 {
+                return Ok("Error: Usage: APPEND <key> <suffix>".to_string());
+            }
+            let write = Write::Append(parts[1].to_string(), parts[2..].join(" "));
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "GCOUNTER" | "PNCOUNTER" => {
+            let usage = format!("Error: Usage: {} INCR <key> [amount]", name);
+            if !(3..=4).contains(&parts.len()) || !parts[1].eq_ignore_ascii_case("INCR") {
+                return Ok(usage);
+            }
+            let amount = parts.get(3).copied().unwrap_or("1");
+            let update = match name.as_str() {
+                "GCOUNTER" => amount.parse().ok().map(Update::Grow),
+                _ => amount.parse().ok().map(Update::Count),
+            };
+            let Some(update) = update else {
+                return Ok(usage);
+            };
+            let write = Write::Crdt(parts[2].to_string(), update);
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "LWW" => {
+            if parts.len() < 4 || !parts[1].eq_ignore_ascii_case("SET") {
+                return Ok("Error: Usage: LWW SET <key> <value>".to_string());
+            }
+            let update = Update::Assign(parts[3..].join(" "));
+            let write = Write::Crdt(parts[2].to_string(), update);
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "ORSET" => {
+            let members = || parts[3..].iter().map(|member| member.to_string()).collect();
+            let update = match parts.get(1).map(|sub| sub.to_uppercase()).as_deref() {
+                Some("ADD") if parts.len() >= 4 => Update::Add(members()),
+                Some("REMOVE") if parts.len() >= 4 => Update::Remove(members()),
+                _ => {
+                    return Ok(
+                        "Error: Usage: ORSET ADD|REMOVE <key> <member> [member...]".to_string()
+                    );
+                }
+            };
+            let write = Write::Crdt(parts[2].to_string(), update);
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        // CRDT state a backup took writes into, for the primary to merge
+        "MERGE" => {
+            if parts.len() < 3 {
+                return Ok("Error: Usage: MERGE <key> <state>".to_string());
+            }
+            let write = Write::Merge(parts[1].to_string(), parts[2..].join(" "));
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "STRLEN" => {
+            if parts.len() != 2 {
+                return Ok("Error: STRLEN <key>".to_string());
+            }
+            Ok(store.value_len(parts[1]).unwrap_or(0).to_string())
+        }
+
+        "GETSET" => {
+            if parts.len() < 3 {
+                return Ok("Error: Usage: GETSET <key> <value>".to_string());
+            }
+            let write = Write::GetSet(parts[1].to_string(), parts[2..].join(" "));
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "DELETE" => {
+            let write = match parts[1..] {
+                [] => return Ok("Error: DELETE <key> [key...]".to_string()),
+                [key] => Write::Delete(key.to_string()),
+                // Several keys at once, answered with how many there were
+                ref keys => Write::DeleteMany(keys.iter().map(|key| key.to_string()).collect()),
+            };
+            submit_write(write, request_id, store, replication_manager, state).await
+        }
+
+        "RANDOMKEY" => match store.random_key() {
+            Some(key) => Ok(key),
+            None => Ok("No keys found".to_string()),
+        },
+
+        "SAMPLE" => {
+            let Some(count) = parts.get(1).and_then(|count| count.parse::<usize>().ok()) else {
+                return Ok("Error: SAMPLE <count>".to_string());
+            };
+            let keys = store.sample(count);
+            if keys.is_empty() {
+                Ok("No keys found".to_string())
+            } else {
+                Ok(keys.join(", "))
+            }
+        }
+
+        "LIST" => {
+            let Some((after, limit)) = list_args(&parts[1..]) else {
+                return Ok(LIST_USAGE.to_string());
+            };
+            let keys = store.list("", after, limit);
+            if keys.is_empty() {
+                Ok("No keys found".to_string())
+            } else {
+                Ok(keys.join(", "))
+            }
+        }
+
+        "DBSIZE" => Ok(db_size(store, "", &parts)),
+
+        "SCAN" => scan_page(store, "", &parts),
+
         "KEYS" => {
             // if parts.len() != 1 {
             //     return Ok("Error: KEYS".to_string());
@@ -261,3 +2313,1219 @@ async fn execute_command(
         _ => Ok(format!("Error: Unknown command '{}'", parts[0])),
     }
 }
+
+const SCAN_USAGE: &str = "Error: Usage: SCAN <cursor> [MATCH <pattern>] [COUNT <n>]";
+
+// Keys SCAN looks at when no COUNT is given
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+// Reply to `SCAN <cursor> [MATCH <pattern>] [COUNT <n>]` with the cursor to
+// pass next and a JSON array of the page's keys under `prefix`, with it
+// taken off
+fn scan_page(store: &KeyValueStore, prefix: &str, parts: &[&str]) -> Result<String> {
+    let Some(Ok(cursor)) = parts.get(1).map(|cursor| cursor.parse::<u64>()) else {
+        return Ok(SCAN_USAGE.to_string());
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in parts[2..].chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case("MATCH") => pattern = Some(*value),
+            [name, value] if name.eq_ignore_ascii_case("COUNT") => match value.parse() {
+                Ok(n) if n > 0 => count = n,
+                _ => return Ok(SCAN_USAGE.to_string()),
+            },
+            _ => return Ok(SCAN_USAGE.to_string()),
+        }
+    }
+
+    let (keys, next) = store.scan(cursor, count, prefix, pattern);
+    let keys: Vec<&str> = keys.iter().map(|key| &key[prefix.len()..]).collect();
+    let keys =
+        serde_json::to_string(&keys).map_err(|e| StoreError::SerializationError(e.to_string()))?;
+    Ok(format!("{} {}", next, keys))
+}
+
+// Reply to `DBSIZE [BYTES]` with the number of keys under `prefix`, followed
+// by the bytes their values take up if asked for
+fn db_size(store: &KeyValueStore, prefix: &str, parts: &[&str]) -> String {
+    let (keys, bytes) = store.count(prefix);
+    match parts {
+        [_] => keys.to_string(),
+        [_, flag] if flag.eq_ignore_ascii_case("BYTES") => format!("{} {}", keys, bytes),
+        _ => "Error: Usage: DBSIZE [BYTES]".to_string(),
+    }
+}
+
+// Split a trailing `EX <seconds>` off a PUT's arguments. None if the number
+// of seconds is malformed.
+fn put_ttl<'a>(args: &'a [&'a str]) -> Option<(&'a [&'a str], Option<u64>)> {
+    match args {
+        [rest @ .., ex, seconds] if !rest.is_empty() && ex.eq_ignore_ascii_case("EX") => {
+            Some((rest, Some(seconds.parse().ok()?)))
+        }
+        _ => Some((args, None)),
+    }
+}
+
+// Split a trailing IF-ABSENT, IF-VALUE <old> or IF-VERSION <n> off a PUT's
+// value. None if the condition is malformed or nothing is left to store.
+fn put_condition<'a>(args: &'a [&'a str]) -> Option<(&'a [&'a str], Condition)> {
+    let is = |i: usize, word: &str| {
+        args.get(i)
+            .is_some_and(|arg| arg.eq_ignore_ascii_case(word))
+    };
+    let n = args.len();
+
+    let (value, condition) = if is(n - 1, "IF-ABSENT") {
+        (&args[..n - 1], Condition::Absent)
+    } else if n >= 2 && is(n - 2, "IF-VALUE") {
+        (&args[..n - 2], Condition::Value(args[n - 1].to_string()))
+    } else if n >= 2 && is(n - 2, "IF-VERSION") {
+        (
+            &args[..n - 2],
+            Condition::Version(args[n - 1].parse().ok()?),
+        )
+    } else {
+        (args, Condition::Always)
+    };
+
+    if value.is_empty() {
+        return None;
+    }
+    Some((value, condition))
+}
+
+const LIST_USAGE: &str = "Error: Usage: LIST [--after <key>] [--limit <n>]";
+
+// Keys LIST returns when no --limit is given
+const DEFAULT_LIST_LIMIT: usize = 100;
+
+// Parse LIST's `--after <key>` and `--limit <n>` options
+fn list_args<'a>(args: &[&'a str]) -> Option<(Option<&'a str>, usize)> {
+    let mut after = None;
+    let mut limit = DEFAULT_LIST_LIMIT;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.to_lowercase().as_str() {
+            "--after" => after = Some(*args.next()?),
+            "--limit" => limit = args.next()?.parse().ok()?,
+            _ => return None,
+        }
+    }
+    Some((after, limit))
+}
+
+// Role, mode and how far behind the primary each backup is
+async fn replication_info(store: &KeyValueStore, rm: &ReplicationManager) -> String {
+    let mut info = vec![format!("mode:{}", rm.mode())];
+    match rm.get_role().await {
+        Role::Primary => {
+            info.insert(0, "role:primary".to_string());
+            info.push(format!("changelog_seq:{}", store.changelog().last_seq()));
+            for (i, lag) in rm.backup_lag().await.iter().enumerate() {
+                info.push(format!("backup{}:{}", i, lag));
+            }
+        }
+        Role::Backup(primary) => {
+            info.insert(0, "role:backup".to_string());
+            info.push(format!("primary:{}", primary));
+            info.push(format!("applied_seq:{}", rm.applied_seq()));
+        }
+        Role::Standalone => info.insert(0, "role:standalone".to_string()),
+    }
+    info.push(format!("epoch:{}", rm.epoch()));
+    info.push(format!("safe_mode:{}", u8::from(rm.safe_mode().is_some())));
+    info.join(", ")
+}
+
+// A tenant's stats alongside the usage of its keyspace
+fn tenant_info(store: &KeyValueStore, tenant: &Tenant) -> String {
+    let mut info = vec![tenant.to_string()];
+    info.extend(store.quota_usage(Some(&tenant.pattern())));
+    info.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::websocket::WebSocketTransport;
+    use tokio::net::TcpStream;
+
+    // A primary and one backup it replicates to, each listening on a port
+    // of its own and serving by the time this returns
+    struct Cluster {
+        primary: ServerHandle,
+        backup: ServerHandle,
+        primary_addr: String,
+        backup_addr: String,
+        backup_rm: Arc<ReplicationManager>,
+    }
+
+    impl Cluster {
+        async fn start(
+            primary_store: Arc<KeyValueStore>,
+            backup_store: Arc<KeyValueStore>,
+        ) -> Self {
+            let primary = Server::with_replication(primary_store, "127.0.0.1:0".to_string());
+            primary.start_as_primary().await.unwrap();
+            let primary_rm = Arc::clone(primary.replication_manager.as_ref().unwrap());
+            let primary = primary.spawn().await.unwrap();
+            let primary_addr = primary.address().to_string();
+
+            // Added to the primary by hand rather than registering, which
+            // would also copy the primary's data to it
+            let backup = Server::with_replication(backup_store, "127.0.0.1:0".to_string());
+            let backup_rm = Arc::clone(backup.replication_manager.as_ref().unwrap());
+            Arc::clone(&backup_rm)
+                .start_backup(primary_addr.clone())
+                .await
+                .unwrap();
+            let backup = backup.spawn().await.unwrap();
+            let backup_addr = backup.address().to_string();
+            primary_rm.add_backup(backup_addr.clone()).await.unwrap();
+
+            Cluster {
+                primary,
+                backup,
+                primary_addr,
+                backup_addr,
+                backup_rm,
+            }
+        }
+
+        fn shutdown(self) {
+            self.primary.shutdown();
+            self.backup.shutdown();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_mode() {
+        let store = Arc::new(KeyValueStore::new());
+        store.put("existing".to_string(), "value".to_string());
+
+        let server_addr = "127.0.0.1:7891".to_string();
+        let server = Server::new(Arc::clone(&store), server_addr.clone())
+            .with_admin_token(Some("secret".to_string()));
+        let server_handle = tokio::spawn(async move {
+            let _ = server.run().await;
+        });
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        // Admin commands need the token
+        let client = Client::new(server_addr.clone());
+        assert!(
+            client
+                .send_command("MAINTENANCE ON")
+                .await
+                .unwrap()
+                .starts_with("ERROR")
+        );
+
+        let admin = Client::new(server_addr.clone()).with_auth_token("secret".to_string());
+        assert_eq!(admin.send_command("MAINTENANCE ON").await.unwrap(), "OK");
+
+        // Writes are refused with a retryable error, reads still work
+        assert!(matches!(
+            client.put("new", "value").await,
+            Err(StoreError::UnavailableError(_))
+        ));
+        assert_eq!(
+            client.get("existing").await.unwrap(),
+            Some("value".to_string())
+        );
+
+        assert_eq!(admin.send_command("MAINTENANCE OFF").await.unwrap(), "OK");
+        client.put("new", "value").await.unwrap();
+
+        // A wrong token is rejected outright
+        let intruder = Client::new(server_addr).with_auth_token("wrong".to_string());
+        assert!(matches!(
+            intruder.send_command("MAINTENANCE ON").await,
+            Err(StoreError::AuthError(_))
+        ));
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_health_checks() {
+        // A log-shipping backup whose primary isn't reachable never syncs
+        let backup_addr = "127.0.0.1:7900".to_string();
+        let backup = Server::with_replication(Arc::new(KeyValueStore::new()), backup_addr.clone())
+            .with_replication_mode(ReplicationMode::Log)
+            .with_admin_token(Some("secret".to_string()));
+        backup
+            .start_as_backup("127.0.0.1:7916".to_string())
+            .await
+            .unwrap();
+        let rm = Arc::clone(backup.replication_manager.as_ref().unwrap());
+        let server_handle = tokio::spawn(async move {
+            let _ = backup.run().await;
+        });
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let client = Client::new(backup_addr.clone());
+        assert_eq!(client.send_command("HEALTH LIVE").await.unwrap(), "OK");
+        assert_eq!(
+            client.send_command("HEALTH READY").await.unwrap(),
+            "NOT READY: catching up with the primary"
+        );
+
+        // Once promoted it has nothing to catch up with, but maintenance
+        // still keeps traffic away
+        Arc::clone(&rm).promote_to_primary().await.unwrap();
+        let admin = Client::new(backup_addr).with_auth_token("secret".to_string());
+        assert_eq!(admin.send_command("MAINTENANCE ON").await.unwrap(), "OK");
+        assert_eq!(
+            client.send_command("HEALTH READY").await.unwrap(),
+            "NOT READY: in maintenance"
+        );
+        assert_eq!(admin.send_command("MAINTENANCE OFF").await.unwrap(), "OK");
+        assert_eq!(client.send_command("HEALTH READY").await.unwrap(), "OK");
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_config_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.json");
+        std::fs::write(&config_path, r#"{"admin_token": "first"}"#).unwrap();
+
+        let server_addr = "127.0.0.1:7892".to_string();
+        let server = Server::new(Arc::new(KeyValueStore::new()), server_addr.clone())
+            .with_config(&config_path)
+            .unwrap();
+        let server_handle = tokio::spawn(async move {
+            let _ = server.run().await;
+        });
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        // Rotate the admin token on disk and reload without restarting
+        std::fs::write(&config_path, r#"{"admin_token": "second"}"#).unwrap();
+        let old_admin = Client::new(server_addr.clone()).with_auth_token("first".to_string());
+        assert_eq!(old_admin.send_command("CONFIG RELOAD").await.unwrap(), "OK");
+
+        assert!(old_admin.send_command("MAINTENANCE").await.is_err());
+        let new_admin = Client::new(server_addr).with_auth_token("second".to_string());
+        assert_eq!(new_admin.send_command("MAINTENANCE").await.unwrap(), "OFF");
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_config_get_set() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.json");
+        std::fs::write(&config_path, r#"{"admin_token": "secret"}"#).unwrap();
+
+        let server_addr = "127.0.0.1:7893".to_string();
+        let store = Arc::new(KeyValueStore::new());
+        let server = Server::with_replication(Arc::clone(&store), server_addr.clone())
+            .with_config(&config_path)
+            .unwrap();
+        let server_handle = tokio::spawn(async move {
+            let _ = server.run().await;
+        });
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let admin = Client::new(server_addr.clone()).with_auth_token("secret".to_string());
+        assert_eq!(
+            admin
+                .send_command("CONFIG GET heartbeat_interval_ms")
+                .await
+                .unwrap(),
+            "1000"
+        );
+
+        // Tighten the memory cap at runtime; client writes now hit it
+        assert_eq!(
+            admin.send_command("CONFIG SET maxmemory 16").await.unwrap(),
+            "OK"
+        );
+        assert_eq!(
+            admin.send_command("CONFIG GET maxmemory").await.unwrap(),
+            "16"
+        );
+        let client = Client::new(server_addr);
+        client.put("small", "value").await.unwrap();
+        assert!(client.put("bigger", "value").await.is_err());
+
+        assert!(
+            admin
+                .send_command("CONFIG SET maxmemory lots")
+                .await
+                .unwrap()
+                .starts_with("ERROR")
+        );
+
+        // Persist the runtime values, keeping the rest of the file
+        assert_eq!(admin.send_command("CONFIG REWRITE").await.unwrap(), "OK");
+        let saved = Config::load(&config_path).unwrap();
+        assert_eq!(saved.maxmemory, Some(16));
+        assert_eq!(saved.admin_token, Some("secret".to_string()));
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_sync_stream() {
+        use crate::changelog::ChangeOp;
+
+        let store = Arc::new(KeyValueStore::new());
+        store.put("a".to_string(), "1".to_string());
+        store.put("b".to_string(), "2".to_string());
+
+        let server_addr = "127.0.0.1:7894".to_string();
+        let server = Server::new(Arc::clone(&store), server_addr.clone());
+        let server_handle = tokio::spawn(async move {
+            let _ = server.run().await;
+        });
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        // Tail from the second change onwards
+        let (sender, mut changes) = tokio::sync::mpsc::unbounded_channel();
+        let consumer = Client::new(server_addr.clone());
+        let sync_handle = tokio::spawn(async move {
+            consumer
+                .sync(2, move |change| {
+                    let _ = sender.send(change);
+                })
+                .await
+        });
+
+        let backlog = changes.recv().await.unwrap();
+        assert_eq!(backlog.seq, 2);
+        assert_eq!(
+            backlog.op,
+            ChangeOp::Put {
+                key: "b".to_string(),
+                value: "2".to_string()
+            }
+        );
+
+        // New writes show up live, in order
+        let client = Client::new(server_addr.clone());
+        client.delete("a").await.unwrap();
+        let live = changes.recv().await.unwrap();
+        assert_eq!(live.seq, 3);
+        assert_eq!(
+            live.op,
+            ChangeOp::Delete {
+                key: "a".to_string()
+            }
+        );
+
+        assert!(
+            client
+                .send_command("SYNC soon")
+                .await
+                .unwrap()
+                .starts_with("ERROR")
+        );
+
+        sync_handle.abort();
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_linearizable_read() {
+        let primary_store = Arc::new(KeyValueStore::new());
+        let cluster =
+            Cluster::start(Arc::clone(&primary_store), Arc::new(KeyValueStore::new())).await;
+
+        primary_store.put("key".to_string(), "value".to_string());
+        let primary = Client::new(cluster.primary_addr.clone());
+        assert_eq!(
+            primary.get_linearizable("key").await.unwrap(),
+            Some("value".to_string())
+        );
+        assert!(
+            Client::new(cluster.backup_addr.clone())
+                .get_linearizable("key")
+                .await
+                .is_err()
+        );
+
+        // Once the backup has taken over, the old primary can't confirm
+        // its leadership and stops serving linearizable reads
+        cluster
+            .backup_rm
+            .clone()
+            .promote_to_primary()
+            .await
+            .unwrap();
+        assert!(matches!(
+            primary.get_linearizable("key").await,
+            Err(StoreError::UnavailableError(_))
+        ));
+        assert_eq!(primary.get("key").await.unwrap(), Some("value".to_string()));
+
+        cluster.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_repair_from_primary() {
+        let primary_store = Arc::new(KeyValueStore::new());
+        let backup_store = Arc::new(KeyValueStore::new());
+        primary_store.put("same".to_string(), "1".to_string());
+        primary_store.put("lost".to_string(), "2".to_string());
+        primary_store.put("stale".to_string(), "new".to_string());
+        backup_store.put("same".to_string(), "1".to_string());
+        backup_store.put("stale".to_string(), "old".to_string());
+        backup_store.put("orphan".to_string(), "3".to_string());
+
+        let cluster = Cluster::start(Arc::clone(&primary_store), Arc::clone(&backup_store)).await;
+
+        // A dry run only reports
+        let client = Client::new(cluster.backup_addr.clone());
+        assert_eq!(
+            client.send_command("REPAIR DRYRUN").await.unwrap(),
+            "dry_run=true, updated=2, deleted=1"
+        );
+        assert_eq!(backup_store.get("stale"), Some("old".to_string()));
+
+        // Repairing a single key leaves the rest alone
+        assert_eq!(
+            client.send_command("REPAIR stale").await.unwrap(),
+            "dry_run=false, updated=1, deleted=0"
+        );
+        assert_eq!(backup_store.get("stale"), Some("new".to_string()));
+        assert_eq!(backup_store.get("orphan"), Some("3".to_string()));
+
+        assert_eq!(
+            client.send_command("REPAIR").await.unwrap(),
+            "dry_run=false, updated=1, deleted=1"
+        );
+        assert_eq!(backup_store.entries(), primary_store.entries());
+
+        // The primary has nothing to repair from
+        assert!(
+            Client::new(cluster.primary_addr.clone())
+                .send_command("REPAIR")
+                .await
+                .unwrap()
+                .starts_with("ERROR")
+        );
+
+        cluster.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_conditional_put() {
+        let primary_store = Arc::new(KeyValueStore::new());
+        let backup_store = Arc::new(KeyValueStore::new());
+        let cluster = Cluster::start(Arc::clone(&primary_store), Arc::clone(&backup_store)).await;
+
+        let client = Client::new(cluster.primary_addr.clone());
+        let put = |command: &'static str| client.send_command(command);
+        assert_eq!(put("PUT lock me IF-ABSENT").await.unwrap(), "OK");
+        assert_eq!(put("PUT lock you IF-ABSENT").await.unwrap(), "NULL");
+        assert_eq!(put("SETNX lock you").await.unwrap(), "NULL");
+        assert_eq!(put("PUT lock you IF-VALUE other").await.unwrap(), "NULL");
+        assert_eq!(put("PUT lock you too IF-VALUE me").await.unwrap(), "OK");
+        assert_eq!(client.send_command("VERSION lock").await.unwrap(), "2");
+        assert_eq!(put("PUT lock again IF-VERSION 1").await.unwrap(), "NULL");
+        assert_eq!(put("PUT lock again IF-VERSION 2").await.unwrap(), "OK");
+        assert!(
+            put("PUT lock IF-VERSION 2")
+                .await
+                .unwrap()
+                .starts_with("Error")
+        );
+        assert!(
+            put("PUT lock x IF-VERSION two")
+                .await
+                .unwrap()
+                .starts_with("Error")
+        );
+
+        // Only the writes that were applied reach the backup
+        assert_eq!(backup_store.get("lock"), Some("again".to_string()));
+        assert_eq!(backup_store.version("lock"), 3);
+
+        assert_eq!(put("GET lock WITHVERSION").await.unwrap(), "3 again");
+        assert_eq!(put("CAS lock 2 stale").await.unwrap(), "NULL");
+        assert_eq!(put("CAS lock 3 fresh").await.unwrap(), "OK");
+
+        // Several keys are deleted on the backup in one go
+        assert_eq!(put("PUT other 1").await.unwrap(), "OK");
+        assert_eq!(put("DELETE lock other missing").await.unwrap(), "2");
+        assert_eq!(backup_store.len(), 0);
+
+        // So are renames and copies
+        assert_eq!(put("PUT from v").await.unwrap(), "OK");
+        assert_eq!(put("RENAME from to").await.unwrap(), "OK");
+        assert_eq!(put("COPY to to2").await.unwrap(), "OK");
+        assert_eq!(put("COPY to to2").await.unwrap(), "NULL");
+        assert_eq!(put("RENAME from to").await.unwrap(), "NULL");
+        assert_eq!(backup_store.get("from"), None);
+        assert_eq!(backup_store.get("to2"), Some("v".to_string()));
+
+        cluster.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_setnx() {
+        let backup_store = Arc::new(KeyValueStore::new());
+        let cluster =
+            Cluster::start(Arc::new(KeyValueStore::new()), Arc::clone(&backup_store)).await;
+
+        // Only the first of two clients takes the lock
+        let client = Client::new(cluster.primary_addr.clone());
+        assert!(client.put_if_absent("lock", "me").await.unwrap());
+        assert!(!client.put_if_absent("lock", "you").await.unwrap());
+        assert_eq!(client.get("lock").await.unwrap().as_deref(), Some("me"));
+        assert_eq!(backup_store.get("lock").as_deref(), Some("me"));
+        assert_eq!(backup_store.version("lock"), 1);
+
+        // And it can be taken again once released
+        assert!(client.delete("lock").await.unwrap());
+        assert!(client.put_if_absent("lock", "you").await.unwrap());
+        assert_eq!(backup_store.get("lock").as_deref(), Some("you"));
+
+        cluster.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_cas() {
+        let backup_store = Arc::new(KeyValueStore::new());
+        let cluster =
+            Cluster::start(Arc::new(KeyValueStore::new()), Arc::clone(&backup_store)).await;
+
+        let client = Client::new(cluster.primary_addr.clone());
+        assert_eq!(client.get_versioned("counter").await.unwrap(), None);
+        // Version 0 stands for a key that doesn't exist yet
+        assert!(client.cas("counter", 0, "1").await.unwrap());
+        assert!(!client.cas("counter", 0, "1").await.unwrap());
+        assert_eq!(
+            client.get_versioned("counter").await.unwrap(),
+            Some(("1".to_string(), 1))
+        );
+
+        // A writer that read an older version loses
+        client.put("counter", "2").await.unwrap();
+        assert!(!client.cas("counter", 1, "stale").await.unwrap());
+        assert!(client.cas("counter", 2, "3").await.unwrap());
+        assert_eq!(
+            client.get_versioned("counter").await.unwrap(),
+            Some(("3".to_string(), 3))
+        );
+        assert_eq!(backup_store.get("counter").as_deref(), Some("3"));
+        assert_eq!(backup_store.version("counter"), 3);
+
+        cluster.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_get_usage() {
+        let server = Server::new(Arc::new(KeyValueStore::new()), "127.0.0.1:0".to_string())
+            .spawn()
+            .await
+            .unwrap();
+        let client = Client::new(server.address().to_string());
+
+        // Answered with the usage rather than dropping the connection
+        let usage = "Error: GET <key> [LINEARIZABLE] [WITHVERSION]";
+        assert_eq!(client.send_command("GET").await.unwrap(), usage);
+        assert_eq!(client.send_command("GET key extra").await.unwrap(), usage);
+        assert_eq!(client.send_command("GET key WITHVERSION").await.unwrap(), "Key not found");
+    }
+
+    #[tokio::test]
+    async fn test_delete_many() {
+        let backup_store = Arc::new(KeyValueStore::new());
+        let cluster =
+            Cluster::start(Arc::new(KeyValueStore::new()), Arc::clone(&backup_store)).await;
+
+        let client = Client::new(cluster.primary_addr.clone());
+        for key in ["a", "b", "c"] {
+            client.put(key, "1").await.unwrap();
+        }
+        // Keys that don't exist aren't counted, or sent to the backup
+        assert_eq!(client.delete_many(&["a", "b", "missing"]).await.unwrap(), 2);
+        assert_eq!(client.delete_many(&["a", "missing"]).await.unwrap(), 0);
+        assert_eq!(client.get("a").await.unwrap(), None);
+        assert_eq!(backup_store.keys(), vec!["c".to_string()]);
+
+        cluster.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_rename_copy() {
+        let primary_store = Arc::new(KeyValueStore::new());
+        let backup_store = Arc::new(KeyValueStore::new());
+        let cluster = Cluster::start(Arc::clone(&primary_store), Arc::clone(&backup_store)).await;
+
+        let client = Client::new(cluster.primary_addr.clone());
+        client.put_with_ttl("draft", "v1", 100).await.unwrap();
+
+        // A rename moves the value and its expiry time
+        assert!(client.rename("draft", "post").await.unwrap());
+        assert!(!client.rename("draft", "post").await.unwrap());
+        assert_eq!(client.get("draft").await.unwrap(), None);
+        assert_eq!(client.ttl("post").await.unwrap(), 100);
+        assert_eq!(backup_store.get("draft"), None);
+        assert_eq!(backup_store.get("post").as_deref(), Some("v1"));
+        let expires = primary_store.metadata("post").unwrap().expires;
+        assert_eq!(backup_store.metadata("post").unwrap().expires, expires);
+
+        // A copy leaves an existing key alone unless told to replace it
+        assert!(client.copy("post", "backup", false).await.unwrap());
+        client.put("post", "v2").await.unwrap();
+        assert!(!client.copy("post", "backup", false).await.unwrap());
+        assert_eq!(backup_store.get("backup").as_deref(), Some("v1"));
+        assert!(client.copy("post", "backup", true).await.unwrap());
+        assert_eq!(backup_store.get("backup").as_deref(), Some("v2"));
+        assert!(!client.copy("missing", "backup", true).await.unwrap());
+        assert_eq!(backup_store.entries(), primary_store.entries());
+
+        cluster.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let primary_store = Arc::new(KeyValueStore::new());
+        let backup_store = Arc::new(KeyValueStore::new());
+        let cluster = Cluster::start(Arc::clone(&primary_store), Arc::clone(&backup_store)).await;
+
+        let client = Client::new(cluster.primary_addr.clone());
+        client
+            .put_with_ttl("session", "abc def", 100)
+            .await
+            .unwrap();
+        assert_eq!(
+            client.get("session").await.unwrap().as_deref(),
+            Some("abc def")
+        );
+        assert_eq!(client.ttl("session").await.unwrap(), 100);
+        assert_eq!(client.ttl("missing").await.unwrap(), -2);
+        client.put("plain", "1").await.unwrap();
+        assert_eq!(client.ttl("plain").await.unwrap(), -1);
+        assert!(!client.expire("missing", 10).await.unwrap());
+        let deadline = crate::store::unix_millis() / 1000 + 50;
+        assert!(client.expire_at("plain", deadline).await.unwrap());
+        assert_eq!(
+            primary_store.metadata("plain").unwrap().expires,
+            deadline * 1000
+        );
+
+        // Backups get the same value, spaces and all, and expiry time
+        assert_eq!(backup_store.get("session").as_deref(), Some("abc def"));
+        let expires = primary_store.metadata("session").unwrap().expires;
+        assert_eq!(backup_store.metadata("session").unwrap().expires, expires);
+
+        // Sliding expiry for keys that have a TTL, and none for those without
+        assert!(client.touch("session", Some(200)).await.unwrap());
+        assert_eq!(client.ttl("session").await.unwrap(), 200);
+        assert!(client.touch("plain", Some(200)).await.unwrap());
+        assert!(client.persist("plain").await.unwrap());
+        assert!(!client.persist("plain").await.unwrap());
+        assert_eq!(client.ttl("plain").await.unwrap(), -1);
+        assert!(!client.touch("missing", None).await.unwrap());
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        let expires = primary_store.metadata("session").unwrap().expires;
+        assert_eq!(backup_store.metadata("session").unwrap().expires, expires);
+        assert_eq!(backup_store.metadata("plain").unwrap().expires, 0);
+
+        // A key is gone for reads once its time is up, then swept away.
+        // The backup goes by the primary's delete, not its own clock.
+        assert!(client.expire("plain", 0).await.unwrap());
+        assert_eq!(client.get("plain").await.unwrap(), None);
+        assert_eq!(backup_store.get("plain").as_deref(), Some("1"));
+        tokio::time::sleep(tokio::time::Duration::from_millis(1500)).await;
+        assert_eq!(primary_store.len(), 1);
+        assert_eq!(primary_store.expired_keys(), 1);
+        assert_eq!(backup_store.get("plain"), None);
+        assert_eq!(backup_store.len(), 1);
+        assert_eq!(backup_store.expired_keys(), 0);
+
+        cluster.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_keyspace_notifications() {
+        use crate::notifications::KeyEvent;
+
+        let store = Arc::new(KeyValueStore::new());
+        let server_addr = "127.0.0.1:7936".to_string();
+        let server = Server::new(Arc::clone(&store), server_addr.clone());
+        let server_handle = tokio::spawn(async move {
+            let _ = server.run().await;
+        });
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let (sender, mut notifications) = tokio::sync::mpsc::unbounded_channel();
+        let subscriber = Client::new(server_addr.clone());
+        let subscribe_handle = tokio::spawn(async move {
+            subscriber
+                .subscribe("cache:", move |notification| {
+                    let _ = sender.send(notification);
+                })
+                .await
+        });
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        // Only keys under the prefix, each change as it happens, including
+        // the key expiring
+        let client = Client::new(server_addr.clone());
+        client.put("cache:a", "1").await.unwrap();
+        client.put("other", "1").await.unwrap();
+        client.put("cache:b", "2").await.unwrap();
+        client.delete("cache:b").await.unwrap();
+        client.expire("cache:a", 0).await.unwrap();
+        let mut events = Vec::new();
+        for _ in 0..5 {
+            let notification =
+                tokio::time::timeout(tokio::time::Duration::from_secs(3), notifications.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+            events.push((notification.event, notification.key));
+        }
+        let event = |event, key: &str| (event, key.to_string());
+        assert_eq!(
+            events,
+            vec![
+                event(KeyEvent::Set, "cache:a"),
+                event(KeyEvent::Set, "cache:b"),
+                event(KeyEvent::Deleted, "cache:b"),
+                event(KeyEvent::Expire, "cache:a"),
+                event(KeyEvent::Expired, "cache:a"),
+            ]
+        );
+
+        assert!(
+            client
+                .send_command("SUBSCRIBE a b")
+                .await
+                .unwrap()
+                .starts_with("ERROR")
+        );
+
+        subscribe_handle.abort();
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_tenant_isolation() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.json");
+        std::fs::write(
+            &config_path,
+            r#"{
+                "admin_token": "admin",
+                "tenants": {
+                    "tenantA": {"token": "a-token", "max_keys": 2},
+                    "tenantB": {"token": "b-token"}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let store = Arc::new(KeyValueStore::new());
+        let server_addr = "127.0.0.1:7897".to_string();
+        let server = Server::new(Arc::clone(&store), server_addr.clone())
+            .with_config(&config_path)
+            .unwrap();
+        let server_handle = tokio::spawn(async move {
+            let _ = server.run().await;
+        });
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let tenant_a = Client::new(server_addr.clone()).with_auth_token("a-token".to_string());
+        let tenant_b = Client::new(server_addr.clone()).with_auth_token("b-token".to_string());
+        let admin = Client::new(server_addr).with_auth_token("admin".to_string());
+
+        // Each tenant has its own keyspace
+        tenant_a.put("user1", "alice").await.unwrap();
+        tenant_b.put("user1", "bob").await.unwrap();
+        assert_eq!(
+            tenant_a.get("user1").await.unwrap(),
+            Some("alice".to_string())
+        );
+        assert_eq!(store.get("tenantB:user1"), Some("bob".to_string()));
+        assert_eq!(tenant_b.send_command("KEYS").await.unwrap(), "user1");
+
+        // Server-wide commands are off limits
+        assert!(
+            tenant_a
+                .send_command("DUMP")
+                .await
+                .unwrap()
+                .starts_with("ERROR")
+        );
+        assert!(
+            tenant_a
+                .send_command("SYNC 0")
+                .await
+                .unwrap()
+                .starts_with("ERROR")
+        );
+
+        // The tenant's quota applies to its keyspace only
+        tenant_a.put("user2", "carol").await.unwrap();
+        assert!(tenant_a.put("user3", "dave").await.is_err());
+        tenant_b.put("user3", "dave").await.unwrap();
+        assert_eq!(
+            tenant_b.send_command("LIST --after user1").await.unwrap(),
+            "user3"
+        );
+        assert!(
+            tenant_a
+                .send_command("INFO")
+                .await
+                .unwrap()
+                .contains("keys=2, max_keys=2")
+        );
+
+        // Frozen tenants can still read but not write
+        assert_eq!(
+            admin.send_command("TENANT FREEZE tenantA").await.unwrap(),
+            "OK"
+        );
+        assert_eq!(
+            tenant_a.send_command("DELETE user1").await.unwrap(),
+            "ERROR: Tenant tenantA is frozen"
+        );
+        assert_eq!(
+            tenant_a.get("user1").await.unwrap(),
+            Some("alice".to_string())
+        );
+        assert!(
+            admin
+                .send_command("TENANT INFO tenantA")
+                .await
+                .unwrap()
+                .starts_with("tenant=tenantA, frozen=true")
+        );
+        assert_eq!(
+            admin.send_command("TENANT LIST").await.unwrap(),
+            "tenantA, tenantB"
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_select_namespace() {
+        let store = Arc::new(KeyValueStore::new());
+        let server_addr = "127.0.0.1:7917".to_string();
+        let server = Server::new(Arc::clone(&store), server_addr.clone());
+        let server_handle = tokio::spawn(async move {
+            let _ = server.run().await;
+        });
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let orders = Client::new(server_addr.clone()).with_namespace("orders".to_string());
+        let top = Client::new(server_addr.clone());
+
+        // Keys live under the namespace and are listed without it
+        orders.put("o1", "pending").await.unwrap();
+        top.put("o1", "unrelated").await.unwrap();
+        assert_eq!(orders.get("o1").await.unwrap(), Some("pending".to_string()));
+        assert_eq!(store.get("orders:o1"), Some("pending".to_string()));
+        assert_eq!(orders.send_command("KEYS").await.unwrap(), "o1");
+        assert_eq!(orders.send_command("DBSIZE").await.unwrap(), "1");
+        assert_eq!(
+            orders.send_command("SCAN 0 COUNT 100").await.unwrap(),
+            "0 [\"o1\"]"
+        );
+        assert!(
+            orders
+                .send_command("SCAN 0 COUNT 0")
+                .await
+                .unwrap()
+                .starts_with("Error")
+        );
+        assert!(
+            orders
+                .send_command("RANDOMKEY")
+                .await
+                .unwrap()
+                .starts_with("ERROR")
+        );
+
+        // The selection lasts for the connection, until SELECT 0
+        let mut stream = BufReader::new(TcpStream::connect(&server_addr).await.unwrap());
+        async fn reply(stream: &mut BufReader<TcpStream>, command: &str) -> String {
+            stream
+                .write_all(format!("{}\n", command).as_bytes())
+                .await
+                .unwrap();
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            line.trim().to_string()
+        }
+        assert_eq!(reply(&mut stream, "SELECT orders").await, "OK");
+        assert_eq!(reply(&mut stream, "GET o1").await, "pending");
+        assert_eq!(reply(&mut stream, "SELECT 0").await, "OK");
+        assert_eq!(reply(&mut stream, "GET o1").await, "unrelated");
+        assert!(reply(&mut stream, "SELECT a:b").await.starts_with("ERROR"));
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_gateway() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let store = Arc::new(KeyValueStore::new());
+        let server = Server::new(Arc::clone(&store), "127.0.0.1:0".to_string())
+            .with_websocket(Some("127.0.0.1:0".to_string()))
+            .with_admin_token(Some("secret".to_string()));
+        let handle = server.spawn().await.unwrap();
+        let url = format!("ws://{}", handle.websocket_address().unwrap());
+
+        // The typed client works over WebSocket as it does over TCP
+        let client = Client::with_transport(
+            WebSocketTransport::new(url.clone())
+                .with_auth_token("secret".to_string())
+                .with_namespace("web".to_string()),
+        );
+        client.put("w1", "hello").await.unwrap();
+        assert_eq!(client.get("w1").await.unwrap(), Some("hello".to_string()));
+        assert_eq!(store.get("web:w1"), Some("hello".to_string()));
+        assert!(client.delete("w1").await.unwrap());
+        assert_eq!(client.get("w1").await.unwrap(), None);
+
+        let intruder = Client::with_transport(
+            WebSocketTransport::new(url.clone()).with_auth_token("wrong".to_string()),
+        );
+        assert!(matches!(
+            intruder.put("w2", "x").await,
+            Err(StoreError::AuthError(_))
+        ));
+
+        // Streams arrive one message per line
+        let (mut socket, _) = tokio_tungstenite::connect_async(url.as_str())
+            .await
+            .unwrap();
+        socket.send(Message::text("SUBSCRIBE web:")).await.unwrap();
+        let mut next_text = async || loop {
+            match socket.next().await.unwrap().unwrap() {
+                Message::Text(text) => return text.to_string(),
+                _ => continue,
+            }
+        };
+        assert_eq!(next_text().await, "OK");
+        client.put("w3", "streamed").await.unwrap();
+        let notification = tokio::time::timeout(Duration::from_secs(3), next_text())
+            .await
+            .unwrap();
+        assert!(notification.contains("web:w3"));
+
+        // Commands have to be text
+        let (mut socket, _) = tokio_tungstenite::connect_async(url.as_str())
+            .await
+            .unwrap();
+        socket
+            .send(Message::binary(b"GET w3".to_vec()))
+            .await
+            .unwrap();
+        match socket.next().await.unwrap().unwrap() {
+            Message::Text(text) => assert!(text.starts_with("ERROR")),
+            other => panic!("unexpected message {:?}", other),
+        }
+
+        handle.shutdown();
+    }
+    #[tokio::test]
+    async fn test_crdt_replication() {
+        let backup_store = Arc::new(KeyValueStore::new());
+        let cluster =
+            Cluster::start(Arc::new(KeyValueStore::new()), Arc::clone(&backup_store)).await;
+        let primary = Client::new(cluster.primary_addr.clone());
+        let backup = Client::new(cluster.backup_addr.clone());
+        let both = async |key: &str| {
+            (
+                primary.get(key).await.unwrap(),
+                backup.get(key).await.unwrap(),
+            )
+        };
+        let same = |value: &str| (Some(value.to_string()), Some(value.to_string()));
+
+        // Both nodes take writes to a counter and count each other's
+        assert_eq!(
+            primary.send_command("PNCOUNTER INCR hits 5").await.unwrap(),
+            "5"
+        );
+        assert_eq!(
+            backup.send_command("PNCOUNTER INCR hits -2").await.unwrap(),
+            "3"
+        );
+        assert_eq!(both("hits").await, same("3"));
+        assert_eq!(
+            primary.send_command("GCOUNTER INCR views").await.unwrap(),
+            "1"
+        );
+        assert_eq!(
+            backup.send_command("GCOUNTER INCR views 2").await.unwrap(),
+            "3"
+        );
+        assert_eq!(both("views").await, same("3"));
+        assert!(
+            backup
+                .send_command("GCOUNTER INCR views -1")
+                .await
+                .unwrap()
+                .starts_with("Error")
+        );
+
+        // A removal only takes out the adds it saw
+        primary
+            .send_command("ORSET ADD cart apple pear")
+            .await
+            .unwrap();
+        backup
+            .send_command("ORSET REMOVE cart apple")
+            .await
+            .unwrap();
+        primary.send_command("ORSET ADD cart plum").await.unwrap();
+        assert_eq!(both("cart").await, same(r#"["pear","plum"]"#));
+
+        // The later assignment wins, wherever it was made
+        backup.send_command("LWW SET owner node b").await.unwrap();
+        primary.send_command("LWW SET owner node a").await.unwrap();
+        assert_eq!(both("owner").await, same("node a"));
+
+        // Types don't mix
+        assert!(
+            primary
+                .send_command("ORSET ADD hits x")
+                .await
+                .unwrap()
+                .starts_with("ERROR")
+        );
+        assert_eq!(backup_store.siblings("hits"), None);
+
+        cluster.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_request_ids() {
+        assert_eq!(
+            split_request_id("#trace-1 PUT k v"),
+            (Some("trace-1"), "PUT k v")
+        );
+        assert_eq!(split_request_id("PUT k v"), (None, "PUT k v"));
+        assert_eq!(split_request_id("# PUT k v"), (None, "# PUT k v"));
+
+        let backup_store = Arc::new(KeyValueStore::new());
+        let cluster =
+            Cluster::start(Arc::new(KeyValueStore::new()), Arc::clone(&backup_store)).await;
+
+        // The ID rides along with the write to the backup
+        let primary = Client::new(cluster.primary_addr.clone());
+        assert_eq!(
+            primary
+                .send_command("#trace-42 PUT traced yes")
+                .await
+                .unwrap(),
+            "OK"
+        );
+        assert_eq!(
+            primary.send_command("#trace-43 GET traced").await.unwrap(),
+            "yes"
+        );
+        assert_eq!(backup_store.get("traced"), Some("yes".to_string()));
+
+        cluster.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serving_while_loading() {
+        let dir = tempfile::tempdir().unwrap();
+        let saved = dir.path().join("saved.json");
+        let source = KeyValueStore::new();
+        for i in 0..3000 {
+            source.put(format!("key{}", i), format!("value{}", i));
+        }
+        source.save(&saved).unwrap();
+        let bytes = std::fs::read(&saved).unwrap();
+
+        // Feed the file through a pipe, holding back its end until told to
+        let pipe = dir.path().join("db.json");
+        let made = std::process::Command::new("mkfifo")
+            .arg(&pipe)
+            .status()
+            .unwrap();
+        assert!(made.success());
+        let (finish, held) = std::sync::mpsc::channel::<()>();
+        let feeder = {
+            let pipe = pipe.clone();
+            std::thread::spawn(move || {
+                use std::io::Write as _;
+                let mut file = std::fs::OpenOptions::new().write(true).open(pipe).unwrap();
+                let split = bytes.len() * 2 / 3;
+                file.write_all(&bytes[..split]).unwrap();
+                let _ = held.recv();
+                file.write_all(&bytes[split..]).unwrap();
+            })
+        };
+        let store = KeyValueStore::load_streaming(&pipe).unwrap();
+        let server = Server::new(Arc::clone(&store), "127.0.0.1:0".to_string())
+            .spawn()
+            .await
+            .unwrap();
+        let address = server.address().to_string();
+        let client = Client::new(address.clone());
+
+        assert_eq!(
+            client.send_command("HEALTH READY").await.unwrap(),
+            "NOT READY: loading"
+        );
+        let info = client.send_command("INFO").await.unwrap();
+        assert!(info.contains("loading:1"), "{}", info);
+
+        // A miss waits for the load, and other connections are still served
+        // meanwhile on this single-threaded runtime
+        let waiting = tokio::spawn(async move { Client::new(address).get("missing").await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+        let info = client.send_command("INFO").await.unwrap();
+        assert!(info.contains("loading:1"), "{}", info);
+
+        finish.send(()).unwrap();
+        feeder.join().unwrap();
+        assert_eq!(waiting.await.unwrap().unwrap(), None);
+        assert_eq!(client.send_command("HEALTH READY").await.unwrap(), "OK");
+        let info = client.send_command("INFO").await.unwrap();
+        assert!(info.starts_with("keys:3000, loading:0"), "{}", info);
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_slow_wal_sync_leaves_reads_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(KeyValueStore::new());
+        store.open_wal(&dir.path().join("db.wal")).unwrap();
+        let delay = Duration::from_millis(500);
+        store.wal().unwrap().set_commit_delay(delay);
+        store.put("ready".to_string(), "1".to_string());
+
+        // On a single-threaded runtime a write waiting for its sync would
+        // hold up every other connection if it waited on the runtime
+        let server = Server::new(Arc::clone(&store), "127.0.0.1:0".to_string())
+            .spawn()
+            .await
+            .unwrap();
+        let address = server.address().to_string();
+        let writer = Client::new(address.clone());
+        let write = tokio::spawn(async move { writer.put("slow", "1").await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let started = Instant::now();
+        let reader = Client::new(address);
+        assert_eq!(reader.get("ready").await.unwrap(), Some("1".to_string()));
+        assert!(started.elapsed() < delay / 2, "{:?}", started.elapsed());
+        write.await.unwrap().unwrap();
+        assert_eq!(store.get("slow"), Some("1".to_string()));
+        server.shutdown();
+    }
+}
@@ -0,0 +1,107 @@
+// src/notifications.rs
+
+// Keyspace notifications. The store publishes an event whenever a key is
+// set, deleted, given or cleared of an expiry time, expires or is evicted,
+// and SUBSCRIBE streams them to clients as lines like `expired session:42`,
+// so caches in front of the store know when to drop an entry. Unlike the
+// changelog nothing is kept: subscribers only see events from while they
+// are connected.
+
+use crate::error::StoreError;
+use std::fmt;
+use std::str::FromStr;
+use tokio::sync::broadcast;
+
+// Events a slow subscriber may fall behind by before it is dropped
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    Set,
+    Deleted,
+    // Given an expiry time
+    Expire,
+    // Expiry time removed
+    Persist,
+    Expired,
+    Evicted,
+}
+
+impl KeyEvent {
+    const ALL: [KeyEvent; 6] = [
+        KeyEvent::Set,
+        KeyEvent::Deleted,
+        KeyEvent::Expire,
+        KeyEvent::Persist,
+        KeyEvent::Expired,
+        KeyEvent::Evicted,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyEvent::Set => "set",
+            KeyEvent::Deleted => "del",
+            KeyEvent::Expire => "expire",
+            KeyEvent::Persist => "persist",
+            KeyEvent::Expired => "expired",
+            KeyEvent::Evicted => "evicted",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub event: KeyEvent,
+    pub key: String,
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.event.name(), self.key)
+    }
+}
+
+impl FromStr for Notification {
+    type Err = StoreError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let invalid = || StoreError::SerializationError(format!("Invalid notification: {}", line));
+        let (name, key) = line.split_once(' ').ok_or_else(invalid)?;
+        let event = KeyEvent::ALL
+            .into_iter()
+            .find(|event| event.name() == name)
+            .ok_or_else(invalid)?;
+        Ok(Notification {
+            event,
+            key: key.to_string(),
+        })
+    }
+}
+
+pub struct Notifier {
+    sender: broadcast::Sender<Notification>,
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+        Notifier { sender }
+    }
+}
+
+impl Notifier {
+    pub fn publish(&self, event: KeyEvent, key: &str) {
+        // Most of the time nobody is listening, so don't copy the key
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let _ = self.sender.send(Notification {
+            event,
+            key: key.to_string(),
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,158 @@
+// src/queue.rs
+
+// Admission control for client commands. At most `max_running` commands
+// execute at once and at most `max_queued` more wait for a turn; beyond that
+// a command is turned away at once, so a flood of expensive commands makes
+// the server answer "busy" instead of piling up work and memory. Both limits
+// can be changed at runtime. A `max_running` of 0 means no limit.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use tokio::sync::Notify;
+
+// Commands that may wait for a turn when none are free
+pub const DEFAULT_MAX_QUEUED: usize = 1024;
+
+pub struct CommandQueue {
+    max_running: AtomicUsize,
+    max_queued: AtomicUsize,
+    running: AtomicUsize,
+    queued: AtomicUsize,
+    rejected: AtomicU64,
+    // Signalled whenever a running command finishes
+    released: Notify,
+}
+
+// A command's turn to run, handed back when dropped
+pub struct Turn<'a>(&'a CommandQueue);
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::SeqCst);
+        self.0.released.notify_one();
+    }
+}
+
+impl Default for CommandQueue {
+    fn default() -> Self {
+        CommandQueue {
+            max_running: AtomicUsize::new(0),
+            max_queued: AtomicUsize::new(DEFAULT_MAX_QUEUED),
+            running: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+            released: Notify::new(),
+        }
+    }
+}
+
+impl CommandQueue {
+    pub fn max_running(&self) -> usize {
+        self.max_running.load(Ordering::SeqCst)
+    }
+
+    pub fn set_max_running(&self, count: usize) {
+        self.max_running.store(count, Ordering::SeqCst);
+        // A higher limit may let waiting commands in
+        self.released.notify_waiters();
+    }
+
+    pub fn max_queued(&self) -> usize {
+        self.max_queued.load(Ordering::SeqCst)
+    }
+
+    pub fn set_max_queued(&self, count: usize) {
+        self.max_queued.store(count, Ordering::SeqCst);
+    }
+
+    // Wait for a turn to run a command, None if too many are already waiting
+    pub async fn enter(&self) -> Option<Turn<'_>> {
+        if self.try_start() {
+            return Some(Turn(self));
+        }
+
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queued() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        loop {
+            // Register before checking, so a release in between isn't missed
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            if self.try_start() {
+                break;
+            }
+            released.await;
+        }
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        Some(Turn(self))
+    }
+
+    fn try_start(&self) -> bool {
+        let max = self.max_running();
+        self.running
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |running| {
+                (max == 0 || running < max).then_some(running + 1)
+            })
+            .is_ok()
+    }
+
+    // `running_commands:3, queued_commands:0, ...` for INFO
+    pub fn info(&self) -> String {
+        format!(
+            "running_commands:{}, queued_commands:{}, rejected_commands:{}",
+            self.running.load(Ordering::SeqCst),
+            self.queued.load(Ordering::SeqCst),
+            self.rejected.load(Ordering::Relaxed)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_command_queue_backpressure() {
+        let queue = Arc::new(CommandQueue::default());
+        queue.set_max_running(1);
+        queue.set_max_queued(1);
+
+        let first = queue.enter().await.unwrap();
+
+        // The second command waits for the first to finish
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.enter().await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        assert!(queue.info().contains("queued_commands:1"));
+
+        // The third finds the queue full
+        assert!(queue.enter().await.is_none());
+        assert!(queue.info().contains("rejected_commands:1"));
+
+        drop(first);
+        assert!(waiting.await.unwrap());
+        assert!(
+            queue
+                .info()
+                .contains("running_commands:0, queued_commands:0")
+        );
+
+        // Raising the limit lets waiting commands in without a release
+        let first = queue.enter().await.unwrap();
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.enter().await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        queue.set_max_running(0);
+        assert!(waiting.await.unwrap());
+        drop(first);
+    }
+}
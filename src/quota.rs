@@ -0,0 +1,133 @@
+// src/quota.rs
+
+// Storage quotas on key prefixes, e.g. `tenantA:*` limited to 100MB and
+// 10k keys, so teams sharing a cluster can't crowd each other out
+
+use crate::error::{Result, StoreError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Limits for one prefix, 0 meaning unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaLimits {
+    pub max_memory: u64,
+    pub max_keys: usize,
+}
+
+pub struct Quota {
+    // As configured, e.g. `tenantA:*`
+    pattern: String,
+    prefix: String,
+    limits: QuotaLimits,
+    used_memory: AtomicUsize,
+    keys: AtomicUsize,
+}
+
+impl Quota {
+    pub fn new(pattern: &str, limits: QuotaLimits) -> Self {
+        Quota {
+            pattern: pattern.to_string(),
+            prefix: pattern.trim_end_matches('*').to_string(),
+            limits,
+            used_memory: AtomicUsize::new(0),
+            keys: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        key.starts_with(&self.prefix)
+    }
+
+    // Account for an entry of `size` bytes being added or removed
+    pub fn add(&self, size: usize) {
+        self.used_memory.fetch_add(size, Ordering::Relaxed);
+        self.keys.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn remove(&self, size: usize) {
+        self.used_memory.fetch_sub(size, Ordering::Relaxed);
+        self.keys.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Whether replacing an entry of `old_size` bytes (0 if it's a new key)
+    // with one of `new_size` bytes stays within the limits. Writes that don't
+    // grow usage are always allowed.
+    pub fn check(&self, old_size: usize, new_size: usize) -> Result<()> {
+        self.check_many(old_size, new_size, usize::from(old_size == 0))
+    }
+
+    // Like `check`, for several writes at once: entries of `old_size` bytes
+    // in all replaced by `new_size` bytes, `added` of them new keys
+    pub fn check_many(&self, old_size: usize, new_size: usize, added: usize) -> Result<()> {
+        let used = self.used_memory.load(Ordering::Relaxed);
+        let new_used = (used - old_size.min(used) + new_size) as u64;
+        if self.limits.max_memory > 0 && new_used > self.limits.max_memory && new_size > old_size {
+            return Err(StoreError::QuotaError(format!(
+                "{} is limited to {} bytes",
+                self.pattern, self.limits.max_memory
+            )));
+        }
+
+        let keys = self.keys.load(Ordering::Relaxed);
+        if self.limits.max_keys > 0 && added > 0 && keys + added > self.limits.max_keys {
+            return Err(StoreError::QuotaError(format!(
+                "{} is limited to {} keys",
+                self.pattern, self.limits.max_keys
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pattern={}, used_memory={}, max_memory={}, keys={}, max_keys={}",
+            self.pattern,
+            self.used_memory.load(Ordering::Relaxed),
+            self.limits.max_memory,
+            self.keys.load(Ordering::Relaxed),
+            self.limits.max_keys
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_limits() {
+        let quota = Quota::new(
+            "tenantA:*",
+            QuotaLimits {
+                max_memory: 20,
+                max_keys: 2,
+            },
+        );
+        assert!(quota.matches("tenantA:user1"));
+        assert!(!quota.matches("tenantB:user1"));
+
+        quota.add(10);
+        assert!(quota.check(0, 10).is_ok());
+        assert!(matches!(quota.check(0, 11), Err(StoreError::QuotaError(_))));
+
+        // Shrinking is fine even when over the limit
+        quota.add(10);
+        assert!(quota.check(10, 5).is_ok());
+        assert!(quota.check(0, 1).is_err());
+
+        quota.remove(10);
+        assert_eq!(
+            quota.to_string(),
+            "pattern=tenantA:*, used_memory=10, max_memory=20, keys=1, max_keys=2"
+        );
+    }
+}
@@ -0,0 +1,485 @@
+// src/rdb.rs
+
+// Reading Redis persistence files, so a small Redis deployment can be
+// loaded without writing an exporter. String keys are imported; lists,
+// hashes and other types are parsed past and counted as skipped, since the
+// store only holds strings. TTLs aren't kept: keys that have already
+// expired are dropped and the rest are imported without one.
+
+use crate::error::{Result, StoreError};
+use crate::transfer;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Opcodes that aren't value types
+const OPCODE_SLOT_INFO: u8 = 244;
+const OPCODE_FUNCTION2: u8 = 245;
+const OPCODE_MODULE_AUX: u8 = 247;
+const OPCODE_IDLE: u8 = 248;
+const OPCODE_FREQ: u8 = 249;
+const OPCODE_AUX: u8 = 250;
+const OPCODE_RESIZEDB: u8 = 251;
+const OPCODE_EXPIRETIME_MS: u8 = 252;
+const OPCODE_EXPIRETIME: u8 = 253;
+const OPCODE_SELECTDB: u8 = 254;
+const OPCODE_EOF: u8 = 255;
+
+// Highest RDB version this reader has been checked against
+const MAX_RDB_VERSION: u32 = 12;
+
+// What was read from one Redis database
+#[derive(Debug, Default)]
+pub struct RedisData {
+    pub entries: HashMap<String, String>,
+    // Number of keys left out, by reason
+    pub skipped: BTreeMap<String, usize>,
+}
+
+impl RedisData {
+    fn skip(&mut self, reason: &str) {
+        *self.skipped.entry(reason.to_string()).or_default() += 1;
+    }
+
+    // Add a key read from the file, unless it can't be stored as a string
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        match (String::from_utf8(key), String::from_utf8(value)) {
+            (Ok(key), Ok(value)) => {
+                self.entries.insert(key, value);
+            }
+            _ => self.skip("not UTF-8"),
+        }
+    }
+}
+
+fn invalid(message: impl Into<String>) -> StoreError {
+    StoreError::SerializationError(message.into())
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// Read the string keys of database `db` from an RDB snapshot
+pub fn read_rdb(input: &[u8], db: u64) -> Result<RedisData> {
+    let mut data = RedisData::default();
+    let mut reader = Reader { input, pos: 0 };
+    read_snapshot(&mut reader, db, &mut data)?;
+    Ok(data)
+}
+
+// Replay an append-only file, including an RDB preamble if it has one, and
+// return the string keys of database `db`
+pub fn read_aof(input: &[u8], db: u64) -> Result<RedisData> {
+    let mut data = RedisData::default();
+    let mut reader = Reader { input, pos: 0 };
+    if input.starts_with(b"REDIS") {
+        read_snapshot(&mut reader, db, &mut data)?;
+    }
+
+    let mut selected = 0;
+    let now = unix_millis();
+    for args in transfer::read_commands(&input[reader.pos..])? {
+        let Some(command) = args.first() else {
+            continue;
+        };
+        let command = String::from_utf8_lossy(command).to_uppercase();
+        let args = &args[1..];
+
+        if command == "SELECT" {
+            selected = args
+                .first()
+                .and_then(|n| std::str::from_utf8(n).ok()?.parse().ok())
+                .ok_or_else(|| invalid("Invalid SELECT"))?;
+            continue;
+        }
+        if selected != db || matches!(command.as_str(), "MULTI" | "EXEC" | "PING") {
+            continue;
+        }
+
+        match (command.as_str(), args) {
+            // Expiry options are dropped along with the TTL
+            ("SET", [key, value, options @ ..]) => {
+                let option = |name: &str| {
+                    options
+                        .iter()
+                        .any(|o| String::from_utf8_lossy(o).eq_ignore_ascii_case(name))
+                };
+                let exists =
+                    std::str::from_utf8(key).is_ok_and(|key| data.entries.contains_key(key));
+                if !(option("NX") && exists || option("XX") && !exists) {
+                    data.insert(key.clone(), value.clone());
+                }
+            }
+            ("MSET", pairs) if pairs.len() % 2 == 0 => {
+                for pair in pairs.chunks(2) {
+                    data.insert(pair[0].clone(), pair[1].clone());
+                }
+            }
+            ("DEL" | "UNLINK", keys) => {
+                for key in keys {
+                    data.entries.remove(String::from_utf8_lossy(key).as_ref());
+                }
+            }
+            ("PEXPIREAT", [key, at]) => {
+                let at: u64 = std::str::from_utf8(at)
+                    .ok()
+                    .and_then(|at| at.parse().ok())
+                    .ok_or_else(|| invalid("Invalid PEXPIREAT"))?;
+                if at <= now
+                    && data
+                        .entries
+                        .remove(String::from_utf8_lossy(key).as_ref())
+                        .is_some()
+                {
+                    data.skip("expired");
+                }
+            }
+            _ => data.skip(&format!("{} command", command)),
+        }
+    }
+    Ok(data)
+}
+
+// Read from the REDIS header up to and including the EOF opcode and checksum
+fn read_snapshot(reader: &mut Reader, db: u64, data: &mut RedisData) -> Result<()> {
+    let header = reader.take(9)?;
+    let version: u32 = header
+        .strip_prefix(b"REDIS")
+        .and_then(|v| std::str::from_utf8(v).ok()?.parse().ok())
+        .ok_or_else(|| invalid("Not an RDB file"))?;
+    if version > MAX_RDB_VERSION {
+        return Err(invalid(format!(
+            "RDB version {} is newer than this importer understands ({})",
+            version, MAX_RDB_VERSION
+        )));
+    }
+
+    let now = unix_millis();
+    let mut selected = 0;
+    let mut expires_at = None;
+    loop {
+        match reader.byte()? {
+            OPCODE_EOF => {
+                // CRC64 of the file, not checked
+                if version >= 5 {
+                    reader.take(8)?;
+                }
+                return Ok(());
+            }
+            OPCODE_SELECTDB => selected = reader.length()?,
+            OPCODE_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            }
+            OPCODE_AUX => {
+                reader.string()?;
+                reader.string()?;
+            }
+            OPCODE_EXPIRETIME_MS => {
+                expires_at = Some(u64::from_le_bytes(reader.array()?));
+            }
+            OPCODE_EXPIRETIME => {
+                expires_at = Some(u32::from_le_bytes(reader.array()?) as u64 * 1000);
+            }
+            OPCODE_IDLE => {
+                reader.length()?;
+            }
+            OPCODE_FREQ => {
+                reader.byte()?;
+            }
+            OPCODE_FUNCTION2 => {
+                reader.string()?;
+            }
+            OPCODE_SLOT_INFO => {
+                for _ in 0..3 {
+                    reader.length()?;
+                }
+            }
+            OPCODE_MODULE_AUX => return Err(invalid("Module data is not supported")),
+            value_type => {
+                let key = reader.string()?;
+                let value = read_value(reader, value_type)?;
+                match (value, expires_at.take()) {
+                    _ if selected != db => data.skip("other database"),
+                    (_, Some(at)) if at <= now => data.skip("expired"),
+                    (Ok(value), _) => data.insert(key, value),
+                    (Err(type_name), _) => data.skip(type_name),
+                }
+            }
+        }
+    }
+}
+
+// A string value, or the name of the type that was skipped over
+fn read_value(
+    reader: &mut Reader,
+    value_type: u8,
+) -> Result<std::result::Result<Vec<u8>, &'static str>> {
+    let (type_name, strings_per_item) = match value_type {
+        0 => return Ok(Ok(reader.string()?)),
+        1 | 14 => ("list", 1),
+        2 => ("set", 1),
+        4 => ("hash", 2),
+        // Member and score, the score as a short string
+        3 => {
+            for _ in 0..reader.length()? {
+                reader.string()?;
+                let len = reader.byte()?;
+                if len < 253 {
+                    reader.take(len as usize)?;
+                }
+            }
+            return Ok(Err("sorted set"));
+        }
+        // Member and binary score
+        5 => {
+            for _ in 0..reader.length()? {
+                reader.string()?;
+                reader.take(8)?;
+            }
+            return Ok(Err("sorted set"));
+        }
+        // Encoded as a single blob
+        9 | 13 | 16 => ("hash", 0),
+        10 => ("list", 0),
+        11 | 20 => ("set", 0),
+        12 | 17 => ("sorted set", 0),
+        // Quicklist nodes: a container type, then a blob
+        18 => {
+            for _ in 0..reader.length()? {
+                reader.length()?;
+                reader.string()?;
+            }
+            return Ok(Err("list"));
+        }
+        15 | 19 | 21 => return Err(invalid("Streams are not supported")),
+        6 | 7 => return Err(invalid("Module types are not supported")),
+        other => return Err(invalid(format!("Unknown value type {}", other))),
+    };
+
+    if strings_per_item == 0 {
+        reader.string()?;
+    } else {
+        let strings = reader
+            .length()?
+            .checked_mul(strings_per_item)
+            .ok_or_else(|| invalid(format!("Invalid {} length", type_name)))?;
+        for _ in 0..strings {
+            reader.string()?;
+        }
+    }
+    Ok(Err(type_name))
+}
+
+struct Reader<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+// A length, or the special encoding used instead of one
+enum Length {
+    Plain(u64),
+    Encoded(u8),
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .input
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| invalid("Truncated RDB file"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn length_or_encoding(&mut self) -> Result<Length> {
+        let first = self.byte()?;
+        let length = match first >> 6 {
+            0 => (first & 0x3f) as u64,
+            1 => ((first & 0x3f) as u64) << 8 | self.byte()? as u64,
+            2 if first == 0x80 => u32::from_be_bytes(self.array()?) as u64,
+            2 if first == 0x81 => u64::from_be_bytes(self.array()?),
+            3 => return Ok(Length::Encoded(first & 0x3f)),
+            _ => return Err(invalid(format!("Invalid length byte {:#x}", first))),
+        };
+        Ok(Length::Plain(length))
+    }
+
+    fn length(&mut self) -> Result<u64> {
+        match self.length_or_encoding()? {
+            Length::Plain(length) => Ok(length),
+            Length::Encoded(_) => Err(invalid("Expected a length")),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        let integer = match self.length_or_encoding()? {
+            Length::Plain(len) => return Ok(self.take(len as usize)?.to_vec()),
+            Length::Encoded(0) => self.byte()? as i8 as i64,
+            Length::Encoded(1) => i16::from_le_bytes(self.array()?) as i64,
+            Length::Encoded(2) => i32::from_le_bytes(self.array()?) as i64,
+            Length::Encoded(3) => {
+                let compressed_len = self.length()? as usize;
+                let len = self.length()? as usize;
+                return lzf_decompress(self.take(compressed_len)?, len);
+            }
+            Length::Encoded(other) => {
+                return Err(invalid(format!("Unknown string encoding {}", other)));
+            }
+        };
+        Ok(integer.to_string().into_bytes())
+    }
+}
+
+// Most bytes LZF gets out of one byte of input: a three-byte back-reference
+// copies up to 264 bytes
+const LZF_MAX_RATIO: usize = 88;
+
+// LZF, used for long strings when rdbcompression is on
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>> {
+    let corrupt = || invalid("Corrupt LZF string");
+    if len > input.len().saturating_mul(LZF_MAX_RATIO) {
+        return Err(corrupt());
+    }
+    let mut output = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // A run of ctrl + 1 literal bytes
+            let literal = input.get(i..i + ctrl + 1).ok_or_else(corrupt)?;
+            output.extend_from_slice(literal);
+            i += ctrl + 1;
+        } else {
+            // A copy of earlier output
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or_else(corrupt)? as usize;
+                i += 1;
+            }
+            let low = *input.get(i).ok_or_else(corrupt)? as usize;
+            i += 1;
+            let distance = ((ctrl & 0x1f) << 8) + low + 1;
+            let start = output.len().checked_sub(distance).ok_or_else(corrupt)?;
+            for j in 0..run + 2 {
+                output.push(output[start + j]);
+            }
+        }
+    }
+
+    if output.len() != len {
+        return Err(corrupt());
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![s.len() as u8];
+        encoded.extend_from_slice(s);
+        encoded
+    }
+
+    #[test]
+    fn test_read_redis_files() -> Result<()> {
+        let mut rdb = b"REDIS0011".to_vec();
+        rdb.push(OPCODE_AUX);
+        rdb.extend(string(b"redis-ver"));
+        rdb.extend(string(b"7.2.4"));
+        rdb.extend([OPCODE_SELECTDB, 0, OPCODE_RESIZEDB, 5, 1]);
+        // Plain, integer-encoded and LZF-compressed strings
+        rdb.push(0);
+        rdb.extend(string(b"name"));
+        rdb.extend(string(b"redis"));
+        rdb.push(0);
+        rdb.extend(string(b"count"));
+        rdb.extend([0xc1, 0x39, 0x30]);
+        rdb.push(0);
+        rdb.extend(string(b"long"));
+        rdb.extend([0xc3, 5, 10, 0x00, b'a', 0xe0, 0x00, 0x00]);
+        // A list, and a key that expired long ago
+        rdb.push(1);
+        rdb.extend(string(b"queue"));
+        rdb.push(2);
+        rdb.extend(string(b"job1"));
+        rdb.extend(string(b"job2"));
+        rdb.push(OPCODE_EXPIRETIME_MS);
+        rdb.extend(1000u64.to_le_bytes());
+        rdb.push(0);
+        rdb.extend(string(b"session"));
+        rdb.extend(string(b"old"));
+        // Another database
+        rdb.extend([OPCODE_SELECTDB, 1, 0]);
+        rdb.extend(string(b"name"));
+        rdb.extend(string(b"other"));
+        rdb.push(OPCODE_EOF);
+        rdb.extend([0; 8]);
+
+        let data = read_rdb(&rdb, 0)?;
+        assert_eq!(data.entries.len(), 3);
+        assert_eq!(data.entries["name"], "redis");
+        assert_eq!(data.entries["count"], "12345");
+        assert_eq!(data.entries["long"], "a".repeat(10));
+        assert_eq!(data.skipped["list"], 1);
+        assert_eq!(data.skipped["expired"], 1);
+        assert_eq!(data.skipped["other database"], 1);
+        assert_eq!(read_rdb(&rdb, 1)?.entries["name"], "other");
+        assert!(read_rdb(&rdb[..rdb.len() - 12], 0).is_err());
+
+        // An AOF with that snapshot as its preamble, then commands
+        let mut aof = rdb.clone();
+        aof.extend_from_slice(
+            b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n\
+              *3\r\n$3\r\nSET\r\n$4\r\nname\r\n$3\r\nnew\r\n\
+              *4\r\n$3\r\nSET\r\n$4\r\nname\r\n$4\r\nlost\r\n$2\r\nNX\r\n\
+              *2\r\n$3\r\nDEL\r\n$5\r\ncount\r\n\
+              *3\r\n$5\r\nLPUSH\r\n$1\r\nq\r\n$1\r\nx\r\n",
+        );
+        let data = read_aof(&aof, 0)?;
+        assert_eq!(data.entries["name"], "new");
+        assert!(!data.entries.contains_key("count"));
+        assert_eq!(data.skipped["LPUSH command"], 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_malformed_lengths() {
+        let with_value = |value_type: u8, value: &[u8]| {
+            let mut rdb = b"REDIS0011".to_vec();
+            rdb.extend([OPCODE_SELECTDB, 0, value_type]);
+            rdb.extend(string(b"key"));
+            rdb.extend_from_slice(value);
+            rdb
+        };
+        let huge = u64::MAX.to_be_bytes();
+
+        // A hash whose field count overflows once doubled
+        let mut hash = vec![0x81];
+        hash.extend(huge);
+        assert!(read_rdb(&with_value(4, &hash), 0).is_err());
+
+        // An LZF string claiming far more than its input could expand to
+        let mut lzf = vec![0xc3, 1, 0x81];
+        lzf.extend(huge);
+        lzf.push(0x00);
+        assert!(read_rdb(&with_value(0, &lzf), 0).is_err());
+        let lzf = [0xc3, 2, 0x80, 0, 0x10, 0, 0, 0x00, b'a'];
+        assert!(read_rdb(&with_value(0, &lzf), 0).is_err());
+    }
+}
@@ -1,9 +1,21 @@
+use crate::changelog::{Change, ChangeOp};
 use crate::client::Client;
+use crate::discovery::PeerSource;
 use crate::error::{Result, StoreError};
+use crate::failure_detector::{DEFAULT_ACCEPTABLE_PAUSE, PhiAccrualDetector};
+use crate::hlc::Timestamp;
+use crate::logging::{log_debug, log_error, log_info, log_warn};
 use crate::store::KeyValueStore;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::sync::Mutex;
+use crate::tls::Tls;
+use crate::vclock::VectorClock;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, mpsc, watch};
+use tokio::task::JoinSet;
 
 // Node roles
 #[derive(Debug, Clone, PartialEq)]
@@ -13,21 +25,66 @@ pub enum Role {
     Standalone,     // Not part of any replication
 }
 
+// How writes reach backups. Both nodes must use the same mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReplicationMode {
+    // The primary sends each write to every backup as a REPLICATE command
+    #[default]
+    Push,
+    // Backups tail the primary's changelog over a SYNC stream, which keeps
+    // writes in order, batches them under load and resumes from any sequence
+    Log,
+}
+
+impl fmt::Display for ReplicationMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplicationMode::Push => f.write_str("push"),
+            ReplicationMode::Log => f.write_str("log"),
+        }
+    }
+}
+
+impl FromStr for ReplicationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "push" => Ok(ReplicationMode::Push),
+            "log" => Ok(ReplicationMode::Log),
+            _ => Err(format!("Unknown replication mode '{}'", s)),
+        }
+    }
+}
+
 // Operation types that can be replicated
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Operation {
     Put(String, String),
     Delete(String),
+    // Several keys deleted together
+    DeleteMany(Vec<String>),
+    // The first key's value and expiry time moved or copied to the second
+    Rename(String, String),
+    Copy(String, String),
+    // The key expires at this unix time in milliseconds, 0 for never
+    Expire(String, u64),
 }
 
-impl Operation {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Operation::Put(key, value) => format!("PUT {} {}", key, value),
-            Operation::Delete(key) => format!("Delete {}", key),
+            Operation::Put(key, value) => write!(f, "PUT {} {}", key, value),
+            Operation::Delete(key) => write!(f, "DELETE {}", key),
+            Operation::DeleteMany(keys) => write!(f, "DELETE {}", keys.join(" ")),
+            Operation::Rename(src, dst) => write!(f, "RENAME {} {}", src, dst),
+            Operation::Copy(src, dst) => write!(f, "COPY {} {}", src, dst),
+            Operation::Expire(key, at) => write!(f, "EXPIRE {} {}", key, at),
         }
     }
+}
 
+impl Operation {
     pub fn from_string(s: &str) -> Option<Self> {
         let parts: Vec<&str> = s.split_whitespace().collect();
         if parts.is_empty() {
@@ -41,47 +98,342 @@ impl Operation {
                 }
 
                 let key = parts[1].to_string();
-                let value = parts[2..].join("");
+                let value = parts[2..].join(" ");
                 Some(Operation::Put(key, value))
             }
-            "DELETE" => {
-                if parts.len() != 2 {
+            "DELETE" => match parts.len() {
+                0..=1 => None,
+                2 => Some(Operation::Delete(parts[1].to_string())),
+                _ => Some(Operation::DeleteMany(
+                    parts[1..].iter().map(|key| key.to_string()).collect(),
+                )),
+            },
+            "RENAME" | "COPY" => {
+                let [_, src, dst] = parts[..] else {
+                    return None;
+                };
+                let (src, dst) = (src.to_string(), dst.to_string());
+                Some(if parts[0] == "RENAME" {
+                    Operation::Rename(src, dst)
+                } else {
+                    Operation::Copy(src, dst)
+                })
+            }
+            "EXPIRE" => {
+                if parts.len() != 3 {
                     return None;
                 }
-                Some(Operation::Delete(parts[1].to_string()))
+                Some(Operation::Expire(parts[1].to_string(), parts[2].parse().ok()?))
             }
             _ => None,
         }
     }
 }
 
+// Keys a REPAIR changed, or would change in a dry run
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    pub dry_run: bool,
+    pub updated: Vec<String>,
+    pub deleted: Vec<String>,
+}
+
+impl fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "dry_run={}, updated={}, deleted={}",
+            self.dry_run,
+            self.updated.len(),
+            self.deleted.len()
+        )
+    }
+}
+
+// How far a backup is behind the primary, as of its last heartbeat ACK
+#[derive(Debug)]
+pub struct BackupLag {
+    pub address: String,
+    pub applied_seq: u64,
+    pub lag_ops: u64,
+    pub lag_seconds: f64,
+    pub last_ack: Option<Duration>,
+    pub failures: u32, // Consecutive failed sends
+}
+
+impl fmt::Display for BackupLag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "address={} applied_seq={} lag_ops={} lag_seconds={:.3}",
+            self.address, self.applied_seq, self.lag_ops, self.lag_seconds
+        )?;
+        match self.last_ack {
+            Some(elapsed) => write!(f, " last_ack_ms={}", elapsed.as_millis())?,
+            None => write!(f, " last_ack_ms=never")?,
+        }
+        write!(
+            f,
+            " failures={} quarantined={}",
+            self.failures,
+            self.failures >= QUARANTINE_AFTER
+        )
+    }
+}
+
 // Replication manager
 pub struct ReplicationManager {
     store: Arc<KeyValueStore>,
     role: Mutex<Role>,
     backups: Mutex<Vec<String>>, // List of backup addresses
-    last_heartbeat: Mutex<Instant>,
+    acks: Mutex<HashMap<String, (u64, Instant)>>, // Applied seq each backup last reported, and when
+    failures: Mutex<HashMap<String, u32>>, // Consecutive failed sends to each backup
+    failure_detector: std::sync::Mutex<PhiAccrualDetector>, // Never held across an await
+    settings: RwLock<ReplicationSettings>, // Reloadable at runtime
+    applied_seq: AtomicU64, // Last primary changelog sequence applied
+    discovery: Mutex<Option<Discovery>>, // Where to look for the primary if it goes quiet
+    synced: AtomicBool, // False while a backup is still catching up with its primary
+    heartbeat_seq: AtomicU64, // Primary's sequence as of the last heartbeat
+    catching_up: AtomicBool, // A backup is resyncing after missing writes
+    lease: RwLock<Option<Instant>>, // When a primary with backups must stop taking writes
+    safe_mode: RwLock<Option<String>>, // Why writes are refused after seeing another primary
+    handoff: RwLock<Option<String>>, // Backup a HANDOFF is giving, or has given, the primary role to
+    auth_token: RwLock<Option<String>>, // Admin token the cluster shares, for commands we send other nodes
+    tls: RwLock<Option<Arc<Tls>>>, // How we reach other nodes over TLS
+    listening: watch::Sender<bool>, // Whether heartbeats can reach us yet
+}
+
+// How a backup finds its primary again
+#[derive(Debug, Clone)]
+struct Discovery {
+    source: PeerSource,
+    own_addr: String, // How a new primary reaches us
+}
+
+// How long a backup gets to answer when a read needs leadership confirmed
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Consecutive failed heartbeats or REPLICATEs after which a backup is
+// quarantined: it only gets heartbeats until one succeeds
+const QUARANTINE_AFTER: u32 = 5;
+
+// How a primary answers replication traffic meant for a backup, which tells
+// the sender that two nodes think they are the primary
+pub(crate) const ALSO_PRIMARY: &str = "ERROR: Not a backup, this node is a primary";
+
+// How replication traffic from a primary that has been replaced starts
+pub(crate) const STALE_EPOCH: &str = "ERROR: Stale epoch";
+
+// How long HANDOFF waits for its target to catch up
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Longest wait between attempts to register with an unreachable primary
+const MAX_REGISTER_DELAY: Duration = Duration::from_secs(30);
+
+// Failure detection tuning
+#[derive(Debug, Clone, Copy)]
+struct ReplicationSettings {
     heartbeat_interval: Duration,
-    failover_timeout: Duration,
+    phi_threshold: f64, // Suspicion level at which we consider the primary dead
+    acceptable_pause: Duration, // Silence beyond the heartbeat interval not held against the primary
+    mode: ReplicationMode,
 }
 
 impl ReplicationManager {
     pub fn new(store: Arc<KeyValueStore>) -> Self {
+        let heartbeat_interval = Duration::from_secs(1);
+
         ReplicationManager {
             store,
             role: Mutex::new(Role::Standalone),
             backups: Mutex::new(Vec::new()),
-            last_heartbeat: Mutex::new(Instant::now()),
-            heartbeat_interval: Duration::from_secs(1),
-            failover_timeout: Duration::from_secs(5),
+            acks: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+            failure_detector: std::sync::Mutex::new(PhiAccrualDetector::new(
+                heartbeat_interval,
+                DEFAULT_ACCEPTABLE_PAUSE,
+            )),
+            settings: RwLock::new(ReplicationSettings {
+                heartbeat_interval,
+                phi_threshold: 8.0,
+                acceptable_pause: DEFAULT_ACCEPTABLE_PAUSE,
+                mode: ReplicationMode::default(),
+            }),
+            applied_seq: AtomicU64::new(0),
+            discovery: Mutex::new(None),
+            synced: AtomicBool::new(true),
+            heartbeat_seq: AtomicU64::new(0),
+            catching_up: AtomicBool::new(false),
+            lease: RwLock::new(None),
+            safe_mode: RwLock::new(None),
+            handoff: RwLock::new(None),
+            auth_token: RwLock::new(None),
+            tls: RwLock::new(None),
+            listening: watch::Sender::new(false),
         }
     }
 
+    // Called once the server accepts connections. Until then heartbeats
+    // can't reach us, so a backup doesn't hold their absence against its
+    // primary.
+    pub fn start_monitoring(&self) {
+        self.listening.send_replace(true);
+    }
+
+    pub fn set_mode(&self, mode: ReplicationMode) {
+        self.settings.write().unwrap().mode = mode;
+    }
+
+    pub fn mode(&self) -> ReplicationMode {
+        self.settings.read().unwrap().mode
+    }
+
+    // Authenticate with this token when telling other nodes to take over or
+    // follow, which they only accept from an admin
+    pub fn set_auth_token(&self, token: Option<String>) {
+        *self.auth_token.write().unwrap() = token;
+    }
+
+    // Connect to other nodes over TLS, checking their certificates against
+    // its CA
+    pub fn set_tls(&self, tls: Arc<Tls>) {
+        *self.tls.write().unwrap() = Some(tls);
+    }
+
+    // A client for another node of the cluster
+    fn client(&self, addr: &str) -> Client {
+        let client = Client::new(addr.to_string());
+        match self.tls.read().unwrap().clone() {
+            Some(tls) => client.with_tls(tls),
+            None => client,
+        }
+    }
+
+    // A client for admin commands to another node of the cluster
+    fn peer(&self, addr: &str) -> Client {
+        let client = self.client(addr);
+        match self.auth_token.read().unwrap().clone() {
+            Some(token) => client.with_auth_token(token),
+            None => client,
+        }
+    }
+
+    // Change how often heartbeats are sent to backups. A backup starts
+    // learning the primary's rhythm afresh, since what it has seen so far
+    // was paced differently.
+    pub fn set_heartbeat_interval(&self, interval: Duration) {
+        self.settings.write().unwrap().heartbeat_interval = interval;
+        *self.failure_detector.lock().unwrap() = self.new_detector();
+    }
+
+    // Change the suspicion level at which a backup takes over
+    pub fn set_phi_threshold(&self, threshold: f64) {
+        self.settings.write().unwrap().phi_threshold = threshold;
+    }
+
+    // Change how much extra silence a backup tolerates before suspicion of
+    // the primary starts to build
+    pub fn set_acceptable_pause(&self, pause: Duration) {
+        self.settings.write().unwrap().acceptable_pause = pause;
+        *self.failure_detector.lock().unwrap() = self.new_detector();
+    }
+
+    pub fn acceptable_pause(&self) -> Duration {
+        self.settings.read().unwrap().acceptable_pause
+    }
+
+    // A failure detector with no history, for a primary we've just started following
+    fn new_detector(&self) -> PhiAccrualDetector {
+        let settings = *self.settings.read().unwrap();
+        PhiAccrualDetector::new(settings.heartbeat_interval, settings.acceptable_pause)
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.settings.read().unwrap().heartbeat_interval
+    }
+
+    pub fn phi_threshold(&self) -> f64 {
+        self.settings.read().unwrap().phi_threshold
+    }
+
+    // How long a heartbeat round answered by a majority lets the primary
+    // take writes. Backups can't suspect us until a heartbeat interval plus
+    // at least min_suspicion_delay has passed without one, so with the same
+    // settings on both sides our lease runs out before any of them takes
+    // over.
+    fn lease_duration(&self) -> Duration {
+        self.heartbeat_interval() + PhiAccrualDetector::min_suspicion_delay(self.phi_threshold()) / 2
+    }
+
+    // Extend the lease from when a round of heartbeats `started`, if a
+    // majority of the cluster, counting ourselves, answered it
+    fn renew_lease(&self, started: Instant, confirmed: usize, members: usize) {
+        if confirmed * 2 <= members {
+            return;
+        }
+        let until = started + self.lease_duration();
+        let mut lease = self.lease.write().unwrap();
+        if lease.is_none_or(|current| current < until) {
+            *lease = Some(until);
+        }
+    }
+
+    // Whether we may take writes: false only for a primary whose backups
+    // haven't renewed its lease in time, which may have been replaced
+    pub async fn holds_lease(&self) -> bool {
+        if !matches!(self.get_role().await, Role::Primary) || self.backups.lock().await.is_empty() {
+            return true;
+        }
+        self.lease.read().unwrap().is_some_and(|until| Instant::now() < until)
+    }
+
+    // Stop taking writes because another node also claims to be the
+    // primary, until an operator has resolved it and calls leave_safe_mode
+    pub fn enter_safe_mode(&self, reason: &str) {
+        let mut safe_mode = self.safe_mode.write().unwrap();
+        if safe_mode.is_none() {
+            log_error!("Split brain: {}. Entering safe mode, writes are refused", reason);
+            *safe_mode = Some(reason.to_string());
+        }
+    }
+
+    pub fn leave_safe_mode(&self) {
+        if self.safe_mode.write().unwrap().take().is_some() {
+            log_info!("Leaving safe mode");
+        }
+    }
+
+    // Why we're in safe mode, if we are
+    pub fn safe_mode(&self) -> Option<String> {
+        self.safe_mode.read().unwrap().clone()
+    }
+
+    // Bumped by every promotion, so a deposed primary can be told apart.
+    // Kept with the store so it survives restarts.
+    pub fn epoch(&self) -> u64 {
+        self.store.epoch()
+    }
+
+    // Note the epoch replication traffic from a primary was sent at. A
+    // primary from an earlier epoch has since been replaced, so its traffic
+    // is refused with the epoch we know of.
+    pub fn observe_epoch(&self, epoch: u64) -> std::result::Result<(), u64> {
+        let known = self.store.raise_epoch(epoch);
+        if epoch < known { Err(known) } else { Ok(()) }
+    }
+
+    // The backup a HANDOFF is moving, or has moved, the primary role to
+    pub fn handoff(&self) -> Option<String> {
+        self.handoff.read().unwrap().clone()
+    }
+
     // Start as primary node
     pub async fn start_primary(self: Arc<Self>) -> Result<()> {
         let mut role = self.role.lock().await;
         *role = Role::Primary;
-        println!("Started as primary node");
+        self.store.set_expiry_deferred(false);
+        *self.handoff.write().unwrap() = None;
+        log_info!("Started as primary node");
 
         // Start heartbeat process in background
         let self_clone = Arc::clone(&self);
@@ -97,7 +449,13 @@ impl ReplicationManager {
         let _ = primary_addr;
         let mut role = self.role.lock().await;
         *role = Role::Backup(primary_addr.clone());
-        println!("Started as primary node");
+        // Keys expire when the primary's deletes say so
+        self.store.set_expiry_deferred(true);
+        if let Some(wal) = self.store.wal() {
+            wal.set_replicas_acked(None);
+        }
+        self.synced.store(false, Ordering::SeqCst);
+        log_info!("Started as backup node");
 
         // Start heartbeat process in background
         let self_clone = Arc::clone(&self);
@@ -105,19 +463,210 @@ impl ReplicationManager {
             self_clone.monitor_primary().await;
         });
 
+        if self.mode() == ReplicationMode::Log {
+            let self_clone = Arc::clone(&self);
+            tokio::spawn(async move {
+                self_clone.follow_primary_log().await;
+            });
+        }
+
+        Ok(())
+    }
+
+    // Start as a backup of whichever primary `source` points at, and look
+    // there again when the primary stops answering
+    pub async fn start_backup_from(self: Arc<Self>, source: PeerSource, own_addr: &str) -> Result<()> {
+        let primary_addr = source.resolve().await?;
+        log_info!("Found primary {} through {}", primary_addr, source);
+        *self.discovery.lock().await = Some(Discovery {
+            source,
+            own_addr: own_addr.to_string(),
+        });
+
+        let registrar = Arc::clone(&self);
+        self.start_backup(primary_addr).await?;
+        tokio::spawn(registrar.register_with_primary(own_addr.to_string()));
+        Ok(())
+    }
+
+    // Send REGISTER with our address until the primary acknowledges it, so
+    // writes reach us without an operator running add-backup. In push mode
+    // we then copy whatever was written before we were registered.
+    async fn register_with_primary(self: Arc<Self>, own_addr: String) {
+        let mut delay = self.heartbeat_interval();
+        while let Role::Backup(primary_addr) = self.get_role().await {
+            let result = self.client(&primary_addr)
+                .send_command(&format!("REGISTER {}", own_addr))
+                .await;
+            match result {
+                Ok(response) if response == "OK" => {
+                    log_info!("Registered with primary {} as {}", primary_addr, own_addr);
+                    if self.mode() == ReplicationMode::Push
+                        && let Err(e) = self.resync(&primary_addr).await
+                    {
+                        log_warn!("Failed to copy data from {}: {}", primary_addr, e);
+                    }
+                    return;
+                }
+                Ok(response) => log_warn!("{} refused to register us: {}", primary_addr, response),
+                Err(e) => log_warn!("Failed to register with {}: {}", primary_addr, e),
+            }
+
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_REGISTER_DELAY);
+        }
+    }
+
+    // Tail the primary's changelog, reconnecting from the last applied
+    // sequence whenever the stream drops. The first connection, and any
+    // after the primary no longer has the changes we need or has been
+    // replaced, starts with a full resync.
+    async fn follow_primary_log(self: Arc<Self>) {
+        let mut resync = true;
+        let mut following = String::new();
+        while let Role::Backup(primary_addr) = self.get_role().await {
+            // A new primary numbers its changes differently
+            if primary_addr != following {
+                following = primary_addr.clone();
+                resync = true;
+            }
+            if resync {
+                match self.resync(&primary_addr).await {
+                    Ok(()) => resync = false,
+                    Err(e) => {
+                        log_warn!("Failed to resync from {}: {}", primary_addr, e);
+                        tokio::time::sleep(self.heartbeat_interval()).await;
+                        continue;
+                    }
+                }
+            }
+
+            let from_seq = self.applied_seq.load(Ordering::SeqCst) + 1;
+            log_debug!("Following {} from seq {}", primary_addr, from_seq);
+            // Changes are applied in order on a blocking thread, as applying
+            // one can wait on the store's locks and its write-ahead log
+            let (changes, mut pending) = mpsc::unbounded_channel();
+            let applier = {
+                let rm = Arc::clone(&self);
+                tokio::task::spawn_blocking(move || {
+                    while let Some(change) = pending.blocking_recv() {
+                        rm.apply_change(change);
+                    }
+                })
+            };
+            let result = self.client(&primary_addr)
+                .sync(from_seq, |change| {
+                    let _ = changes.send(change);
+                })
+                .await;
+            drop(changes);
+            if let Err(e) = applier.await {
+                log_error!("Applying changes from {} failed: {}", primary_addr, e);
+            }
+            match result {
+                Ok(()) => log_warn!("Log stream from {} closed", primary_addr),
+                Err(e) => {
+                    log_warn!("Log stream from {} failed: {}", primary_addr, e);
+                    resync = e.to_string().contains("no longer available");
+                }
+            }
+            tokio::time::sleep(self.heartbeat_interval()).await;
+        }
+    }
+
+    // Copy the primary's whole store, then continue from the sequence it had
+    // reached before the copy. Changes made during the copy are applied
+    // again afterwards, which leaves the same result.
+    async fn resync(&self, primary_addr: &str) -> Result<()> {
+        let info = self.client(primary_addr).send_command("INFO").await?;
+        let seq = info
+            .split(", ")
+            .find_map(|field| field.strip_prefix("changelog_seq:"))
+            .and_then(|seq| seq.parse().ok())
+            .ok_or_else(|| StoreError::ReplicationError(format!("Unexpected INFO: {}", info)))?;
+
+        self.repair(&[], false).await?;
+        self.applied_seq.store(seq, Ordering::SeqCst);
+        self.synced.store(true, Ordering::SeqCst);
+        log_info!("Resynced from {} at seq {}", primary_addr, seq);
         Ok(())
     }
 
+    fn apply_change(&self, change: Change) {
+        if change.seq <= self.applied_seq.load(Ordering::SeqCst) {
+            return;
+        }
+        match change.op {
+            ChangeOp::Put { key, value } => self.store.put_at(key, value, change.hlc, None),
+            ChangeOp::Delete { key } => {
+                self.store.delete_at(&key, change.hlc);
+            }
+            ChangeOp::Expire { key, at } => {
+                self.store.expire_at(&key, at, change.hlc);
+            }
+        }
+        self.applied_seq.store(change.seq, Ordering::SeqCst);
+    }
+
+    // Join the cluster `member` belongs to as a backup of its primary,
+    // copying the primary's data and registering `own_addr` with it.
+    // Returns the primary's address.
+    pub async fn join(self: Arc<Self>, member: &str, own_addr: &str) -> Result<String> {
+        let info = self.client(member).send_command("INFO REPLICATION").await?;
+        let field = |name: &str| {
+            info.split(", ")
+                .find_map(|field| field.strip_prefix(name)?.strip_prefix(':'))
+                .map(str::to_string)
+        };
+        let primary_addr = match field("role").as_deref() {
+            Some("primary") => member.to_string(),
+            Some("backup") => field("primary").ok_or_else(|| {
+                StoreError::ReplicationError(format!("{} doesn't know its primary", member))
+            })?,
+            _ => {
+                return Err(StoreError::ReplicationError(format!(
+                    "{} is not part of a cluster: {}",
+                    member, info
+                )));
+            }
+        };
+
+        Arc::clone(&self).start_backup(primary_addr.clone()).await?;
+        let response = self.client(&primary_addr)
+            .send_command(&format!("ADD_BACKUP {}", own_addr))
+            .await?;
+        if response != "OK" {
+            return Err(StoreError::ReplicationError(format!(
+                "{} refused to add us: {}",
+                primary_addr, response
+            )));
+        }
+
+        // In log mode the backup copies the primary when it starts following
+        if self.mode() == ReplicationMode::Push {
+            self.repair(&[], false).await?;
+            self.synced.store(true, Ordering::SeqCst);
+        }
+        log_info!("Joined the cluster of {} through {}", primary_addr, member);
+        Ok(primary_addr)
+    }
+
     // Add a backup to this primary
     pub async fn add_backup(&self, backup_addr: String) -> Result<()> {
         let role = self.role.lock().await;
 
         if let Role::Primary = *role {
             let mut backups = self.backups.lock().await;
+            // Alone we couldn't have been replaced, so start with a lease
+            if backups.is_empty() {
+                self.renew_lease(Instant::now(), 1, 1);
+            }
             if !backups.contains(&backup_addr) {
                 backups.push(backup_addr.clone());
-                println!("Added backup node at {}", backup_addr);
+                log_info!("Added backup node at {}", backup_addr);
             }
+            // A backup registering again has copied our data itself
+            self.failures.lock().await.remove(&backup_addr);
             Ok(())
         } else {
             Err(StoreError::ReplicationError(
@@ -129,7 +678,7 @@ impl ReplicationManager {
     // Send heartbeats to all backups
     async fn send_heartbeats(&self) {
         loop {
-            tokio::time::sleep(self.heartbeat_interval).await;
+            tokio::time::sleep(self.heartbeat_interval()).await;
 
             // Check if we're still primary
             let role = self.role.lock().await;
@@ -144,36 +693,169 @@ impl ReplicationManager {
                 backups_lock.clone()
             };
 
-            // Send heartbeat to each backup
+            // Send heartbeat to each backup, including quarantined ones to
+            // find out when they're back
+            let started = Instant::now();
+            let mut confirmed = 1;
             for backup_addr in &backups {
-                if let Err(e) = self.send_heartbeat(backup_addr).await {
-                    eprintln!("Failed to send heartbeat to {}: {}", backup_addr, e);
+                match self.send_heartbeat(backup_addr).await {
+                    Ok(()) => {
+                        confirmed += 1;
+                        self.record_success(backup_addr).await;
+                    }
+                    Err(e) => {
+                        if self.record_failure(backup_addr, &e).await {
+                            log_warn!("Failed to send heartbeat to {}: {}", backup_addr, e);
+                        }
+                    }
                 }
             }
+            self.renew_lease(started, confirmed, backups.len() + 1);
+            self.retain_log_for_backups(&backups).await;
+        }
+    }
+
+    // Keep the write-ahead log segments backups in log mode haven't applied
+    // yet, so one that falls behind the changelog catches up from the log
+    // rather than copying the whole store. Quarantined backups don't hold
+    // segments back, or one that is gone for good would fill the disk.
+    async fn retain_log_for_backups(&self, backups: &[String]) {
+        let Some(wal) = self.store.wal() else {
+            return;
+        };
+        if self.mode() != ReplicationMode::Log {
+            wal.set_replicas_acked(None);
+            return;
         }
+        let acks = self.acks.lock().await;
+        let failures = self.failures.lock().await;
+        let slowest = backups
+            .iter()
+            .filter(|addr| failures.get(*addr).is_none_or(|&count| count < QUARANTINE_AFTER))
+            .map(|addr| acks.get(addr).map_or(0, |&(seq, _)| seq))
+            .min();
+        wal.set_replicas_acked(slowest);
     }
 
     // Send a single heartbeat
     async fn send_heartbeat(&self, backup_addr: &str) -> Result<()> {
         // Connect to backup using our client
-        let client = Client::new(backup_addr.to_string());
+        let client = self.client(backup_addr);
 
-        // Send a HEARTBEAT command
-        match client.send_command("HEARTBEAT").await {
-            Ok(response) if response == "OK" => {
-                // Heartbeat acknowledged
-                Ok(())
-            }
-            Ok(response) => Err(StoreError::ReplicationError(format!(
+        // Send a HEARTBEAT command carrying our latest seq, acknowledged
+        // with the backup's applied seq
+        let response = client.send_command(&self.heartbeat_command()).await?;
+        self.record_ack(backup_addr, &response).await
+    }
+
+    // Count a failed send to a backup, quarantining it once there have been
+    // QUARANTINE_AFTER in a row. Returns whether the failure is worth
+    // logging, which it stops being once the backup is quarantined.
+    async fn record_failure(&self, backup_addr: &str, e: &StoreError) -> bool {
+        let mut failures = self.failures.lock().await;
+        let count = failures.entry(backup_addr.to_string()).or_insert(0);
+        *count += 1;
+        if *count == QUARANTINE_AFTER {
+            log_warn!(
+                "Quarantining backup {} after {} consecutive failures, last: {}",
+                backup_addr,
+                QUARANTINE_AFTER,
+                e
+            );
+        }
+        *count < QUARANTINE_AFTER
+    }
+
+    // A backup answered. One coming out of quarantine has missed writes,
+    // which it notices from the sequence in our heartbeat and resyncs.
+    async fn record_success(&self, backup_addr: &str) {
+        let failures = self.failures.lock().await.remove(backup_addr);
+        if failures.is_some_and(|count| count >= QUARANTINE_AFTER) {
+            log_info!("Backup {} is reachable again, readmitting it", backup_addr);
+        }
+    }
+
+    fn heartbeat_command(&self) -> String {
+        format!("HEARTBEAT {} {}", self.store.changelog().last_seq(), self.epoch())
+    }
+
+    // Note the applied seq a backup answered a heartbeat with
+    async fn record_ack(&self, backup_addr: &str, response: &str) -> Result<()> {
+        if response == ALSO_PRIMARY {
+            self.enter_safe_mode(&format!("backup {} claims to be the primary", backup_addr));
+        }
+        // Unless we've just handed off to it, someone else has taken over
+        if response.starts_with(STALE_EPOCH) && self.handoff().is_none() {
+            self.enter_safe_mode(&format!("backup {} follows a newer primary", backup_addr));
+        }
+        if !response.starts_with("OK") {
+            return Err(StoreError::ReplicationError(format!(
                 "Unexpected response: {}",
                 response
-            ))),
-            Err(e) => Err(e),
+            )));
+        }
+        if let Some(Ok(seq)) = response.split_whitespace().nth(1).map(str::parse) {
+            self.acks.lock().await.insert(backup_addr.to_string(), (seq, Instant::now()));
+        }
+        Ok(())
+    }
+
+    // Check we're still the primary by having a majority of the cluster,
+    // counting ourselves, answer a heartbeat. A backup that has promoted
+    // itself refuses heartbeats, so after a failover we haven't noticed
+    // this fails rather than letting us serve stale reads.
+    pub async fn confirm_leadership(&self) -> Result<()> {
+        if !matches!(*self.role.lock().await, Role::Primary) {
+            return Err(StoreError::ReplicationError("Not the primary".to_string()));
+        }
+
+        let backups = self.backups.lock().await.clone();
+        let command = self.heartbeat_command();
+        let started = Instant::now();
+        let mut heartbeats = JoinSet::new();
+        for backup_addr in backups.iter().cloned() {
+            let command = command.clone();
+            let client = self.client(&backup_addr);
+            heartbeats.spawn(async move {
+                let response =
+                    tokio::time::timeout(CONFIRM_TIMEOUT, client.send_command(&command)).await;
+                (backup_addr, response)
+            });
+        }
+
+        let mut confirmed = 1;
+        while let Some(joined) = heartbeats.join_next().await {
+            if let Ok((backup_addr, Ok(Ok(response)))) = joined
+                && self.record_ack(&backup_addr, &response).await.is_ok()
+            {
+                confirmed += 1;
+            }
+        }
+
+        let members = backups.len() + 1;
+        self.renew_lease(started, confirmed, members);
+        if confirmed * 2 > members {
+            Ok(())
+        } else {
+            Err(StoreError::UnavailableError(format!(
+                "Only {} of {} nodes confirmed leadership",
+                confirmed, members
+            )))
         }
     }
 
     // Monitor primary for failures
     async fn monitor_primary(self: Arc<Self>) {
+        // Joining can take a while, and heartbeats only start arriving once
+        // we're listening, so judge the primary from then on
+        let mut listening = self.listening.subscribe();
+        if !*listening.borrow() {
+            if listening.wait_for(|up| *up).await.is_err() {
+                return;
+            }
+            *self.failure_detector.lock().unwrap() = self.new_detector();
+        }
+
         loop {
             tokio::time::sleep(Duration::from_millis(500)).await;
 
@@ -184,21 +866,27 @@ impl ReplicationManager {
                 _ => break, // Not a backup anymore
             };
             drop(role);
-            // Check last heartbeat time
-            let last_heartbeat = {
-                let heartbeat = self.last_heartbeat.lock().await;
-                *heartbeat
+            // Check how suspicious the silence from the primary is
+            let (phi, silence) = {
+                let detector = self.failure_detector.lock().unwrap();
+                (detector.phi(Instant::now()), detector.elapsed())
             };
-    
-            if last_heartbeat.elapsed() > self.failover_timeout {
-                println!(
-                    "Primary node at {} failed! Promoting to primary.",
-                    primary_addr
+
+            if phi > self.phi_threshold() {
+                // The primary may have moved rather than died
+                if let Some(new_primary) = self.rediscover_primary(&primary_addr).await {
+                    self.switch_primary(new_primary).await;
+                    continue;
+                }
+
+                log_warn!(
+                    "Primary node at {} failed (phi {:.1} after {:?} without heartbeat)! Promoting to primary.",
+                    primary_addr, phi, silence
                 );
-    
+
                 // Promote this backup to primary using a clone of self
                 if let Err(e) = Arc::clone(&self).promote_to_primary().await {
-                    eprintln!("Failed to promote to primary: {}", e);
+                    log_error!("Failed to promote to primary: {}", e);
                 } else {
                     break;
                 }
@@ -206,21 +894,167 @@ impl ReplicationManager {
         }
     }
 
-    // Record recevied heartbeat
-    pub async fn receive_heartbeat(&self) -> Result<()> {
-        let mut last_heartbeat = self.last_heartbeat.lock().await;
-        *last_heartbeat = Instant::now();
+    // Resolve the discovery source again, returning the primary it points
+    // at if that's not the one that went quiet
+    async fn rediscover_primary(&self, failed_addr: &str) -> Option<String> {
+        let discovery = self.discovery.lock().await.clone()?;
+        match discovery.source.resolve().await {
+            Ok(address) if address != failed_addr && address != discovery.own_addr => Some(address),
+            Ok(_) => None,
+            Err(e) => {
+                log_warn!("Failed to resolve {}: {}", discovery.source, e);
+                None
+            }
+        }
+    }
+
+    // Follow a different primary, registering with it and catching up
+    async fn switch_primary(&self, primary_addr: String) {
+        log_warn!("Primary moved, following {}", primary_addr);
+        *self.role.lock().await = Role::Backup(primary_addr.clone());
+        self.store.set_expiry_deferred(true);
+        if let Some(wal) = self.store.wal() {
+            wal.set_replicas_acked(None);
+        }
+        *self.failure_detector.lock().unwrap() = self.new_detector();
+        self.synced.store(false, Ordering::SeqCst);
+
+        let Some(discovery) = self.discovery.lock().await.clone() else {
+            return;
+        };
+        let result = self.client(&primary_addr)
+            .send_command(&format!("ADD_BACKUP {}", discovery.own_addr))
+            .await;
+        match result {
+            Ok(response) if response == "OK" => {}
+            Ok(response) => log_warn!("{} refused to add us: {}", primary_addr, response),
+            Err(e) => log_warn!("Failed to register with {}: {}", primary_addr, e),
+        }
+
+        // In log mode the follower resyncs when it sees the new primary
+        if self.mode() == ReplicationMode::Push {
+            match self.repair(&[], false).await {
+                Ok(_) => self.synced.store(true, Ordering::SeqCst),
+                Err(e) => log_warn!("Failed to catch up with {}: {}", primary_addr, e),
+            }
+        }
+    }
+
+    // Last primary changelog sequence this backup has applied
+    pub fn applied_seq(&self) -> u64 {
+        self.applied_seq.load(Ordering::SeqCst)
+    }
+
+    // How far behind each backup was when it last answered a heartbeat.
+    // Seconds are measured from the oldest change it hadn't applied.
+    pub async fn backup_lag(&self) -> Vec<BackupLag> {
+        let changelog = self.store.changelog();
+        let last_seq = changelog.last_seq();
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        let backups = self.backups.lock().await.clone();
+        let acks = self.acks.lock().await;
+        let failures = self.failures.lock().await;
+        backups
+            .into_iter()
+            .map(|address| {
+                let ack = acks.get(&address);
+                let failures = failures.get(&address).copied().unwrap_or(0);
+                let applied_seq = ack.map_or(0, |&(seq, _)| seq);
+                let lag_ops = last_seq.saturating_sub(applied_seq);
+                let lag_seconds = if lag_ops == 0 {
+                    0.0
+                } else {
+                    let first_missing = changelog.timestamp(applied_seq + 1).unwrap_or(now_ms);
+                    now_ms.saturating_sub(first_missing) as f64 / 1000.0
+                };
+                BackupLag {
+                    address,
+                    applied_seq,
+                    lag_ops,
+                    lag_seconds,
+                    last_ack: ack.map(|(_, at)| at.elapsed()),
+                    failures,
+                }
+            })
+            .collect()
+    }
+
+    // Record recevied heartbeat, with the primary's latest seq if it sent one
+    pub async fn receive_heartbeat(self: &Arc<Self>, primary_seq: Option<u64>) -> Result<()> {
+        let mut detector = self.failure_detector.lock().unwrap();
+        detector.heartbeat(Instant::now());
+        drop(detector);
+
+        // A primary only heartbeats backups it pushes writes to
+        if self.mode() != ReplicationMode::Push {
+            return Ok(());
+        }
+        let Some(primary_seq) = primary_seq else {
+            self.synced.store(true, Ordering::SeqCst);
+            return Ok(());
+        };
+
+        // Writes the primary had made by the previous heartbeat have had a
+        // whole interval to arrive, so any still missing were lost
+        let expected = self.heartbeat_seq.swap(primary_seq, Ordering::SeqCst).min(primary_seq);
+        let applied = self.applied_seq();
+        if applied >= expected {
+            if !self.catching_up.load(Ordering::SeqCst) {
+                self.synced.store(true, Ordering::SeqCst);
+            }
+            return Ok(());
+        }
+
+        self.synced.store(false, Ordering::SeqCst);
+        if !self.catching_up.swap(true, Ordering::SeqCst) {
+            log_warn!(
+                "Missed writes from the primary (applied seq {}, primary at {}), catching up",
+                applied,
+                primary_seq
+            );
+            let rm = Arc::clone(self);
+            tokio::spawn(async move {
+                if let Role::Backup(primary_addr) = rm.get_role().await
+                    && let Err(e) = rm.resync(&primary_addr).await
+                {
+                    log_warn!("Failed to catch up with {}: {}", primary_addr, e);
+                }
+                rm.catching_up.store(false, Ordering::SeqCst);
+            });
+        }
         Ok(())
     }
 
+    // Whether this node has caught up with its primary, always true for
+    // primaries and standalone nodes
+    pub fn is_synced(&self) -> bool {
+        self.synced.load(Ordering::SeqCst)
+    }
+
     // Promote backup to primary
-    async fn promote_to_primary(self: Arc<Self>) -> Result<()> {
+    pub(crate) async fn promote_to_primary(self: Arc<Self>) -> Result<()> {
+        let epoch = self.epoch() + 1;
+        self.promote_at(epoch).await
+    }
+
+    async fn promote_at(self: Arc<Self>, epoch: u64) -> Result<()> {
         let mut role = self.role.lock().await;
 
         if let Role::Backup(_) = *role {
-            // Change role to primary
+            // Change role to primary, which expires keys for everyone now
             *role = Role::Primary;
-            println!("Promoted to Primary node");
+            self.store.set_expiry_deferred(false);
+            self.synced.store(true, Ordering::SeqCst);
+            self.store.raise_epoch(epoch);
+            *self.handoff.write().unwrap() = None;
+            // The old primary has stopped taking writes, by its lease or
+            // because it handed off to us
+            self.renew_lease(Instant::now(), 1, 1);
+            log_info!("Promoted to Primary node at epoch {}", self.epoch());
 
             // Start sending heartbeats
             let self_arc = Arc::new(self.clone());
@@ -236,24 +1070,177 @@ impl ReplicationManager {
         }
     }
 
-    // Replicate an operation to all backups
-    pub async fn replicate_operation(&self, operation: &Operation) -> Result<()> {
-        let role = self.role.lock().await;
+    // Hand the primary role to the backup at `target` without losing
+    // writes: stop taking them, wait for the target to apply everything we
+    // have, then have it take over at the next epoch and follow it. On
+    // failure we carry on as the primary.
+    pub async fn hand_off(self: Arc<Self>, target: &str) -> Result<()> {
+        if !matches!(self.get_role().await, Role::Primary) {
+            return Err(StoreError::ReplicationError("Not the primary".to_string()));
+        }
+        let backups = self.backups.lock().await.clone();
+        if !backups.iter().any(|addr| addr == target) {
+            return Err(StoreError::ReplicationError(format!("{} is not one of our backups", target)));
+        }
+
+        *self.handoff.write().unwrap() = Some(target.to_string());
+        log_info!("Handing off to {}, writes are paused", target);
+        let result = Arc::clone(&self).complete_hand_off(target, &backups).await;
+        if let Err(e) = &result {
+            *self.handoff.write().unwrap() = None;
+            log_warn!("Handoff to {} failed, still the primary: {}", target, e);
+        }
+        result
+    }
+
+    async fn complete_hand_off(self: Arc<Self>, target: &str, backups: &[String]) -> Result<()> {
+        // Writes that passed the check before we paused may still be
+        // replicating, so look at our latest seq each time
+        let deadline = Instant::now() + HANDOFF_TIMEOUT;
+        loop {
+            let last_seq = self.store.changelog().last_seq();
+            self.send_heartbeat(target).await?;
+            let applied = self.acks.lock().await.get(target).map_or(0, |&(seq, _)| seq);
+            if applied >= last_seq {
+                break;
+            }
+            if Instant::now() >= deadline {
+                return Err(StoreError::ReplicationError(format!(
+                    "{} applied seq {} of {} within {:?}",
+                    target, applied, last_seq, HANDOFF_TIMEOUT
+                )));
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        // Step down before the target takes over, so nothing we send after
+        // that looks like it comes from a rival primary
+        let epoch = self.epoch() + 1;
+        *self.failure_detector.lock().unwrap() = self.new_detector();
+        Arc::clone(&self).start_backup(target.to_string()).await?;
+
+        let others: Vec<&str> = backups.iter().map(String::as_str).filter(|addr| *addr != target).collect();
+        let command = format!("TAKEOVER {} {}", epoch, others.join(" "));
+        let response = self.peer(target).send_command(command.trim_end()).await;
+        let target_seq = match response {
+            Ok(response) if response.starts_with("OK") => {
+                response.split_whitespace().nth(1).and_then(|seq| seq.parse().ok()).unwrap_or(0)
+            }
+            Ok(response) => {
+                Arc::clone(&self).start_primary().await?;
+                return Err(StoreError::ReplicationError(format!("{} refused to take over: {}", target, response)));
+            }
+            Err(e) => {
+                Arc::clone(&self).start_primary().await?;
+                return Err(e);
+            }
+        };
+
+        // We applied nothing after pausing, so we're as far as the target
+        self.store.raise_epoch(epoch);
+        self.applied_seq.store(target_seq, Ordering::SeqCst);
+        self.heartbeat_seq.store(target_seq, Ordering::SeqCst);
+        self.synced.store(true, Ordering::SeqCst);
+        log_info!("Handed off to {} at epoch {}", target, epoch);
+
+        // The rest of the cluster follows the new primary too
+        for backup_addr in others {
+            let result = self.peer(backup_addr).send_command(&format!("FOLLOW {}", target)).await;
+            if let Err(e) = result {
+                log_warn!("Failed to point {} at {}: {}", backup_addr, target, e);
+            }
+        }
+        Ok(())
+    }
+
+    // Become the primary at `epoch` because ours is handing off to us,
+    // taking over its other backups and it. Returns our changelog seq,
+    // which the old primary continues from. Only the epoch right after ours
+    // is accepted, so a stale or made-up handoff can't skip ahead of it.
+    pub async fn take_over(self: Arc<Self>, epoch: u64, backups: Vec<String>) -> Result<u64> {
+        let Role::Backup(old_primary) = self.get_role().await else {
+            return Err(StoreError::ReplicationError("Not a backup".to_string()));
+        };
+        if epoch != self.epoch() + 1 {
+            return Err(StoreError::ReplicationError(format!(
+                "Can't take over at epoch {} from epoch {}",
+                epoch,
+                self.epoch()
+            )));
+        }
+        Arc::clone(&self).promote_at(epoch).await?;
+        for backup_addr in backups.into_iter().chain([old_primary]) {
+            self.add_backup(backup_addr).await?;
+        }
+        Ok(self.store.changelog().last_seq())
+    }
+
+    // Follow the primary at `primary_addr` after a handoff
+    pub async fn follow(&self, primary_addr: String) -> Result<()> {
+        match self.get_role().await {
+            Role::Backup(current) if current == primary_addr => Ok(()),
+            Role::Backup(_) => {
+                self.switch_primary(primary_addr).await;
+                Ok(())
+            }
+            _ => Err(StoreError::ReplicationError("Not a backup".to_string())),
+        }
+    }
+
+    // Replicate an operation to all backups, passing on the ID of the
+    // request that made it so it can be followed through their logs. `seq`
+    // is the changelog sequence number the operation was committed under.
+    pub async fn replicate_operation(
+        &self,
+        operation: &Operation,
+        seq: u64,
+        hlc: Timestamp,
+        vclock: Option<&VectorClock>,
+        request_id: &str,
+    ) -> Result<()> {
+        // Only checked, not held while sending, so writes to different keys
+        // replicate at the same time
+        let primary = matches!(*self.role.lock().await, Role::Primary);
+
+        if primary {
+            // Backups pull the changelog themselves
+            if self.mode() == ReplicationMode::Log {
+                return Ok(());
+            }
 
-        if let Role::Primary = *role {
             let backups = {
                 let backups_lock = self.backups.lock().await;
-                backups_lock.clone()
+                let failures = self.failures.lock().await;
+                backups_lock
+                    .iter()
+                    .filter(|addr| failures.get(*addr).is_none_or(|&count| count < QUARANTINE_AFTER))
+                    .cloned()
+                    .collect::<Vec<_>>()
             };
 
-            // Convert operation to string format
-            let op_str = operation.to_string();
+            // Tag the operation with its changelog position so backups can
+            // report how far they've got, with the time we took it at and
+            // with its vector clock if we track them
+            let mut op_str = format!("{} {} ", seq, hlc);
+            if let Some(vclock) = vclock.filter(|vclock| !vclock.is_empty()) {
+                op_str.push_str(&format!("vc:{} ", vclock));
+            }
+            op_str.push_str(&operation.to_string());
 
             // Send to all backups
             for backup_addr in &backups {
-                if let Err(e) = self.send_operation_to_backup(backup_addr, &op_str).await {
-                    eprintln!("Failed to replicate to {}: {}", backup_addr, e);
-                    // In production you might want to handle this more gracefully
+                match self.send_operation_to_backup(backup_addr, &op_str, request_id).await {
+                    Ok(()) => self.record_success(backup_addr).await,
+                    Err(e) => {
+                        if self.record_failure(backup_addr, &e).await {
+                            log_error!(
+                                "Failed to replicate request {} to {}: {}",
+                                request_id,
+                                backup_addr,
+                                e
+                            );
+                        }
+                    }
                 }
             }
 
@@ -266,39 +1253,106 @@ impl ReplicationManager {
     }
 
     // Send operation to a backup
-    async fn send_operation_to_backup(&self, backup_addr: &str, op_str: &str) -> Result<()> {
+    async fn send_operation_to_backup(&self, backup_addr: &str, op_str: &str, request_id: &str) -> Result<()> {
         // Connect to backup
-        let client = Client::new(backup_addr.to_string());
+        let client = self.client(backup_addr);
 
         // Send REPLICATE command
-        match client.send_command(&format!("REPLICATE {}", op_str)).await {
+        match client.send_command(&format!("#{} REPLICATE {}", request_id, op_str)).await {
             Ok(response) if response == "OK" => {
-                println!("Replicated '{}' to {}", op_str, backup_addr);
+                log_debug!("Replicated request {} '{}' to {}", request_id, op_str, backup_addr);
                 Ok(())
             }
-            Ok(response) => Err(StoreError::ReplicationError(format!(
-                "Unexpected response: {}",
-                response
-            ))),
+            Ok(response) => {
+                if response == ALSO_PRIMARY {
+                    self.enter_safe_mode(&format!("backup {} claims to be the primary", backup_addr));
+                }
+                Err(StoreError::ReplicationError(format!(
+                    "Unexpected response: {}",
+                    response
+                )))
+            }
             Err(e) => Err(e),
         }
     }
 
+    // Pass CRDT state a backup took a write to on to its primary, which
+    // merges it and replicates the result. A primary we can't reach gets
+    // the state with the next write to the key, as every send carries all
+    // of it.
+    pub async fn forward_crdt(&self, primary_addr: &str, key: &str, state: &str, request_id: &str) {
+        let client = self.client(primary_addr);
+        match client.send_command(&format!("#{} MERGE {} {}", request_id, key, state)).await {
+            Ok(response) if response == "OK" => {
+                log_debug!("Forwarded request {} for '{}' to {}", request_id, key, primary_addr);
+            }
+            Ok(response) => {
+                log_warn!("Primary {} refused CRDT state for '{}': {}", primary_addr, key, response);
+            }
+            Err(e) => {
+                log_warn!("Failed to forward CRDT state for '{}' to {}: {}", key, primary_addr, e);
+            }
+        }
+    }
+
     // Apply an operation received from primary
     pub async fn apply_operation(&self, op_str: &str) -> Result<()> {
         let role = self.role.lock().await;
 
+        if self.mode() == ReplicationMode::Log {
+            return Err(StoreError::ReplicationError(
+                "This backup follows the primary's log and doesn't take REPLICATE".to_string(),
+            ));
+        }
+
         if let Role::Backup(_) = *role {
+            // Operations from current primaries start with their sequence
+            let (seq, op_str) = match op_str.split_once(' ') {
+                Some((seq, rest)) if seq.parse::<u64>().is_ok() => (seq.parse().ok(), rest),
+                _ => (None, op_str),
+            };
+            // and the time the primary took them at
+            let (hlc, op_str) = match op_str.split_once(' ') {
+                Some((hlc, rest)) if hlc.parse::<Timestamp>().is_ok() => (hlc.parse().ok(), rest),
+                _ => (None, op_str),
+            };
+            let hlc = hlc.unwrap_or_default();
+            // and the writes it had seen, if it tracks vector clocks
+            let tagged = op_str
+                .split_once(' ')
+                .and_then(|(vclock, rest)| Some((vclock.strip_prefix("vc:")?, rest)));
+            let (vclock, op_str) = match tagged {
+                Some((vclock, rest)) => (vclock.parse::<VectorClock>().ok(), rest),
+                None => (None, op_str),
+            };
+
             // Parse the operation
             if let Some(operation) = Operation::from_string(op_str) {
                 // Apply to local store
-                match operation {
+                self.on_store(move |store| match operation {
                     Operation::Put(key, value) => {
-                        self.store.put(key, value);
+                        store.put_at(key, value, hlc, vclock);
                     }
                     Operation::Delete(key) => {
-                        self.store.delete(&key);
+                        store.delete_at(&key, hlc);
+                    }
+                    Operation::Rename(src, dst) => {
+                        store.rename_at(&src, &dst, hlc);
                     }
+                    Operation::Copy(src, dst) => {
+                        store.copy_at(&src, &dst, hlc);
+                    }
+                    Operation::DeleteMany(keys) => {
+                        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+                        store.delete_many_at(&keys, hlc);
+                    }
+                    Operation::Expire(key, at) => {
+                        store.expire_at(&key, at, hlc);
+                    }
+                })
+                .await?;
+                if let Some(seq) = seq {
+                    self.applied_seq.fetch_max(seq, Ordering::SeqCst);
                 }
 
                 Ok(())
@@ -315,6 +1369,75 @@ impl ReplicationManager {
         }
     }
 
+    // Bring this backup back in line with the primary. Only `keys` are
+    // checked if given, otherwise the whole store. With `dry_run` nothing is
+    // changed and the report says what would have been.
+    pub async fn repair(&self, keys: &[String], dry_run: bool) -> Result<RepairReport> {
+        let primary_addr = match self.get_role().await {
+            Role::Backup(addr) => addr,
+            _ => {
+                return Err(StoreError::ReplicationError(
+                    "Only backups can repair from a primary".to_string(),
+                ));
+            }
+        };
+
+        let authoritative = self.client(&primary_addr).dump(keys).await?;
+        let local = self.store.entries();
+        let candidates: Vec<String> = if keys.is_empty() {
+            let mut all: Vec<String> = local.keys().chain(authoritative.keys()).cloned().collect();
+            all.sort();
+            all.dedup();
+            all
+        } else {
+            keys.to_vec()
+        };
+
+        let mut report = RepairReport {
+            dry_run,
+            ..RepairReport::default()
+        };
+        for key in candidates {
+            match (local.get(&key), authoritative.get(&key)) {
+                (current, Some(value)) if current != Some(value) => report.updated.push(key),
+                (Some(_), None) => report.deleted.push(key),
+                _ => {}
+            }
+        }
+        if !dry_run {
+            let updated: Vec<(String, String)> =
+                report.updated.iter().map(|key| (key.clone(), authoritative[key].clone())).collect();
+            let deleted = report.deleted.clone();
+            self.on_store(move |store| {
+                for (key, value) in updated {
+                    store.put(key, value);
+                }
+                for key in &deleted {
+                    store.delete(key);
+                }
+            })
+            .await?;
+        }
+
+        log_info!(
+            "Repair from {}: {} (updated {:?}, deleted {:?})",
+            primary_addr,
+            report,
+            report.updated,
+            report.deleted
+        );
+        Ok(report)
+    }
+
+    // Run store writes on a blocking thread, as they can wait on the
+    // store's locks and on syncing its write-ahead log
+    async fn on_store<R: Send + 'static>(&self, f: impl FnOnce(&KeyValueStore) -> R + Send + 'static) -> Result<R> {
+        let store = Arc::clone(&self.store);
+        tokio::task::spawn_blocking(move || f(&store))
+            .await
+            .map_err(|e| StoreError::ReplicationError(format!("Store write failed: {}", e)))
+    }
+
     // Get current role
     pub async fn get_role(&self) -> Role {
         let role = self.role.lock().await;
@@ -376,9 +1499,477 @@ mod tests {
         
         // Verify the value exists in the backup's store
         assert_eq!(backup_store.get("replicated_key").unwrap(), "replicated_value");
+
+        // The write keeps the primary's timestamp, and the backup's clock moves past it
+        let written = primary_store.metadata("replicated_key").unwrap().hlc;
+        assert!(!written.is_zero());
+        assert_eq!(backup_store.metadata("replicated_key").unwrap().hlc, written);
+        assert!(backup_store.hlc().now() > written);
+
+        // The backup's heartbeat ACKs tell the primary it is caught up
+        client.send_command("CONFIG SET heartbeat_interval_ms 50").await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(1200)).await;
+        let info = client.send_command("INFO REPLICATION").await.unwrap();
+        assert!(info.starts_with("role:primary, mode:push, changelog_seq:1"), "{}", info);
+        assert!(info.contains(&format!("backup0:address={} applied_seq=1 lag_ops=0 lag_seconds=0.000", backup_addr)), "{}", info);
+
+        // Taking the key on the primary removes it from the backup too
+        assert_eq!(client.send_command("GETDEL replicated_key").await.unwrap(), "replicated_value");
+        assert_eq!(client.send_command("GETDEL replicated_key").await.unwrap(), "Key not found");
+        assert_eq!(backup_store.get("replicated_key"), None);
         
         // Clean up
         primary_handle.abort();
         backup_handle.abort();
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_join_through_backup() {
+        let primary_store = Arc::new(KeyValueStore::new());
+        primary_store.put("existing".to_string(), "1".to_string());
+        let primary_addr = "127.0.0.1:7908".to_string();
+        let backup_addr = "127.0.0.1:7909".to_string();
+        let joiner_addr = "127.0.0.1:7910".to_string();
+
+        let primary = Server::with_replication(Arc::clone(&primary_store), primary_addr.clone());
+        primary.start_as_primary().await.unwrap();
+        let backup = Server::with_replication(Arc::new(KeyValueStore::new()), backup_addr.clone());
+        backup.start_as_backup(primary_addr.clone()).await.unwrap();
+        let primary_handle = tokio::spawn(async move {
+            let _ = primary.run().await;
+        });
+        let backup_handle = tokio::spawn(async move {
+            let _ = backup.run().await;
+        });
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        // Joining through a backup finds the primary and copies its data
+        let joiner_store = Arc::new(KeyValueStore::new());
+        let joiner = Arc::new(ReplicationManager::new(Arc::clone(&joiner_store)));
+        let found = Arc::clone(&joiner).join(&backup_addr, &joiner_addr).await.unwrap();
+        assert_eq!(found, primary_addr);
+        assert_eq!(joiner.get_role().await, Role::Backup(primary_addr.clone()));
+        assert_eq!(joiner_store.get("existing").unwrap(), "1");
+
+        // and registers with it
+        let info = Client::new(primary_addr).send_command("INFO REPLICATION").await.unwrap();
+        assert!(info.contains(&format!("address={}", joiner_addr)), "{}", info);
+
+        primary_handle.abort();
+        backup_handle.abort();
+    }
+
+    #[test]
+    fn test_operation_round_trip() {
+        let ops = [
+            Operation::Put("greeting".to_string(), "hello there world".to_string()),
+            Operation::Delete("a".to_string()),
+            Operation::DeleteMany(vec!["a".to_string(), "b".to_string()]),
+            Operation::Rename("a".to_string(), "b".to_string()),
+            Operation::Expire("a".to_string(), 1700000000000),
+        ];
+        for op in ops {
+            assert_eq!(Operation::from_string(&op.to_string()), Some(op));
+        }
+    }
+
+    #[test]
+    fn test_heartbeat_interval_reseeds_detector() {
+        let rm = ReplicationManager::new(Arc::new(KeyValueStore::new()));
+        rm.set_acceptable_pause(Duration::ZERO);
+        let quiet = Instant::now() + Duration::from_secs(2);
+        assert!(rm.failure_detector.lock().unwrap().phi(quiet) > 1.0);
+
+        // Two seconds without a heartbeat is nothing unusual once they are
+        // three seconds apart
+        rm.set_heartbeat_interval(Duration::from_secs(3));
+        let quiet = Instant::now() + Duration::from_secs(2);
+        assert!(rm.failure_detector.lock().unwrap().phi(quiet) < 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_no_failover_before_listening() {
+        let backup = Arc::new(ReplicationManager::new(Arc::new(KeyValueStore::new())));
+        backup.set_heartbeat_interval(Duration::from_millis(50));
+        backup.set_acceptable_pause(Duration::ZERO);
+        let primary_addr = "127.0.0.1:7937".to_string();
+        Arc::clone(&backup).start_backup(primary_addr.clone()).await.unwrap();
+
+        // No heartbeats arrive, but none could reach us yet either
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(backup.get_role().await, Role::Backup(primary_addr));
+
+        backup.start_monitoring();
+        for _ in 0..50 {
+            if backup.get_role().await == Role::Primary {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(backup.get_role().await, Role::Primary);
+    }
+
+    #[tokio::test]
+    async fn test_rediscover_moved_primary() {
+        let dir = tempfile::tempdir().unwrap();
+        let source_file = dir.path().join("primary");
+        let new_primary_addr = "127.0.0.1:7914".to_string();
+        let backup_addr = "127.0.0.1:7915";
+
+        // Nothing answers at the address the file points at first
+        std::fs::write(&source_file, "127.0.0.1:7913\n").unwrap();
+        let backup_store = Arc::new(KeyValueStore::new());
+        let backup = Arc::new(ReplicationManager::new(Arc::clone(&backup_store)));
+        backup.set_phi_threshold(0.01);
+        backup.set_acceptable_pause(Duration::ZERO);
+        backup.start_monitoring();
+        let source = PeerSource::File(source_file.clone());
+        Arc::clone(&backup).start_backup_from(source, backup_addr).await.unwrap();
+        assert_eq!(backup.get_role().await, Role::Backup("127.0.0.1:7913".to_string()));
+
+        let new_primary_store = Arc::new(KeyValueStore::new());
+        new_primary_store.put("moved".to_string(), "1".to_string());
+        let new_primary = Server::with_replication(Arc::clone(&new_primary_store), new_primary_addr.clone());
+        new_primary.start_as_primary().await.unwrap();
+        let primary_handle = tokio::spawn(async move {
+            let _ = new_primary.run().await;
+        });
+        std::fs::write(&source_file, format!("{}\n", new_primary_addr)).unwrap();
+
+        // Once the old primary is suspected, the backup finds the new one,
+        // registers with it and copies its data
+        for _ in 0..50 {
+            if backup_store.get("moved").is_some() {
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(backup_store.get("moved"), Some("1".to_string()));
+        let info = Client::new(new_primary_addr).send_command("INFO REPLICATION").await.unwrap();
+        assert!(info.contains(&format!("address={}", backup_addr)), "{}", info);
+
+        primary_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_log_shipping_replication() {
+        let primary_store = Arc::new(KeyValueStore::new());
+        let backup_store = Arc::new(KeyValueStore::new());
+        primary_store.put("existing".to_string(), "1".to_string());
+        backup_store.put("stray".to_string(), "x".to_string());
+
+        let primary_addr = "127.0.0.1:7903".to_string();
+        let backup_addr = "127.0.0.1:7904".to_string();
+        let primary_server = Server::with_replication(Arc::clone(&primary_store), primary_addr.clone())
+            .with_replication_mode(ReplicationMode::Log);
+        let backup_server = Server::with_replication(Arc::clone(&backup_store), backup_addr.clone())
+            .with_replication_mode(ReplicationMode::Log);
+
+        primary_server.start_as_primary().await.unwrap();
+        let primary_handle = tokio::spawn(async move {
+            let _ = primary_server.run().await;
+        });
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        backup_server.start_as_backup(primary_addr.clone()).await.unwrap();
+        let backup_handle = tokio::spawn(async move {
+            let _ = backup_server.run().await;
+        });
+        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+
+        // The backup starts from a copy of the primary
+        assert_eq!(backup_store.get("existing").unwrap(), "1");
+        assert_eq!(backup_store.get("stray"), None);
+
+        // Then follows its writes in order
+        let client = Client::new(primary_addr.clone());
+        client.put("key", "a").await.unwrap();
+        client.put("key", "b").await.unwrap();
+        client.delete("existing").await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        assert_eq!(backup_store.get("key").unwrap(), "b");
+        assert_eq!(backup_store.get("existing"), None);
+
+        // Pushed operations are refused
+        let response = Client::new(backup_addr).send_command("REPLICATE PUT key c").await.unwrap();
+        assert!(response.starts_with("ERROR"));
+
+        primary_handle.abort();
+        backup_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_backup_registers_itself() {
+        let primary_store = Arc::new(KeyValueStore::new());
+        primary_store.put("existing".to_string(), "1".to_string());
+        let backup_store = Arc::new(KeyValueStore::new());
+        let primary_addr = "127.0.0.1:7918".to_string();
+        let backup_addr = "127.0.0.1:7919".to_string();
+
+        // The backup starts first and keeps trying until the primary is up
+        let backup = Server::with_replication(Arc::clone(&backup_store), backup_addr.clone());
+        backup.start_as_backup(primary_addr.clone()).await.unwrap();
+        let backup_handle = tokio::spawn(async move {
+            let _ = backup.run().await;
+        });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let primary = Server::with_replication(Arc::clone(&primary_store), primary_addr.clone());
+        primary.start_as_primary().await.unwrap();
+        let primary_handle = tokio::spawn(async move {
+            let _ = primary.run().await;
+        });
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        // Registered without ADD_BACKUP, and caught up with earlier writes
+        let client = Client::new(primary_addr);
+        let info = client.send_command("INFO REPLICATION").await.unwrap();
+        assert!(info.contains(&format!("address={}", backup_addr)), "{}", info);
+        assert_eq!(backup_store.get("existing"), Some("1".to_string()));
+        client.put("new", "2").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(backup_store.get("new"), Some("2".to_string()));
+
+        primary_handle.abort();
+        backup_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_reveals_missed_writes() {
+        let primary_store = Arc::new(KeyValueStore::new());
+        let backup_store = Arc::new(KeyValueStore::new());
+        let primary_addr = "127.0.0.1:7920".to_string();
+        let backup_addr = "127.0.0.1:7921".to_string();
+
+        let primary = Server::with_replication(Arc::clone(&primary_store), primary_addr.clone());
+        primary.start_as_primary().await.unwrap();
+        let primary_handle = tokio::spawn(async move {
+            let _ = primary.run().await;
+        });
+        let backup = Server::with_replication(Arc::clone(&backup_store), backup_addr.clone());
+        backup.start_as_backup(primary_addr.clone()).await.unwrap();
+        let backup_handle = tokio::spawn(async move {
+            let _ = backup.run().await;
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let client = Client::new(primary_addr);
+        client.send_command("CONFIG SET heartbeat_interval_ms 50").await.unwrap();
+
+        // A write the backup never receives
+        primary_store.put("lost".to_string(), "1".to_string());
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(backup_store.get("lost"), Some("1".to_string()));
+
+        // Up to date again, so the next heartbeats are quiet
+        let info = client.send_command("INFO REPLICATION").await.unwrap();
+        assert!(info.contains(&format!("address={} applied_seq=1 lag_ops=0", backup_addr)), "{}", info);
+
+        primary_handle.abort();
+        backup_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_quarantine_unreachable_backup() {
+        let primary_store = Arc::new(KeyValueStore::new());
+        let backup_store = Arc::new(KeyValueStore::new());
+        let primary_addr = "127.0.0.1:7922".to_string();
+        let backup_addr = "127.0.0.1:7923".to_string();
+
+        let primary = Server::with_replication(Arc::clone(&primary_store), primary_addr.clone());
+        primary.start_as_primary().await.unwrap();
+        let primary_handle = tokio::spawn(async move {
+            let _ = primary.run().await;
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let client = Client::new(primary_addr.clone());
+        client.send_command("CONFIG SET heartbeat_interval_ms 50").await.unwrap();
+
+        // A healthy backup keeps the primary's lease, so it goes on taking writes
+        let healthy = Server::with_replication(Arc::new(KeyValueStore::new()), "127.0.0.1:7924".to_string());
+        healthy.start_as_backup(primary_addr.clone()).await.unwrap();
+        let healthy_handle = tokio::spawn(async move {
+            let _ = healthy.run().await;
+        });
+
+        // Nothing is listening at the other backup's address yet
+        client.send_command(&format!("ADD_BACKUP {}", backup_addr)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let backup_info = |info: &str| {
+            info.split(", ").find(|field| field.contains(&backup_addr)).unwrap_or_default().to_string()
+        };
+        let info = client.send_command("INFO REPLICATION").await.unwrap();
+        assert!(backup_info(&info).contains("quarantined=true"), "{}", info);
+
+        // Writes carry on without trying the quarantined backup
+        client.put("during", "1").await.unwrap();
+
+        let backup = Server::with_replication(Arc::clone(&backup_store), backup_addr.clone());
+        backup.start_as_backup(primary_addr).await.unwrap();
+        let backup_handle = tokio::spawn(async move {
+            let _ = backup.run().await;
+        });
+        tokio::time::sleep(Duration::from_millis(600)).await;
+
+        let info = client.send_command("INFO REPLICATION").await.unwrap();
+        assert!(backup_info(&info).contains("failures=0 quarantined=false"), "{}", info);
+        assert_eq!(backup_store.get("during"), Some("1".to_string()));
+        client.put("after", "2").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(backup_store.get("after"), Some("2".to_string()));
+
+        primary_handle.abort();
+        healthy_handle.abort();
+        backup_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_primary_lease() {
+        let primary_addr = "127.0.0.1:7925".to_string();
+        let backup_addr = "127.0.0.1:7926".to_string();
+
+        let primary = Server::with_replication(Arc::new(KeyValueStore::new()), primary_addr.clone());
+        primary.start_as_primary().await.unwrap();
+        let primary_handle = tokio::spawn(async move {
+            let _ = primary.run().await;
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let client = Client::new(primary_addr.clone());
+        client.send_command("CONFIG SET heartbeat_interval_ms 50").await.unwrap();
+
+        let start_backup = || {
+            let backup = Server::with_replication(Arc::new(KeyValueStore::new()), backup_addr.clone());
+            let primary_addr = primary_addr.clone();
+            tokio::spawn(async move {
+                backup.start_as_backup(primary_addr).await.unwrap();
+                let _ = backup.run().await;
+            })
+        };
+        let backup_handle = start_backup();
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        client.put("a", "1").await.unwrap();
+
+        // Cut off from its only backup, the primary stops taking writes
+        // before the backup would have promoted itself
+        backup_handle.abort();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let refused = client.send_command("PUT b 2").await;
+        assert!(format!("{:?}", refused).contains("lease expired"), "{:?}", refused);
+        assert_eq!(client.get("a").await.unwrap(), Some("1".to_string()));
+
+        // Heartbeats reaching it again renew the lease
+        let backup_handle = start_backup();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        client.put("b", "2").await.unwrap();
+
+        primary_handle.abort();
+        backup_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_split_brain_safe_mode() {
+        let first_addr = "127.0.0.1:7927".to_string();
+        let second_addr = "127.0.0.1:7928".to_string();
+
+        // Two primaries, one of which was told the other is its backup
+        let mut handles = Vec::new();
+        for addr in [&first_addr, &second_addr] {
+            let server = Server::with_replication(Arc::new(KeyValueStore::new()), addr.clone());
+            server.start_as_primary().await.unwrap();
+            handles.push(tokio::spawn(async move {
+                let _ = server.run().await;
+            }));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let first = Client::new(first_addr);
+        let second = Client::new(second_addr.clone());
+        second.put("before", "1").await.unwrap();
+        first.send_command(&format!("ADD_BACKUP {}", second_addr)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        // Each notices the other and stops taking writes, but still serves reads
+        for client in [&first, &second] {
+            let info = client.send_command("INFO REPLICATION").await.unwrap();
+            assert!(info.ends_with("safe_mode:1"), "{}", info);
+            let refused = client.send_command("PUT k v").await.unwrap();
+            assert!(refused.starts_with("ERROR: Safe mode"), "{}", refused);
+        }
+        assert!(second.send_command("SAFEMODE").await.unwrap().starts_with("ON: "));
+        assert_eq!(second.get("before").await.unwrap(), Some("1".to_string()));
+
+        // Until an operator resolves it, here by stopping the other node
+        handles[0].abort();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(second.send_command("SAFEMODE OFF").await.unwrap(), "OK");
+        assert_eq!(second.send_command("SAFEMODE").await.unwrap(), "OFF");
+        second.put("after", "2").await.unwrap();
+
+        for handle in handles {
+            handle.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handoff() {
+        let primary_addr = "127.0.0.1:7929".to_string();
+        let target_addr = "127.0.0.1:7930".to_string();
+        let other_addr = "127.0.0.1:7931".to_string();
+        let primary_store = Arc::new(KeyValueStore::new());
+        let other_store = Arc::new(KeyValueStore::new());
+
+        // The nodes share an admin token, which handing off authenticates with
+        let token = Some("secret".to_string());
+        let primary = Server::with_replication(Arc::clone(&primary_store), primary_addr.clone()).with_admin_token(token.clone());
+        primary.start_as_primary().await.unwrap();
+        let mut handles = vec![tokio::spawn(async move {
+            let _ = primary.run().await;
+        })];
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for (addr, store) in [(&target_addr, KeyValueStore::new().into()), (&other_addr, Arc::clone(&other_store))] {
+            let backup = Server::with_replication(store, addr.clone()).with_admin_token(token.clone());
+            backup.start_as_backup(primary_addr.clone()).await.unwrap();
+            handles.push(tokio::spawn(async move {
+                let _ = backup.run().await;
+            }));
+        }
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        // Only an admin can make a backup take over or follow someone else,
+        // and only at the epoch after its own
+        let stranger = Client::new(target_addr.clone());
+        for command in ["TAKEOVER 1", "FOLLOW 127.0.0.1:1"] {
+            assert_eq!(stranger.send_command(command).await.unwrap(), "ERROR: Admin authentication required");
+        }
+        let target = Client::new(target_addr.clone()).with_auth_token("secret".to_string());
+        assert_eq!(target.send_command("TAKEOVER 5").await.unwrap(), "ERROR: Replication error: Can't take over at epoch 5 from epoch 0");
+        assert!(target.send_command("INFO REPLICATION").await.unwrap().starts_with("role:backup"));
+
+        let primary = Client::new(primary_addr.clone()).with_auth_token("secret".to_string());
+        primary.put("before", "1").await.unwrap();
+        assert_eq!(primary.send_command("HANDOFF 127.0.0.1:1").await.unwrap(), "ERROR: Replication error: 127.0.0.1:1 is not one of our backups");
+        assert_eq!(primary.send_command(&format!("HANDOFF {}", target_addr)).await.unwrap(), "OK");
+
+        // The target leads at a new epoch and clients are sent to it
+        let info = target.send_command("INFO REPLICATION").await.unwrap();
+        assert!(info.starts_with("role:primary") && info.contains("epoch:1"), "{}", info);
+        assert_eq!(target.get("before").await.unwrap(), Some("1".to_string()));
+        let moved = primary.send_command("PUT k v").await.unwrap();
+        assert_eq!(moved, format!("ERROR: MOVED {}", target_addr));
+
+        // Everyone, the old primary included, follows the new one
+        target.put("after", "2").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(primary_store.get("after"), Some("2".to_string()));
+        assert_eq!(other_store.get("after"), Some("2".to_string()));
+        for (addr, expected) in [(&primary_addr, "role:backup"), (&other_addr, "role:backup")] {
+            let info = Client::new(addr.clone()).with_auth_token("secret".to_string()).send_command("INFO REPLICATION").await.unwrap();
+            assert!(info.starts_with(expected), "{}", info);
+            assert!(info.contains(&format!("primary:{}", target_addr)), "{}", info);
+        }
+        let info = target.send_command("INFO REPLICATION").await.unwrap();
+        assert!(info.ends_with("epoch:1, safe_mode:0"), "{}", info);
+
+        for handle in handles {
+            handle.abort();
+        }
+    }
+}
@@ -0,0 +1,887 @@
+// src/snapshot.rs
+
+// Persisting a running server's store to its database file, either on
+// request or automatically according to a save policy
+
+use crate::backup::BackupSink;
+use crate::error::{Result, StoreError};
+use crate::logging::{log_debug, log_error, log_info, log_warn};
+use crate::store::{self, KeyValueStore};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+
+// How long to wait before retrying an automatic save that failed
+const SAVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// Save once the write-ahead log grows this large, which compacts it
+pub const DEFAULT_WAL_REWRITE_SIZE: u64 = 64 * 1024 * 1024;
+
+// Save after `seconds` have passed if at least `changes` writes were made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+// Rules for automatic background saves, written like "900 1 60 1000":
+// save after 900 seconds if at least 1 key changed, or after 60 seconds if
+// at least 1000 keys changed. An empty policy disables automatic saves.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SavePolicy {
+    pub rules: Vec<SaveRule>,
+}
+
+impl SavePolicy {
+    // First rule that is satisfied, if any
+    fn due(&self, elapsed: Duration, changes: u64) -> Option<SaveRule> {
+        self.rules
+            .iter()
+            .find(|rule| changes >= rule.changes && elapsed.as_secs() >= rule.seconds)
+            .copied()
+    }
+}
+
+impl fmt::Display for SavePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rules: Vec<String> = self
+            .rules
+            .iter()
+            .map(|rule| format!("{} {}", rule.seconds, rule.changes))
+            .collect();
+        f.write_str(&rules.join(" "))
+    }
+}
+
+impl FromStr for SavePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        // Allow `""` so the policy can be cleared with CONFIG SET save ""
+        let numbers = s
+            .trim_matches('"')
+            .split_whitespace()
+            .map(|n| n.parse::<u64>().map_err(|e| format!("'{}': {}", n, e)))
+            .collect::<std::result::Result<Vec<u64>, String>>()?;
+
+        if numbers.len() % 2 != 0 {
+            return Err("expected pairs of <seconds> <changes>".to_string());
+        }
+
+        let rules = numbers
+            .chunks(2)
+            .map(|pair| SaveRule {
+                seconds: pair[0],
+                changes: pair[1],
+            })
+            .collect();
+        Ok(SavePolicy { rules })
+    }
+}
+
+impl TryFrom<String> for SavePolicy {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<SavePolicy> for String {
+    fn from(policy: SavePolicy) -> Self {
+        policy.to_string()
+    }
+}
+
+// Outcome of comparing the persisted files with the in-memory store
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub memory_keys: usize,
+    pub disk_keys: usize,
+    // Keys in memory that are absent from, or differ in, the database file
+    pub missing: Vec<String>,
+    pub mismatched: Vec<String>,
+    // Keys in the database file that are no longer in memory
+    pub extra: Vec<String>,
+    // Writes not yet saved, which explain differences until the next save
+    pub unsaved_changes: u64,
+    pub snapshots: usize,
+    pub bad_snapshots: Vec<PathBuf>,
+    // Why the database file couldn't be read, if it couldn't
+    pub error: Option<String>,
+}
+
+impl VerifyReport {
+    pub fn status(&self) -> &'static str {
+        if self.error.is_some() || !self.bad_snapshots.is_empty() {
+            "corrupt"
+        } else if self.differing_keys().is_empty() {
+            "ok"
+        } else if self.unsaved_changes > 0 {
+            "stale"
+        } else {
+            "diverged"
+        }
+    }
+
+    // Every key whose persisted state doesn't match memory, sorted
+    pub fn differing_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .missing
+            .iter()
+            .chain(&self.mismatched)
+            .chain(&self.extra)
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "status={}, keys={}, on_disk={}, missing={}, mismatched={}, extra={}, unsaved_changes={}, snapshots={}, bad_snapshots={}",
+            self.status(),
+            self.memory_keys,
+            self.disk_keys,
+            self.missing.len(),
+            self.mismatched.len(),
+            self.extra.len(),
+            self.unsaved_changes,
+            self.snapshots,
+            self.bad_snapshots.len()
+        )?;
+        if let Some(error) = &self.error {
+            write!(f, ", error={}", error)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct SnapshotManager {
+    store: Arc<KeyValueStore>,
+    db_path: PathBuf,
+
+    // Unix time of the last successful save, 0 if we haven't saved yet
+    last_save: AtomicU64,
+
+    // Set while a save is running so they never overlap
+    in_progress: AtomicBool,
+
+    // When automatic saves are due
+    policy: RwLock<SavePolicy>,
+
+    // Timestamped copies of the database file to keep (0 = none)
+    retention: AtomicUsize,
+
+    // Also keep the newest copy from each of this many days (0 = none)
+    retention_days: AtomicU64,
+
+    // Write-ahead log size that starts a save (0 = never)
+    wal_rewrite_size: AtomicU64,
+
+    // When we last saved (or started up), and when a save last failed
+    saved_at: Mutex<Instant>,
+    failed_at: Mutex<Option<Instant>>,
+
+    // Where completed snapshots are uploaded, if anywhere
+    sink: RwLock<Option<BackupSink>>,
+}
+
+impl SnapshotManager {
+    pub fn new(store: Arc<KeyValueStore>, db_path: PathBuf) -> Self {
+        SnapshotManager {
+            store,
+            db_path,
+            last_save: AtomicU64::new(0),
+            in_progress: AtomicBool::new(false),
+            policy: RwLock::new(SavePolicy::default()),
+            retention: AtomicUsize::new(0),
+            retention_days: AtomicU64::new(0),
+            wal_rewrite_size: AtomicU64::new(DEFAULT_WAL_REWRITE_SIZE),
+            saved_at: Mutex::new(Instant::now()),
+            failed_at: Mutex::new(None),
+            sink: RwLock::new(None),
+        }
+    }
+
+    pub fn policy(&self) -> SavePolicy {
+        self.policy.read().unwrap().clone()
+    }
+
+    pub fn set_policy(&self, policy: SavePolicy) {
+        *self.policy.write().unwrap() = policy;
+    }
+
+    pub fn retention(&self) -> usize {
+        self.retention.load(Ordering::Relaxed)
+    }
+
+    pub fn set_retention(&self, count: usize) {
+        self.retention.store(count, Ordering::Relaxed);
+    }
+
+    pub fn retention_days(&self) -> u64 {
+        self.retention_days.load(Ordering::Relaxed)
+    }
+
+    pub fn set_retention_days(&self, days: u64) {
+        self.retention_days.store(days, Ordering::Relaxed);
+    }
+
+    pub fn wal_rewrite_size(&self) -> u64 {
+        self.wal_rewrite_size.load(Ordering::Relaxed)
+    }
+
+    pub fn set_wal_rewrite_size(&self, bytes: u64) {
+        self.wal_rewrite_size.store(bytes, Ordering::Relaxed);
+    }
+
+    // Snapshots currently on disk, oldest first
+    pub fn snapshots(&self) -> Result<Vec<SnapshotInfo>> {
+        list_snapshots(&self.db_path)?
+            .into_iter()
+            .map(|path| {
+                let size = fs::metadata(&path)?.len();
+                let time = snapshot_time(&path).unwrap_or_default();
+                Ok(SnapshotInfo { path, size, time })
+            })
+            .collect()
+    }
+
+    pub fn set_backup_sink(&self, sink: Option<BackupSink>) {
+        *self.sink.write().unwrap() = sink;
+    }
+
+    // Check the save policy once a second and start a background save when
+    // one of its rules is satisfied or the write-ahead log has grown too large
+    pub fn start_scheduler(self: &Arc<Self>) -> JoinHandle<()> {
+        let manager = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                if let Some(rule) = manager.due_rule() {
+                    log_info!(
+                        "{} changes in {} seconds, saving",
+                        rule.changes,
+                        rule.seconds
+                    );
+                } else if let Some(size) = manager.wal_rewrite_due() {
+                    log_info!("Write-ahead log is {} bytes, saving to compact it", size);
+                } else {
+                    continue;
+                }
+                if let Err(e) = manager.bgsave() {
+                    log_debug!("Skipping automatic save: {}", e);
+                }
+            }
+        })
+    }
+
+    // Whether an automatic save failed recently enough to hold off retrying
+    fn backing_off(&self) -> bool {
+        self.failed_at
+            .lock()
+            .unwrap()
+            .is_some_and(|failed_at| failed_at.elapsed() < SAVE_RETRY_DELAY)
+    }
+
+    fn due_rule(&self) -> Option<SaveRule> {
+        if self.backing_off() {
+            return None;
+        }
+
+        let elapsed = self.saved_at.lock().unwrap().elapsed();
+        let changes = self.store.changes_since_save();
+        if changes == 0 {
+            return None;
+        }
+        self.policy.read().unwrap().due(elapsed, changes)
+    }
+
+    // Size of the write-ahead log once it has outgrown the rewrite threshold
+    fn wal_rewrite_due(&self) -> Option<u64> {
+        let threshold = self.wal_rewrite_size();
+        let size = self.store.wal_size();
+        (threshold > 0 && size >= threshold && !self.backing_off()).then_some(size)
+    }
+
+    // Save in the foreground, returning once the file has been written
+    pub async fn save(self: &Arc<Self>) -> Result<()> {
+        self.begin()?;
+        let result = Arc::clone(self).write_snapshot().await;
+        self.in_progress.store(false, Ordering::SeqCst);
+        result
+    }
+
+    // Save, folding the write-ahead log into the database file so the
+    // overwritten and deleted values it holds are dropped. Returns the
+    // bytes freed on disk.
+    pub async fn compact(self: &Arc<Self>) -> Result<u64> {
+        let before = store::disk_usage(&self.db_path)?;
+        self.save().await?;
+        Ok(before.saturating_sub(store::disk_usage(&self.db_path)?))
+    }
+
+    // Start a save in the background and return immediately
+    pub fn bgsave(self: &Arc<Self>) -> Result<()> {
+        self.begin()?;
+
+        let manager = Arc::clone(self);
+        tokio::spawn(async move {
+            if let Err(e) = Arc::clone(&manager).write_snapshot().await {
+                log_error!("Background save failed: {}", e);
+            }
+            manager.in_progress.store(false, Ordering::SeqCst);
+        });
+        Ok(())
+    }
+
+    // Save whatever hasn't been saved yet before the server exits, after any
+    // background save that is already running
+    pub async fn final_save(self: &Arc<Self>) -> Result<()> {
+        while self.in_progress.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        if self.store.changes_since_save() == 0 {
+            return Ok(());
+        }
+        self.save().await
+    }
+
+    // Unix time of the last successful save, 0 if none
+    pub fn last_save(&self) -> u64 {
+        self.last_save.load(Ordering::SeqCst)
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.in_progress.load(Ordering::SeqCst)
+    }
+
+    // Whether the most recent save failed
+    pub fn last_save_failed(&self) -> bool {
+        self.failed_at.lock().unwrap().is_some()
+    }
+
+    // Read back the database file and retained snapshots and compare them
+    // with the store. Holds off saves while it runs so the file is stable.
+    pub async fn verify(self: &Arc<Self>) -> Result<VerifyReport> {
+        self.begin()?;
+        let manager = Arc::clone(self);
+        let result = tokio::task::spawn_blocking(move || manager.check())
+            .await
+            .map_err(|e| StoreError::PersistenceError(e.to_string()))
+            .and_then(|result| result);
+        self.in_progress.store(false, Ordering::SeqCst);
+        result
+    }
+
+    fn check(&self) -> Result<VerifyReport> {
+        let unsaved_changes = self.store.changes_since_save();
+        let memory = self.store.entries();
+        let mut report = VerifyReport {
+            memory_keys: memory.len(),
+            unsaved_changes,
+            ..VerifyReport::default()
+        };
+
+        match KeyValueStore::load(&self.db_path) {
+            Ok(disk) => {
+                let disk = disk.entries();
+                report.disk_keys = disk.len();
+                for (key, value) in &memory {
+                    match disk.get(key) {
+                        None => report.missing.push(key.clone()),
+                        Some(saved) if saved != value => report.mismatched.push(key.clone()),
+                        Some(_) => {}
+                    }
+                }
+                report.extra = disk
+                    .into_keys()
+                    .filter(|key| !memory.contains_key(key))
+                    .collect();
+            }
+            Err(e) => report.error = Some(e.to_string()),
+        }
+
+        for snapshot in list_snapshots(&self.db_path)? {
+            report.snapshots += 1;
+            if let Err(e) = KeyValueStore::load(&snapshot) {
+                log_warn!("Snapshot {} is unreadable: {}", snapshot.display(), e);
+                report.bad_snapshots.push(snapshot);
+            }
+        }
+        Ok(report)
+    }
+
+    fn begin(&self) -> Result<()> {
+        if self.in_progress.swap(true, Ordering::SeqCst) {
+            return Err(StoreError::PersistenceError(
+                "A save is already in progress".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // The store copies its data under a short read lock, so serializing and
+    // writing happen on a blocking thread without holding up writers
+    async fn write_snapshot(self: Arc<Self>) -> Result<()> {
+        let start = Instant::now();
+        let changes = self.store.changes_since_save();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let manager = Arc::clone(&self);
+        let result = tokio::task::spawn_blocking(move || {
+            manager.store.save(&manager.db_path)?;
+            manager.archive(now)
+        })
+        .await
+        .map_err(|e| StoreError::PersistenceError(e.to_string()))
+        .and_then(|result| result);
+
+        let archived = match result {
+            Ok(archived) => archived,
+            Err(e) => {
+                *self.failed_at.lock().unwrap() = Some(Instant::now());
+                return Err(e);
+            }
+        };
+
+        self.store.mark_saved(changes);
+        self.last_save.store(now, Ordering::SeqCst);
+        *self.saved_at.lock().unwrap() = Instant::now();
+        *self.failed_at.lock().unwrap() = None;
+        log_info!(
+            "Saved database to {} in {:?}",
+            self.db_path.display(),
+            start.elapsed()
+        );
+
+        // Ship the snapshot off the machine. This runs inside the save so
+        // the next one can't overwrite the file while it's being uploaded.
+        let sink = self.sink.read().unwrap().clone();
+        if let Some(sink) = sink {
+            let name = snapshot_path(&self.db_path, now);
+            let name = name.file_name().unwrap_or_default().to_string_lossy();
+            let file = archived.as_deref().unwrap_or(&self.db_path);
+            match sink.upload(file, &name).await {
+                Ok(()) => log_info!("Uploaded snapshot {}", name),
+                Err(e) => log_error!("Failed to upload snapshot {}: {}", name, e),
+            }
+        }
+        Ok(())
+    }
+
+    // Copy the freshly saved database file to a timestamped snapshot next to
+    // it, then prune the ones no retention rule keeps
+    fn archive(&self, now: u64) -> Result<Option<PathBuf>> {
+        let retention = self.retention();
+        let days = self.retention_days();
+        if retention == 0 && days == 0 {
+            return Ok(None);
+        }
+
+        let snapshot = snapshot_path(&self.db_path, now);
+        fs::copy(&self.db_path, &snapshot)?;
+
+        let snapshots = list_snapshots(&self.db_path)?;
+        for old in expired(&snapshots, retention, days, now) {
+            fs::remove_file(old)?;
+            log_debug!("Removed old snapshot {}", old.display());
+        }
+        Ok(Some(snapshot))
+    }
+}
+
+// A snapshot as listed by SNAPSHOTS
+pub struct SnapshotInfo {
+    pub path: PathBuf,
+    pub size: u64,
+    // Unix time it was taken
+    pub time: u64,
+}
+
+impl fmt::Display for SnapshotInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "name={}, size={}, time={}",
+            self.path.file_name().unwrap_or_default().to_string_lossy(),
+            self.size,
+            self.time
+        )
+    }
+}
+
+// Snapshots (oldest first) kept by neither rule: the newest `retention`, and
+// the newest of each UTC day among the last `days` days including today
+fn expired(snapshots: &[PathBuf], retention: usize, days: u64, now: u64) -> Vec<&PathBuf> {
+    let recent = snapshots.len().saturating_sub(retention);
+    let first_day = format_timestamp(now.saturating_sub(days.saturating_sub(1) * 86400));
+    let first_day = &first_day[..8];
+
+    snapshots
+        .iter()
+        .enumerate()
+        .filter(|&(i, path)| {
+            if i >= recent {
+                return false;
+            }
+            let Some(day) = snapshot_day(path) else {
+                return true;
+            };
+            let newest_of_day =
+                snapshots.get(i + 1).and_then(|next| snapshot_day(next)) != Some(day);
+            !(days > 0 && newest_of_day && day >= first_day)
+        })
+        .map(|(_, path)| path)
+        .collect()
+}
+
+// YYYYMMDD a snapshot was taken on
+fn snapshot_day(path: &Path) -> Option<&str> {
+    let name = path.file_name()?.to_str()?;
+    Some(&name[name.len().checked_sub(15)?..][..8])
+}
+
+// Unix time encoded in a snapshot's name
+pub(crate) fn snapshot_time(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    parse_timestamp(&name[name.len().checked_sub(15)?..])
+}
+
+// `kv-store.json` saved at a given unix time is kept as
+// `kv-store.json.20240101-120000`
+fn snapshot_path(db_path: &Path, unix_secs: u64) -> PathBuf {
+    let mut name = db_path.as_os_str().to_owned();
+    name.push(".");
+    name.push(format_timestamp(unix_secs));
+    PathBuf::from(name)
+}
+
+// Timestamped snapshots of a database file, oldest first
+pub fn list_snapshots(db_path: &Path) -> Result<Vec<PathBuf>> {
+    let dir = match db_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let Some(db_name) = db_path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(Vec::new());
+    };
+    let prefix = format!("{}.", db_name);
+
+    let mut snapshots: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix(&prefix))
+                .is_some_and(is_timestamp)
+        })
+        .map(|entry| entry.path())
+        .collect();
+
+    // The timestamp format sorts chronologically
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+// Format a unix time as YYYYMMDD-HHMMSS in UTC
+fn format_timestamp(unix_secs: u64) -> String {
+    let days = (unix_secs / 86400) as i64;
+    let secs = unix_secs % 86400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+// A time given on the command line, as unix seconds or in the
+// YYYYMMDD-HHMMSS form snapshots are named with (UTC)
+pub fn parse_time(s: &str) -> std::result::Result<u64, String> {
+    s.parse()
+        .ok()
+        .or_else(|| parse_timestamp(s))
+        .ok_or_else(|| format!("'{}' is neither unix seconds nor YYYYMMDD-HHMMSS", s))
+}
+
+// Inverse of format_timestamp
+fn parse_timestamp(s: &str) -> Option<u64> {
+    if !is_timestamp(s) {
+        return None;
+    }
+    let field = |range: std::ops::Range<usize>| s[range].parse::<i64>().ok();
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let secs = field(9..11)? * 3600 + field(11..13)? * 60 + field(13..15)?;
+
+    // Days since 1970-01-01 from a civil date (Howard Hinnant's algorithm)
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    u64::try_from(days * 86400 + secs).ok()
+}
+
+fn is_timestamp(s: &str) -> bool {
+    s.len() == 15
+        && s.char_indices()
+            .all(|(i, c)| if i == 8 { c == '-' } else { c.is_ascii_digit() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_save_and_bgsave() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("snapshot-db.json");
+        let store = Arc::new(KeyValueStore::new());
+        let manager = Arc::new(SnapshotManager::new(Arc::clone(&store), db_path.clone()));
+        assert_eq!(manager.last_save(), 0);
+
+        store.put("key1".to_string(), "value1".to_string());
+        manager.save().await?;
+        assert!(manager.last_save() > 0);
+        assert_eq!(
+            KeyValueStore::load(&db_path)?.get("key1"),
+            Some("value1".to_string())
+        );
+
+        // A second save can't start while a background one is running
+        store.put("key2".to_string(), "value2".to_string());
+        manager.bgsave()?;
+        assert!(manager.in_progress());
+        assert!(manager.bgsave().is_err());
+
+        while manager.in_progress() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            KeyValueStore::load(&db_path)?.get("key2"),
+            Some("value2".to_string())
+        );
+
+        // The save on exit waits out a running one and catches what it missed
+        manager.bgsave()?;
+        store.put("key3".to_string(), "value3".to_string());
+        manager.final_save().await?;
+        assert_eq!(store.changes_since_save(), 0);
+        assert_eq!(
+            KeyValueStore::load(&db_path)?.get("key3"),
+            Some("value3".to_string())
+        );
+
+        assert!(!manager.last_save_failed());
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_policy() {
+        let policy: SavePolicy = "900 1 60 1000".parse().unwrap();
+        assert_eq!(policy.to_string(), "900 1 60 1000");
+        assert!("900".parse::<SavePolicy>().is_err());
+        assert!("\"\"".parse::<SavePolicy>().unwrap().rules.is_empty());
+
+        assert_eq!(policy.due(Duration::from_secs(30), 5000), None);
+        assert_eq!(
+            policy.due(Duration::from_secs(60), 1000),
+            Some(SaveRule {
+                seconds: 60,
+                changes: 1000
+            })
+        );
+        assert_eq!(policy.due(Duration::from_secs(600), 10), None);
+        assert!(policy.due(Duration::from_secs(900), 1).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_wal_rewrite() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("wal-rewrite-db.json");
+        let store = Arc::new(KeyValueStore::new());
+        store.open_wal(&crate::wal::wal_path(&db_path))?;
+        let manager = Arc::new(SnapshotManager::new(Arc::clone(&store), db_path.clone()));
+        manager.set_wal_rewrite_size(1024);
+
+        store.put("key".to_string(), "x".repeat(600));
+        assert_eq!(manager.wal_rewrite_due(), None);
+        store.put("key".to_string(), "y".repeat(600));
+        assert_eq!(manager.wal_rewrite_due(), Some(store.wal_size()));
+
+        // Saving folds the log into the database file and empties it
+        manager.save().await?;
+        assert_eq!(store.wal_size(), 0);
+        assert_eq!(manager.wal_rewrite_due(), None);
+        assert_eq!(
+            KeyValueStore::load(&db_path)?.get("key"),
+            Some("y".repeat(600))
+        );
+
+        store.put("key".to_string(), "z".repeat(2000));
+        manager.set_wal_rewrite_size(0);
+        assert_eq!(manager.wal_rewrite_due(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("verify-db.json");
+        let store = Arc::new(KeyValueStore::new());
+        let manager = Arc::new(SnapshotManager::new(Arc::clone(&store), db_path.clone()));
+
+        store.put("key1".to_string(), "value1".to_string());
+        store.put("key2".to_string(), "value2".to_string());
+        manager.save().await?;
+        assert_eq!(manager.verify().await?.status(), "ok");
+
+        // Unsaved writes show up as expected drift
+        store.put("key2".to_string(), "changed".to_string());
+        let report = manager.verify().await?;
+        assert_eq!(report.status(), "stale");
+        assert_eq!(report.mismatched, vec!["key2".to_string()]);
+
+        // A file that no longer matches memory with nothing pending has diverged
+        manager.save().await?;
+        let other = KeyValueStore::new();
+        other.put("key3".to_string(), "value3".to_string());
+        other.save(&db_path)?;
+        let report = manager.verify().await?;
+        assert_eq!(report.status(), "diverged");
+        assert_eq!(report.differing_keys(), vec!["key1", "key2", "key3"]);
+
+        // A damaged file is reported rather than failing the command
+        fs::write(&db_path, "{\"data\": {\"key1\": ")?;
+        let report = manager.verify().await?;
+        assert_eq!(report.status(), "corrupt");
+        assert!(report.to_string().contains("error="));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_retention() -> Result<()> {
+        assert_eq!(format_timestamp(0), "19700101-000000");
+        assert_eq!(format_timestamp(1709210096), "20240229-123456");
+
+        let dir = tempdir()?;
+        let db_path = dir.path().join("retained-db.json");
+        let store = Arc::new(KeyValueStore::new());
+        let manager = SnapshotManager::new(Arc::clone(&store), db_path.clone());
+        manager.set_retention(2);
+
+        store.put("key1".to_string(), "value1".to_string());
+        store.save(&db_path)?;
+        for secs in [1_000, 2_000, 3_000] {
+            manager.archive(secs)?;
+        }
+
+        // Only the two newest snapshots are kept
+        assert_eq!(
+            list_snapshots(&db_path)?,
+            vec![
+                snapshot_path(&db_path, 2_000),
+                snapshot_path(&db_path, 3_000)
+            ]
+        );
+        assert_eq!(
+            KeyValueStore::load(&snapshot_path(&db_path, 3_000))?.get("key1"),
+            Some("value1".to_string())
+        );
+
+        let listed = manager.snapshots()?;
+        assert_eq!(listed[1].time, 3_000);
+        assert_eq!(listed[1].size, fs::metadata(&db_path)?.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_daily_retention() {
+        assert_eq!(parse_timestamp("20240229-123456"), Some(1709210096));
+        assert_eq!(parse_timestamp("19700101-000000"), Some(0));
+        assert_eq!(parse_time("1709210096"), Ok(1709210096));
+        assert_eq!(parse_time("20240229-123456"), Ok(1709210096));
+        assert!(parse_time("yesterday").is_err());
+
+        // Two snapshots a day for four days
+        let day = 86400;
+        let db_path = Path::new("db.json");
+        let snapshots: Vec<PathBuf> = (0..8)
+            .map(|i| snapshot_path(db_path, i / 2 * day + i % 2 * 3600))
+            .collect();
+        let now = 3 * day + 7200;
+
+        // The newest one, plus the last of each of the three most recent days
+        let expired = expired(&snapshots, 1, 3, now);
+        assert_eq!(
+            expired,
+            vec![
+                &snapshots[0],
+                &snapshots[1],
+                &snapshots[2],
+                &snapshots[4],
+                &snapshots[6]
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compact() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("compact-db.json");
+        let store = Arc::new(KeyValueStore::new());
+        let manager = Arc::new(SnapshotManager::new(Arc::clone(&store), db_path.clone()));
+        store.open_wal(&crate::wal::wal_path(&db_path))?;
+        for i in 0..50 {
+            store.try_put("key".to_string(), format!("value{}", i))?;
+        }
+        store.try_put("gone".to_string(), "x".repeat(1000))?;
+        store.delete("gone");
+
+        // Only the last value of one key is left on disk
+        let before = store::disk_usage(&db_path)?;
+        let reclaimed = manager.compact().await?;
+        assert!(reclaimed > 0);
+        assert_eq!(store::disk_usage(&db_path)?, before - reclaimed);
+        assert_eq!(crate::wal::disk_size(&crate::wal::wal_path(&db_path))?, 0);
+        assert_eq!(KeyValueStore::load(&db_path)?.entries(), store.entries());
+        Ok(())
+    }
+}
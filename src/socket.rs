@@ -0,0 +1,169 @@
+// src/socket.rs
+
+// Tunable TCP socket options shared by the server and the client
+
+use crate::error::{Result, StoreError};
+use crate::logging::log_debug;
+use clap::Args;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, lookup_host};
+
+#[derive(Args, Debug, Clone)]
+pub struct SocketOptions {
+    // Disable Nagle's algorithm (TCP_NODELAY) on every connection
+    #[clap(long)]
+    pub tcp_nodelay: bool,
+
+    // Size of the kernel receive buffer (SO_RCVBUF), in bytes
+    #[clap(long)]
+    pub recv_buffer_size: Option<u32>,
+
+    // Size of the kernel send buffer (SO_SNDBUF), in bytes
+    #[clap(long)]
+    pub send_buffer_size: Option<u32>,
+
+    // Maximum number of pending connections waiting to be accepted
+    #[clap(long, default_value = "1024")]
+    pub backlog: u32,
+
+    // How long to wait on each address a peer resolves to before trying
+    // the next, in milliseconds
+    #[clap(long, default_value = "3000")]
+    pub connect_timeout_ms: u64,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            tcp_nodelay: false,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            backlog: 1024,
+            connect_timeout_ms: 3000,
+        }
+    }
+}
+
+impl SocketOptions {
+    // Bind a listener on the first address `address` resolves to
+    pub async fn bind(&self, address: &str) -> Result<TcpListener> {
+        let addr = lookup_host(address).await?.next().ok_or_else(|| {
+            StoreError::IoError(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("Could not resolve {}", address),
+            ))
+        })?;
+
+        let socket = self.new_socket(addr)?;
+        socket.set_reuseaddr(true)?;
+        socket.bind(addr)?;
+        Ok(socket.listen(self.backlog)?)
+    }
+
+    // Connect to `address`, trying every address it resolves to. Names are
+    // resolved again on every connect, so a hostname backed by several nodes,
+    // like a headless Kubernetes service, follows them as they come and go.
+    pub async fn connect(&self, address: &str) -> Result<TcpStream> {
+        let mut last_err = None;
+        let timeout = Duration::from_millis(self.connect_timeout_ms);
+
+        for addr in lookup_host(address).await? {
+            let socket = self.new_socket(addr)?;
+            let error = match tokio::time::timeout(timeout, socket.connect(addr)).await {
+                Ok(Ok(stream)) => {
+                    self.apply(&stream)?;
+                    return Ok(stream);
+                }
+                Ok(Err(e)) => e,
+                Err(_) => io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Connecting to {} timed out", addr),
+                ),
+            };
+            log_debug!("Failed to connect to {} ({}): {}", address, addr, error);
+            last_err = Some(error);
+        }
+
+        Err(StoreError::IoError(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("Could not resolve {}", address),
+            )
+        })))
+    }
+
+    // Apply per-connection options to an accepted or connected stream
+    pub fn apply(&self, stream: &TcpStream) -> Result<()> {
+        if self.tcp_nodelay {
+            stream.set_nodelay(true)?;
+        }
+        Ok(())
+    }
+
+    fn new_socket(&self, addr: SocketAddr) -> Result<TcpSocket> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+
+        // Buffer sizes must be set before connect/listen to affect the
+        // TCP window negotiated with the peer
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_bind_and_connect_with_options() {
+        let options = SocketOptions {
+            tcp_nodelay: true,
+            recv_buffer_size: Some(64 * 1024),
+            send_buffer_size: Some(64 * 1024),
+            backlog: 16,
+            connect_timeout_ms: 1000,
+        };
+
+        let listener = options.bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let accept = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+
+        let mut stream = options.connect(&addr).await.unwrap();
+        assert!(stream.nodelay().unwrap());
+        stream.write_all(b"ping").await.unwrap();
+
+        assert_eq!(&accept.await.unwrap(), b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        let options = SocketOptions {
+            connect_timeout_ms: 100,
+            ..SocketOptions::default()
+        };
+
+        // A documentation-only address that never answers, or is refused
+        // outright where there is no route
+        let started = std::time::Instant::now();
+        assert!(options.connect("192.0.2.1:7000").await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,169 @@
+// src/statsd.rs
+
+// Pushes server metrics to a StatsD daemon over UDP, for monitoring stacks
+// that can't scrape a server (Graphite can take them through a StatsD
+// relay). Gauges report the current value, counters what happened since
+// the last flush.
+
+use crate::error::{Result, StoreError};
+use crate::latency::{Latencies, PERCENTILES};
+use crate::store::KeyValueStore;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::net::{UdpSocket, lookup_host};
+
+pub const DEFAULT_PREFIX: &str = "kv_store";
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsdSettings {
+    // host:port of the StatsD daemon
+    pub address: String,
+    // Prepended to every metric name, e.g. "kv_store.keys"
+    pub prefix: String,
+    pub flush_interval: Duration,
+}
+
+// Counters the server keeps for the exporter
+#[derive(Default)]
+pub struct Metrics {
+    commands: AtomicU64,
+    connections: AtomicUsize,
+    latencies: Latencies,
+}
+
+impl Metrics {
+    pub fn record_command(&self) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn latencies(&self) -> &Latencies {
+        &self.latencies
+    }
+}
+
+// Turns the server's totals into StatsD lines, remembering what was
+// reported last so counters only send the difference
+#[derive(Default)]
+pub struct Reporter {
+    last_commands: u64,
+    last_seq: u64,
+}
+
+impl Reporter {
+    pub fn lines(&mut self, prefix: &str, store: &KeyValueStore, metrics: &Metrics) -> Vec<String> {
+        let commands = metrics.commands.load(Ordering::Relaxed);
+        let seq = store.changelog().last_seq();
+        let mut lines = vec![
+            format!("{}.keys:{}|g", prefix, store.loaded_len()),
+            format!("{}.used_memory:{}|g", prefix, store.used_memory()),
+            format!(
+                "{}.connections:{}|g",
+                prefix,
+                metrics.connections.load(Ordering::Relaxed)
+            ),
+            format!(
+                "{}.commands:{}|c",
+                prefix,
+                commands.saturating_sub(self.last_commands)
+            ),
+            format!("{}.writes:{}|c", prefix, seq.saturating_sub(self.last_seq)),
+        ];
+        // Latency percentiles since the server started
+        for (command, histogram) in metrics.latencies.iter() {
+            for p in PERCENTILES {
+                lines.push(format!(
+                    "{}.latency.{}.p{}_us:{}|g",
+                    prefix,
+                    command.to_lowercase(),
+                    p,
+                    histogram.percentile(p)
+                ));
+            }
+        }
+        self.last_commands = commands;
+        self.last_seq = seq;
+        lines
+    }
+
+    // Send one packet with every metric
+    pub async fn flush(
+        &mut self,
+        settings: &StatsdSettings,
+        store: &KeyValueStore,
+        metrics: &Metrics,
+    ) -> Result<()> {
+        let target = lookup_host(&settings.address)
+            .await?
+            .next()
+            .ok_or_else(|| {
+                StoreError::IoError(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("Could not resolve {}", settings.address),
+                ))
+            })?;
+        let local = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local).await?;
+
+        let packet = self.lines(&settings.prefix, store, metrics).join("\n");
+        socket.send_to(packet.as_bytes(), target).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_statsd_flush() -> Result<()> {
+        let daemon = UdpSocket::bind("127.0.0.1:0").await?;
+        let settings = StatsdSettings {
+            address: daemon.local_addr()?.to_string(),
+            prefix: "kv".to_string(),
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+        };
+
+        let store = KeyValueStore::new();
+        let metrics = Metrics::default();
+        let mut reporter = Reporter::default();
+        store.put("key1".to_string(), "value1".to_string());
+        metrics.connection_opened();
+        metrics.record_command();
+        metrics.record_command();
+        metrics.latencies().record("GET", Duration::from_micros(12));
+
+        reporter.flush(&settings, &store, &metrics).await?;
+        let mut buf = [0u8; 1024];
+        let len = daemon.recv(&mut buf).await?;
+        let packet = String::from_utf8_lossy(&buf[..len]).to_string();
+        let lines: Vec<&str> = packet.lines().collect();
+        assert!(lines.contains(&"kv.keys:1|g"), "{}", packet);
+        assert!(lines.contains(&"kv.connections:1|g"), "{}", packet);
+        assert!(lines.contains(&"kv.commands:2|c"), "{}", packet);
+        assert!(lines.contains(&"kv.writes:1|c"), "{}", packet);
+        assert!(lines.contains(&"kv.latency.get.p99_us:12|g"), "{}", packet);
+        assert!(lines.contains(&"kv.latency.put.p50_us:0|g"), "{}", packet);
+
+        // Counters restart from zero after each flush, gauges don't
+        let lines = reporter.lines("kv", &store, &metrics);
+        assert!(lines.contains(&"kv.keys:1|g".to_string()));
+        assert!(lines.contains(&"kv.commands:0|c".to_string()));
+        assert!(lines.contains(&"kv.writes:0|c".to_string()));
+
+        Ok(())
+    }
+}
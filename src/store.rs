@@ -1,37 +1,764 @@
 // src/store.rs
 
 // // Module for the key-value store
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{BufRead, BufReader, BufWriter, Write};
 // use std::io::{BufReader, BufWriter, Read, Write};
+use crate::changelog::{Change, ChangeOp, Changelog};
+use crate::cow::{self, CowMap};
+use crate::crdt::{Crdt, Update};
+use crate::encryption;
 use crate::error::{Result, StoreError};
-use serde::{Deserialize, Serialize};
-use std::path::Path;
-use std::sync::RwLock;
+use crate::eviction::{EvictionPolicy, random_index};
+use crate::faults::{self, Faulty};
+use crate::format::{self, FORMAT_VERSION, Header};
+use crate::hlc::{self, Timestamp};
+use crate::logging::{log_error, log_info, log_warn};
+use crate::lsm::{self, Lsm};
+use crate::notifications::{KeyEvent, Notification, Notifier};
+use crate::quota::{Quota, QuotaLimits};
+use crate::value::StoredValue;
+use crate::vclock::VectorClock;
+use crate::wal::{self, Wal};
+use memmap2::Mmap;
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, Visitor};
+use serde::ser::{Error as _, SerializeMap};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::value::RawValue;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, RwLock, RwLockWriteGuard};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, watch};
+
+// How the database file is encoded, picked with --db-format
+pub use crate::format::Encoding;
+
+// Number of entries inserted per write-lock acquisition during a streaming load
+const LOAD_BATCH_SIZE: usize = 1024;
+
+// Reads between tiering passes that make a cold key hot enough to promote
+const PROMOTE_HITS: u32 = 2;
+
+thread_local! {
+    // Sequence numbers of the changes made on this thread inside `recording`
+    static RECORDED: RefCell<Option<Vec<u64>>> = const { RefCell::new(None) };
+}
 
 // A thread-safe key-value store
 #[derive(Serialize, Deserialize)]
 pub struct KeyValueStore {
-    // Wrap the HashMap in a RwLock to allow concurrent access
-    data_lock: RwLock<HashMap<String, String>>,
+    // Wrap the map in a RwLock to allow concurrent access. Its shards are
+    // shared with snapshots until written to.
+    #[serde(skip)]
+    data_lock: RwLock<CowMap<Entry>>,
 
     // Keep a separate field for serialization/deserialization
     #[serde(rename = "data")]
-    data_for_serde: Option<HashMap<String, String>>,
+    data_for_serde: Option<HashMap<String, SavedEntry>>,
+
+    // Every key, each at the slot recorded in its entry, so random keys can be
+    // picked without walking the map. Only changed under the data write lock.
+    #[serde(skip)]
+    key_slots: RwLock<Vec<String>>,
+
+    // Progress of a background load started by `load_streaming`
+    #[serde(skip)]
+    load_state: LoadState,
+
+    // Approximate bytes used by keys and values, and the cap on it (0 = unlimited)
+    #[serde(skip)]
+    used_memory: AtomicUsize,
+    #[serde(skip)]
+    max_memory: AtomicUsize,
+
+    // Caps on the number of keys and on key length (0 = unlimited)
+    #[serde(skip)]
+    max_keys: AtomicUsize,
+    #[serde(skip)]
+    max_key_length: AtomicUsize,
+
+    // Values at least this many bytes long are compressed (0 = never)
+    #[serde(skip)]
+    compression_threshold: AtomicUsize,
+
+    // Values at least this many bytes long are written to their own file in
+    // the spill directory (0 = never), numbered by a counter
+    #[serde(skip)]
+    spill_threshold: AtomicUsize,
+    #[serde(skip)]
+    spill_dir: RwLock<Option<PathBuf>>,
+    #[serde(skip)]
+    spill_files: AtomicU64,
+
+    // LSM tree holding every entry with --engine lsm, and how many keys it
+    // holds. The map then only holds the entries a write is working on.
+    #[serde(skip)]
+    lsm: OnceLock<Arc<Lsm>>,
+    #[serde(skip)]
+    lsm_keys: AtomicUsize,
+
+    // Memory that hot values may use before the coldest are moved to the
+    // spill directory (0 = no tiering)
+    #[serde(skip)]
+    memory_budget: AtomicUsize,
+
+    // Limits on key prefixes, with their current usage
+    #[serde(skip)]
+    quotas: RwLock<Vec<Quota>>,
+
+    // What to evict when a write would exceed max_memory, and how many keys
+    // have been evicted so far
+    #[serde(skip)]
+    eviction_policy: RwLock<EvictionPolicy>,
+    #[serde(skip)]
+    evicted_keys: AtomicU64,
+
+    // Keys removed by the expiry sweeper so far
+    #[serde(skip)]
+    expired_keys: AtomicU64,
+
+    // Logical clock stamped on entries as they're accessed, for LRU eviction
+    #[serde(skip)]
+    clock: AtomicU64,
+
+    // Hybrid logical clock stamped on every write
+    #[serde(skip)]
+    hlc: hlc::Clock,
+
+    // Whether writes carry a vector clock, so that writes other nodes made
+    // concurrently with ours are kept as siblings instead of replacing them
+    #[serde(skip)]
+    vector_clocks: AtomicBool,
+
+    // Set on backups, which keep showing keys whose time has come until the
+    // primary's delete arrives, so a clock running ahead doesn't hide them
+    // early
+    #[serde(skip)]
+    expiry_deferred: AtomicBool,
+
+    // Writes made since the last successful save
+    #[serde(skip)]
+    changes: AtomicU64,
+
+    // Sequence number of the last write, saved so numbering survives restarts
+    #[serde(rename = "seq", default)]
+    seq_for_serde: u64,
+
+    // Recent writes for change data capture consumers
+    #[serde(skip)]
+    changelog: Changelog,
+
+    // Keyspace notifications for subscribers
+    #[serde(skip)]
+    notifier: Notifier,
+
+    // Where writes are logged before they're applied, if anywhere
+    #[serde(skip)]
+    wal: OnceLock<Wal>,
+
+    // The node the database file belongs to, and the highest replication
+    // epoch it has seen, both kept in the file's header
+    #[serde(skip)]
+    node_id: String,
+    #[serde(skip)]
+    epoch: AtomicU64,
+
+    // How saves encode the store, by default as the file was when loaded
+    #[serde(skip)]
+    encoding: RwLock<Encoding>,
+}
+
+// A stored value and the bookkeeping kept alongside it
+struct Entry {
+    value: StoredValue,
+    // Number of times the key has been written since it was created
+    version: u64,
+    // Unix times in milliseconds, 0 if unknown
+    created: u64,
+    updated: u64,
+    // Store clock at the last read or write
+    last_access: AtomicU64,
+    // Reads since the last tiering pass
+    hits: AtomicU32,
+    // Index of the key in `key_slots`
+    slot: usize,
+    // Hybrid logical clock time of the last write, from the node that took it
+    hlc: Timestamp,
+    // Writes the value reflects, empty unless vector clocks are on
+    vclock: VectorClock,
+    // Values other nodes wrote concurrently with `value`, kept until a client
+    // resolves them. Rare enough not to count towards memory limits.
+    siblings: Vec<String>,
+    // Unix time in milliseconds the key expires at, 0 if never
+    expires: u64,
+}
+
+impl Clone for Entry {
+    fn clone(&self) -> Self {
+        Entry {
+            value: self.value.clone(),
+            version: self.version,
+            created: self.created,
+            updated: self.updated,
+            last_access: AtomicU64::new(self.last_access.load(Ordering::Relaxed)),
+            hits: AtomicU32::new(self.hits.load(Ordering::Relaxed)),
+            slot: self.slot,
+            hlc: self.hlc,
+            vclock: self.vclock.clone(),
+            siblings: self.siblings.clone(),
+            expires: self.expires,
+        }
+    }
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
+
+    fn to_saved(&self) -> SavedEntry {
+        let (value, compressed) = self.value.to_saved();
+        SavedEntry {
+            value,
+            compressed,
+            version: self.version,
+            created: self.created,
+            updated: self.updated,
+            hlc: self.hlc,
+            vclock: self.vclock.clone(),
+            siblings: self.siblings.clone(),
+            expires: self.expires,
+        }
+    }
+}
+
+// An entry as written to the database file. Its value is read as a string,
+// or borrowed from a mapped file to be read in place.
+#[derive(Serialize, Deserialize)]
+struct SavedEntry<V = String> {
+    // Base64 of the compressed data if `compressed` is set
+    value: V,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    compressed: bool,
+    version: u64,
+    #[serde(default)]
+    created: u64,
+    #[serde(default)]
+    updated: u64,
+    #[serde(default, skip_serializing_if = "Timestamp::is_zero")]
+    hlc: Timestamp,
+    #[serde(default, skip_serializing_if = "VectorClock::is_empty")]
+    vclock: VectorClock,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    siblings: Vec<String>,
+    #[serde(default, skip_serializing_if = "never_expires")]
+    expires: u64,
+}
+
+impl SavedEntry {
+    // The entry as held in memory, at `slot` in `key_slots`
+    fn into_entry(self, slot: usize) -> Result<Entry> {
+        Ok(Entry {
+            value: self.value.into_stored(self.compressed, None)?,
+            version: self.version,
+            created: self.created,
+            updated: self.updated,
+            last_access: AtomicU64::new(0),
+            hits: AtomicU32::new(0),
+            slot,
+            hlc: self.hlc,
+            vclock: self.vclock,
+            siblings: self.siblings,
+            expires: self.expires,
+        })
+    }
+}
+
+fn never_expires(expires: &u64) -> bool {
+    *expires == 0
+}
+
+// A value as read from a database file
+trait SavedValue {
+    // How to hold it, given the mapped file it was borrowed from if any
+    fn into_stored(self, compressed: bool, file: Option<&Arc<Mmap>>) -> Result<StoredValue>;
+}
+
+impl SavedValue for String {
+    fn into_stored(self, compressed: bool, _: Option<&Arc<Mmap>>) -> Result<StoredValue> {
+        StoredValue::from_saved(self, compressed)
+    }
+}
+
+// Text in a MessagePack file, which is held as is
+impl SavedValue for &str {
+    fn into_stored(self, compressed: bool, file: Option<&Arc<Mmap>>) -> Result<StoredValue> {
+        if !compressed
+            && let Some(mapped) =
+                file.and_then(|file| StoredValue::mapped(file, self.as_bytes(), false))
+        {
+            return mapped;
+        }
+        StoredValue::from_saved(self.to_string(), compressed)
+    }
+}
+
+// A string literal in a JSON file. Without escapes its text is the part
+// between the quotes.
+impl SavedValue for &RawValue {
+    fn into_stored(self, compressed: bool, file: Option<&Arc<Mmap>>) -> Result<StoredValue> {
+        let literal = self.get();
+        if !compressed
+            && literal.starts_with('"')
+            && let Some(file) = file
+        {
+            let escaped = literal.contains('\\');
+            let text = if escaped {
+                literal
+            } else {
+                &literal[1..literal.len() - 1]
+            };
+            if let Some(mapped) = StoredValue::mapped(file, text.as_bytes(), escaped) {
+                return mapped;
+            }
+        }
+        let text: String = serde_json::from_str(literal).map_err(corrupt)?;
+        StoredValue::from_saved(text, compressed)
+    }
+}
+
+// What OBJECT INFO reports about a key
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMetadata {
+    pub version: u64,
+    pub created: u64,
+    pub updated: u64,
+    pub compressed: bool,
+    pub spilled: bool,
+    pub hlc: Timestamp,
+    pub vclock: VectorClock,
+    // Values held besides the current one while writes conflict
+    pub siblings: usize,
+    // Unix time in milliseconds the key expires at, 0 if never
+    pub expires: u64,
+}
+
+impl fmt::Display for KeyMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "version={}, created={}, updated={}, compressed={}, spilled={}, hlc={}",
+            self.version, self.created, self.updated, self.compressed, self.spilled, self.hlc
+        )?;
+        if !self.vclock.is_empty() {
+            write!(f, ", vclock={}, siblings={}", self.vclock, self.siblings)?;
+        }
+        if self.expires != 0 {
+            write!(f, ", expires={}", self.expires)?;
+        }
+        Ok(())
+    }
+}
+
+// Condition a write must meet to be applied
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Always,
+    // The key doesn't exist
+    Absent,
+    // The key currently holds this value
+    Value(String),
+    // The key is at this version, 0 meaning it doesn't exist
+    Version(u64),
+    // The key holds conflicting values, which the write replaces
+    Conflicted,
+}
+
+// A line of a seed file
+#[derive(Deserialize)]
+struct SeedEntry {
+    key: String,
+    value: String,
+}
+
+fn serialization_error(e: serde_json::Error) -> StoreError {
+    StoreError::SerializationError(e.to_string())
+}
+
+// A database file whose body can't be read was damaged or cut short
+fn corrupt(e: impl fmt::Display) -> StoreError {
+    StoreError::Corruption(e.to_string())
+}
+
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// Expiry time `seconds` from now, in unix milliseconds
+pub fn expires_in(seconds: u64) -> u64 {
+    unix_millis().saturating_add(seconds.saturating_mul(1000))
+}
+
+// Whether `key` matches `pattern`, where `*` stands for any run of characters
+// and `?` for any one
+fn glob_match(pattern: &str, key: &str) -> bool {
+    let (pattern, key): (Vec<char>, Vec<char>) = (pattern.chars().collect(), key.chars().collect());
+    let (mut p, mut k) = (0, 0);
+    // The last `*` seen and where in the key it started matching
+    let mut star = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, k));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            // Let the last `*` take one more character
+            _ => match star {
+                Some((star_p, star_k)) => {
+                    p = star_p + 1;
+                    k = star_k + 1;
+                    star = Some((star_p, star_k + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Approximate memory used by an entry
+fn entry_size(key: &str, value: &StoredValue) -> usize {
+    key.len() + value.size()
+}
+
+// Why a write that nothing could be evicted for was refused
+fn limit_error(over_keys: bool, max_keys: usize, max_memory: usize) -> StoreError {
+    StoreError::LimitError(if over_keys {
+        format!("max_keys of {} reached", max_keys)
+    } else {
+        format!("maxmemory of {} bytes reached", max_memory)
+    })
+}
+
+// Keys in the LSM tree: each entry under its key, an empty record per
+// expiry time so due keys are found without reading every entry, and the
+// store's own bookkeeping
+const TREE_ENTRY: u8 = b'd';
+const TREE_EXPIRY: u8 = b'e';
+const TREE_SEQ: &[u8] = b"mseq";
+const TREE_KEYS: &[u8] = b"mkeys";
+const TREE_HLC: &[u8] = b"mhlc";
+
+// Picks from the tree before `random_key` settles for the first key
+const RANDOM_KEY_TRIES: usize = 32;
+
+// File in the tree's directory with the size and modification time of the
+// database file when the two were last in step
+const TREE_STAMP: &str = "DATABASE";
+
+fn tree_key(key: &str) -> Vec<u8> {
+    let mut tree_key = Vec::with_capacity(key.len() + 1);
+    tree_key.push(TREE_ENTRY);
+    tree_key.extend_from_slice(key.as_bytes());
+    tree_key
+}
+
+fn expiry_key(expires: u64, key: &str) -> Vec<u8> {
+    let mut tree_key = vec![TREE_EXPIRY];
+    tree_key.extend_from_slice(&expires.to_be_bytes());
+    tree_key.extend_from_slice(key.as_bytes());
+    tree_key
+}
+
+// Entries are kept in the tree as MessagePack, the way they're saved
+fn encode_entry(entry: &Entry) -> Result<Vec<u8>> {
+    rmp_serde::to_vec_named(&entry.to_saved())
+        .map_err(|e| StoreError::SerializationError(e.to_string()))
+}
+
+fn decode_entry(bytes: &[u8]) -> Result<SavedEntry> {
+    rmp_serde::from_slice(bytes).map_err(corrupt)
+}
+
+// Entries in the tree in key order, from the first key not below the
+// bytes `from` on. Stops at the first one that can't be read, logging why.
+fn tree_entries(view: &lsm::View, from: &[u8]) -> impl Iterator<Item = (String, Entry)> + use<> {
+    let mut start = vec![TREE_ENTRY];
+    start.extend_from_slice(from);
+    view.range(&start).map_while(|record| {
+        let entry = record.and_then(|(key, bytes)| {
+            let Some(key) = key.strip_prefix(&[TREE_ENTRY]) else {
+                return Ok(None);
+            };
+            let key = String::from_utf8(key.to_vec()).map_err(corrupt)?;
+            Ok(Some((key, decode_entry(&bytes)?.into_entry(0)?)))
+        });
+        entry.unwrap_or_else(|e| {
+            log_error!("Could not read the LSM tree: {}", e);
+            None
+        })
+    })
+}
+
+// Keys due to expire by `now`, from the tree's expiry records
+fn tree_due_keys(view: &lsm::View, now: u64) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    for record in view.range(&[TREE_EXPIRY]) {
+        let (key, _) = record?;
+        let Some(key) = key.strip_prefix(&[TREE_EXPIRY]) else {
+            break;
+        };
+        let (expires, key) = key.split_first_chunk::<8>().ok_or_else(|| {
+            StoreError::Corruption("Bad expiry record in the LSM tree".to_string())
+        })?;
+        if u64::from_be_bytes(*expires) > now {
+            break;
+        }
+        keys.push(String::from_utf8(key.to_vec()).map_err(corrupt)?);
+    }
+    Ok(keys)
+}
+
+// SCAN cursor for resuming at `key` in the tree: its first eight bytes.
+// Keys sharing them are all returned on the same page.
+fn scan_cursor(key: &str) -> u64 {
+    let mut bytes = [0; 8];
+    let len = key.len().min(8);
+    bytes[..len].copy_from_slice(&key.as_bytes()[..len]);
+    u64::from_be_bytes(bytes)
+}
+
+// A number the store keeps in the tree, None if it isn't there yet
+fn tree_number(lsm: &Lsm, key: &[u8]) -> Result<Option<u64>> {
+    let Some(bytes) = lsm.get(key)? else {
+        return Ok(None);
+    };
+    let bytes = bytes.try_into().map_err(|_| {
+        StoreError::Corruption(format!(
+            "Bad {} record in the LSM tree",
+            String::from_utf8_lossy(key)
+        ))
+    })?;
+    Ok(Some(u64::from_le_bytes(bytes)))
+}
+
+// Size and modification time of the file at `path`, None if there is none
+fn file_stamp(path: &Path) -> Result<Option<String>> {
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let modified = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(Some(format!("{} {}", meta.len(), modified.as_nanos())))
+}
+
+// What the tree held for a key paged in, and the key's expiry time then
+type PagedEntry = Option<(Vec<u8>, u64)>;
+
+// The store's data, locked for writing. With --engine lsm a write first
+// pages in the entries of the keys it touches, which are written back to
+// the tree and dropped from memory when the lock is released.
+struct DataGuard<'a> {
+    store: &'a KeyValueStore,
+    data: RwLockWriteGuard<'a, CowMap<Entry>>,
+    paged: HashMap<String, PagedEntry>,
+}
+
+impl DataGuard<'_> {
+    // Bring the entries of `keys` into the map from the tree, if the store
+    // keeps one. Each key is read once per lock.
+    fn page_in(&mut self, keys: &[&str]) -> Result<()> {
+        let Some(lsm) = self.store.lsm.get() else {
+            return Ok(());
+        };
+        for key in keys {
+            if self.paged.contains_key(*key) {
+                continue;
+            }
+            let paged = match lsm.get(&tree_key(key))? {
+                Some(bytes) => {
+                    let mut slots = self.store.key_slots.write().unwrap();
+                    let entry = decode_entry(&bytes)?.into_entry(slots.len())?;
+                    slots.push(key.to_string());
+                    drop(slots);
+                    let size = entry_size(key, &entry.value);
+                    self.store.used_memory.fetch_add(size, Ordering::Relaxed);
+                    let expires = entry.expires;
+                    self.data.insert(key.to_string(), entry);
+                    Some((bytes, expires))
+                }
+                None => None,
+            };
+            self.paged.insert(key.to_string(), paged);
+        }
+        Ok(())
+    }
+
+    // For writes that go ahead regardless: a key that can't be read is
+    // logged, and the write is to be skipped. Returns whether all were
+    // paged in.
+    fn page_in_or_log(&mut self, keys: &[&str]) -> bool {
+        match self.page_in(keys) {
+            Ok(()) => true,
+            Err(e) => {
+                log_error!("Could not read {:?} from the LSM tree: {}", keys, e);
+                false
+            }
+        }
+    }
+}
+
+impl Deref for DataGuard<'_> {
+    type Target = CowMap<Entry>;
+
+    fn deref(&self) -> &CowMap<Entry> {
+        &self.data
+    }
+}
+
+impl DerefMut for DataGuard<'_> {
+    fn deref_mut(&mut self) -> &mut CowMap<Entry> {
+        &mut self.data
+    }
+}
+
+impl Drop for DataGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(lsm) = self.store.lsm.get() {
+            let paged = mem::take(&mut self.paged);
+            self.store.write_back(lsm, &mut self.data, paged);
+        }
+    }
+}
+
+// Outcome of a background load
+#[derive(Debug, Clone, PartialEq)]
+enum LoadStatus {
+    Loading,
+    Loaded,
+    Failed(String),
+}
+
+struct LoadState {
+    // Fast path so fully loaded stores never touch the mutex
+    loading: AtomicBool,
+    status: Mutex<LoadStatus>,
+    done: Condvar,
+    // The same for async callers, which mustn't block on `done`
+    finished: watch::Sender<bool>,
+}
+
+impl Default for LoadState {
+    fn default() -> Self {
+        LoadState {
+            loading: AtomicBool::new(false),
+            status: Mutex::new(LoadStatus::Loaded),
+            done: Condvar::new(),
+            finished: watch::Sender::new(true),
+        }
+    }
+}
+
+impl LoadState {
+    fn loading() -> Self {
+        LoadState {
+            loading: AtomicBool::new(true),
+            status: Mutex::new(LoadStatus::Loading),
+            done: Condvar::new(),
+            finished: watch::Sender::new(false),
+        }
+    }
+
+    fn finish(&self, status: LoadStatus) {
+        *self.status.lock().unwrap() = status;
+        self.loading.store(false, Ordering::Release);
+        self.done.notify_all();
+        self.finished.send_replace(true);
+    }
+
+    fn wait(&self) -> LoadStatus {
+        let mut status = self.status.lock().unwrap();
+        while *status == LoadStatus::Loading {
+            status = self.done.wait(status).unwrap();
+        }
+        status.clone()
+    }
+}
+
+impl Default for KeyValueStore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl KeyValueStore {
     // Create a new empty store
     pub fn new() -> Self {
         KeyValueStore {
-            data_lock: RwLock::new(HashMap::new()),
+            data_lock: RwLock::new(CowMap::default()),
             data_for_serde: None,
+            key_slots: RwLock::new(Vec::new()),
+            load_state: LoadState::default(),
+            used_memory: AtomicUsize::new(0),
+            max_memory: AtomicUsize::new(0),
+            max_keys: AtomicUsize::new(0),
+            max_key_length: AtomicUsize::new(0),
+            compression_threshold: AtomicUsize::new(0),
+            spill_threshold: AtomicUsize::new(0),
+            spill_dir: RwLock::new(None),
+            spill_files: AtomicU64::new(0),
+            lsm: OnceLock::new(),
+            lsm_keys: AtomicUsize::new(0),
+            memory_budget: AtomicUsize::new(0),
+            quotas: RwLock::new(Vec::new()),
+            eviction_policy: RwLock::new(EvictionPolicy::default()),
+            evicted_keys: AtomicU64::new(0),
+            expired_keys: AtomicU64::new(0),
+            clock: AtomicU64::new(0),
+            hlc: hlc::Clock::default(),
+            vector_clocks: AtomicBool::new(false),
+            expiry_deferred: AtomicBool::new(false),
+            changes: AtomicU64::new(0),
+            seq_for_serde: 0,
+            changelog: Changelog::default(),
+            notifier: Notifier::default(),
+            wal: OnceLock::new(),
+            node_id: format::new_node_id(),
+            epoch: AtomicU64::new(0),
+            encoding: RwLock::new(Encoding::default()),
         }
     }
 
     // Load from file
     pub fn load(path: &Path) -> Result<Self> {
+        let store = Self::load_file(path)?;
+        store.replay_wal(&wal::wal_path(path))?;
+        Ok(store)
+    }
+
+    // Load a database file, or a snapshot of one, without its write-ahead log
+    pub(crate) fn load_file(path: &Path) -> Result<Self> {
         let file = match File::open(path) {
             Ok(file) => file,
             Err(e) => match e.kind() {
@@ -40,126 +767,4083 @@ impl KeyValueStore {
             },
         };
 
-        // Deserialize the store
-        let reader = BufReader::new(file);
-        let mut store: Self = serde_json::from_reader(reader)
-            .map_err(|e| StoreError::SerializationError(e.to_string()))?;
+        // Deserialize the store, upgrading files written in an older format
+        let mut reader = format::Checksummed::new(BufReader::new(encryption::Reader::new(
+            Faulty::reader(path, file),
+        )?));
+        let header = format::read_header(&mut reader)?;
+        let mut store: Self = if header.encoding == Encoding::MessagePack {
+            rmp_serde::from_read(&mut reader).map_err(corrupt)?
+        } else {
+            let mut deserializer = serde_json::Deserializer::from_reader(&mut reader);
+            if header.format == FORMAT_VERSION {
+                Self::deserialize(&mut deserializer).map_err(corrupt)?
+            } else {
+                let doc = serde_json::Value::deserialize(&mut deserializer).map_err(corrupt)?;
+                serde_json::from_value(format::migrate(doc, header.format)?).map_err(corrupt)?
+            }
+        };
+        let records = store.data_for_serde.as_ref().map_or(0, HashMap::len);
+        format::check_trailer(&mut reader, &header, records)?;
+        store.node_id = header.node_id.unwrap_or_else(format::new_node_id);
+        store.epoch = AtomicU64::new(header.epoch);
+        store.encoding = RwLock::new(header.encoding);
 
         // Transfer data from serialization field to the RWLock
         if let Some(data) = store.data_for_serde.take() {
-            *store.data_lock.write().unwrap() = data;
+            let mut entries = store.data_lock.write().unwrap();
+            for (key, saved) in data {
+                store.load_locked(&mut entries, key, saved, None)?;
+            }
+        }
+        store.changelog.set_last_seq(store.seq_for_serde);
+        Ok(store)
+    }
+
+    // Load what can still be read from a damaged database file: keys before
+    // the damage are kept and the rest are dropped. The trailer isn't checked.
+    pub fn salvage(path: &Path) -> Result<Self> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(StoreError::IoError(e)),
+        };
+        let mut reader = BufReader::new(encryption::Reader::new(Faulty::reader(path, file))?);
+        let header = format::read_header(&mut reader)?;
+        // Older files are migrated as a whole, so they load fully or not at all
+        if header.encoding == Encoding::Json && header.format != FORMAT_VERSION {
+            return Self::load(path);
         }
 
+        let mut store = Self::new();
+        store.node_id = header.node_id.unwrap_or_else(format::new_node_id);
+        store.epoch = AtomicU64::new(header.epoch);
+        store.encoding = RwLock::new(header.encoding);
+        let result = if header.encoding == Encoding::MessagePack {
+            StoreSeed::new(&store)
+                .deserialize(&mut rmp_serde::Deserializer::new(reader))
+                .map_err(corrupt)
+        } else {
+            StoreSeed::new(&store)
+                .deserialize(&mut serde_json::Deserializer::from_reader(reader))
+                .map_err(corrupt)
+        };
+        if let Err(e) = result {
+            log_warn!(
+                "Salvaged {} keys from {}, dropping the rest: {}",
+                store.len(),
+                path.display(),
+                e
+            );
+        }
+        store.replay_wal(&wal::wal_path(path))?;
         Ok(store)
     }
 
-    // Save to file
-    pub fn save(&self, path: &Path) -> Result<()> {
-        // Create a temporary structure for serialization
-        let temp_store = KeyValueStore {
-            data_lock: RwLock::new(HashMap::new()),
-            data_for_serde: Some(self.data_lock.read().unwrap().clone()),
+    // Load from file, reading values in place from a memory map of it rather
+    // than copying them into memory. Only keys and metadata are deserialized,
+    // and the OS pages values in as they're read. Encrypted files and those
+    // in an older format are loaded into memory as usual, as are values
+    // written from then on.
+    pub fn load_mapped(path: &Path) -> Result<Self> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(StoreError::IoError(e)),
         };
+        // SAFETY: the database file is only ever replaced by renaming a new
+        // file over it, never written in place, and the database lock keeps
+        // other kv-store processes away from it
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        if encryption::is_encrypted(&map) {
+            log_info!(
+                "{} is encrypted, so it is loaded into memory",
+                path.display()
+            );
+            return Self::load(path);
+        }
 
-        // Serialize the store
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(path)?;
+        let mut body = &map[..];
+        let header = format::read_header(&mut body)?;
+        let start = map.len() - body.len();
+        let end = format::trailer_start(&map).filter(|end| *end >= start);
+        // Older files are migrated as a whole, and damaged ones are best
+        // reported by the usual loader
+        let Some(end) = end.filter(|_| header.format == FORMAT_VERSION) else {
+            return Self::load(path);
+        };
 
-        let writer = BufWriter::new(file);
-        serde_json::to_writer_pretty(writer, &temp_store)
-            .map_err(|e| StoreError::SerializationError(e.to_string()))
-    }
+        let mut store = Self::new();
+        store.node_id = header.node_id.clone().unwrap_or_else(format::new_node_id);
+        store.epoch = AtomicU64::new(header.epoch);
+        store.encoding = RwLock::new(header.encoding);
+        let body = &map[start..end];
+        if header.encoding == Encoding::MessagePack {
+            StoreSeed::<&str>::mapped(&store, &map)
+                .deserialize(&mut rmp_serde::Deserializer::from_read_ref(body))
+                .map_err(corrupt)?;
+        } else {
+            let mut deserializer = serde_json::Deserializer::from_slice(body);
+            StoreSeed::<&RawValue>::mapped(&store, &map)
+                .deserialize(&mut deserializer)
+                .map_err(corrupt)?;
+            deserializer.end().map_err(corrupt)?;
+        }
 
-    // Get a value by key (only needs read access)
-    pub fn get(&self, key: &str) -> Option<String> {
-        // Acquire read lock, then look up the key
-        let data = self.data_lock.read().unwrap();
-        data.get(key).cloned() // Return a copy of the value to avoid lifetime issues
+        // The header counts towards the checksum too
+        let mut reader = format::Checksummed::new(&map[..]);
+        reader.consume(end);
+        let records = store.data_lock.read().unwrap().len();
+        format::check_trailer(&mut reader, &header, records)?;
+        store.replay_wal(&wal::wal_path(path))?;
+        Ok(store)
     }
 
-    // Set a value by key (needs write access)
-    pub fn put(&self, key: String, value: String) {
-        // Acquire write lock, then insert the key-value pair
-        let mut data = self.data_lock.write().unwrap();
-        data.insert(key, value);
+    // Load from file in a background thread. Reads of keys that are already
+    // loaded are served immediately; misses, key listings and writes wait
+    // until the whole file has been read.
+    pub fn load_streaming(path: &Path) -> Result<Arc<Self>> {
+        Self::load_streaming_with(path, None)
     }
 
-    // Delete a key (needs write access)
-    pub fn delete(&self, key: &str) -> bool {
-        // Acquire write lock, then remove the key
-        let mut data = self.data_lock.write().unwrap();
-        data.remove(key).is_some()
+    // Keep every entry in an LSM tree in `lsm_dir`. The tree is reopened as
+    // it was left if it is in step with the database file at `path`, which
+    // isn't read then. Otherwise it is rebuilt from the file, streamed in
+    // the background like load_streaming so the file never has to fit in
+    // memory; files in a format older than the current one are still read
+    // whole to be migrated.
+    pub fn load_streaming_lsm(path: &Path, lsm_dir: &Path) -> Result<Arc<Self>> {
+        if let Some(store) = Self::reopen_lsm(path, lsm_dir)? {
+            return Ok(Arc::new(store));
+        }
+        if lsm_dir.exists() {
+            log_info!(
+                "{} has been written since {} was, rebuilding the tree from it",
+                path.display(),
+                lsm_dir.display()
+            );
+        }
+        Self::load_streaming_with(path, Some(lsm_dir))
     }
 
-    // List all keys (only needs read access)
-    pub fn keys(&self) -> Vec<String> {
-        // Acquire read lock, then return a copy of the keys
-        let data = self.data_lock.read().unwrap();
-        data.keys().cloned().collect()
+    // The store as the tree in `lsm_dir` left it, with the writes logged
+    // since then replayed. None if there is no tree yet or the database file
+    // has been written since the tree was last in step with it.
+    fn reopen_lsm(path: &Path, lsm_dir: &Path) -> Result<Option<Self>> {
+        if !lsm_dir.exists() {
+            return Ok(None);
+        }
+        let stamp = file_stamp(path)?;
+        if stamp.is_some() && fs::read_to_string(lsm_dir.join(TREE_STAMP)).ok() != stamp {
+            return Ok(None);
+        }
+        let lsm = Lsm::open(lsm_dir, lsm::DEFAULT_MEMTABLE_SIZE)?;
+        let Some(keys) = tree_number(&lsm, TREE_KEYS)? else {
+            return Ok(None);
+        };
+
+        let mut store = Self::new();
+        // The file's header still names the node and its epoch
+        if stamp.is_some() {
+            let mut reader = BufReader::new(encryption::Reader::new(Faulty::reader(
+                path,
+                File::open(path)?,
+            ))?);
+            let header = format::read_header(&mut reader)?;
+            store.node_id = header.node_id.unwrap_or_else(format::new_node_id);
+            store.epoch = AtomicU64::new(header.epoch);
+            store.encoding = RwLock::new(header.encoding);
+        }
+        store
+            .changelog
+            .set_last_seq(tree_number(&lsm, TREE_SEQ)?.unwrap_or(0));
+        // Later writes must be stamped after the ones in the tree
+        if let Some(hlc) = lsm.get(TREE_HLC)?
+            && let Some(hlc) = String::from_utf8(hlc).ok().and_then(|hlc| hlc.parse().ok())
+        {
+            store.hlc.observe(hlc);
+        }
+        store.lsm_keys.store(keys as usize, Ordering::Relaxed);
+        let _ = store.lsm.set(lsm);
+        store.replay_wal(&wal::wal_path(path))?;
+        log_info!(
+            "Reopened {} with {} keys",
+            lsm_dir.display(),
+            store.loaded_len()
+        );
+        Ok(Some(store))
     }
-}
 
-// Unit tests -> Cannot test private functions inside [ tests/store_tests.rs] thats why we have the test codes here.
+    fn load_streaming_with(path: &Path, lsm_dir: Option<&Path>) -> Result<Arc<Self>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => {
+                    let store = Self::new();
+                    if let Some(dir) = lsm_dir {
+                        store.set_lsm_dir(dir)?;
+                    }
+                    return Ok(Arc::new(store));
+                }
+                _ => return Err(StoreError::IoError(e)),
+            },
+        };
+        let mut reader = format::Checksummed::new(BufReader::new(encryption::Reader::new(
+            Faulty::reader(path, file),
+        )?));
+        let header = format::read_header(&mut reader)?;
+        let version = header.format;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    // use std::path::PathBuf;
-    use std::sync::Arc;
-    use std::thread;
-    use tempfile::tempdir;
+        let store = Arc::new(KeyValueStore {
+            data_lock: RwLock::new(CowMap::default()),
+            data_for_serde: None,
+            key_slots: RwLock::new(Vec::new()),
+            load_state: LoadState::loading(),
+            used_memory: AtomicUsize::new(0),
+            max_memory: AtomicUsize::new(0),
+            max_keys: AtomicUsize::new(0),
+            max_key_length: AtomicUsize::new(0),
+            compression_threshold: AtomicUsize::new(0),
+            spill_threshold: AtomicUsize::new(0),
+            spill_dir: RwLock::new(None),
+            spill_files: AtomicU64::new(0),
+            lsm: OnceLock::new(),
+            lsm_keys: AtomicUsize::new(0),
+            memory_budget: AtomicUsize::new(0),
+            quotas: RwLock::new(Vec::new()),
+            eviction_policy: RwLock::new(EvictionPolicy::default()),
+            evicted_keys: AtomicU64::new(0),
+            expired_keys: AtomicU64::new(0),
+            clock: AtomicU64::new(0),
+            hlc: hlc::Clock::default(),
+            vector_clocks: AtomicBool::new(false),
+            expiry_deferred: AtomicBool::new(false),
+            changes: AtomicU64::new(0),
+            seq_for_serde: 0,
+            changelog: Changelog::default(),
+            notifier: Notifier::default(),
+            wal: OnceLock::new(),
+            node_id: header.node_id.clone().unwrap_or_else(format::new_node_id),
+            epoch: AtomicU64::new(header.epoch),
+            encoding: RwLock::new(header.encoding),
+        });
+        if let Some(dir) = lsm_dir {
+            store.set_lsm_dir(dir)?;
+        }
 
-    #[test]
-    fn test_store_operations() {
-        // Create a new store
-        let store = KeyValueStore::new();
+        let loader = Arc::clone(&store);
+        let wal_path = wal::wal_path(path);
+        let db_path = path.to_path_buf();
+        let path = path.display().to_string();
+        thread::spawn(move || {
+            let start = Instant::now();
 
-        // Test set and get
-        store.put("key1".to_string(), "value1".to_string());
-        assert_eq!(store.get("key1"), Some("value1".to_string()));
+            // Only the current format can be streamed; older files are read
+            // whole so they can be migrated first
+            let result = if header.encoding == Encoding::MessagePack {
+                let mut deserializer = rmp_serde::Deserializer::new(&mut reader);
+                StoreSeed::new(&loader)
+                    .deserialize(&mut deserializer)
+                    .map_err(corrupt)
+            } else if version == FORMAT_VERSION {
+                let mut deserializer = serde_json::Deserializer::from_reader(&mut reader);
+                StoreSeed::new(&loader)
+                    .deserialize(&mut deserializer)
+                    .map_err(corrupt)
+            } else {
+                let mut deserializer = serde_json::Deserializer::from_reader(&mut reader);
+                serde_json::Value::deserialize(&mut deserializer)
+                    .map_err(corrupt)
+                    .and_then(|doc| format::migrate(doc, version))
+                    .and_then(|doc| StoreSeed::new(&loader).deserialize(doc).map_err(corrupt))
+            };
+            let result = result
+                .and_then(|()| {
+                    let records = loader.loaded_len();
+                    format::check_trailer(&mut reader, &header, records)
+                })
+                .and_then(|()| loader.replay_wal(&wal_path))
+                .and_then(|()| match loader.lsm.get() {
+                    Some(lsm) => loader.sync_tree(lsm, &db_path),
+                    None => Ok(()),
+                });
 
-        // Test non-existent key
-        assert_eq!(store.get("nonexistent"), None);
+            match result {
+                Ok(()) => {
+                    let count = loader.loaded_len();
+                    log_info!(
+                        "Loaded {} keys from {} in {:?}",
+                        count,
+                        path,
+                        start.elapsed()
+                    );
+                    loader.load_state.finish(LoadStatus::Loaded);
+                }
+                Err(e) => {
+                    log_error!("Failed to load {}: {}", path, e);
+                    loader.load_state.finish(LoadStatus::Failed(e.to_string()));
+                }
+            }
+        });
 
-        // Test delete
-        assert_eq!(store.delete("key1"), true);
-        assert_eq!(store.get("key1"), None);
+        Ok(store)
+    }
 
-        // Test delete non-existent key
-        assert_eq!(store.delete("nonexistent"), false);
+    // Block until a background load has finished, returning its error if it failed
+    pub fn wait_until_loaded(&self) -> Result<()> {
+        match self.load_state.wait() {
+            LoadStatus::Failed(e) => Err(StoreError::PersistenceError(e)),
+            _ => Ok(()),
+        }
+    }
 
-        // Test keys
-        store.put("keys2".to_string(), "value2".to_string());
-        store.put("keys3".to_string(), "value3".to_string());
-        let keys = store.keys();
-        assert_eq!(keys.len(), 2);
-        assert!(keys.contains(&"keys2".to_string()));
-        assert!(keys.contains(&"keys3".to_string()));
+    // Whether a background load is still reading the file
+    pub fn is_loading(&self) -> bool {
+        self.load_state.loading.load(Ordering::Acquire)
     }
 
-    #[test]
-    fn test_persistence() -> Result<()> {
-        // Create a temporary directory
-        let dir = tempdir()?;
-        let file_path = dir.path().join("test-db.json");
+    // Wait for a background load to finish (successfully or not) without
+    // blocking the thread, for callers on the runtime
+    pub async fn loaded(&self) {
+        let mut finished = self.load_state.finished.subscribe();
+        let _ = finished.wait_for(|finished| *finished).await;
+    }
 
-        // Create and populate a store
+    // Block until a background load has finished (successfully or not)
+    fn wait_for_load(&self) {
+        if self.load_state.loading.load(Ordering::Acquire) {
+            self.load_state.wait();
+        }
+    }
+
+    // Insert a batch of entries read by the streaming loader
+    fn insert_loaded<V: SavedValue>(
+        &self,
+        entries: &mut Vec<(String, SavedEntry<V>)>,
+        file: Option<&Arc<Mmap>>,
+    ) -> Result<()> {
+        let mut data = self.write_data();
+        for (key, saved) in entries.drain(..) {
+            data.page_in(&[&key])?;
+            self.load_locked(&mut data, key, saved, file)?;
+        }
+        Ok(())
+    }
+
+    // Save to file. Writes carry on while the file is written; it holds the
+    // store as it was when the save started.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let snapshot = self.snapshot();
+        snapshot.save(path)?;
+        // The tree must hold at least as much before the log is cut
+        if let Some(lsm) = self.lsm.get() {
+            self.sync_tree(lsm, path)?;
+        }
+        // The file now holds what was logged up to the snapshot
+        if let Some(wal) = self.wal.get()
+            && wal.path() == wal::wal_path(path)
         {
-            let store = KeyValueStore::new();
-            store.put("persist1".to_string(), "value1".to_string());
-            store.put("persist2".to_string(), "value2".to_string());
-            store.save(&file_path)?;
+            wal.truncate_through(snapshot.seq())?;
         }
+        Ok(())
+    }
 
-        // Load the store from disk and verify data
+    // Log every write to `path` before applying it, from now on
+    pub fn open_wal(&self, path: &Path) -> Result<()> {
+        self.wal
+            .set(Wal::open(path)?)
+            .map_err(|_| StoreError::PersistenceError("Write-ahead log already open".to_string()))
+    }
+
+    // Bytes in the write-ahead log, 0 if there is none
+    pub fn wal_size(&self) -> u64 {
+        self.wal.get().map_or(0, Wal::size)
+    }
+
+    // The write-ahead log, for its settings and stats
+    pub fn wal(&self) -> Option<&Wal> {
+        self.wal.get()
+    }
+
+    // Wait until the writes logged so far are on disk. Called once the
+    // write lock is released, so concurrent writers can share an fsync.
+    fn sync_wal(&self) -> Result<()> {
+        match self.wal.get() {
+            Some(wal) => wal.sync(),
+            None => Ok(()),
+        }
+    }
+
+    // For writes that go ahead even if they can't be logged
+    fn sync_wal_or_log(&self) {
+        if let Err(e) = self.sync_wal() {
+            log_error!("Could not sync the write-ahead log: {}", e);
+        }
+    }
+
+    // Apply the writes logged at `path` that the database file doesn't hold
+    // yet, keeping the sequence numbers they had. Called while loading, so
+    // it doesn't wait for the load.
+    fn replay_wal(&self, path: &Path) -> Result<()> {
+        let replayed = self.replay(wal::read(path)?)?;
+        if replayed > 0 {
+            log_info!("Replayed {} writes from {}", replayed, path.display());
+        }
+        Ok(())
+    }
+
+    // Apply logged writes newer than the store, returning how many
+    pub(crate) fn replay(&self, changes: Vec<Change>) -> Result<usize> {
+        let mut data = self.write_data();
+        let mut replayed = 0;
+        for change in changes {
+            if change.seq <= self.changelog.last_seq() {
+                continue;
+            }
+            let key = match &change.op {
+                ChangeOp::Put { key, .. }
+                | ChangeOp::Delete { key }
+                | ChangeOp::Expire { key, .. } => key,
+            };
+            data.page_in(&[key])?;
+            self.changelog.set_last_seq(change.seq - 1);
+            match change.op {
+                ChangeOp::Put { key, value } => {
+                    let stored = self.store_value(value.clone());
+                    let hlc = self.stamp(Some(change.hlc));
+                    let op = ChangeOp::Put {
+                        key: key.clone(),
+                        value,
+                    };
+                    self.record_change(op, hlc)?;
+                    self.insert_locked(&mut data, key, stored, hlc, VectorClock::default());
+                }
+                ChangeOp::Delete { key } => {
+                    self.remove_locked(&mut data, &key, Some(change.hlc), KeyEvent::Deleted);
+                }
+                ChangeOp::Expire { key, at } => {
+                    self.expire_locked(&mut data, &key, at, Some(change.hlc))?;
+                }
+            }
+            self.changelog.set_last_seq(change.seq);
+            replayed += 1;
+        }
+        Ok(replayed)
+    }
+
+    // The store's contents as of now, unaffected by later writes
+    pub fn snapshot(&self) -> StoreSnapshot {
+        self.wait_for_load();
+        // Read the sequence under the same lock so it matches the data
+        let data = self.data_lock.read().unwrap();
+        StoreSnapshot {
+            data: match self.lsm.get() {
+                Some(lsm) => SnapshotData::Tree(lsm.view(), self.key_count(&data)),
+                None => SnapshotData::Map(data.snapshot()),
+            },
+            now: self.expiry_now(),
+            seq: self.changelog.last_seq(),
+            node_id: self.node_id.clone(),
+            epoch: self.epoch(),
+            encoding: self.encoding(),
+        }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        *self.encoding.read().unwrap()
+    }
+
+    // Encode the database file this way from the next save on
+    pub fn set_encoding(&self, encoding: Encoding) {
+        *self.encoding.write().unwrap() = encoding;
+    }
+
+    // Identifies this node's database file
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    // Highest replication epoch this node has seen
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    // Record an epoch we've seen, returning the highest seen before it
+    pub fn raise_epoch(&self, epoch: u64) -> u64 {
+        self.epoch.fetch_max(epoch, Ordering::SeqCst)
+    }
+
+    // Get a value by key (only needs read access)
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.get_versioned(key).map(|(value, _)| value)
+    }
+
+    // A key's value together with its version, read at the same time, for
+    // a compare-and-swap with IF-VERSION
+    pub fn get_versioned(&self, key: &str) -> Option<(String, u64)> {
+        if let Some(found) = self.get_loaded(key) {
+            return Some(found);
+        }
+
+        // A miss is only authoritative once the store has finished loading
+        if self.load_state.loading.load(Ordering::Acquire) {
+            self.wait_for_load();
+            let data = self.data_lock.read().unwrap();
+            return self
+                .read_live(&data, key)
+                .map(|entry| (self.touch(&entry), entry.version));
+        }
+        None
+    }
+
+    // Like `get_versioned`, but never waits: a key a background load
+    // hasn't reached yet is missing
+    pub fn get_loaded(&self, key: &str) -> Option<(String, u64)> {
+        let data = self.data_lock.read().unwrap();
+        self.read_live(&data, key)
+            .map(|entry| (self.touch(&entry), entry.version))
+    }
+
+    // Keys loaded so far, without waiting for a background load
+    pub fn loaded_len(&self) -> usize {
+        self.key_count(&self.data_lock.read().unwrap())
+    }
+
+    // Number of keys, counting those in the LSM tree with --engine lsm
+    fn key_count(&self, data: &CowMap<Entry>) -> usize {
+        match self.lsm.get() {
+            Some(_) => self.lsm_keys.load(Ordering::Relaxed),
+            None => data.len(),
+        }
+    }
+
+    // The entry for `key` unless it has expired. Expired keys stay hidden
+    // until the sweeper removes them.
+    fn live<'a>(&self, data: &'a CowMap<Entry>, key: &str) -> Option<&'a Entry> {
+        data.get(key)
+            .filter(|entry| !entry.is_expired(self.expiry_now()))
+    }
+
+    // Like `live`, for reads. With --engine lsm the map is empty while no
+    // write holds the lock, so the entry is read from the tree.
+    fn read_live<'a>(&self, data: &'a CowMap<Entry>, key: &str) -> Option<Cow<'a, Entry>> {
+        let Some(lsm) = self.lsm.get() else {
+            return self.live(data, key).map(Cow::Borrowed);
+        };
+        let entry = lsm.get(&tree_key(key)).and_then(|bytes| {
+            bytes
+                .map(|bytes| decode_entry(&bytes)?.into_entry(0))
+                .transpose()
+        });
+        match entry {
+            Ok(entry) => entry
+                .filter(|entry| !entry.is_expired(self.expiry_now()))
+                .map(Cow::Owned),
+            Err(e) => {
+                log_error!("Could not read '{}' from the LSM tree: {}", key, e);
+                None
+            }
+        }
+    }
+
+    // Take the write lock on the data
+    fn write_data(&self) -> DataGuard<'_> {
+        DataGuard {
+            store: self,
+            data: self.data_lock.write().unwrap(),
+            paged: HashMap::new(),
+        }
+    }
+
+    // Write the entries a write paged in back to the tree in one batch,
+    // along with the store's bookkeeping, and drop them from memory
+    fn write_back(
+        &self,
+        lsm: &Lsm,
+        data: &mut CowMap<Entry>,
+        mut paged: HashMap<String, PagedEntry>,
+    ) {
+        // Keys written without being paged in first weren't in the tree
+        for (key, _) in data.iter() {
+            if !paged.contains_key(key) {
+                paged.insert(key.clone(), None);
+            }
+        }
+
+        let mut batch = Vec::new();
+        for (key, before) in &paged {
+            let entry = data.get(key);
+            let after = match entry.map(encode_entry).transpose() {
+                Ok(after) => after,
+                Err(e) => {
+                    log_error!("Could not write '{}' to the LSM tree: {}", key, e);
+                    continue;
+                }
+            };
+            let (before, old_expires) = match before {
+                Some((bytes, expires)) => (Some(bytes), *expires),
+                None => (None, 0),
+            };
+            if after.as_ref() == before {
+                continue;
+            }
+            let expires = entry.map_or(0, |entry| entry.expires);
+            if expires != old_expires {
+                if old_expires != 0 {
+                    batch.push((expiry_key(old_expires, key), None));
+                }
+                if expires != 0 {
+                    batch.push((expiry_key(expires, key), Some(Vec::new())));
+                }
+            }
+            batch.push((tree_key(key), after));
+        }
+        if !batch.is_empty() {
+            batch.extend(self.tree_meta());
+            lsm.write(batch);
+        }
+
+        for key in paged.keys() {
+            data.remove(key);
+        }
+        self.key_slots.write().unwrap().clear();
+        self.used_memory.store(0, Ordering::Relaxed);
+    }
+
+    // The store's bookkeeping as kept in the tree, so a restart carries on
+    // from the same sequence number, key count and clock
+    fn tree_meta(&self) -> [(Vec<u8>, Option<Vec<u8>>); 3] {
+        let seq = self.changelog.last_seq();
+        let keys = self.lsm_keys.load(Ordering::Relaxed) as u64;
+        [
+            (TREE_SEQ.to_vec(), Some(seq.to_le_bytes().to_vec())),
+            (TREE_KEYS.to_vec(), Some(keys.to_le_bytes().to_vec())),
+            (
+                TREE_HLC.to_vec(),
+                Some(self.hlc.current().to_string().into_bytes()),
+            ),
+        ]
+    }
+
+    // Write everything the tree holds in memory out to its tables, and note
+    // that it is in step with the database file at `path` if it belongs to
+    // that file
+    fn sync_tree(&self, lsm: &Lsm, path: &Path) -> Result<()> {
         {
-            let store = KeyValueStore::load(&file_path)?;
-            assert_eq!(store.get("persist1"), Some("value1".to_string()));
-            assert_eq!(store.get("persist2"), Some("value2".to_string()));
+            let _data = self.data_lock.read().unwrap();
+            lsm.write(self.tree_meta());
+        }
+        lsm.flush()?;
+        if lsm_dir(path) == lsm.dir()
+            && let Some(stamp) = file_stamp(path)?
+        {
+            fs::write(lsm.dir().join(TREE_STAMP), stamp)?;
+        }
+        Ok(())
+    }
+
+    // A key picked from the tree by `View::random_key`, which may land on
+    // a deleted key or one of the store's own records, so a few picks are
+    // tried before settling for the first key
+    fn tree_random_key(&self, view: &lsm::View) -> Option<String> {
+        if self.lsm_keys.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        for _ in 0..RANDOM_KEY_TRIES {
+            let key = match view.random_key() {
+                Ok(key) => key?,
+                Err(e) => {
+                    log_error!("Could not read the LSM tree: {}", e);
+                    return None;
+                }
+            };
+            if key.first() == Some(&TREE_ENTRY)
+                && let Ok(Some(_)) = view.get(&key)
+                && let Ok(key) = String::from_utf8(key[1..].to_vec())
+            {
+                return Some(key);
+            }
+        }
+        tree_entries(view, b"").next().map(|(key, _)| key)
+    }
+
+    // Mark an entry as just used and return its value
+    fn touch(&self, entry: &Entry) -> String {
+        self.mark_used(entry);
+        entry.hits.fetch_add(1, Ordering::Relaxed);
+        entry.value.text()
+    }
+
+    fn mark_used(&self, entry: &Entry) {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        entry.last_access.store(now, Ordering::Relaxed);
+    }
+
+    // Set a value by key (needs write access)
+    pub fn put(&self, key: String, value: String) {
+        self.put_stamped(key, value, None, None);
+    }
+
+    // Apply a write another node took at `hlc`, keeping its timestamp. With
+    // vector clocks on, a write we've already seen is skipped and one made
+    // concurrently with ours is kept alongside it as a sibling.
+    pub fn put_at(&self, key: String, value: String, hlc: Timestamp, vclock: Option<VectorClock>) {
+        self.put_stamped(key, value, Some(hlc), vclock);
+    }
+
+    fn put_stamped(
+        &self,
+        key: String,
+        value: String,
+        origin: Option<Timestamp>,
+        remote: Option<VectorClock>,
+    ) {
+        // Acquire write lock, then insert the key-value pair
+        self.wait_for_load();
+        let stored = self.store_value(value.clone());
+        let mut data = self.write_data();
+        if !data.page_in_or_log(&[&key]) {
+            return;
+        }
+        // CRDT state from another node is merged into ours rather than
+        // replacing it, so it never conflicts
+        let merged = origin.and_then(|_| self.merged_crdt(&data, &key, &value));
+        let (value, stored, remote) = match merged {
+            Some(merged) => (merged.clone(), self.store_value(merged), None),
+            None => (value, stored, remote),
+        };
+        let vclock = match remote {
+            Some(remote) if self.tracks_vector_clocks() => {
+                let seen = data.get(&key).map(|entry| remote.compare(&entry.vclock));
+                match seen {
+                    None | Some(Some(cmp::Ordering::Greater)) => remote,
+                    Some(Some(_)) => return,
+                    Some(None) => {
+                        self.stamp(origin);
+                        self.add_sibling(&mut data, &key, value, &remote);
+                        return;
+                    }
+                }
+            }
+            // From a node that doesn't track them
+            _ if origin.is_some() => VectorClock::default(),
+            _ => self.next_vclock(&data, &key),
+        };
+        let hlc = self.stamp(origin);
+        let op = ChangeOp::Put {
+            key: key.clone(),
+            value,
+        };
+        // Replicated writes are applied regardless, to stay in step
+        if let Err(e) = self.record_change(op, hlc) {
+            log_error!("Could not log write to '{}': {}", key, e);
+        }
+        self.insert_locked(&mut data, key, stored, hlc, vclock);
+        drop(data);
+        self.sync_wal_or_log();
+    }
+
+    // `value` merged into the CRDT `key` holds, if both are CRDTs of the same
+    // type
+    fn merged_crdt(&self, data: &CowMap<Entry>, key: &str, value: &str) -> Option<String> {
+        let theirs = Crdt::parse(value)?;
+        let mut ours = Crdt::parse(&self.live(data, key)?.value.text())?;
+        ours.merge(&theirs).ok()?;
+        Some(ours.to_string())
+    }
+
+    // Keep a write made concurrently with the key's current value next to it
+    fn add_sibling(
+        &self,
+        data: &mut CowMap<Entry>,
+        key: &str,
+        value: String,
+        vclock: &VectorClock,
+    ) {
+        let Some(entry) = data.get_mut(key) else {
+            return;
+        };
+        entry.vclock.merge(vclock);
+        if entry.value.text() != value && !entry.siblings.contains(&value) {
+            entry.siblings.push(value);
+        }
+        self.notifier.publish(KeyEvent::Set, key);
+        log_warn!(
+            "Concurrent writes to '{}', keeping {} values",
+            key,
+            entry.siblings.len() + 1
+        );
+        self.changes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Clock for a write made here: every write to the key we know of, plus
+    // this one. Empty with vector clocks off.
+    fn next_vclock(&self, data: &CowMap<Entry>, key: &str) -> VectorClock {
+        if !self.tracks_vector_clocks() {
+            return VectorClock::default();
+        }
+        let mut vclock = data
+            .get(key)
+            .map(|entry| entry.vclock.clone())
+            .unwrap_or_default();
+        vclock.increment(&self.node_id);
+        vclock
+    }
+
+    // Time of a write: the origin's timestamp for writes from another node,
+    // otherwise our own. Called with the write lock held.
+    fn stamp(&self, origin: Option<Timestamp>) -> Timestamp {
+        match origin {
+            Some(hlc) if !hlc.is_zero() => {
+                self.hlc.observe(hlc);
+                hlc
+            }
+            _ => self.hlc.now(),
+        }
+    }
+
+    // The clock writes are stamped with
+    pub fn hlc(&self) -> &hlc::Clock {
+        &self.hlc
+    }
+
+    // Set a value on behalf of a client, enforcing the store's limits
+    pub fn try_put(&self, key: String, value: String) -> Result<()> {
+        self.try_put_if(key, value, &Condition::Always).map(|_| ())
+    }
+
+    // Like `try_put`, but only if `condition` holds, checked under the same
+    // lock as the write. Returns whether the write was applied.
+    pub fn try_put_if(&self, key: String, value: String, condition: &Condition) -> Result<bool> {
+        self.try_put_expiring(key, value, condition, 0)
+    }
+
+    // Like `try_put_if`, also setting the key to expire at `expires` (unix
+    // milliseconds, 0 = never) in the same write
+    pub fn try_put_expiring(
+        &self,
+        key: String,
+        value: String,
+        condition: &Condition,
+        expires: u64,
+    ) -> Result<bool> {
+        self.wait_for_load();
+        self.check_key_length(&key)?;
+
+        // Spill or compress before taking the lock. A spilled file is
+        // removed again if the write doesn't go ahead.
+        let stored = self.store_value(value.clone());
+        let applied = self.try_insert(key, value, stored, condition, expires)?;
+        if applied {
+            self.sync_wal()?;
+        }
+        Ok(applied)
+    }
+
+    fn check_key_length(&self, key: &str) -> Result<()> {
+        let max_key_length = self.max_key_length.load(Ordering::Relaxed);
+        if max_key_length > 0 && key.len() > max_key_length {
+            return Err(StoreError::LimitError(format!(
+                "key length {} exceeds max_key_length of {}",
+                key.len(),
+                max_key_length
+            )));
+        }
+        Ok(())
+    }
+
+    // The locked part of `try_put_if`
+    fn try_insert(
+        &self,
+        key: String,
+        value: String,
+        stored: StoredValue,
+        condition: &Condition,
+        expires: u64,
+    ) -> Result<bool> {
+        let mut data = self.write_data();
+        data.page_in(&[&key])?;
+
+        let current = self.live(&data, &key);
+        let conflicted = current.is_some_and(|entry| !entry.siblings.is_empty());
+        let applies = match condition {
+            Condition::Always => true,
+            Condition::Absent => current.is_none(),
+            Condition::Value(expected) => {
+                current.is_some_and(|entry| &entry.value.text() == expected)
+            }
+            Condition::Version(expected) => current.map_or(0, |entry| entry.version) == *expected,
+            Condition::Conflicted => conflicted,
+        };
+        if !applies {
+            return Ok(false);
+        }
+        // Only a resolve may pick one of the values
+        if conflicted && *condition != Condition::Conflicted {
+            return Err(StoreError::ConflictError(format!(
+                "'{}' has concurrent values, RESOLVE it first",
+                key
+            )));
+        }
+        self.write_locked(&mut data, key, value, stored, expires)?;
+        Ok(true)
+    }
+
+    // Write a key while holding the write lock, once any condition has been
+    // checked: enforce quotas and limits, evicting if the policy allows,
+    // then record and apply the write
+    fn write_locked(
+        &self,
+        data: &mut CowMap<Entry>,
+        key: String,
+        value: String,
+        stored: StoredValue,
+        expires: u64,
+    ) -> Result<()> {
+        let old_size = data.get(&key).map_or(0, |old| entry_size(&key, &old.value));
+        for quota in self.quotas.read().unwrap().iter() {
+            if quota.matches(&key) {
+                quota.check(old_size, entry_size(&key, &stored))?;
+            }
+        }
+
+        // Writes that don't grow the store are always allowed; others may
+        // first evict keys to make room, depending on the policy
+        let max_memory = self.max_memory_limit();
+        let max_keys = self.max_keys.load(Ordering::Relaxed);
+        loop {
+            let used = self.used_memory();
+            let old_size = data.get(&key).map_or(0, |old| entry_size(&key, &old.value));
+            let new_used = used - old_size + entry_size(&key, &stored);
+            let over_memory = max_memory > 0 && new_used > max_memory && new_used > used;
+            let over_keys = max_keys > 0 && old_size == 0 && self.key_count(data) >= max_keys;
+            if !over_memory && !over_keys {
+                break;
+            }
+
+            let Some(victim) = self.eviction_victim(data, &[&key]) else {
+                return Err(limit_error(over_keys, max_keys, max_memory));
+            };
+            self.remove_locked(data, &victim, None, KeyEvent::Evicted);
+            self.evicted_keys.fetch_add(1, Ordering::Relaxed);
+        }
+        self.commit_locked(data, key, value, stored, expires)
+    }
+
+    // Check a batch of writes against the quotas and the store's limits as
+    // a whole, evicting other keys to make room if the policy allows, so
+    // either all of it fits or none of it is written
+    fn make_room_for_many(
+        &self,
+        data: &mut CowMap<Entry>,
+        entries: &[(String, String)],
+        stored: &[StoredValue],
+    ) -> Result<()> {
+        // The last write to a key is the one that stays
+        let mut sizes: HashMap<&str, usize> = HashMap::new();
+        for ((key, _), stored) in entries.iter().zip(stored) {
+            sizes.insert(key, entry_size(key, stored));
+        }
+        let totals = |matches: &dyn Fn(&str) -> bool| {
+            let (mut old, mut new, mut added) = (0, 0, 0);
+            for (key, &size) in sizes.iter().filter(|(key, _)| matches(key)) {
+                let old_size = data.get(key).map_or(0, |old| entry_size(key, &old.value));
+                old += old_size;
+                new += size;
+                added += usize::from(old_size == 0);
+            }
+            (old, new, added)
+        };
+        for quota in self.quotas.read().unwrap().iter() {
+            let (old, new, added) = totals(&|key| quota.matches(key));
+            quota.check_many(old, new, added)?;
+        }
+
+        // Keys of the batch are never evicted for it
+        let (old, new, added) = totals(&|_| true);
+        let writing: Vec<&str> = sizes.keys().copied().collect();
+        let max_memory = self.max_memory_limit();
+        let max_keys = self.max_keys.load(Ordering::Relaxed);
+        loop {
+            let used = self.used_memory();
+            let new_used = used - old + new;
+            let over_memory = max_memory > 0 && new_used > max_memory && new_used > used;
+            let over_keys = max_keys > 0 && added > 0 && self.key_count(data) + added > max_keys;
+            if !over_memory && !over_keys {
+                return Ok(());
+            }
+
+            let Some(victim) = self.eviction_victim(data, &writing) else {
+                return Err(limit_error(over_keys, max_keys, max_memory));
+            };
+            self.remove_locked(data, &victim, None, KeyEvent::Evicted);
+            self.evicted_keys.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // maxmemory as enforced, 0 with --engine lsm where entries aren't held
+    // in memory
+    fn max_memory_limit(&self) -> usize {
+        match self.lsm.get() {
+            Some(_) => 0,
+            None => self.max_memory.load(Ordering::Relaxed),
+        }
+    }
+
+    // Log and apply a write that has been checked against the limits
+    fn commit_locked(
+        &self,
+        data: &mut CowMap<Entry>,
+        key: String,
+        value: String,
+        stored: StoredValue,
+        expires: u64,
+    ) -> Result<()> {
+        let hlc = self.stamp(None);
+        let vclock = self.next_vclock(data, &key);
+        self.record_change(
+            ChangeOp::Put {
+                key: key.clone(),
+                value,
+            },
+            hlc,
+        )?;
+        self.insert_locked(data, key.clone(), stored, hlc, vclock);
+        if expires != 0 {
+            self.expire_locked(data, &key, expires, None)?;
+        }
+        Ok(())
+    }
+
+    // Add `delta` to the integer `key` holds, a missing key counting as 0,
+    // and return the result. The key keeps its expiry time.
+    pub fn increment(&self, key: &str, delta: i64) -> Result<i64> {
+        let value = self.update(key, |current| {
+            let current = match current {
+                Some(text) => text
+                    .parse::<i64>()
+                    .map_err(|_| StoreError::ValueError(format!("'{}' is not an integer", key)))?,
+                None => 0,
+            };
+            let value = current.checked_add(delta).ok_or_else(|| {
+                StoreError::ValueError(format!("incrementing '{}' would overflow", key))
+            })?;
+            Ok(value.to_string())
+        })?;
+        Ok(value.parse().unwrap_or_default())
+    }
+
+    // Add `suffix` to the end of the value `key` holds, a missing key
+    // counting as empty, and return the new value. The key keeps its expiry
+    // time.
+    pub fn append(&self, key: &str, suffix: &str) -> Result<String> {
+        self.update(key, |current| Ok(current.unwrap_or_default() + suffix))
+    }
+
+    // Change the CRDT `key` holds, a missing key counting as an empty one of
+    // the type the update is for, and return its new state. The key keeps
+    // its expiry time.
+    pub fn update_crdt(&self, key: &str, update: Update) -> Result<String> {
+        let at = self.hlc.now();
+        self.update(key, |current| {
+            let mut crdt = match current {
+                Some(text) => Crdt::parse(&text).ok_or_else(|| {
+                    StoreError::ValueError(format!("'{}' doesn't hold a {}", key, update.kind()))
+                })?,
+                None => Crdt::empty_for(&update),
+            };
+            crdt.apply(update, &self.node_id, at)
+                .map_err(|e| StoreError::ValueError(format!("'{}' {}", key, e)))?;
+            Ok(crdt.to_string())
+        })
+    }
+
+    // Merge CRDT state another node sent into what `key` holds, a missing
+    // key taking it as it is. Returns the merged state.
+    pub fn merge_crdt(&self, key: &str, state: &str) -> Result<String> {
+        let Some(theirs) = Crdt::parse(state) else {
+            return Err(StoreError::ValueError(format!(
+                "Invalid CRDT state for '{}'",
+                key
+            )));
+        };
+        self.update(key, |current| {
+            let Some(text) = current else {
+                return Ok(theirs.to_string());
+            };
+            let mut ours = Crdt::parse(&text).ok_or_else(|| {
+                StoreError::ValueError(format!("'{}' doesn't hold a {}", key, theirs.kind()))
+            })?;
+            ours.merge(&theirs)
+                .map_err(|e| StoreError::ValueError(format!("'{}' {}", key, e)))?;
+            Ok(ours.to_string())
+        })
+    }
+
+    // Length in bytes of the value `key` holds, None if it doesn't exist
+    pub fn value_len(&self, key: &str) -> Option<usize> {
+        self.wait_for_load();
+        let data = self.data_lock.read().unwrap();
+        self.read_live(&data, key)
+            .map(|entry| entry.value.text_len())
+    }
+
+    // Replace the value `key` holds (None if it doesn't exist) with what
+    // `update` makes of it, under the write lock so no other write can come
+    // in between. Returns the new value. The key keeps its expiry time.
+    fn update(
+        &self,
+        key: &str,
+        update: impl FnOnce(Option<String>) -> Result<String>,
+    ) -> Result<String> {
+        self.wait_for_load();
+        self.check_key_length(key)?;
+        let mut data = self.write_data();
+        data.page_in(&[key])?;
+        let (current, expires) = match self.unconflicted(&data, key)? {
+            Some(entry) => (Some(entry.value.text()), entry.expires),
+            None => (None, 0),
+        };
+
+        let value = update(current)?;
+        let stored = self.store_value(value.clone());
+        self.write_locked(&mut data, key.to_string(), value.clone(), stored, expires)?;
+        drop(data);
+        self.sync_wal()?;
+        Ok(value)
+    }
+
+    // Store several values under one hold of the write lock, so readers see
+    // all of them or none. Nothing is written if any of them is unfit or the
+    // batch would go over a limit. Returns how many were written, the first
+    // ones in order, with the error that stopped the rest if one couldn't be
+    // logged.
+    pub fn put_many(&self, entries: &[(String, String)]) -> (usize, Result<()>) {
+        self.wait_for_load();
+        // Nothing is written if a key is unfit
+        if let Err(e) = entries
+            .iter()
+            .try_for_each(|(key, _)| self.check_key_length(key))
+        {
+            return (0, Err(e));
+        }
+        let stored: Vec<StoredValue> = entries
+            .iter()
+            .map(|(_, value)| self.store_value(value.clone()))
+            .collect();
+
+        let mut data = self.write_data();
+        let keys: Vec<&str> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        if let Err(e) = data.page_in(&keys) {
+            return (0, Err(e));
+        }
+        if let Err(e) = entries
+            .iter()
+            .try_for_each(|(key, _)| self.unconflicted(&data, key).map(|_| ()))
+        {
+            return (0, Err(e));
+        }
+        if let Err(e) = self.make_room_for_many(&mut data, entries, &stored) {
+            return (0, Err(e));
+        }
+        let mut written = 0;
+        let mut result = Ok(());
+        for ((key, value), stored) in entries.iter().zip(stored) {
+            result = self.commit_locked(&mut data, key.clone(), value.clone(), stored, 0);
+            if result.is_err() {
+                break;
+            }
+            written += 1;
+        }
+        drop(data);
+        if written > 0 {
+            result = result.and(self.sync_wal());
+        }
+        (written, result)
+    }
+
+    // Values of several keys, read at the same moment
+    pub fn get_many(&self, keys: &[&str]) -> Vec<Option<String>> {
+        self.wait_for_load();
+        let data = self.data_lock.read().unwrap();
+        keys.iter()
+            .map(|key| self.read_live(&data, key).map(|entry| self.touch(&entry)))
+            .collect()
+    }
+
+    // Store `value` and return what `key` held before, None if it didn't
+    // exist. Like a put, this clears the key's expiry time.
+    pub fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        self.wait_for_load();
+        self.check_key_length(&key)?;
+        let stored = self.store_value(value.clone());
+        let mut data = self.write_data();
+        data.page_in(&[&key])?;
+        let old = self
+            .unconflicted(&data, &key)?
+            .map(|entry| entry.value.text());
+        self.write_locked(&mut data, key, value, stored, 0)?;
+        drop(data);
+        self.sync_wal()?;
+        Ok(old)
+    }
+
+    // The live entry for `key`, which a write based on its value can't
+    // replace while it holds concurrent values
+    fn unconflicted<'a>(&self, data: &'a CowMap<Entry>, key: &str) -> Result<Option<&'a Entry>> {
+        match self.live(data, key) {
+            Some(entry) if !entry.siblings.is_empty() => Err(StoreError::ConflictError(format!(
+                "'{}' has concurrent values, RESOLVE it first",
+                key
+            ))),
+            entry => Ok(entry),
+        }
+    }
+
+    // Value of `key`, storing `default` first if the key doesn't exist.
+    // Returns the value and whether it was stored.
+    pub fn get_or_set(&self, key: String, default: String) -> Result<(String, bool)> {
+        loop {
+            if let Some(value) = self.get(&key) {
+                return Ok((value, false));
+            }
+            // Retry if someone else created the key (and maybe deleted it) in between
+            if self.try_put_if(key.clone(), default.clone(), &Condition::Absent)? {
+                return Ok((default, true));
+            }
+        }
+    }
+
+    // How to hold a newly written value: spilled to disk,
+    // compressed, or as is
+    fn store_value(&self, value: String) -> StoredValue {
+        if let Some(path) = self.spill_path(value.len()) {
+            match StoredValue::spill(&value, path) {
+                Ok(stored) => return stored,
+                Err(e) => log_error!("Could not spill value, keeping it in memory: {}", e),
+            }
+        }
+        StoredValue::new(value, self.compression_threshold())
+    }
+
+    // Spill `value` to disk if it is over the spill threshold
+    fn spill_large(&self, value: StoredValue) -> StoredValue {
+        if value.is_spilled() || value.is_mapped() {
+            return value;
+        }
+        let Some(path) = self.spill_path(value.text_len()) else {
+            return value;
+        };
+        match StoredValue::spill(&value.text(), path) {
+            Ok(spilled) => spilled,
+            Err(e) => {
+                log_error!("Could not spill value, keeping it in memory: {}", e);
+                value
+            }
+        }
+    }
+
+    // File for the next spilled value, None if a value of `len` bytes stays
+    // in memory. Entries in the LSM tree are on disk already.
+    fn spill_path(&self, len: usize) -> Option<PathBuf> {
+        let threshold = self.spill_threshold.load(Ordering::Relaxed);
+        if threshold == 0 || len < threshold || self.lsm.get().is_some() {
+            return None;
+        }
+        self.next_spill_file()
+    }
+
+    fn next_spill_file(&self) -> Option<PathBuf> {
+        let dir = self.spill_dir.read().unwrap().clone()?;
+        Some(dir.join(self.spill_files.fetch_add(1, Ordering::Relaxed).to_string()))
+    }
+
+    // Spill values already in the store that are over the threshold
+    fn spill_existing(&self) {
+        let mut data = self.data_lock.write().unwrap();
+        let quotas = self.quotas.read().unwrap();
+        for (key, entry) in data.iter_mut() {
+            if entry.value.is_spilled() || entry.value.is_mapped() {
+                continue;
+            }
+            let Some(path) = self.spill_path(entry.value.text_len()) else {
+                continue;
+            };
+            match StoredValue::spill(&entry.value.text(), path) {
+                Ok(spilled) => self.swap_value(&quotas, key, entry, spilled),
+                Err(e) => log_error!("Could not spill value, keeping it in memory: {}", e),
+            }
+        }
+    }
+
+    // Replace an entry's value in place, keeping memory accounting in sync
+    fn swap_value(&self, quotas: &[Quota], key: &str, entry: &mut Entry, value: StoredValue) {
+        let old_size = entry_size(key, &entry.value);
+        let new_size = entry_size(key, &value);
+        entry.value = value;
+
+        self.used_memory.fetch_sub(old_size, Ordering::Relaxed);
+        self.used_memory.fetch_add(new_size, Ordering::Relaxed);
+        for quota in quotas.iter().filter(|quota| quota.matches(key)) {
+            quota.remove(old_size);
+            quota.add(new_size);
+        }
+    }
+
+    // Move values between memory and the spill directory to keep memory use
+    // within the budget: the coldest values are demoted when over it, and
+    // cold values read often since the last pass are promoted while they fit.
+    // Returns how many values were demoted and promoted.
+    pub fn rebalance_tiers(&self) -> (usize, usize) {
+        let budget = self.memory_budget.load(Ordering::Relaxed);
+        // Values in the LSM tree aren't held in memory to begin with
+        if budget == 0 || self.spill_dir.read().unwrap().is_none() || self.lsm.get().is_some() {
+            return (0, 0);
+        }
+
+        let mut data = self.data_lock.write().unwrap();
+        let quotas = self.quotas.read().unwrap();
+        let spill_threshold = self.spill_threshold.load(Ordering::Relaxed);
+        let (mut demoted, mut promoted) = (0, 0);
+
+        // Hot candidates first, ordered by how often they were read
+        let mut cold: Vec<(&String, &mut Entry)> = Vec::new();
+        let mut hot: Vec<(&String, &mut Entry)> = Vec::new();
+        for (key, entry) in data.iter_mut() {
+            // Mapped values are paged in and out by the OS
+            if entry.value.is_mapped() {
+                continue;
+            }
+            if !entry.value.is_spilled() {
+                hot.push((key, entry));
+            } else if entry.hits.load(Ordering::Relaxed) >= PROMOTE_HITS
+                // Values over the spill threshold always stay on disk
+                && (spill_threshold == 0 || entry.value.text_len() < spill_threshold)
+            {
+                cold.push((key, entry));
+            }
+        }
+
+        if self.used_memory() > budget {
+            hot.sort_by_key(|(_, entry)| {
+                (
+                    entry.hits.load(Ordering::Relaxed),
+                    entry.last_access.load(Ordering::Relaxed),
+                )
+            });
+            for (key, entry) in hot {
+                if self.used_memory() <= budget {
+                    break;
+                }
+                let Some(path) = self.next_spill_file() else {
+                    break;
+                };
+                match StoredValue::spill(&entry.value.text(), path) {
+                    Ok(spilled) => {
+                        self.swap_value(&quotas, key, entry, spilled);
+                        demoted += 1;
+                    }
+                    Err(e) => {
+                        log_error!("Could not demote {}: {}", key, e);
+                        break;
+                    }
+                }
+            }
+        } else {
+            cold.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.hits.load(Ordering::Relaxed)));
+            for (key, entry) in cold {
+                let value = StoredValue::new(entry.value.text(), self.compression_threshold());
+                let grows_by =
+                    entry_size(key, &value).saturating_sub(entry_size(key, &entry.value));
+                if self.used_memory() + grows_by > budget {
+                    continue;
+                }
+                self.swap_value(&quotas, key, entry, value);
+                promoted += 1;
+            }
+        }
+
+        for entry in data.values() {
+            entry.hits.store(0, Ordering::Relaxed);
         }
+        (demoted, promoted)
+    }
+
+    // Insert while holding the write lock, keeping memory accounting in sync
+    fn insert_locked(
+        &self,
+        data: &mut CowMap<Entry>,
+        key: String,
+        value: StoredValue,
+        hlc: Timestamp,
+        vclock: VectorClock,
+    ) {
+        let size = entry_size(&key, &value);
+        let key_len = key.len();
+        let now = unix_millis();
+        let old = data.get(&key);
+        let slot = old.map_or_else(
+            || {
+                let mut slots = self.key_slots.write().unwrap();
+                slots.push(key.clone());
+                slots.len() - 1
+            },
+            |old| old.slot,
+        );
+        let entry = Entry {
+            value,
+            version: old.map_or(1, |old| old.version + 1),
+            created: old.map_or(now, |old| old.created),
+            updated: now,
+            last_access: AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed)),
+            hits: AtomicU32::new(0),
+            slot,
+            hlc,
+            vclock,
+            siblings: Vec::new(),
+            expires: 0,
+        };
+        let quotas = self.quotas.read().unwrap();
+        let quotas: Vec<&Quota> = quotas.iter().filter(|quota| quota.matches(&key)).collect();
+        // Subscribers can't look before the lock is released
+        self.notifier.publish(KeyEvent::Set, &key);
+        match data.insert(key, entry) {
+            Some(old) => {
+                let old_size = key_len + old.value.size();
+                self.used_memory.fetch_sub(old_size, Ordering::Relaxed);
+                quotas.iter().for_each(|quota| quota.remove(old_size));
+            }
+            None if self.lsm.get().is_some() => {
+                self.lsm_keys.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {}
+        }
+        self.used_memory.fetch_add(size, Ordering::Relaxed);
+        quotas.iter().for_each(|quota| quota.add(size));
+    }
+
+    // Insert an entry read from the database file, keeping its metadata. Its
+    // value is read in place if it was borrowed from the mapped `file`.
+    fn load_locked<V: SavedValue>(
+        &self,
+        data: &mut CowMap<Entry>,
+        key: String,
+        saved: SavedEntry<V>,
+        file: Option<&Arc<Mmap>>,
+    ) -> Result<()> {
+        let value = self.spill_large(saved.value.into_stored(saved.compressed, file)?);
+        // Later writes must be stamped after the ones we saved
+        self.hlc.observe(saved.hlc);
+        self.insert_locked(data, key.clone(), value, saved.hlc, saved.vclock);
+        if let Some(entry) = data.get_mut(&key) {
+            entry.version = saved.version;
+            entry.created = saved.created;
+            entry.updated = saved.updated;
+            entry.siblings = saved.siblings;
+            entry.expires = saved.expires;
+        }
+        Ok(())
+    }
+
+    // Remove while holding the write lock, recording the delete and
+    // notifying subscribers with `event`. Returns the removed value.
+    fn remove_locked(
+        &self,
+        data: &mut CowMap<Entry>,
+        key: &str,
+        origin: Option<Timestamp>,
+        event: KeyEvent,
+    ) -> Option<String> {
+        if !data.contains_key(key) {
+            return None;
+        }
+        let op = ChangeOp::Delete {
+            key: key.to_string(),
+        };
+        // Evictions, expiries and replicated deletes go ahead even if they
+        // can't be logged
+        if let Err(e) = self.record_change(op, self.stamp(origin)) {
+            log_error!("Could not log delete of '{}': {}", key, e);
+        }
+        self.unlink_locked(data, key, event)
+    }
+
+    // Remove a key on behalf of a client, leaving it in place if the delete
+    // can't be logged
+    fn try_remove_locked(&self, data: &mut CowMap<Entry>, key: &str) -> Result<Option<String>> {
+        if !data.contains_key(key) {
+            return Ok(None);
+        }
+        let op = ChangeOp::Delete {
+            key: key.to_string(),
+        };
+        self.record_change(op, self.stamp(None))?;
+        Ok(self.unlink_locked(data, key, KeyEvent::Deleted))
+    }
+
+    // Take a key out of the map and its bookkeeping once its delete is logged
+    fn unlink_locked(
+        &self,
+        data: &mut CowMap<Entry>,
+        key: &str,
+        event: KeyEvent,
+    ) -> Option<String> {
+        match data.remove(key) {
+            Some(old) => {
+                if self.lsm.get().is_some() {
+                    self.lsm_keys.fetch_sub(1, Ordering::Relaxed);
+                }
+                self.notifier.publish(event, key);
+                // Fill the hole with the last key
+                let mut slots = self.key_slots.write().unwrap();
+                slots.swap_remove(old.slot);
+                if let Some(moved) = slots.get(old.slot)
+                    && let Some(entry) = data.get_mut(moved)
+                {
+                    entry.slot = old.slot;
+                }
+                drop(slots);
+
+                let size = entry_size(key, &old.value);
+                self.used_memory.fetch_sub(size, Ordering::Relaxed);
+                for quota in self.quotas.read().unwrap().iter() {
+                    if quota.matches(key) {
+                        quota.remove(size);
+                    }
+                }
+                Some(old.value.text())
+            }
+            None => None,
+        }
+    }
+
+    // Key to evict under the current policy, never one of those being written
+    fn eviction_victim(&self, data: &CowMap<Entry>, writing: &[&str]) -> Option<String> {
+        // Only the keys being written are in memory with --engine lsm
+        if self.lsm.get().is_some() {
+            return None;
+        }
+        let mut candidates = data
+            .iter()
+            .filter(|(key, _)| !writing.contains(&key.as_str()));
+        match *self.eviction_policy.read().unwrap() {
+            EvictionPolicy::NoEviction => None,
+            EvictionPolicy::AllKeysLru => candidates
+                .min_by_key(|(_, entry)| entry.last_access.load(Ordering::Relaxed))
+                .map(|(key, _)| key.clone()),
+            EvictionPolicy::AllKeysRandom => {
+                let count =
+                    data.len() - writing.iter().filter(|key| data.contains_key(key)).count();
+                candidates
+                    .nth(random_index(count))
+                    .map(|(key, _)| key.clone())
+            }
+            EvictionPolicy::VolatileTtl => candidates
+                .filter(|(_, entry)| entry.expires != 0)
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone()),
+        }
+    }
+
+    // Number a write, log it ahead if the write-ahead log is on, and publish
+    // it. Called with the write lock held, before the write is applied, so
+    // sequence numbers follow commit order. Nothing is recorded if the write
+    // can't be logged. The log is synced after the lock is released.
+    fn record_change(&self, op: ChangeOp, hlc: Timestamp) -> Result<()> {
+        if let Some(wal) = self.wal.get() {
+            wal.append(self.changelog.last_seq() + 1, hlc, &op)?;
+        }
+        self.changes.fetch_add(1, Ordering::Relaxed);
+        let seq = self.changelog.record(op, hlc);
+        RECORDED.with_borrow_mut(|recorded| {
+            if let Some(seqs) = recorded {
+                seqs.push(seq);
+            }
+        });
+        Ok(())
+    }
+
+    // Run `f` and also return the sequence numbers given to the changes it
+    // made, in commit order. Only changes made on the calling thread count.
+    pub fn recording<R>(&self, f: impl FnOnce() -> R) -> (R, Vec<u64>) {
+        let outer = RECORDED.replace(Some(Vec::new()));
+        let result = f();
+        let seqs = RECORDED.replace(outer).unwrap_or_default();
+        (result, seqs)
+    }
+
+    // Delete a key (needs write access)
+    pub fn delete(&self, key: &str) -> bool {
+        // Acquire write lock, then remove the key
+        self.wait_for_load();
+        let mut data = self.write_data();
+        if !data.page_in_or_log(&[key]) {
+            return false;
+        }
+        let removed = self
+            .remove_locked(&mut data, key, None, KeyEvent::Deleted)
+            .is_some();
+        drop(data);
+        self.sync_wal_or_log();
+        removed
+    }
+
+    // Delete a key on behalf of a client, failing if the delete can't be
+    // logged. Returns whether the key existed.
+    pub fn try_delete(&self, key: &str) -> Result<bool> {
+        Ok(self.try_take(key)?.is_some())
+    }
+
+    // Apply a delete another node took at `hlc`
+    pub fn delete_at(&self, key: &str, hlc: Timestamp) -> bool {
+        self.wait_for_load();
+        let mut data = self.write_data();
+        if !data.page_in_or_log(&[key]) {
+            return false;
+        }
+        let removed = self
+            .remove_locked(&mut data, key, Some(hlc), KeyEvent::Deleted)
+            .is_some();
+        drop(data);
+        self.sync_wal_or_log();
+        removed
+    }
+
+    // Give `dst` the value and expiry time of `src` and remove `src`, in one
+    // step. Whatever `dst` held is replaced. Returns false if `src` doesn't
+    // exist.
+    pub fn rename(&self, src: &str, dst: &str) -> Result<bool> {
+        self.copy_stamped(src, dst, true, true, None)
+    }
+
+    // Give `dst` the value and expiry time of `src`, unless `dst` exists and
+    // `replace` isn't set. Returns whether it was copied.
+    pub fn copy(&self, src: &str, dst: &str, replace: bool) -> Result<bool> {
+        self.copy_stamped(src, dst, replace, false, None)
+    }
+
+    // Apply a rename another node made at `hlc`
+    pub fn rename_at(&self, src: &str, dst: &str, hlc: Timestamp) -> bool {
+        self.copy_replicated(src, dst, true, hlc)
+    }
+
+    // Apply a copy another node made at `hlc`
+    pub fn copy_at(&self, src: &str, dst: &str, hlc: Timestamp) -> bool {
+        self.copy_replicated(src, dst, false, hlc)
+    }
+
+    fn copy_replicated(&self, src: &str, dst: &str, remove_src: bool, hlc: Timestamp) -> bool {
+        match self.copy_stamped(src, dst, true, remove_src, Some(hlc)) {
+            Ok(copied) => copied,
+            Err(e) => {
+                log_error!("Could not copy '{}' to '{}': {}", src, dst, e);
+                false
+            }
+        }
+    }
+
+    fn copy_stamped(
+        &self,
+        src: &str,
+        dst: &str,
+        replace: bool,
+        remove_src: bool,
+        origin: Option<Timestamp>,
+    ) -> Result<bool> {
+        self.wait_for_load();
+        self.check_key_length(dst)?;
+        let mut data = self.write_data();
+        data.page_in(&[src, dst])?;
+        let Some(entry) = self.unconflicted(&data, src)? else {
+            return Ok(false);
+        };
+        if !replace && self.live(&data, dst).is_some() {
+            return Ok(false);
+        }
+        if src == dst {
+            return Ok(true);
+        }
+
+        let (value, expires) = (entry.value.text(), entry.expires);
+        let stored = self.store_value(value.clone());
+        match origin {
+            None => self.write_locked(&mut data, dst.to_string(), value, stored, expires)?,
+            // Replicated writes are applied regardless, to stay in step
+            Some(_) => {
+                let hlc = self.stamp(origin);
+                let op = ChangeOp::Put {
+                    key: dst.to_string(),
+                    value,
+                };
+                if let Err(e) = self.record_change(op, hlc) {
+                    log_error!("Could not log write to '{}': {}", dst, e);
+                }
+                self.insert_locked(
+                    &mut data,
+                    dst.to_string(),
+                    stored,
+                    hlc,
+                    VectorClock::default(),
+                );
+                if expires != 0
+                    && let Err(e) = self.expire_locked(&mut data, dst, expires, origin)
+                {
+                    log_error!("Could not log expiry of '{}': {}", dst, e);
+                }
+            }
+        }
+        if remove_src {
+            match origin {
+                None => {
+                    self.try_remove_locked(&mut data, src)?;
+                }
+                Some(_) => {
+                    self.remove_locked(&mut data, src, origin, KeyEvent::Deleted);
+                }
+            }
+        }
+        drop(data);
+        if origin.is_some() {
+            self.sync_wal_or_log();
+        } else {
+            self.sync_wal()?;
+        }
+        Ok(true)
+    }
+
+    // Delete several keys under one hold of the write lock, returning how
+    // many there were
+    pub fn delete_many(&self, keys: &[&str]) -> usize {
+        self.delete_many_stamped(keys, None)
+    }
+
+    // Apply a multi-key delete another node took at `hlc`
+    pub fn delete_many_at(&self, keys: &[&str], hlc: Timestamp) -> usize {
+        self.delete_many_stamped(keys, Some(hlc))
+    }
+
+    // Like `delete_many`, on behalf of a client. Returns how many of the
+    // keys were gone through, the first ones in order, with how many of
+    // those existed or the error that stopped the rest if a delete couldn't
+    // be logged.
+    pub fn try_delete_many(&self, keys: &[&str]) -> (usize, Result<usize>) {
+        self.wait_for_load();
+        let mut data = self.write_data();
+        if let Err(e) = data.page_in(keys) {
+            return (0, Err(e));
+        }
+        let mut done = 0;
+        let mut removed = 0;
+        for key in keys {
+            match self.try_remove_locked(&mut data, key) {
+                Ok(value) => removed += usize::from(value.is_some()),
+                Err(e) => return (done, Err(e)),
+            }
+            done += 1;
+        }
+        drop(data);
+        (done, self.sync_wal().map(|()| removed))
+    }
+
+    fn delete_many_stamped(&self, keys: &[&str], origin: Option<Timestamp>) -> usize {
+        self.wait_for_load();
+        let mut data = self.write_data();
+        if !data.page_in_or_log(keys) {
+            return 0;
+        }
+        let removed = keys
+            .iter()
+            .filter(|key| {
+                self.remove_locked(&mut data, key, origin, KeyEvent::Deleted)
+                    .is_some()
+            })
+            .count();
+        drop(data);
+        self.sync_wal_or_log();
+        removed
+    }
+
+    // Delete a key, returning the value it held
+    pub fn take(&self, key: &str) -> Option<String> {
+        self.wait_for_load();
+        let mut data = self.write_data();
+        if !data.page_in_or_log(&[key]) {
+            return None;
+        }
+        let value = self.remove_locked(&mut data, key, None, KeyEvent::Deleted);
+        drop(data);
+        self.sync_wal_or_log();
+        value
+    }
+
+    // Like `take`, on behalf of a client: fails if the delete can't be
+    // logged
+    pub fn try_take(&self, key: &str) -> Result<Option<String>> {
+        self.wait_for_load();
+        let mut data = self.write_data();
+        data.page_in(&[key])?;
+        let value = self.try_remove_locked(&mut data, key)?;
+        drop(data);
+        self.sync_wal()?;
+        Ok(value)
+    }
+
+    // Make `key` expire at `expires` (unix milliseconds, 0 = never). Returns
+    // whether the key exists.
+    pub fn expire(&self, key: &str, expires: u64) -> Result<bool> {
+        self.expire_stamped(key, expires, None)
+    }
+
+    // Apply an expiry time another node set at `hlc`
+    pub fn expire_at(&self, key: &str, expires: u64, hlc: Timestamp) -> bool {
+        match self.expire_stamped(key, expires, Some(hlc)) {
+            Ok(found) => found,
+            // Replicated writes are applied regardless, to stay in step
+            Err(e) => {
+                log_error!("Could not log expiry of '{}': {}", key, e);
+                true
+            }
+        }
+    }
+
+    fn expire_stamped(&self, key: &str, expires: u64, origin: Option<Timestamp>) -> Result<bool> {
+        self.wait_for_load();
+        let mut data = self.write_data();
+        data.page_in(&[key])?;
+        if self.live(&data, key).is_none() {
+            return Ok(false);
+        }
+        let result = self.expire_locked(&mut data, key, expires, origin);
+        drop(data);
+        if origin.is_some() {
+            self.sync_wal_or_log();
+        } else {
+            self.sync_wal()?;
+        }
+        result.map(|()| true)
+    }
+
+    // Remove a key's expiry time. Returns whether it had one.
+    pub fn persist(&self, key: &str) -> Result<bool> {
+        self.wait_for_load();
+        let mut data = self.write_data();
+        data.page_in(&[key])?;
+        if self.live(&data, key).is_none_or(|entry| entry.expires == 0) {
+            return Ok(false);
+        }
+        let result = self.expire_locked(&mut data, key, 0, None);
+        drop(data);
+        self.sync_wal()?;
+        result.map(|()| true)
+    }
+
+    // Count a key as just used and, if it has an expiry time and `expires`
+    // is given, move the time there. Returns the key's expiry time
+    // afterwards (0 if none), None if it doesn't exist.
+    pub fn touch_key(&self, key: &str, expires: Option<u64>) -> Result<Option<u64>> {
+        self.wait_for_load();
+        let mut data = self.write_data();
+        data.page_in(&[key])?;
+        let Some(entry) = self.live(&data, key) else {
+            return Ok(None);
+        };
+        self.mark_used(entry);
+        let Some(expires) = expires.filter(|_| entry.expires != 0) else {
+            return Ok(Some(entry.expires));
+        };
+        let result = self.expire_locked(&mut data, key, expires, None);
+        drop(data);
+        self.sync_wal()?;
+        result.map(|()| Some(expires))
+    }
+
+    // Seconds until a key expires, rounded up: -1 if it never does, -2 if it
+    // doesn't exist
+    pub fn ttl(&self, key: &str) -> i64 {
+        match self.metadata(key) {
+            None => -2,
+            Some(meta) if meta.expires == 0 => -1,
+            Some(meta) => meta.expires.saturating_sub(unix_millis()).div_ceil(1000) as i64,
+        }
+    }
+
+    // Set an existing key's expiry time while holding the write lock,
+    // recording the change. The time is set even if it can't be logged.
+    fn expire_locked(
+        &self,
+        data: &mut CowMap<Entry>,
+        key: &str,
+        expires: u64,
+        origin: Option<Timestamp>,
+    ) -> Result<()> {
+        let op = ChangeOp::Expire {
+            key: key.to_string(),
+            at: expires,
+        };
+        let recorded = self.record_change(op, self.stamp(origin));
+        if let Some(entry) = data.get_mut(key) {
+            entry.expires = expires;
+            let event = if expires == 0 {
+                KeyEvent::Persist
+            } else {
+                KeyEvent::Expire
+            };
+            self.notifier.publish(event, key);
+        }
+        recorded
+    }
+
+    // Remove the keys whose time has come, returning how many there were.
+    // Each is recorded as a delete.
+    pub fn remove_expired(&self) -> usize {
+        self.due_keys()
+            .iter()
+            .filter(|key| self.remove_if_expired(key))
+            .count()
+    }
+
+    // Keys whose time has come, which the sweeper is to remove. None while
+    // expiry is left to another node.
+    pub fn due_keys(&self) -> Vec<String> {
+        self.wait_for_load();
+        let now = self.expiry_now();
+        if let Some(lsm) = self.lsm.get() {
+            return tree_due_keys(&lsm.view(), now).unwrap_or_else(|e| {
+                log_error!("Could not read expiry times from the LSM tree: {}", e);
+                Vec::new()
+            });
+        }
+        let data = self.data_lock.read().unwrap();
+        data.iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    // Remove `key` if its time has come, recording a delete. Returns
+    // whether it did; the key may have been written again meanwhile.
+    pub fn remove_if_expired(&self, key: &str) -> bool {
+        self.wait_for_load();
+        let now = self.expiry_now();
+        let mut data = self.write_data();
+        let removed = data.page_in_or_log(&[key])
+            && data.get(key).is_some_and(|entry| entry.is_expired(now))
+            && self
+                .remove_locked(&mut data, key, None, KeyEvent::Expired)
+                .is_some();
+        drop(data);
+        if removed {
+            self.sync_wal_or_log();
+            self.expired_keys.fetch_add(1, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    pub fn expired_keys(&self) -> u64 {
+        self.expired_keys.load(Ordering::Relaxed)
+    }
+
+    // Approximate bytes used by keys and values
+    pub fn used_memory(&self) -> usize {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+
+    // Memory cap enforced on client writes, 0 for unlimited
+    pub fn max_memory(&self) -> usize {
+        self.max_memory.load(Ordering::Relaxed)
+    }
+
+    pub fn set_max_memory(&self, bytes: usize) {
+        self.max_memory.store(bytes, Ordering::Relaxed);
+    }
+
+    // Cap on the number of keys client writes may create, 0 for unlimited
+    pub fn max_keys(&self) -> usize {
+        self.max_keys.load(Ordering::Relaxed)
+    }
+
+    pub fn set_max_keys(&self, count: usize) {
+        self.max_keys.store(count, Ordering::Relaxed);
+    }
+
+    // Longest key client writes may use, in bytes, 0 for unlimited
+    pub fn max_key_length(&self) -> usize {
+        self.max_key_length.load(Ordering::Relaxed)
+    }
+
+    pub fn set_max_key_length(&self, bytes: usize) {
+        self.max_key_length.store(bytes, Ordering::Relaxed);
+    }
+
+    // Size from which values are stored compressed, 0 for never
+    pub fn compression_threshold(&self) -> usize {
+        self.compression_threshold.load(Ordering::Relaxed)
+    }
+
+    pub fn set_compression_threshold(&self, bytes: usize) {
+        self.compression_threshold.store(bytes, Ordering::Relaxed);
+    }
+
+    // Size from which values are spilled to disk, 0 for never
+    pub fn spill_threshold(&self) -> usize {
+        self.spill_threshold.load(Ordering::Relaxed)
+    }
+
+    pub fn set_spill_threshold(&self, bytes: usize) {
+        self.spill_threshold.store(bytes, Ordering::Relaxed);
+        self.spill_existing();
+    }
+
+    // Spill large values into `dir`. Anything already in it is left over from
+    // a previous run, since the database file holds spilled values inline.
+    pub fn set_spill_dir(&self, dir: &Path) -> Result<()> {
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+        fs::create_dir_all(dir)?;
+        *self.spill_dir.write().unwrap() = Some(dir.to_path_buf());
+        self.spill_existing();
+        Ok(())
+    }
+
+    // Keep every entry in a new LSM tree in `dir` from now on, moving the
+    // entries already in the store there too. Anything already in `dir` is
+    // replaced.
+    pub fn set_lsm_dir(&self, dir: &Path) -> Result<()> {
+        if self.lsm.get().is_some() {
+            return Err(StoreError::ConfigError(
+                "Entries are already kept in an LSM tree".to_string(),
+            ));
+        }
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+        let lsm = Lsm::open(dir, lsm::DEFAULT_MEMTABLE_SIZE)?;
+
+        let mut data = self.data_lock.write().unwrap();
+        self.lsm_keys.store(data.len(), Ordering::Relaxed);
+        if self.lsm.set(lsm).is_err() {
+            return Err(StoreError::ConfigError(
+                "Entries are already kept in an LSM tree".to_string(),
+            ));
+        }
+        let paged = data.iter().map(|(key, _)| (key.clone(), None)).collect();
+        self.write_back(self.lsm.get().unwrap(), &mut data, paged);
+        Ok(())
+    }
+
+    // The LSM tree entries are kept in, if any
+    pub fn lsm(&self) -> Option<&Arc<Lsm>> {
+        self.lsm.get()
+    }
+
+    // Memory hot values may use before cold ones move to disk, 0 for no tiering
+    pub fn memory_budget(&self) -> usize {
+        self.memory_budget.load(Ordering::Relaxed)
+    }
+
+    pub fn set_memory_budget(&self, bytes: usize) {
+        self.memory_budget.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn tracks_vector_clocks(&self) -> bool {
+        self.vector_clocks.load(Ordering::Relaxed)
+    }
+
+    pub fn set_vector_clocks(&self, on: bool) {
+        self.vector_clocks.store(on, Ordering::Relaxed);
+    }
+
+    // Leave expiry to another node, whose deletes we apply
+    pub fn set_expiry_deferred(&self, deferred: bool) {
+        self.expiry_deferred.store(deferred, Ordering::Relaxed);
+    }
+
+    // The time keys are checked against for expiry: now, or before any
+    // expiry time when that is left to another node
+    fn expiry_now(&self) -> u64 {
+        if self.expiry_deferred.load(Ordering::Relaxed) {
+            0
+        } else {
+            unix_millis()
+        }
+    }
+
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        *self.eviction_policy.read().unwrap()
+    }
+
+    pub fn set_eviction_policy(&self, policy: EvictionPolicy) {
+        *self.eviction_policy.write().unwrap() = policy;
+    }
+
+    // Replace the configured quotas, counting what already lives under them
+    pub fn set_quotas(&self, limits: &BTreeMap<String, QuotaLimits>) {
+        let data = self.data_lock.read().unwrap();
+        let quotas: Vec<Quota> = limits
+            .iter()
+            .map(|(pattern, limits)| Quota::new(pattern, *limits))
+            .collect();
+        let add = |key: &str, entry: &Entry| {
+            for quota in quotas.iter().filter(|quota| quota.matches(key)) {
+                quota.add(entry_size(key, &entry.value));
+            }
+        };
+        match self.lsm.get() {
+            Some(lsm) => tree_entries(&lsm.view(), b"").for_each(|(key, entry)| add(&key, &entry)),
+            None => data.iter().for_each(|(key, entry)| add(key, entry)),
+        }
+        *self.quotas.write().unwrap() = quotas;
+    }
+
+    // Usage of every quota, or just the one configured as `pattern`
+    pub fn quota_usage(&self, pattern: Option<&str>) -> Vec<String> {
+        self.quotas
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|quota| pattern.is_none_or(|pattern| quota.pattern() == pattern))
+            .map(|quota| quota.to_string())
+            .collect()
+    }
+
+    // Keys evicted to stay under max_memory since startup
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted_keys.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.wait_for_load();
+        self.key_count(&self.data_lock.read().unwrap())
+    }
+
+    // Number of writes since the last successful save
+    pub fn changes_since_save(&self) -> u64 {
+        self.changes.load(Ordering::Relaxed)
+    }
+
+    // Forget the writes a save has captured. Writes that landed while the
+    // save was running stay counted.
+    pub fn mark_saved(&self, changes: u64) {
+        self.changes.fetch_sub(changes, Ordering::Relaxed);
+    }
+
+    // Log of recent writes, for SYNC consumers
+    pub fn changelog(&self) -> &Changelog {
+        &self.changelog
+    }
+
+    // Changes from `from_seq` onwards, plus a receiver for everything
+    // committed after them. Those the changelog no longer buffers are read
+    // from the write-ahead log if it still has them, so this can block on
+    // the disk.
+    pub fn changes_from(
+        &self,
+        from_seq: u64,
+    ) -> Result<(Vec<Change>, broadcast::Receiver<Change>)> {
+        let unbuffered = match self.changelog.subscribe(from_seq) {
+            Ok(subscription) => return Ok(subscription),
+            Err(e) => e,
+        };
+        let Some(wal) = self.wal.get() else {
+            return Err(unbuffered);
+        };
+        // Subscribed first, so a change logged while we read isn't missed
+        let receiver = self.changelog.follow();
+        let logged = match wal::read_from(wal.path(), from_seq) {
+            Ok(logged) => logged,
+            Err(e) => {
+                log_warn!(
+                    "Could not read the write-ahead log from seq {}: {}",
+                    from_seq,
+                    e
+                );
+                return Err(unbuffered);
+            }
+        };
+        let complete = !logged.is_empty()
+            && logged
+                .iter()
+                .zip(from_seq.max(1)..)
+                .all(|(change, seq)| change.seq == seq);
+        if complete {
+            Ok((logged, receiver))
+        } else {
+            Err(unbuffered)
+        }
+    }
+
+    // Follow keyspace notifications from now on
+    pub fn notifications(&self) -> broadcast::Receiver<Notification> {
+        self.notifier.subscribe()
+    }
+
+    // Load key/value pairs from a JSON lines file, one `{"key": ..., "value": ...}`
+    // per line. Unless `overwrite` is set this only happens on first boot, when
+    // the store is empty. Returns the number of pairs written.
+    pub fn seed(&self, path: &Path, overwrite: bool) -> Result<usize> {
+        if !overwrite && !self.is_empty() {
+            return Ok(0);
+        }
+
+        let reader = BufReader::new(File::open(path)?);
+        let mut count = 0;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let entry: SeedEntry = serde_json::from_str(&line).map_err(|e| {
+                StoreError::SerializationError(format!("{} line {}: {}", path.display(), i + 1, e))
+            })?;
+            self.put(entry.key, entry.value);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.wait_for_load();
+        let data = self.data_lock.read().unwrap();
+        match self.lsm.get() {
+            Some(_) => self.key_count(&data) == 0,
+            None => data.is_empty(),
+        }
+    }
+
+    // Copy of every entry, made without holding up writes
+    pub fn entries(&self) -> HashMap<String, String> {
+        self.snapshot().entries()
+    }
+
+    // Number of writes to a key since it was created, 0 if it doesn't exist
+    pub fn version(&self, key: &str) -> u64 {
+        self.metadata(key).map_or(0, |meta| meta.version)
+    }
+
+    // Version and timestamps of a key, without counting as an access
+    pub fn metadata(&self, key: &str) -> Option<KeyMetadata> {
+        self.wait_for_load();
+        let data = self.data_lock.read().unwrap();
+        self.read_live(&data, key).map(|entry| KeyMetadata {
+            version: entry.version,
+            created: entry.created,
+            updated: entry.updated,
+            compressed: entry.value.is_compressed(),
+            spilled: entry.value.is_spilled(),
+            hlc: entry.hlc,
+            vclock: entry.vclock.clone(),
+            siblings: entry.siblings.len(),
+            expires: entry.expires,
+        })
+    }
+
+    // Every value a key holds while concurrent writes to it are unresolved,
+    // None if it holds just one
+    pub fn siblings(&self, key: &str) -> Option<Vec<String>> {
+        // A key that's already loaded has all of its values
+        if self
+            .read_live(&self.data_lock.read().unwrap(), key)
+            .is_none()
+        {
+            self.wait_for_load();
+        }
+        let data = self.data_lock.read().unwrap();
+        let entry = self
+            .read_live(&data, key)
+            .filter(|entry| !entry.siblings.is_empty())?;
+        let mut values = vec![entry.value.text()];
+        values.extend(entry.siblings.iter().cloned());
+        Some(values)
+    }
+
+    // A key picked uniformly at random, None if the store is empty. With
+    // --engine lsm keys in larger tables are likelier to be picked.
+    pub fn random_key(&self) -> Option<String> {
+        self.wait_for_load();
+        if let Some(lsm) = self.lsm.get() {
+            return self.tree_random_key(&lsm.view());
+        }
+        let slots = self.key_slots.read().unwrap();
+        if slots.is_empty() {
+            return None;
+        }
+        Some(slots[random_index(slots.len())].clone())
+    }
+
+    // Up to `count` distinct keys picked uniformly at random
+    pub fn sample(&self, count: usize) -> Vec<String> {
+        self.wait_for_load();
+        if let Some(lsm) = self.lsm.get() {
+            let view = lsm.view();
+            if count >= self.len() {
+                return tree_entries(&view, b"").map(|(key, _)| key).collect();
+            }
+            let mut picked = HashSet::with_capacity(count);
+            for _ in 0..count * RANDOM_KEY_TRIES {
+                if picked.len() == count {
+                    break;
+                }
+                picked.extend(self.tree_random_key(&view));
+            }
+            return picked.into_iter().collect();
+        }
+        let slots = self.key_slots.read().unwrap();
+        if count >= slots.len() {
+            return slots.clone();
+        }
+
+        let mut picked = HashSet::with_capacity(count);
+        while picked.len() < count {
+            picked.insert(random_index(slots.len()));
+        }
+        picked.into_iter().map(|slot| slots[slot].clone()).collect()
+    }
+
+    // Up to `limit` keys starting with `prefix`, in sorted order, starting
+    // after `after`. Only the requested page is sorted.
+    pub fn list(&self, prefix: &str, after: Option<&str>, limit: usize) -> Vec<String> {
+        self.wait_for_load();
+        let now = self.expiry_now();
+        if let Some(lsm) = self.lsm.get() {
+            // The tree is in key order already
+            let from = after.filter(|after| *after > prefix).unwrap_or(prefix);
+            return tree_entries(&lsm.view(), from.as_bytes())
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .filter(|(key, entry)| Some(key.as_str()) != after && !entry.is_expired(now))
+                .take(limit)
+                .map(|(key, _)| key)
+                .collect();
+        }
+        let data = self.data_lock.read().unwrap();
+        let mut keys: Vec<&String> = data
+            .iter()
+            .filter(|(key, entry)| {
+                key.starts_with(prefix)
+                    && after.is_none_or(|after| key.as_str() > after)
+                    && !entry.is_expired(now)
+            })
+            .map(|(key, _)| key)
+            .collect();
+
+        if keys.len() > limit {
+            keys.select_nth_unstable(limit);
+            keys.truncate(limit);
+        }
+        keys.sort_unstable();
+        keys.into_iter().cloned().collect()
+    }
+
+    // Number of live keys starting with `prefix` and the bytes their values
+    // take up, without copying any of them
+    pub fn count(&self, prefix: &str) -> (usize, usize) {
+        self.wait_for_load();
+        let now = self.expiry_now();
+        let add = |(keys, bytes), entry: &Entry| (keys + 1, bytes + entry.value.text_len());
+        if let Some(lsm) = self.lsm.get() {
+            return tree_entries(&lsm.view(), prefix.as_bytes())
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .filter(|(_, entry)| !entry.is_expired(now))
+                .fold((0, 0), |totals, (_, entry)| add(totals, &entry));
+        }
+        let data = self.data_lock.read().unwrap();
+        data.iter()
+            .filter(|(key, entry)| key.starts_with(prefix) && !entry.is_expired(now))
+            .fold((0, 0), |totals, (_, entry)| add(totals, entry))
+    }
+
+    // One page of a SCAN: of the `count` keys from `cursor` on, those that
+    // start with `prefix` and, past it, match the glob `pattern`, with the
+    // cursor for the next page (0 when done). A page may hold fewer keys
+    // than `count`, even none. Reads a snapshot, so writes aren't held up.
+    pub fn scan(
+        &self,
+        cursor: u64,
+        count: usize,
+        prefix: &str,
+        pattern: Option<&str>,
+    ) -> (Vec<String>, u64) {
+        self.wait_for_load();
+        let now = self.expiry_now();
+        let wanted = |key: &str, entry: &Entry| {
+            !entry.is_expired(now)
+                && key
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| pattern.is_none_or(|pattern| glob_match(pattern, rest)))
+        };
+        if let Some(lsm) = self.lsm.get() {
+            return Self::scan_tree(&lsm.view(), cursor, count, wanted);
+        }
+        let data = self.data_lock.read().unwrap().snapshot();
+        let (page, next) = data.scan(cursor, count);
+        let keys = page
+            .into_iter()
+            .filter(|(key, entry)| wanted(key, entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+        (keys, next)
+    }
+
+    // A page of a SCAN through the tree. The cursor is where the next page
+    // starts, as `scan_cursor` has it, so keys written meanwhile are seen
+    // if they sort after it.
+    fn scan_tree(
+        view: &lsm::View,
+        cursor: u64,
+        count: usize,
+        wanted: impl Fn(&str, &Entry) -> bool,
+    ) -> (Vec<String>, u64) {
+        let mut from = cursor.to_be_bytes().to_vec();
+        while from.last() == Some(&0) {
+            from.pop();
+        }
+        let (mut keys, mut seen) = (Vec::new(), 0);
+        for (key, entry) in tree_entries(view, &from) {
+            // The next page has to start past this one's cursor
+            let next = scan_cursor(&key);
+            if seen >= count.max(1) && next != cursor {
+                return (keys, next);
+            }
+            seen += 1;
+            if wanted(&key, &entry) {
+                keys.push(key);
+            }
+        }
+        (keys, 0)
+    }
+
+    // List all keys (only needs read access)
+    pub fn keys(&self) -> Vec<String> {
+        // Acquire read lock, then return a copy of the keys
+        self.wait_for_load();
+        let now = self.expiry_now();
+        if let Some(lsm) = self.lsm.get() {
+            return tree_entries(&lsm.view(), b"")
+                .filter(|(_, entry)| !entry.is_expired(now))
+                .map(|(key, _)| key)
+                .collect();
+        }
+        let data = self.data_lock.read().unwrap();
+        data.iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+}
+
+// The store's contents at one moment, for saves, exports and full syncs.
+// Taking one is cheap, and reading it doesn't hold up writes.
+pub struct StoreSnapshot {
+    data: SnapshotData,
+    now: u64, // What keys are checked against for expiry
+    seq: u64,
+    node_id: String,
+    epoch: u64,
+    encoding: Encoding,
+}
+
+// The entries a snapshot holds: a snapshot of the map, or with --engine lsm
+// a view of the tree and how many keys it held
+enum SnapshotData {
+    Map(cow::Snapshot<Entry>),
+    Tree(lsm::View, usize),
+}
+
+impl StoreSnapshot {
+    pub fn len(&self) -> usize {
+        match &self.data {
+            SnapshotData::Map(data) => data.len(),
+            SnapshotData::Tree(_, len) => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Sequence number of the last write included
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    // Expired keys are left out, as they are from the store
+    pub fn get(&self, key: &str) -> Option<String> {
+        let entry = match &self.data {
+            SnapshotData::Map(data) => data.get(key).map(Cow::Borrowed),
+            SnapshotData::Tree(view, _) => {
+                let entry = view.get(&tree_key(key)).and_then(|bytes| {
+                    bytes
+                        .map(|bytes| decode_entry(&bytes)?.into_entry(0))
+                        .transpose()
+                });
+                match entry {
+                    Ok(entry) => entry.map(Cow::Owned),
+                    Err(e) => {
+                        log_error!("Could not read '{}' from the LSM tree: {}", key, e);
+                        None
+                    }
+                }
+            }
+        };
+        entry
+            .filter(|entry| !entry.is_expired(self.now))
+            .map(|entry| entry.value.text())
+    }
+
+    pub fn entries(&self) -> HashMap<String, String> {
+        let now = self.now;
+        let live = |(key, entry): (String, &Entry)| {
+            (!entry.is_expired(now)).then(|| (key, entry.value.text()))
+        };
+        match &self.data {
+            SnapshotData::Map(data) => data
+                .iter()
+                .filter_map(|(key, entry)| live((key.clone(), entry)))
+                .collect(),
+            SnapshotData::Tree(view, _) => tree_entries(view, b"")
+                .filter_map(|(key, entry)| live((key, &entry)))
+                .collect(),
+        }
+    }
+
+    // Write the snapshot as a database file. It goes to a temporary file
+    // next to it first, which replaces the old file only once it is synced,
+    // so a crash part way through leaves the previous save intact.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+
+        let result = self
+            .write(path, &temp)
+            .and_then(|()| Ok(fs::rename(&temp, path)?))
+            .and_then(|()| sync_parent(path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
+
+    // Write to `temp`, with any disk faults injected for `path`
+    fn write(&self, path: &Path, temp: &Path) -> Result<()> {
+        let file = File::create(temp)?;
+
+        let saved = SavedStore {
+            data: SavedEntries(&self.data),
+            seq: self.seq,
+        };
+        let mut writer = format::Checksummed::new(encryption::Writer::new(BufWriter::new(
+            Faulty::writer(path, &file),
+        ))?);
+        let header = Header::current(&self.node_id, self.epoch, self.encoding);
+        format::write_header(&mut writer, &header)?;
+        match self.encoding {
+            Encoding::Json => {
+                serde_json::to_writer_pretty(&mut writer, &saved).map_err(serialization_error)?
+            }
+            // As maps, so fields can be left out or added later like in JSON
+            Encoding::MessagePack => rmp_serde::encode::write_named(&mut writer, &saved)
+                .map_err(|e| StoreError::SerializationError(e.to_string()))?,
+        }
+        writeln!(writer)?;
+        let (mut writer, checksum) = writer.into_parts();
+        format::write_trailer(&mut writer, self.len(), checksum)?;
+        drop(writer.finish()?);
+        faults::sync(path, &file)?;
+        Ok(())
+    }
+}
+
+// The database file's contents, written straight from a snapshot
+#[derive(Serialize)]
+struct SavedStore<'a> {
+    data: SavedEntries<'a>,
+    seq: u64,
+}
+
+struct SavedEntries<'a>(&'a SnapshotData);
+
+impl Serialize for SavedEntries<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let (view, len) = match self.0 {
+            SnapshotData::Map(data) => {
+                return serializer
+                    .collect_map(data.iter().map(|(key, entry)| (key, entry.to_saved())));
+            }
+            SnapshotData::Tree(view, len) => (view, *len),
+        };
+        // The count is written ahead of the entries and in the trailer, so
+        // a tree that can't be read in full fails the save
+        let mut map = serializer.serialize_map(Some(len))?;
+        let mut written = 0;
+        for (key, entry) in tree_entries(view, b"") {
+            map.serialize_entry(&key, &entry.to_saved())?;
+            written += 1;
+        }
+        if written != len {
+            return Err(S::Error::custom(format!(
+                "LSM tree holds {} keys, expected {}",
+                written, len
+            )));
+        }
+        map.end()
+    }
+}
+
+// Sync the directory holding `path`, so a file just renamed into it stays
+// there after a crash
+pub(crate) fn sync_parent(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// Bytes a database file and its write-ahead log take up on disk
+pub fn disk_usage(db_path: &Path) -> Result<u64> {
+    let file = fs::metadata(db_path).map_or(0, |meta| meta.len());
+    Ok(file + wal::disk_size(&wal::wal_path(db_path))?)
+}
+
+// Directory next to a database file that large values spill to
+pub fn spill_dir(db_path: &Path) -> PathBuf {
+    let mut dir = db_path.as_os_str().to_owned();
+    dir.push(".spill");
+    PathBuf::from(dir)
+}
+
+// Directory next to a database file that the LSM tree is kept in
+pub fn lsm_dir(db_path: &Path) -> PathBuf {
+    let mut dir = db_path.as_os_str().to_owned();
+    dir.push(".lsm");
+    PathBuf::from(dir)
+}
+
+// Where a server keeps values, picked with --engine
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Engine {
+    // Everything in memory, spilling only large values
+    #[default]
+    Memory,
+    // Entries in an LSM tree on disk, only those being written in memory
+    Lsm,
+    // Values read in place from a memory map of the database file
+    Mmap,
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Engine::Memory => write!(f, "memory"),
+            Engine::Lsm => write!(f, "lsm"),
+            Engine::Mmap => write!(f, "mmap"),
+        }
+    }
+}
+
+impl std::str::FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "memory" => Ok(Engine::Memory),
+            "lsm" => Ok(Engine::Lsm),
+            "mmap" => Ok(Engine::Mmap),
+            _ => Err(format!(
+                "Unknown engine '{}', expected memory, lsm or mmap",
+                s
+            )),
+        }
+    }
+}
+
+// Exclusive advisory lock on a database file, held for the life of the process.
+// The lock lives on a sibling `.lock` file so it survives the data file being
+// truncated or replaced during saves.
+pub struct DatabaseLock {
+    _file: File,
+}
+
+impl DatabaseLock {
+    pub fn acquire(db_path: &Path) -> Result<Self> {
+        let mut lock_path = db_path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(StoreError::LockError(format!(
+                    "{} is in use by another process (lock file {})",
+                    db_path.display(),
+                    lock_path.display()
+                )));
+            }
+            Err(TryLockError::Error(e)) => return Err(StoreError::IoError(e)),
+        }
+
+        // Record the owner to make a stuck lock easy to diagnose
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+
+        Ok(DatabaseLock { _file: file })
+    }
+}
+
+// Streams the top-level store object, feeding entries into the store as
+// they're read. `V` is how values are read: as strings, or borrowed from the
+// mapped `file` being read.
+struct StoreSeed<'a, V = String> {
+    store: &'a KeyValueStore,
+    file: Option<&'a Arc<Mmap>>,
+    values: PhantomData<fn() -> V>,
+}
+
+impl<'a> StoreSeed<'a> {
+    fn new(store: &'a KeyValueStore) -> Self {
+        StoreSeed {
+            store,
+            file: None,
+            values: PhantomData,
+        }
+    }
+}
+
+impl<'a, V> StoreSeed<'a, V> {
+    fn mapped(store: &'a KeyValueStore, file: &'a Arc<Mmap>) -> Self {
+        StoreSeed {
+            store,
+            file: Some(file),
+            values: PhantomData,
+        }
+    }
+}
+
+impl<'de, V: SavedValue + Deserialize<'de>> DeserializeSeed<'de> for StoreSeed<'_, V> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, V: SavedValue + Deserialize<'de>> Visitor<'de> for StoreSeed<'_, V> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a key-value store object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<(), A::Error> {
+        while let Some(field) = map.next_key::<String>()? {
+            match field.as_str() {
+                "data" => map.next_value_seed(EntriesSeed::<V> {
+                    store: self.store,
+                    file: self.file,
+                    values: PhantomData,
+                })?,
+                "seq" => self.store.changelog.set_last_seq(map.next_value()?),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Streams a (possibly null) map of entries into the store in batches
+struct EntriesSeed<'a, V> {
+    store: &'a KeyValueStore,
+    file: Option<&'a Arc<Mmap>>,
+    values: PhantomData<fn() -> V>,
+}
+
+impl<'de, V: SavedValue + Deserialize<'de>> DeserializeSeed<'de> for EntriesSeed<'_, V> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<(), D::Error> {
+        deserializer.deserialize_option(self)
+    }
+}
+
+impl<'de, V: SavedValue + Deserialize<'de>> Visitor<'de> for EntriesSeed<'_, V> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map of keys to values")
+    }
+
+    fn visit_none<E: de::Error>(self) -> std::result::Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_unit<E: de::Error>(self) -> std::result::Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_some<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<(), A::Error> {
+        let mut batch = Vec::with_capacity(LOAD_BATCH_SIZE);
+        let result = loop {
+            match map.next_entry::<String, SavedEntry<V>>() {
+                Ok(Some(entry)) => batch.push(entry),
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+            if batch.len() == LOAD_BATCH_SIZE {
+                self.store
+                    .insert_loaded(&mut batch, self.file)
+                    .map_err(de::Error::custom)?;
+            }
+        };
+        // Entries read before any damage are kept, for salvage
+        self.store
+            .insert_loaded(&mut batch, self.file)
+            .map_err(de::Error::custom)?;
+        result
+    }
+}
+
+// Unit tests -> Cannot test private functions inside [ tests/store_tests.rs] thats why we have the test codes here.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_store_operations() {
+        // Create a new store
+        let store = KeyValueStore::new();
+
+        // Test set and get
+        store.put("key1".to_string(), "value1".to_string());
+        assert_eq!(store.get("key1"), Some("value1".to_string()));
+
+        // Test non-existent key
+        assert_eq!(store.get("nonexistent"), None);
+
+        // Test delete
+        assert!(store.delete("key1"));
+        assert_eq!(store.get("key1"), None);
+
+        // Test delete non-existent key
+        assert!(!store.delete("nonexistent"));
+
+        // Test take
+        store.put("token".to_string(), "once".to_string());
+        assert_eq!(store.take("token"), Some("once".to_string()));
+        assert_eq!(store.take("token"), None);
+
+        // Test keys
+        store.put("keys2".to_string(), "value2".to_string());
+        store.put("keys3".to_string(), "value3".to_string());
+        let keys = store.keys();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.contains(&"keys2".to_string()));
+        assert!(keys.contains(&"keys3".to_string()));
+    }
+
+    #[test]
+    fn test_persistence() -> Result<()> {
+        // Create a temporary directory
+        let dir = tempdir()?;
+        let file_path = dir.path().join("test-db.json");
+
+        // Create and populate a store
+        let node_id = {
+            let store = KeyValueStore::new();
+            store.put("persist1".to_string(), "value1".to_string());
+            store.put("persist2".to_string(), "value2".to_string());
+            store.raise_epoch(3);
+            store.save(&file_path)?;
+            store.node_id().to_string()
+        };
+
+        // Load the store from disk and verify data
+        {
+            let store = KeyValueStore::load(&file_path)?;
+            assert_eq!(store.get("persist1"), Some("value1".to_string()));
+            assert_eq!(store.get("persist2"), Some("value2".to_string()));
+
+            // Change numbering carries on where it left off
+            assert_eq!(store.changelog().last_seq(), 2);
+
+            // As does the node's identity from the header
+            assert_eq!(store.node_id(), node_id);
+            assert_eq!(store.epoch(), 3);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_format() -> Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("legacy-db.json");
+
+        // Plain JSON as written before the file had a format header
+        std::fs::write(
+            &file_path,
+            r#"{"data_lock": {}, "data": {"old1": "value1", "old2": "value2"}, "seq": 7}"#,
+        )?;
+        let store = KeyValueStore::load(&file_path)?;
+        assert_eq!(store.get("old1"), Some("value1".to_string()));
+        assert_eq!(store.changelog().last_seq(), 7);
+
+        let streamed = KeyValueStore::load_streaming(&file_path)?;
+        streamed.wait_until_loaded()?;
+        assert_eq!(streamed.get("old2"), Some("value2".to_string()));
+
+        // Saving upgrades the file to the current format
+        store.save(&file_path)?;
+        let contents = std::fs::read_to_string(&file_path)?;
+        assert!(contents.starts_with(&format!("kv-store format {} version=", FORMAT_VERSION)));
+        assert_eq!(KeyValueStore::load(&file_path)?.keys().len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksums() -> Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("checksum-db.json");
+        let store = KeyValueStore::new();
+        for i in 0..10 {
+            store.put(format!("key{}", i), format!("value{}", i));
+        }
+        store.save(&file_path)?;
+        let saved = std::fs::read_to_string(&file_path)?;
+        assert!(
+            saved
+                .lines()
+                .last()
+                .unwrap()
+                .starts_with("kv-store end records=10 crc32=")
+        );
+
+        // A changed value still parses, but no longer matches the checksum
+        std::fs::write(&file_path, saved.replace("value3", "valueX"))?;
+        let err = KeyValueStore::load(&file_path).err().unwrap();
+        assert!(matches!(err, StoreError::Corruption(_)), "{}", err);
+        assert!(err.to_string().contains("checksum"), "{}", err);
+
+        // So does a file that lost its trailer, or was cut off mid-value
+        let trailer = saved.rfind("kv-store end").unwrap();
+        std::fs::write(&file_path, &saved[..trailer])?;
+        assert!(matches!(
+            KeyValueStore::load(&file_path),
+            Err(StoreError::Corruption(_))
+        ));
+        let cut = saved.rfind("\"value\"").unwrap();
+        std::fs::write(&file_path, &saved[..cut])?;
+        let streamed = KeyValueStore::load_streaming(&file_path)?;
+        assert!(streamed.wait_until_loaded().is_err());
+
+        // Salvage keeps the keys before the damage
+        let salvaged = KeyValueStore::salvage(&file_path)?;
+        assert_eq!(salvaged.len(), 9);
+        let kept = salvaged.keys();
+        assert!(kept.iter().all(|key| salvaged.get(key) == store.get(key)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_seed() -> Result<()> {
+        let dir = tempdir()?;
+        let seed_path = dir.path().join("seed.jsonl");
+        std::fs::write(
+            &seed_path,
+            "{\"key\": \"color\", \"value\": \"blue\"}\n\n{\"key\": \"size\", \"value\": \"10\"}\n",
+        )?;
+
+        // First boot seeds the empty store
+        let store = KeyValueStore::new();
+        assert_eq!(store.seed(&seed_path, false)?, 2);
+        assert_eq!(store.get("color"), Some("blue".to_string()));
+
+        // Later boots leave existing data alone unless told to overwrite
+        store.put("color".to_string(), "red".to_string());
+        assert_eq!(store.seed(&seed_path, false)?, 0);
+        assert_eq!(store.get("color"), Some("red".to_string()));
+        assert_eq!(store.seed(&seed_path, true)?, 2);
+        assert_eq!(store.get("color"), Some("blue".to_string()));
+
+        std::fs::write(&seed_path, "{\"key\": \"broken\"}\n")?;
+        assert!(KeyValueStore::new().seed(&seed_path, false).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_streaming_load() -> Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("stream-db.json");
+
+        // More entries than a single load batch
+        {
+            let store = KeyValueStore::new();
+            for i in 0..5000 {
+                store.put(format!("key{}", i), format!("value{}", i));
+            }
+            store.save(&file_path)?;
+        }
+
+        let store = KeyValueStore::load_streaming(&file_path)?;
+
+        // Reads are valid even while loading is still in progress
+        assert_eq!(store.get("key4999"), Some("value4999".to_string()));
+        assert_eq!(store.get("missing"), None);
+
+        store.wait_until_loaded()?;
+        assert_eq!(store.keys().len(), 5000);
+
+        // A missing file is just an empty, already loaded store
+        let empty = KeyValueStore::load_streaming(&dir.path().join("nope.json"))?;
+        empty.wait_until_loaded()?;
+        assert!(empty.keys().is_empty());
+
+        // A corrupt file surfaces its error through the readiness signal
+        std::fs::write(&file_path, "{\"data\": {\"a\": ")?;
+        let corrupt = KeyValueStore::load_streaming(&file_path)?;
+        assert!(corrupt.wait_until_loaded().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_memory() {
+        let store = KeyValueStore::new();
+        store.set_max_memory(20);
+
+        // 4 + 6 = 10 bytes
+        store
+            .try_put("key1".to_string(), "value1".to_string())
+            .unwrap();
+        assert_eq!(store.used_memory(), 10);
+
+        // Another 10 bytes still fits, one more byte doesn't
+        store
+            .try_put("key2".to_string(), "value2".to_string())
+            .unwrap();
+        assert!(matches!(
+            store.try_put("key3".to_string(), "v".to_string()),
+            Err(StoreError::LimitError(_))
+        ));
+
+        // Shrinking or freeing space is always allowed
+        store.try_put("key2".to_string(), "v".to_string()).unwrap();
+        assert_eq!(store.used_memory(), 15);
+        assert!(store.delete("key1"));
+        store
+            .try_put("key3".to_string(), "value3".to_string())
+            .unwrap();
+        assert_eq!(store.used_memory(), 15);
+    }
+
+    #[test]
+    fn test_key_limits() {
+        let store = KeyValueStore::new();
+        store.set_max_keys(2);
+        store.set_max_key_length(8);
+
+        store.try_put("key1".to_string(), "a".to_string()).unwrap();
+        store.try_put("key2".to_string(), "b".to_string()).unwrap();
+        assert!(matches!(
+            store.try_put("key3".to_string(), "c".to_string()),
+            Err(StoreError::LimitError(e)) if e.contains("max_keys")
+        ));
+
+        // Overwriting an existing key doesn't add one
+        store.try_put("key1".to_string(), "z".to_string()).unwrap();
+
+        assert!(store.delete("key2"));
+        assert!(matches!(
+            store.try_put("much-too-long".to_string(), "c".to_string()),
+            Err(StoreError::LimitError(e)) if e.contains("max_key_length")
+        ));
+        store.try_put("key3".to_string(), "c".to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_conditional_put() -> Result<()> {
+        let store = KeyValueStore::new();
+        let put_if = |value: &str, condition: Condition| {
+            store.try_put_if("key".to_string(), value.to_string(), &condition)
+        };
+
+        assert!(!put_if("a", Condition::Value("a".to_string()))?);
+        assert!(put_if("a", Condition::Absent)?);
+        assert!(!put_if("b", Condition::Absent)?);
+        assert_eq!(store.version("key"), 1);
+
+        assert!(put_if("b", Condition::Value("a".to_string()))?);
+        assert!(!put_if("c", Condition::Version(1))?);
+        assert!(put_if("c", Condition::Version(2))?);
+        assert_eq!(store.get("key"), Some("c".to_string()));
+
+        // Versions survive a save and load, and restart once a key is deleted
+        let dir = tempdir()?;
+        let file_path = dir.path().join("versions-db.json");
+        store.save(&file_path)?;
+        assert_eq!(KeyValueStore::load(&file_path)?.version("key"), 3);
+        let streamed = KeyValueStore::load_streaming(&file_path)?;
+        streamed.wait_until_loaded()?;
+        assert_eq!(streamed.version("key"), 3);
+
+        assert!(store.delete("key"));
+        assert!(put_if("d", Condition::Version(0))?);
+        assert_eq!(store.version("key"), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_metadata() -> Result<()> {
+        let store = KeyValueStore::new();
+        store.put("key".to_string(), "a".to_string());
+        let created = store.metadata("key").unwrap();
+        assert_eq!(created.created, created.updated);
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        store.put("key".to_string(), "b".to_string());
+        let updated = store.metadata("key").unwrap();
+        assert_eq!(updated.version, 2);
+        assert_eq!(updated.created, created.created);
+        assert!(updated.updated > created.updated);
+
+        // Timestamps are saved with the data
+        let dir = tempdir()?;
+        let file_path = dir.path().join("metadata-db.json");
+        store.save(&file_path)?;
+        assert_eq!(
+            KeyValueStore::load(&file_path)?.metadata("key"),
+            Some(updated)
+        );
+        assert_eq!(store.metadata("missing"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vector_clocks() -> Result<()> {
+        let store = KeyValueStore::new();
+        store.set_vector_clocks(true);
+        store.try_put("key".to_string(), "ours".to_string())?;
+        let ours = store.metadata("key").unwrap().vclock;
+        let clock = |s: &str| s.parse::<VectorClock>().unwrap();
+        let hlc = store.hlc().current();
+
+        // A write that saw ours replaces it, one that didn't becomes a sibling
+        let mut later = ours.clone();
+        later.increment("other");
+        store.put_at(
+            "key".to_string(),
+            "later".to_string(),
+            hlc,
+            Some(later.clone()),
+        );
+        assert_eq!(store.get("key"), Some("later".to_string()));
+        store.put_at("key".to_string(), "stale".to_string(), hlc, Some(ours));
+        assert_eq!(store.siblings("key"), None);
+        store.put_at(
+            "key".to_string(),
+            "theirs".to_string(),
+            hlc,
+            Some(clock("third=1")),
+        );
+        assert_eq!(
+            store.siblings("key"),
+            Some(vec!["later".to_string(), "theirs".to_string()])
+        );
+
+        // Plain writes are refused until a client picks a value
+        assert!(matches!(
+            store.try_put("key".to_string(), "x".to_string()),
+            Err(StoreError::ConflictError(_))
+        ));
+        let dir = tempdir()?;
+        let file_path = dir.path().join("vclock-db.json");
+        store.save(&file_path)?;
+        assert_eq!(
+            KeyValueStore::load(&file_path)?.siblings("key"),
+            store.siblings("key")
+        );
+        assert!(store.try_put_if(
+            "key".to_string(),
+            "both".to_string(),
+            &Condition::Conflicted
+        )?);
+        assert_eq!(store.siblings("key"), None);
+        assert!(!store.try_put_if("key".to_string(), "x".to_string(), &Condition::Conflicted)?);
+        let resolved = store.metadata("key").unwrap().vclock;
+        assert_eq!(resolved.compare(&later), Some(cmp::Ordering::Greater));
+        assert_eq!(
+            resolved.compare(&clock("third=1")),
+            Some(cmp::Ordering::Greater)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_recovery() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("wal-db.json");
+        let store = KeyValueStore::new();
+        store.put("saved".to_string(), "1".to_string());
+        store.save(&db_path)?;
+        store.open_wal(&wal::wal_path(&db_path))?;
+        store.try_put("logged".to_string(), "2".to_string())?;
+        store.delete("saved");
+
+        // Writes since the save survive a crash, with their numbering
+        let recovered = KeyValueStore::load(&db_path)?;
+        assert_eq!(recovered.get("logged"), Some("2".to_string()));
+        assert_eq!(recovered.get("saved"), None);
+        assert_eq!(recovered.changelog().last_seq(), 3);
+
+        // A save makes the logged writes redundant
+        store.save(&db_path)?;
+        assert!(wal::read(&wal::wal_path(&db_path))?.is_empty());
+        assert_eq!(KeyValueStore::load(&db_path)?.entries(), store.entries());
+        Ok(())
+    }
+
+    #[test]
+    fn test_increment() -> Result<()> {
+        let store = KeyValueStore::new();
+        assert_eq!(store.increment("hits", 5)?, 5);
+        assert_eq!(store.increment("hits", -7)?, -2);
+        assert_eq!(store.get("hits").as_deref(), Some("-2"));
+
+        // The expiry time stays, and only integers can be incremented
+        let later = unix_millis() + 60_000;
+        store.expire("hits", later)?;
+        store.increment("hits", 1)?;
+        assert_eq!(store.metadata("hits").unwrap().expires, later);
+        store.put("name".to_string(), "alice".to_string());
+        assert!(matches!(
+            store.increment("name", 1),
+            Err(StoreError::ValueError(_))
+        ));
+        store.put("big".to_string(), i64::MAX.to_string());
+        assert!(store.increment("big", 1).is_err());
+        assert_eq!(store.get("big"), Some(i64::MAX.to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_set() -> Result<()> {
+        let store = KeyValueStore::new();
+        assert_eq!(store.get_set("owner".to_string(), "a".to_string())?, None);
+        store.expire("owner", unix_millis() + 60_000)?;
+        assert_eq!(
+            store.get_set("owner".to_string(), "b".to_string())?,
+            Some("a".to_string())
+        );
+        assert_eq!(store.get("owner").as_deref(), Some("b"));
+        assert_eq!(store.metadata("owner").unwrap().expires, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_put_many() -> Result<()> {
+        let store = KeyValueStore::new();
+        let entries: Vec<(String, String)> = (0..3)
+            .map(|i| (format!("key{}", i), i.to_string()))
+            .collect();
+        let (written, result) = store.put_many(&entries);
+        assert_eq!(written, 3);
+        result?;
+        assert_eq!(
+            store.get_many(&["key2", "missing", "key0"]),
+            [Some("2".to_string()), None, Some("0".to_string())]
+        );
+
+        // A batch that would go over a limit writes nothing, even where its
+        // first entries would fit
+        store.set_max_keys(4);
+        let entries: Vec<(String, String)> = (3..6)
+            .map(|i| (format!("key{}", i), i.to_string()))
+            .collect();
+        let (written, result) = store.put_many(&entries);
+        assert_eq!(written, 0);
+        assert!(matches!(result, Err(StoreError::LimitError(_))));
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.get("key3"), None);
+
+        // Keys written twice, or already there, only count once
+        let entries = [("key0", "a"), ("key3", "b"), ("key3", "c")]
+            .map(|(key, value)| (key.to_string(), value.to_string()));
+        assert_eq!(store.put_many(&entries).0, 3);
+        assert_eq!(store.get("key3").as_deref(), Some("c"));
+
+        // Room is made by evicting keys outside the batch only
+        store.set_eviction_policy(EvictionPolicy::AllKeysLru);
+        let entries: Vec<(String, String)> = ["key0", "new1", "new2"]
+            .iter()
+            .map(|key| (key.to_string(), "d".to_string()))
+            .collect();
+        let (written, result) = store.put_many(&entries);
+        result?;
+        assert_eq!(written, 3);
+        assert_eq!(store.len(), 4);
+        for (key, _) in &entries {
+            assert_eq!(store.get(key).as_deref(), Some("d"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_put_many_within_quota() {
+        let store = KeyValueStore::new();
+        let mut quotas = BTreeMap::new();
+        quotas.insert(
+            "tenantA:*".to_string(),
+            QuotaLimits {
+                max_memory: 0,
+                max_keys: 2,
+            },
+        );
+        store.set_quotas(&quotas);
+        store.put("tenantA:a".to_string(), "1".to_string());
+
+        // The quota is checked for the batch as a whole before anything is
+        // written
+        let entries = [("other", "1"), ("tenantA:b", "2"), ("tenantA:c", "3")]
+            .map(|(key, value)| (key.to_string(), value.to_string()));
+        let (written, result) = store.put_many(&entries);
+        assert_eq!(written, 0);
+        assert!(matches!(result, Err(StoreError::QuotaError(_))));
+        assert_eq!(store.get("other"), None);
+        assert_eq!(store.put_many(&entries[..2]).0, 2);
+    }
+
+    #[test]
+    fn test_delete_many() {
+        let store = KeyValueStore::new();
+        for key in ["a", "b", "c"] {
+            store.put(key.to_string(), "1".to_string());
+        }
+        assert_eq!(store.delete_many(&["a", "missing", "c", "a"]), 2);
+        assert_eq!(store.keys(), ["b"]);
+    }
+
+    #[test]
+    fn test_scan() -> Result<()> {
+        let store = KeyValueStore::new();
+        for i in 0..50 {
+            store.put(format!("user:{}", i), "x".to_string());
+            store.put(format!("order:{}", i), "x".to_string());
+        }
+        store.put("user:gone".to_string(), "x".to_string());
+        store.expire("user:gone", 1)?;
+
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (page, next) = store.scan(cursor, 10, "user:", Some("1*"));
+            keys.extend(page);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        keys.sort();
+        let expected: Vec<String> = [
+            "1", "10", "11", "12", "13", "14", "15", "16", "17", "18", "19",
+        ]
+        .iter()
+        .map(|i| format!("user:{}", i))
+        .collect();
+        assert_eq!(keys, expected);
+
+        assert!(glob_match("a*b?c", "axxbyc"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("a*b", "acbd"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_count() -> Result<()> {
+        let store = KeyValueStore::new();
+        store.put("a:1".to_string(), "xy".to_string());
+        store.put("a:2".to_string(), "xyz".to_string());
+        store.put("b:1".to_string(), "x".to_string());
+        store.put("a:3".to_string(), "gone".to_string());
+        store.expire("a:3", 1)?;
+
+        assert_eq!(store.count(""), (3, 6));
+        assert_eq!(store.count("a:"), (2, 5));
+        assert_eq!(store.count("c:"), (0, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_and_copy() -> Result<()> {
+        let store = KeyValueStore::new();
+        store.put("old".to_string(), "v".to_string());
+        let later = unix_millis() + 60_000;
+        store.expire("old", later)?;
+        store.put("taken".to_string(), "x".to_string());
+
+        // A copy leaves an existing key alone unless told to replace it
+        assert!(!store.copy("old", "taken", false)?);
+        assert!(store.copy("old", "taken", true)?);
+        assert_eq!(store.get("taken").as_deref(), Some("v"));
+
+        // A rename takes the expiry time along
+        assert!(store.rename("old", "new")?);
+        assert_eq!(store.get("old"), None);
+        assert_eq!(store.metadata("new").unwrap().expires, later);
+        assert!(!store.rename("old", "new")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_append() -> Result<()> {
+        let store = KeyValueStore::new();
+        assert_eq!(store.value_len("log"), None);
+        assert_eq!(store.append("log", "a")?, "a");
+        assert_eq!(store.append("log", " b")?, "a b");
+        assert_eq!(store.value_len("log"), Some(3));
+        assert_eq!(store.version("log"), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_crdt_values() -> Result<()> {
+        let (primary, backup) = (KeyValueStore::new(), KeyValueStore::new());
+        backup.set_vector_clocks(true);
+
+        // Each node counts its own increments; replicated state is merged
+        // into what the key holds instead of replacing it
+        let ours = primary.update_crdt("hits", Update::Count(5))?;
+        let theirs = backup.update_crdt("hits", Update::Count(-2))?;
+        backup.put_at("hits".to_string(), ours, primary.hlc().now(), None);
+        assert_eq!(
+            Crdt::parse(&backup.get("hits").unwrap()).unwrap().value(),
+            "3"
+        );
+        assert_eq!(backup.siblings("hits"), None);
+        let merged = primary.merge_crdt("hits", &theirs)?;
+        assert_eq!(Crdt::parse(&merged).unwrap().value(), "3");
+        assert_eq!(primary.get("hits"), backup.get("hits"));
+
+        // Updates and merges of another type are refused
+        assert!(primary.update_crdt("hits", Update::Grow(1)).is_err());
+        let set = backup.update_crdt("tags", Update::Add(vec!["a".to_string()]))?;
+        assert!(primary.merge_crdt("hits", &set).is_err());
+        primary.put("plain".to_string(), "1".to_string());
+        assert!(primary.update_crdt("plain", Update::Count(1)).is_err());
+        assert!(primary.merge_crdt("plain", &set).is_err());
+        assert!(primary.merge_crdt("tags", "not state").is_err());
+
+        // A plain value replicated over a CRDT replaces it
+        backup.put_at(
+            "hits".to_string(),
+            "7".to_string(),
+            primary.hlc().now(),
+            None,
+        );
+        assert_eq!(backup.get("hits").as_deref(), Some("7"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_expiry() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("expiry-db.json");
+        let store = KeyValueStore::new();
+        store.save(&db_path)?;
+        store.open_wal(&wal::wal_path(&db_path))?;
+        let later = unix_millis() + 60_000;
+        store.try_put_expiring(
+            "later".to_string(),
+            "1".to_string(),
+            &Condition::Always,
+            later,
+        )?;
+        store.put("now".to_string(), "2".to_string());
+        assert!(store.expire("now", 1)?);
+        assert!(!store.expire("missing", later)?);
+
+        // An expired key reads as missing before it is swept
+        assert_eq!(store.get("now"), None);
+        assert_eq!(store.keys(), vec!["later".to_string()]);
+        assert!(store.try_put_if("now".to_string(), "3".to_string(), &Condition::Absent)?);
+        assert_eq!(store.metadata("now").unwrap().expires, 0);
+        store.expire("now", 1)?;
+
+        // Expiry times survive a crash, and a save
+        let recovered = KeyValueStore::load(&db_path)?;
+        assert_eq!(recovered.metadata("later").unwrap().expires, later);
+        assert_eq!(recovered.get("now"), None);
+        store.save(&db_path)?;
+        assert_eq!(
+            KeyValueStore::load(&db_path)?
+                .metadata("later")
+                .unwrap()
+                .expires,
+            later
+        );
+
+        // A backup shows the key until the primary says it's gone
+        store.set_expiry_deferred(true);
+        assert_eq!(store.get("now").as_deref(), Some("3"));
+        assert_eq!(store.keys().len(), 2);
+        assert_eq!(store.remove_expired(), 0);
+        store.set_expiry_deferred(false);
+
+        assert_eq!(store.remove_expired(), 1);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.expired_keys(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_changes_from_log() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("changes-db.json");
+        let store = KeyValueStore::new();
+        store.open_wal(&wal::wal_path(&db_path))?;
+        for i in 1..=20 {
+            store.try_put(format!("key{}", i), i.to_string())?;
+        }
+        // A backup has applied the first five, so a save keeps the rest
+        store.wal().unwrap().set_replicas_acked(Some(5));
+        store.save(&db_path)?;
+
+        // After a restart the changelog buffers none of them, but the log
+        // still has them
+        let restarted = KeyValueStore::load(&db_path)?;
+        restarted.open_wal(&wal::wal_path(&db_path))?;
+        assert!(restarted.changelog().subscribe(6).is_err());
+        let (backlog, _) = restarted.changes_from(6)?;
+        let seqs: Vec<u64> = backlog.iter().map(|change| change.seq).collect();
+        assert_eq!(seqs, (6..=20).collect::<Vec<_>>());
+
+        // Once no backup needs them they go, and so does catching up
+        restarted.try_put("key21".to_string(), "21".to_string())?;
+        restarted.save(&db_path)?;
+        assert!(restarted.changes_from(6).is_err());
+        assert_eq!(restarted.changes_from(22)?.0, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_msgpack_encoding() -> Result<()> {
+        let dir = tempdir()?;
+        let json_path = dir.path().join("encoded-db.json");
+        let msgpack_path = dir.path().join("encoded-db.msgpack");
+        let store = KeyValueStore::new();
+        for i in 0..100 {
+            store.put(format!("key{}", i), "value".repeat(i));
+        }
+        store.save(&json_path)?;
+        store.set_encoding(Encoding::MessagePack);
+        store.save(&msgpack_path)?;
+        assert!(fs::metadata(&msgpack_path)?.len() < fs::metadata(&json_path)?.len());
+
+        // The header says how the file is encoded, and saves keep to it
+        let loaded = KeyValueStore::load(&msgpack_path)?;
+        assert_eq!(loaded.encoding(), Encoding::MessagePack);
+        assert_eq!(loaded.entries(), store.entries());
+        assert_eq!(loaded.metadata("key5"), store.metadata("key5"));
+        let streamed = KeyValueStore::load_streaming(&msgpack_path)?;
+        streamed.wait_until_loaded()?;
+        assert_eq!(streamed.entries(), store.entries());
+        assert_eq!(streamed.changelog().last_seq(), 100);
+        assert_eq!(KeyValueStore::load(&json_path)?.encoding(), Encoding::Json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_or_set() -> Result<()> {
+        let store = KeyValueStore::new();
+        assert_eq!(
+            store.get_or_set("counter".to_string(), "0".to_string())?,
+            ("0".to_string(), true)
+        );
+        assert_eq!(
+            store.get_or_set("counter".to_string(), "1".to_string())?,
+            ("0".to_string(), false)
+        );
+
+        // Limits still apply to the default
+        store.set_max_keys(1);
+        assert!(
+            store
+                .get_or_set("other".to_string(), "1".to_string())
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_random_keys() {
+        let store = KeyValueStore::new();
+        assert_eq!(store.random_key(), None);
+        assert!(store.sample(3).is_empty());
+
+        for i in 0..10 {
+            store.put(format!("key{}", i), "value".to_string());
+        }
+        // Deleting moves the last key into the freed slot
+        assert!(store.delete("key3"));
+        assert!(store.delete("key9"));
+        store.put("key0".to_string(), "changed".to_string());
+
+        let mut slots = store.key_slots.read().unwrap().clone();
+        for (key, entry) in store.data_lock.read().unwrap().iter() {
+            assert_eq!(&slots[entry.slot], key);
+        }
+        slots.sort();
+        let mut keys = store.keys();
+        keys.sort();
+        assert_eq!(slots, keys);
+
+        let key = store.random_key().unwrap();
+        assert!(keys.contains(&key));
+        let sample: HashSet<String> = store.sample(5).into_iter().collect();
+        assert_eq!(sample.len(), 5);
+        assert!(sample.iter().all(|key| keys.contains(key)));
+        assert_eq!(store.sample(100).len(), 8);
+    }
+
+    #[test]
+    fn test_list() {
+        let store = KeyValueStore::new();
+        for key in ["b", "e", "a", "d", "c", "x:1"] {
+            store.put(key.to_string(), "value".to_string());
+        }
+
+        assert_eq!(store.list("", None, 2), vec!["a", "b"]);
+        assert_eq!(store.list("", Some("b"), 2), vec!["c", "d"]);
+        assert_eq!(store.list("", Some("d"), 10), vec!["e", "x:1"]);
+        assert!(store.list("", Some("x:1"), 10).is_empty());
+        assert_eq!(store.list("x:", None, 10), vec!["x:1"]);
+    }
+
+    #[test]
+    fn test_compression() -> Result<()> {
+        let store = KeyValueStore::new();
+        store.set_compression_threshold(64);
+        let large = "{\"name\": \"value\"}".repeat(50);
+        store.put("large".to_string(), large.clone());
+        store.put("small".to_string(), "value".to_string());
+
+        // Memory use reflects the compressed size
+        assert!(store.used_memory() < large.len());
+        assert_eq!(store.get("large"), Some(large.clone()));
+        assert!(store.metadata("large").unwrap().compressed);
+        assert!(!store.metadata("small").unwrap().compressed);
+        assert!(store.try_put_if(
+            "large".to_string(),
+            large.clone(),
+            &Condition::Value(large.clone())
+        )?);
+
+        // Compressed values stay compressed on disk
+        let dir = tempdir()?;
+        let file_path = dir.path().join("compressed-db.json");
+        store.save(&file_path)?;
+        assert!(!std::fs::read_to_string(&file_path)?.contains(&large));
+        let loaded = KeyValueStore::load(&file_path)?;
+        assert_eq!(loaded.get("large"), Some(large.clone()));
+        let streamed = KeyValueStore::load_streaming(&file_path)?;
+        streamed.wait_until_loaded()?;
+        assert_eq!(streamed.get("large"), Some(large));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_spillover() -> Result<()> {
+        let dir = tempdir()?;
+        let spill_dir = dir.path().join("spill");
+        let store = KeyValueStore::new();
+        let large = "x".repeat(1000);
+        store.put("before".to_string(), large.clone());
+
+        // Existing values spill once a threshold is set
+        store.set_spill_dir(&spill_dir)?;
+        store.set_spill_threshold(100);
+        assert!(store.metadata("before").unwrap().spilled);
+        assert!(store.used_memory() < 100);
+
+        store.put("after".to_string(), large.clone());
+        store.put("small".to_string(), "value".to_string());
+        assert_eq!(std::fs::read_dir(&spill_dir)?.count(), 2);
+        assert_eq!(store.get("after"), Some(large.clone()));
+        assert!(!store.metadata("small").unwrap().spilled);
+
+        // Files go away with their keys, including rejected writes
+        assert_eq!(store.take("before"), Some(large.clone()));
+        store.put("after".to_string(), "small now".to_string());
+        store.set_max_keys(1);
+        assert!(store.try_put("third".to_string(), large.clone()).is_err());
+        assert_eq!(std::fs::read_dir(&spill_dir)?.count(), 0);
+
+        // The database file keeps values inline
+        store.put("after".to_string(), large.clone());
+        let file_path = dir.path().join("spill-db.json");
+        store.save(&file_path)?;
+        assert_eq!(KeyValueStore::load(&file_path)?.get("after"), Some(large));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lsm_engine() -> Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("lsm-db.json");
+        let lsm_dir = lsm_dir(&file_path);
+        let store = KeyValueStore::new();
+        for i in 0..1000 {
+            store.put(format!("key{}", i), format!("value {}", i));
+        }
+        store.save(&file_path)?;
+
+        // Entries go into the tree as the file is read, none stay in memory
+        let store = KeyValueStore::load_streaming_lsm(&file_path, &lsm_dir)?;
+        store.wait_until_loaded()?;
+        assert_eq!(store.len(), 1000);
+        assert_eq!(store.get("key500"), Some("value 500".to_string()));
+        assert_eq!(store.used_memory(), 0);
+
+        store.put("key1".to_string(), "changed".to_string());
+        store.delete("key2");
+        store.put("new".to_string(), "x".repeat(1000));
+        store.expire("key3", 1)?;
+        assert_eq!(store.get("key1"), Some("changed".to_string()));
+        assert_eq!(store.get("key2"), None);
+        assert_eq!(store.get("key3"), None);
+        assert_eq!(store.used_memory(), 0);
+        assert_eq!(store.len(), 1000);
+        assert_eq!(store.count("key99"), (11, 98));
+        assert_eq!(
+            store.list("key", Some("key2"), 3),
+            vec!["key20", "key200", "key201"]
+        );
+        assert_eq!(store.due_keys(), vec!["key3".to_string()]);
+        assert!(store.remove_if_expired("key3"));
+        assert!(store.due_keys().is_empty());
+        assert_eq!(store.keys().len(), 999);
+        assert!(
+            store
+                .random_key()
+                .is_some_and(|key| store.get(&key).is_some())
+        );
+        assert_eq!(store.sample(10).len(), 10);
+
+        // SCAN pages through every key once
+        let (mut scanned, mut cursor) = (HashSet::new(), 0);
+        loop {
+            let (keys, next) = store.scan(cursor, 100, "", None);
+            scanned.extend(keys);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(scanned.len(), 999);
+
+        // A save puts the tree in step with the file, which a restart then
+        // doesn't need to read
+        store.save(&file_path)?;
+        assert_eq!(
+            KeyValueStore::load(&file_path)?.get("key1"),
+            Some("changed".to_string())
+        );
+        store.put("later".to_string(), "1".to_string());
+        store.lsm().unwrap().flush()?;
+        drop(store);
+        let store = KeyValueStore::load_streaming_lsm(&file_path, &lsm_dir)?;
+        store.wait_until_loaded()?;
+        assert_eq!(store.len(), 1000);
+        assert_eq!(store.get("later"), Some("1".to_string()));
+        assert_eq!(store.get("new"), Some("x".repeat(1000)));
+        drop(store);
+
+        // A file written since the tree was in step with it is read again
+        let other = KeyValueStore::new();
+        other.put("other".to_string(), "2".to_string());
+        other.save(&file_path)?;
+        let store = KeyValueStore::load_streaming_lsm(&file_path, &lsm_dir)?;
+        store.wait_until_loaded()?;
+        assert_eq!(store.keys(), vec!["other".to_string()]);
+
+        // Entries already in memory move to the tree
+        let reloaded = KeyValueStore::load(&file_path)?;
+        reloaded.set_lsm_dir(&dir.path().join("other.lsm"))?;
+        assert_eq!(reloaded.used_memory(), 0);
+        assert_eq!(reloaded.get("other"), Some("2".to_string()));
+        assert!(reloaded.set_lsm_dir(&dir.path().join("again.lsm")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mapped_load() -> Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("mapped-db.json");
+        let is_mapped = |store: &KeyValueStore, key: &str| {
+            store
+                .data_lock
+                .read()
+                .unwrap()
+                .get(key)
+                .unwrap()
+                .value
+                .is_mapped()
+        };
+        let store = KeyValueStore::new();
+        store.put("plain".to_string(), "value".to_string());
+        store.put("quoted".to_string(), "{\"field\": \"value\"}\n".to_string());
+        for i in 0..100 {
+            store.put(i.to_string(), format!("value {} {}", i, "x".repeat(100)));
+        }
+        store.set_compression_threshold(1000);
+        store.put("compressed".to_string(), "abc".repeat(1000));
+        store.expire("plain", expires_in(60))?;
+        store.save(&file_path)?;
+
+        for encoding in [Encoding::Json, Encoding::MessagePack] {
+            store.set_encoding(encoding);
+            store.save(&file_path)?;
+
+            // Values are read from the file, escaped or not
+            let mapped = KeyValueStore::load_mapped(&file_path)?;
+            assert_eq!(mapped.len(), 103);
+            assert!(is_mapped(&mapped, "plain"));
+            assert!(is_mapped(&mapped, "quoted"));
+            assert!(!is_mapped(&mapped, "compressed"));
+            assert_eq!(mapped.get("plain"), Some("value".to_string()));
+            assert_eq!(mapped.get("quoted"), store.get("quoted"));
+            assert_eq!(mapped.get("compressed"), Some("abc".repeat(1000)));
+            assert_eq!(mapped.metadata("plain"), store.metadata("plain"));
+            assert!(mapped.used_memory() < store.used_memory());
+
+            // Saving replaces the file without disturbing the mapped one
+            mapped.put("plain".to_string(), "changed".to_string());
+            mapped.save(&file_path)?;
+            assert_eq!(mapped.get("quoted"), store.get("quoted"));
+            assert_eq!(mapped.get("42"), store.get("42"));
+            let reloaded = KeyValueStore::load_mapped(&file_path)?;
+            assert_eq!(reloaded.get("plain"), Some("changed".to_string()));
+        }
+
+        // The checksum still covers the values
+        store.set_encoding(Encoding::Json);
+        store.save(&file_path)?;
+        let damaged = fs::read_to_string(&file_path)?.replace("value 42", "value 24");
+        fs::write(&file_path, damaged)?;
+        assert!(matches!(
+            KeyValueStore::load_mapped(&file_path),
+            Err(StoreError::Corruption(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshots() -> Result<()> {
+        let dir = tempdir()?;
+        let store = KeyValueStore::new();
+        store.set_spill_dir(&dir.path().join("spill"))?;
+        store.set_spill_threshold(100);
+        let large = "x".repeat(1000);
+        store.put("spilled".to_string(), large.clone());
+        for i in 0..100 {
+            store.put(i.to_string(), "old".to_string());
+        }
+
+        // Writes after the snapshot don't show up in it, even ones that
+        // remove a spilled value's file from the store
+        let snapshot = store.snapshot();
+        store.put("0".to_string(), "new".to_string());
+        store.delete("1");
+        store.put("added".to_string(), "new".to_string());
+        store.delete("spilled");
+        assert_eq!(snapshot.len(), 101);
+        assert_eq!(snapshot.seq(), 101);
+        assert_eq!(snapshot.get("0").as_deref(), Some("old"));
+        assert_eq!(snapshot.get("added"), None);
+
+        let file_path = dir.path().join("snapshot-db.json");
+        snapshot.save(&file_path)?;
+        drop(snapshot);
+        let loaded = KeyValueStore::load(&file_path)?;
+        assert_eq!(loaded.len(), 101);
+        assert_eq!(loaded.get("1").as_deref(), Some("old"));
+        assert_eq!(loaded.get("spilled"), Some(large));
+        assert_eq!(loaded.changelog().last_seq(), 101);
+
+        // The spilled file went once the snapshot did
+        assert_eq!(std::fs::read_dir(dir.path().join("spill"))?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tiering() -> Result<()> {
+        let dir = tempdir()?;
+        let store = KeyValueStore::new();
+        store.set_spill_dir(&dir.path().join("spill"))?;
+        for i in 0..10 {
+            store.put(format!("key{}", i), "v".repeat(100));
+        }
+        // key0 is hot
+        for _ in 0..3 {
+            store.get("key0");
+        }
+
+        // The coldest values move to disk until memory fits the budget
+        store.set_memory_budget(500);
+        let (demoted, promoted) = store.rebalance_tiers();
+        assert!(demoted > 0 && demoted < 10);
+        assert_eq!(promoted, 0);
+        assert!(store.used_memory() <= 500);
+        assert!(!store.metadata("key0").unwrap().spilled);
+
+        // A demoted key that gets busy comes back once there is room
+        let cold = store
+            .keys()
+            .into_iter()
+            .find(|key| store.metadata(key).unwrap().spilled)
+            .unwrap();
+        assert_eq!(store.get(&cold), Some("v".repeat(100)));
+        store.get(&cold);
+        assert!(store.delete("key0"));
+        assert_eq!(store.rebalance_tiers(), (0, 1));
+        assert!(!store.metadata(&cold).unwrap().spilled);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quotas() {
+        let store = KeyValueStore::new();
+        store.put("tenantA:user1".to_string(), "value1".to_string());
+
+        // Existing keys count towards a newly configured quota
+        let mut quotas = BTreeMap::new();
+        quotas.insert(
+            "tenantA:*".to_string(),
+            QuotaLimits {
+                max_memory: 0,
+                max_keys: 2,
+            },
+        );
+        store.set_quotas(&quotas);
+        assert_eq!(
+            store.quota_usage(Some("tenantA:*")),
+            vec!["pattern=tenantA:*, used_memory=19, max_memory=0, keys=1, max_keys=2"]
+        );
+
+        store
+            .try_put("tenantA:user2".to_string(), "value2".to_string())
+            .unwrap();
+        assert!(matches!(
+            store.try_put("tenantA:user3".to_string(), "value3".to_string()),
+            Err(StoreError::QuotaError(_))
+        ));
+
+        // Other prefixes and overwrites are unaffected
+        store
+            .try_put("tenantB:user3".to_string(), "value3".to_string())
+            .unwrap();
+        store
+            .try_put("tenantA:user1".to_string(), "changed".to_string())
+            .unwrap();
+
+        assert!(store.delete("tenantA:user2"));
+        store
+            .try_put("tenantA:user3".to_string(), "value3".to_string())
+            .unwrap();
+        assert!(store.quota_usage(Some("tenantB:*")).is_empty());
+    }
+
+    #[test]
+    fn test_eviction_policies() {
+        let store = KeyValueStore::new();
+        store.set_max_memory(30);
+        store.set_eviction_policy(EvictionPolicy::AllKeysLru);
+
+        for key in ["key1", "key2", "key3"] {
+            store
+                .try_put(key.to_string(), "value1".to_string())
+                .unwrap();
+        }
+
+        // key1 is read, so key2 is now the least recently used
+        assert!(store.get("key1").is_some());
+        store
+            .try_put("key4".to_string(), "value4".to_string())
+            .unwrap();
+        assert_eq!(store.get("key2"), None);
+        assert_eq!(store.evicted_keys(), 1);
+        assert_eq!(store.used_memory(), 30);
+
+        // Random eviction makes room too, just not predictably
+        store.set_eviction_policy(EvictionPolicy::AllKeysRandom);
+        store
+            .try_put("key5".to_string(), "value5".to_string())
+            .unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.evicted_keys(), 2);
+
+        // Without any TTLs there is nothing volatile-ttl may evict
+        store.set_eviction_policy(EvictionPolicy::VolatileTtl);
+        assert!(matches!(
+            store.try_put("key6".to_string(), "value6".to_string()),
+            Err(StoreError::LimitError(_))
+        ));
+    }
+
+    #[test]
+    fn test_database_lock() -> Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("locked-db.json");
+
+        let lock = DatabaseLock::acquire(&file_path)?;
+
+        // A second owner is refused while the first lock is held
+        assert!(matches!(
+            DatabaseLock::acquire(&file_path),
+            Err(StoreError::LockError(_))
+        ));
+
+        // And can take over once it has been released
+        drop(lock);
+        DatabaseLock::acquire(&file_path)?;
 
         Ok(())
     }
@@ -0,0 +1,169 @@
+// src/tenant.rs
+
+// Tenants share a server but not a keyspace. A connection that AUTHs with a
+// tenant's token has every key it touches stored under `<tenant>:`, is held
+// to the tenant's quota, and can be frozen by an admin.
+
+use crate::error::{Result, StoreError};
+use crate::quota::QuotaLimits;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantConfig {
+    // Token the tenant AUTHs with
+    pub token: String,
+
+    // Limits on the tenant's keyspace (0 = unlimited)
+    pub max_memory: u64,
+    pub max_keys: usize,
+
+    // Refuse the tenant's writes
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub frozen: bool,
+}
+
+impl TenantConfig {
+    pub fn limits(&self) -> QuotaLimits {
+        QuotaLimits {
+            max_memory: self.max_memory,
+            max_keys: self.max_keys,
+        }
+    }
+}
+
+// Make sure tenants can't reach into each other's keyspace or the admin's
+pub fn validate(tenants: &BTreeMap<String, TenantConfig>, admin_token: Option<&str>) -> Result<()> {
+    let mut tokens = Vec::new();
+    for (name, tenant) in tenants {
+        if name.is_empty() || name.contains(':') || name.contains(char::is_whitespace) {
+            return Err(StoreError::ConfigError(format!(
+                "Invalid tenant name '{}'",
+                name
+            )));
+        }
+        if tenant.token.is_empty()
+            || admin_token == Some(tenant.token.as_str())
+            || tokens.contains(&&tenant.token)
+        {
+            return Err(StoreError::ConfigError(format!(
+                "Tenant '{}' needs a token of its own",
+                name
+            )));
+        }
+        tokens.push(&tenant.token);
+    }
+    Ok(())
+}
+
+pub struct Tenant {
+    name: String,
+    token: String,
+    frozen: AtomicBool,
+    reads: AtomicU64,
+    writes: AtomicU64,
+}
+
+impl Tenant {
+    // Build a tenant from its config, keeping the stats of the one it replaces
+    pub fn new(name: &str, config: &TenantConfig, previous: Option<&Tenant>) -> Self {
+        let count = |counter: fn(&Tenant) -> &AtomicU64| {
+            previous.map_or(0, |tenant| counter(tenant).load(Ordering::Relaxed))
+        };
+        Tenant {
+            name: name.to_string(),
+            token: config.token.clone(),
+            frozen: AtomicBool::new(config.frozen),
+            reads: AtomicU64::new(count(|tenant| &tenant.reads)),
+            writes: AtomicU64::new(count(|tenant| &tenant.writes)),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    // Quota pattern covering the tenant's keyspace
+    pub fn pattern(&self) -> String {
+        format!("{}:*", self.name)
+    }
+
+    // Key as stored, for a key as the tenant sees it
+    pub fn scoped(&self, key: &str) -> String {
+        format!("{}:{}", self.name, key)
+    }
+
+    // Key as the tenant sees it, None if the stored key isn't the tenant's
+    pub fn unscoped<'a>(&self, key: &'a str) -> Option<&'a str> {
+        key.strip_prefix(self.name.as_str())?.strip_prefix(':')
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::SeqCst)
+    }
+
+    pub fn set_frozen(&self, frozen: bool) {
+        self.frozen.store(frozen, Ordering::SeqCst);
+    }
+
+    pub fn record_read(&self) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_write(&self) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tenant={}, frozen={}, reads={}, writes={}",
+            self.name,
+            self.is_frozen(),
+            self.reads.load(Ordering::Relaxed),
+            self.writes.load(Ordering::Relaxed)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenant_keyspace() {
+        let config = TenantConfig {
+            token: "a-token".to_string(),
+            ..Default::default()
+        };
+        let tenant = Tenant::new("tenantA", &config, None);
+        assert_eq!(tenant.scoped("user1"), "tenantA:user1");
+        assert_eq!(tenant.unscoped("tenantA:user1"), Some("user1"));
+        assert_eq!(tenant.unscoped("tenantAB:user1"), None);
+        assert_eq!(tenant.pattern(), "tenantA:*");
+
+        // Stats survive a config reload
+        tenant.record_write();
+        let reloaded = Tenant::new("tenantA", &config, Some(&tenant));
+        assert_eq!(
+            reloaded.to_string(),
+            "tenant=tenantA, frozen=false, reads=0, writes=1"
+        );
+
+        let mut tenants = BTreeMap::new();
+        tenants.insert("tenantA".to_string(), config.clone());
+        assert!(validate(&tenants, None).is_ok());
+        assert!(validate(&tenants, Some("a-token")).is_err());
+        tenants.insert("tenantB".to_string(), config);
+        assert!(validate(&tenants, None).is_err());
+    }
+}
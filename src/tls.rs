@@ -0,0 +1,585 @@
+// src/tls.rs
+
+// TLS for client connections and for the connections between the nodes of a
+// cluster. Certificates and keys are read from PEM files and read again on
+// SIGHUP, CONFIG RELOAD or when the files change, so short-lived
+// certificates from an internal CA can be rotated without a restart. A
+// reload only affects new connections; open ones keep the certificate they
+// started with.
+
+use crate::error::{Result, StoreError};
+use crate::logging::{log_error, log_info};
+use clap::Args;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+// How often the certificate files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct TlsOptions {
+    // Certificate chain (PEM) the server presents; its listeners only accept
+    // TLS when given
+    #[clap(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    // Private key (PEM) for --tls-cert
+    #[clap(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    // CA certificates (PEM) that servers we connect to must chain to.
+    // Connections to servers and other nodes use TLS when it is given,
+    // presenting --tls-cert if there is one.
+    #[clap(long)]
+    pub tls_ca: Option<PathBuf>,
+
+    // Only accept clients whose certificate chains to --tls-ca
+    #[clap(long, requires = "tls_ca")]
+    pub tls_client_auth: bool,
+}
+
+impl TlsOptions {
+    // Read the certificates, or None if no TLS option was given
+    pub fn load(&self) -> Result<Option<Arc<Tls>>> {
+        if self.tls_cert.is_none() && self.tls_ca.is_none() {
+            return Ok(None);
+        }
+        let configs = Configs::read(self)?;
+        Ok(Some(Arc::new(Tls {
+            options: self.clone(),
+            modified: Mutex::new(self.modified()),
+            configs: RwLock::new(configs),
+        })))
+    }
+
+    fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        [&self.tls_cert, &self.tls_key, &self.tls_ca]
+            .into_iter()
+            .flatten()
+    }
+
+    // When each file was last changed, None for those we can't stat
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.files()
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+// Certificates read from the files TlsOptions names, replaced as a whole on
+// reload
+pub struct Tls {
+    options: TlsOptions,
+    // As of the last time the watcher looked
+    modified: Mutex<Vec<Option<SystemTime>>>,
+    configs: RwLock<Configs>,
+}
+
+struct Configs {
+    // Present when we have a certificate to serve with
+    acceptor: Option<TlsAcceptor>,
+    // Present when we have a CA to check servers against
+    connector: Option<TlsConnector>,
+}
+
+impl Configs {
+    fn read(options: &TlsOptions) -> Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let roots = options.tls_ca.as_deref().map(read_roots).transpose()?;
+        let identity = match (&options.tls_cert, &options.tls_key) {
+            (Some(cert), Some(key)) => Some((read_certs(cert)?, read_key(key)?)),
+            _ => None,
+        };
+
+        let acceptor = match &identity {
+            Some((certs, key)) => {
+                let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+                    .with_safe_default_protocol_versions()
+                    .map_err(tls_error)?;
+                let builder = match &roots {
+                    Some(roots) if options.tls_client_auth => {
+                        let verifier = WebPkiClientVerifier::builder_with_provider(
+                            Arc::clone(roots),
+                            Arc::clone(&provider),
+                        )
+                        .build()
+                        .map_err(tls_error)?;
+                        builder.with_client_cert_verifier(verifier)
+                    }
+                    _ => builder.with_no_client_auth(),
+                };
+                let config = builder
+                    .with_single_cert(certs.clone(), key.clone_key())
+                    .map_err(tls_error)?;
+                Some(TlsAcceptor::from(Arc::new(config)))
+            }
+            None => None,
+        };
+
+        let connector = match roots {
+            Some(roots) => {
+                let builder = ClientConfig::builder_with_provider(provider)
+                    .with_safe_default_protocol_versions()
+                    .map_err(tls_error)?
+                    .with_root_certificates(roots);
+                let config = match identity {
+                    Some((certs, key)) => builder
+                        .with_client_auth_cert(certs, key)
+                        .map_err(tls_error)?,
+                    None => builder.with_no_client_auth(),
+                };
+                Some(TlsConnector::from(Arc::new(config)))
+            }
+            None => None,
+        };
+        Ok(Configs {
+            acceptor,
+            connector,
+        })
+    }
+}
+
+impl Tls {
+    // Read the files again. On failure the certificates in use are kept.
+    pub fn reload(&self) -> Result<()> {
+        let configs = Configs::read(&self.options)?;
+        *self.configs.write().unwrap() = configs;
+        log_info!("Reloaded TLS certificates");
+        Ok(())
+    }
+
+    // Reload whenever one of the files changes. A failed reload is retried
+    // when a file changes again, as happens when the key is replaced just
+    // after its certificate.
+    pub fn watch(self: &Arc<Self>) -> JoinHandle<()> {
+        let tls = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+            loop {
+                interval.tick().await;
+                if tls.changed()
+                    && let Err(e) = tls.reload()
+                {
+                    log_error!("Failed to reload TLS certificates: {}", e);
+                }
+            }
+        })
+    }
+
+    // Whether any file changed since we last looked
+    fn changed(&self) -> bool {
+        let modified = self.options.modified();
+        let mut seen = self.modified.lock().unwrap();
+        if *seen == modified {
+            return false;
+        }
+        *seen = modified;
+        true
+    }
+
+    // Complete the server side of the handshake, if we have a certificate
+    pub async fn accept(&self, stream: TcpStream) -> Result<Stream> {
+        let acceptor = self.configs.read().unwrap().acceptor.clone();
+        match acceptor {
+            Some(acceptor) => {
+                let stream = acceptor.accept(stream).await?;
+                Ok(Stream::Tls(Box::new(stream.into())))
+            }
+            None => Ok(Stream::Plain(stream)),
+        }
+    }
+
+    // Complete the client side of the handshake with the server at
+    // `address`, if we have a CA to check its certificate against
+    pub async fn connect(&self, address: &str, stream: TcpStream) -> Result<Stream> {
+        let connector = self.configs.read().unwrap().connector.clone();
+        match connector {
+            Some(connector) => {
+                let stream = connector.connect(server_name(address)?, stream).await?;
+                Ok(Stream::Tls(Box::new(stream.into())))
+            }
+            None => Ok(Stream::Plain(stream)),
+        }
+    }
+}
+
+// The name a server's certificate must be valid for: the host part of its
+// address, a DNS name or an IP address
+fn server_name(address: &str) -> Result<ServerName<'static>> {
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string())
+        .map_err(|_| StoreError::ConfigError(format!("Invalid server name in {}", address)))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(path, e))?;
+    if certs.is_empty() {
+        return Err(StoreError::ConfigError(format!(
+            "No certificates in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| pem_error(path, e))
+}
+
+fn read_roots(path: &Path) -> Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| StoreError::ConfigError(format!("{}: {}", path.display(), e)))?;
+    }
+    Ok(Arc::new(roots))
+}
+
+fn pem_error(path: &Path, e: rustls::pki_types::pem::Error) -> StoreError {
+    match e {
+        rustls::pki_types::pem::Error::Io(e) => StoreError::IoError(e),
+        e => StoreError::ConfigError(format!("{}: {:?}", path.display(), e)),
+    }
+}
+
+fn tls_error(e: impl std::fmt::Display) -> StoreError {
+    StoreError::ConfigError(format!("TLS: {}", e))
+}
+
+// A connection that is either plain TCP or TLS over it
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<tokio_rustls::TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            // Clients hang up without a close_notify once they have their
+            // answer. Every command and response is a whole line, so a
+            // truncated one can't pass for complete and this is a clean close.
+            Stream::Tls(stream) => match Pin::new(stream).poll_read(cx, buf) {
+                Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    Poll::Ready(Ok(()))
+                }
+                poll => poll,
+            },
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::network::Server;
+    use crate::store::KeyValueStore;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+
+    // A CA that issues certificates for 127.0.0.1 as PEM files in `dir`
+    struct Authority {
+        cert: Certificate,
+        key: KeyPair,
+    }
+
+    impl Authority {
+        fn new() -> Self {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let cert = params.self_signed(&key).unwrap();
+            Authority { cert, key }
+        }
+
+        fn write_ca(&self, path: &Path) {
+            std::fs::write(path, self.cert.pem()).unwrap();
+        }
+
+        // Issue a certificate, writing it and its key to `name`.pem and
+        // `name`.key
+        fn issue(&self, dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec!["127.0.0.1".to_string()])
+                .unwrap()
+                .signed_by(&key, &self.cert, &self.key)
+                .unwrap();
+            let cert_path = dir.join(format!("{}.pem", name));
+            let key_path = dir.join(format!("{}.key", name));
+            std::fs::write(&cert_path, cert.pem()).unwrap();
+            std::fs::write(&key_path, key.serialize_pem()).unwrap();
+            (cert_path, key_path)
+        }
+    }
+
+    fn trusting(ca: &Path) -> Arc<Tls> {
+        let options = TlsOptions {
+            tls_ca: Some(ca.to_path_buf()),
+            ..TlsOptions::default()
+        };
+        options.load().unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_no_options() {
+        assert!(TlsOptions::default().load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_server_name() {
+        assert_eq!(
+            server_name("127.0.0.1:7000").unwrap(),
+            ServerName::try_from("127.0.0.1").unwrap()
+        );
+        assert_eq!(
+            server_name("[::1]:7000").unwrap(),
+            ServerName::try_from("::1").unwrap()
+        );
+        assert_eq!(
+            server_name("kv.internal:7000").unwrap(),
+            ServerName::try_from("kv.internal").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_bad_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = dir.path().join("ca.pem");
+        std::fs::write(&ca, "not a certificate").unwrap();
+        let options = TlsOptions {
+            tls_ca: Some(ca),
+            ..TlsOptions::default()
+        };
+        assert!(matches!(options.load(), Err(StoreError::ConfigError(_))));
+
+        // A key that doesn't belong to the certificate
+        let authority = Authority::new();
+        let (cert, _) = authority.issue(dir.path(), "server");
+        let (_, other_key) = authority.issue(dir.path(), "other");
+        let options = TlsOptions {
+            tls_cert: Some(cert),
+            tls_key: Some(other_key),
+            ..TlsOptions::default()
+        };
+        assert!(options.load().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tls_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let authority = Authority::new();
+        let ca = dir.path().join("ca.pem");
+        authority.write_ca(&ca);
+        let (cert, key) = authority.issue(dir.path(), "server");
+        let options = TlsOptions {
+            tls_cert: Some(cert),
+            tls_key: Some(key),
+            tls_ca: Some(ca.clone()),
+            tls_client_auth: false,
+        };
+
+        // A primary and a backup that registers with it, both over TLS
+        let primary_addr = "127.0.0.1:7938".to_string();
+        let primary =
+            Server::with_replication(Arc::new(KeyValueStore::new()), primary_addr.clone())
+                .with_tls(options.load().unwrap());
+        primary.start_as_primary().await.unwrap();
+        let _primary = primary.spawn().await.unwrap();
+
+        let backup_addr = "127.0.0.1:7939".to_string();
+        let backup = Server::with_replication(Arc::new(KeyValueStore::new()), backup_addr.clone())
+            .with_tls(options.load().unwrap());
+        backup.start_as_backup(primary_addr.clone()).await.unwrap();
+        let _backup = backup.spawn().await.unwrap();
+
+        let client = Client::new(primary_addr.clone()).with_tls(trusting(&ca));
+        let mut registered = false;
+        for _ in 0..50 {
+            let info = client.send_command("INFO REPLICATION").await.unwrap();
+            if info.contains(&backup_addr) {
+                registered = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(registered, "the backup never registered");
+
+        client.put("key", "value").await.unwrap();
+        let backup_client = Client::new(backup_addr).with_tls(trusting(&ca));
+        let mut replicated = None;
+        for _ in 0..50 {
+            replicated = backup_client.get("key").await.unwrap();
+            if replicated.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(replicated.as_deref(), Some("value"));
+
+        // Plain TCP gets nowhere
+        let plain = Client::new(primary_addr);
+        assert_ne!(
+            plain.send_command("HEALTH LIVE").await.ok().as_deref(),
+            Some("OK")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_websocket_gateway() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let dir = tempfile::tempdir().unwrap();
+        let authority = Authority::new();
+        let ca = dir.path().join("ca.pem");
+        authority.write_ca(&ca);
+        let (cert, key) = authority.issue(dir.path(), "server");
+        let options = TlsOptions {
+            tls_cert: Some(cert),
+            tls_key: Some(key),
+            ..TlsOptions::default()
+        };
+        let server = Server::new(Arc::new(KeyValueStore::new()), "127.0.0.1:0".to_string())
+            .with_websocket(Some("127.0.0.1:0".to_string()))
+            .with_tls(options.load().unwrap());
+        let server = server.spawn().await.unwrap();
+        let address = server.websocket_address().unwrap().to_string();
+
+        // What a browser does for a wss:// URL
+        let stream = TcpStream::connect(&address).await.unwrap();
+        let stream = trusting(&ca).connect(&address, stream).await.unwrap();
+        let (mut websocket, _) =
+            tokio_tungstenite::client_async(format!("wss://{}/", address), stream)
+                .await
+                .unwrap();
+        websocket.send(Message::text("HEALTH LIVE")).await.unwrap();
+        let response = websocket.next().await.unwrap().unwrap();
+        assert_eq!(response.into_text().unwrap().as_str(), "OK");
+    }
+
+    #[tokio::test]
+    async fn test_certificate_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old_authority, new_authority) = (Authority::new(), Authority::new());
+        let (old_ca, new_ca) = (dir.path().join("old-ca.pem"), dir.path().join("new-ca.pem"));
+        old_authority.write_ca(&old_ca);
+        new_authority.write_ca(&new_ca);
+
+        let (cert, key) = old_authority.issue(dir.path(), "server");
+        let options = TlsOptions {
+            tls_cert: Some(cert),
+            tls_key: Some(key),
+            ..TlsOptions::default()
+        };
+        let tls = options.load().unwrap().unwrap();
+        let server = Server::new(Arc::new(KeyValueStore::new()), "127.0.0.1:0".to_string())
+            .with_tls(Some(Arc::clone(&tls)));
+        let server = server.spawn().await.unwrap();
+        let address = server.address().to_string();
+
+        let old_client = Client::new(address.clone()).with_tls(trusting(&old_ca));
+        let new_client = Client::new(address.clone()).with_tls(trusting(&new_ca));
+        assert_eq!(old_client.send_command("HEALTH LIVE").await.unwrap(), "OK");
+        assert!(new_client.send_command("HEALTH LIVE").await.is_err());
+
+        // The watcher notices the new files, and CONFIG RELOAD works without
+        // a config file
+        assert!(!tls.changed());
+        new_authority.issue(dir.path(), "server");
+        assert!(tls.changed());
+        assert!(!tls.changed());
+        assert_eq!(
+            old_client.send_command("CONFIG RELOAD").await.unwrap(),
+            "OK"
+        );
+
+        assert_eq!(new_client.send_command("HEALTH LIVE").await.unwrap(), "OK");
+        assert!(old_client.send_command("HEALTH LIVE").await.is_err());
+
+        // A half-written rotation keeps the certificate in use
+        std::fs::write(dir.path().join("server.key"), "").unwrap();
+        assert!(tls.reload().is_err());
+        assert_eq!(new_client.send_command("HEALTH LIVE").await.unwrap(), "OK");
+    }
+
+    #[tokio::test]
+    async fn test_client_auth() {
+        let dir = tempfile::tempdir().unwrap();
+        let authority = Authority::new();
+        let ca = dir.path().join("ca.pem");
+        authority.write_ca(&ca);
+        let (cert, key) = authority.issue(dir.path(), "server");
+        let options = TlsOptions {
+            tls_cert: Some(cert),
+            tls_key: Some(key),
+            tls_ca: Some(ca.clone()),
+            tls_client_auth: true,
+        };
+        let server = Server::new(Arc::new(KeyValueStore::new()), "127.0.0.1:0".to_string())
+            .with_tls(options.load().unwrap());
+        let server = server.spawn().await.unwrap();
+        let address = server.address().to_string();
+
+        // TLS 1.3 reports a rejected client certificate after the handshake,
+        // so the first command is what fails
+        let anonymous = Client::new(address.clone()).with_tls(trusting(&ca));
+        assert!(anonymous.send_command("HEALTH LIVE").await.is_err());
+
+        let (cert, key) = authority.issue(dir.path(), "client");
+        let options = TlsOptions {
+            tls_cert: Some(cert),
+            tls_key: Some(key),
+            tls_ca: Some(ca),
+            tls_client_auth: false,
+        };
+        let client = Client::new(address).with_tls(options.load().unwrap().unwrap());
+        assert_eq!(client.send_command("HEALTH LIVE").await.unwrap(), "OK");
+    }
+}
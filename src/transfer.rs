@@ -0,0 +1,366 @@
+// src/transfer.rs
+
+// Moving data in and out of the store in formats other tools understand:
+// JSON lines (the seed file format), CSV, and the Redis protocol, so an
+// export can be piped into `redis-cli --pipe` and a Redis dump of SET
+// commands can be imported.
+
+use crate::error::{Result, StoreError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    // One {"key": ..., "value": ...} object per line
+    #[default]
+    Jsonl,
+    // key,value rows under a header row
+    Csv,
+    // A SET command per key, as RESP arrays
+    Redis,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Format::Jsonl => "jsonl",
+            Format::Csv => "csv",
+            Format::Redis => "redis",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            "redis" => Ok(Format::Redis),
+            _ => Err(format!(
+                "Unknown format '{}', expected jsonl, csv or redis",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsvOptions {
+    pub delimiter: char,
+    // Quote every field, rather than only those that need it
+    pub quote_all: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: ',',
+            quote_all: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonEntry<'a> {
+    key: std::borrow::Cow<'a, str>,
+    value: std::borrow::Cow<'a, str>,
+}
+
+fn invalid(message: String) -> StoreError {
+    StoreError::SerializationError(message)
+}
+
+// Write `entries` in `format`, returning how many were written
+pub fn export<'a>(
+    entries: impl IntoIterator<Item = (&'a str, &'a str)>,
+    format: Format,
+    csv: CsvOptions,
+    writer: &mut impl Write,
+) -> Result<usize> {
+    if format == Format::Csv {
+        write_csv_row(writer, "key", "value", csv)?;
+    }
+
+    let mut count = 0;
+    for (key, value) in entries {
+        match format {
+            Format::Jsonl => {
+                let entry = JsonEntry {
+                    key: key.into(),
+                    value: value.into(),
+                };
+                serde_json::to_writer(&mut *writer, &entry).map_err(|e| invalid(e.to_string()))?;
+                writer.write_all(b"\n")?;
+            }
+            Format::Csv => write_csv_row(writer, key, value, csv)?,
+            Format::Redis => {
+                write!(writer, "*3\r\n$3\r\nSET\r\n")?;
+                for arg in [key, value] {
+                    write!(writer, "${}\r\n{}\r\n", arg.len(), arg)?;
+                }
+            }
+        }
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+// Read key/value pairs written in `format`
+pub fn import(
+    reader: &mut impl BufRead,
+    format: Format,
+    csv: CsvOptions,
+) -> Result<Vec<(String, String)>> {
+    match format {
+        Format::Jsonl => {
+            let mut entries = Vec::new();
+            for (i, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let entry: JsonEntry = serde_json::from_str(&line)
+                    .map_err(|e| invalid(format!("line {}: {}", i + 1, e)))?;
+                entries.push((entry.key.into_owned(), entry.value.into_owned()));
+            }
+            Ok(entries)
+        }
+        Format::Csv => {
+            let mut input = String::new();
+            reader.read_to_string(&mut input)?;
+            read_csv(&input, csv.delimiter)
+        }
+        Format::Redis => {
+            let mut input = Vec::new();
+            reader.read_to_end(&mut input)?;
+            read_redis(&input)
+        }
+    }
+}
+
+fn write_csv_row(writer: &mut impl Write, key: &str, value: &str, csv: CsvOptions) -> Result<()> {
+    let field = |text: &str| {
+        let needs_quotes = text.contains([csv.delimiter, '"', '\n', '\r']);
+        if csv.quote_all || needs_quotes {
+            format!("\"{}\"", text.replace('"', "\"\""))
+        } else {
+            text.to_string()
+        }
+    };
+    write!(
+        writer,
+        "{}{}{}\r\n",
+        field(key),
+        csv.delimiter,
+        field(value)
+    )?;
+    Ok(())
+}
+
+// RFC 4180 records of exactly two fields. Quoted fields may hold the
+// delimiter, newlines and doubled quotes. A leading key,value header is
+// skipped.
+fn read_csv(input: &str, delimiter: char) -> Result<Vec<(String, String)>> {
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            '\n' if quoted => {
+                line += 1;
+                field.push(c);
+            }
+            _ if quoted => field.push(c),
+            c if c == delimiter => fields.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                fields.push(std::mem::take(&mut field));
+                records.push((line, std::mem::take(&mut fields)));
+                line += 1;
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(invalid(format!("line {}: unterminated quoted field", line)));
+    }
+    if !field.is_empty() || !fields.is_empty() {
+        fields.push(field);
+        records.push((line, fields));
+    }
+
+    let mut entries = Vec::new();
+    for (i, (line, fields)) in records.into_iter().enumerate() {
+        match <[String; 2]>::try_from(fields) {
+            Ok([key, value]) if i == 0 && key == "key" && value == "value" => {}
+            Ok([key, value]) => entries.push((key, value)),
+            // Blank lines
+            Err(fields) if fields.len() == 1 && fields[0].is_empty() => {}
+            Err(fields) => {
+                return Err(invalid(format!(
+                    "line {}: expected 2 fields, found {}",
+                    line,
+                    fields.len()
+                )));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// SET commands in the Redis protocol: arrays of bulk strings
+fn read_redis(input: &[u8]) -> Result<Vec<(String, String)>> {
+    let mut entries = Vec::new();
+    for args in read_commands(input)? {
+        let args = args
+            .into_iter()
+            .map(String::from_utf8)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| invalid("Bulk string is not UTF-8".to_string()))?;
+
+        match <[String; 3]>::try_from(args) {
+            Ok([command, key, value]) if command.eq_ignore_ascii_case("SET") => {
+                entries.push((key, value))
+            }
+            Ok([command, ..]) => return Err(invalid(format!("Unsupported command {}", command))),
+            Err(args) => {
+                let command = args.first().map_or("", String::as_str);
+                return Err(invalid(format!(
+                    "Unsupported command {} with {} arguments",
+                    command,
+                    args.len().saturating_sub(1)
+                )));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// Split Redis protocol input into commands and their raw arguments
+pub(crate) fn read_commands(mut input: &[u8]) -> Result<Vec<Vec<Vec<u8>>>> {
+    let mut commands = Vec::new();
+    while !input.is_empty() {
+        let count = read_length(&mut input, b'*')?;
+        // Every argument takes up several bytes, so a count beyond what is
+        // left can only come from a damaged file
+        let mut args = Vec::with_capacity(count.min(input.len()));
+        for _ in 0..count {
+            let len = read_length(&mut input, b'$')?;
+            let end = len
+                .checked_add(2)
+                .filter(|&end| end <= input.len())
+                .ok_or_else(|| invalid("Truncated bulk string".to_string()))?;
+            if &input[len..end] != b"\r\n" {
+                return Err(invalid("Truncated bulk string".to_string()));
+            }
+            args.push(input[..len].to_vec());
+            input = &input[end..];
+        }
+        commands.push(args);
+    }
+    Ok(commands)
+}
+
+// Parse a `<marker><length>\r\n` line
+fn read_length(input: &mut &[u8], marker: u8) -> Result<usize> {
+    let end = input
+        .windows(2)
+        .position(|pair| pair == b"\r\n")
+        .ok_or_else(|| invalid("Truncated command".to_string()))?;
+    let line = &input[..end];
+    if line.first() != Some(&marker) {
+        return Err(invalid(format!(
+            "Expected '{}', found {:?}",
+            marker as char,
+            String::from_utf8_lossy(line)
+        )));
+    }
+    let length = std::str::from_utf8(&line[1..])
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| {
+            invalid(format!(
+                "Invalid length {:?}",
+                String::from_utf8_lossy(line)
+            ))
+        })?;
+    *input = &input[end + 2..];
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_import_round_trip() -> Result<()> {
+        let entries = [
+            ("plain", "value"),
+            ("with;delimiter", "say \"hi\""),
+            ("multi\nline", ""),
+        ];
+
+        for (format, csv) in [
+            (Format::Jsonl, CsvOptions::default()),
+            (Format::Csv, CsvOptions::default()),
+            (
+                Format::Csv,
+                CsvOptions {
+                    delimiter: ';',
+                    quote_all: true,
+                },
+            ),
+            (Format::Redis, CsvOptions::default()),
+        ] {
+            let mut out = Vec::new();
+            assert_eq!(export(entries, format, csv, &mut out)?, 3);
+            let imported = import(&mut out.as_slice(), format, csv)?;
+            let imported: Vec<(&str, &str)> = imported
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect();
+            assert_eq!(imported, entries, "{} {:?}", format, csv);
+        }
+
+        // Output other tools can read
+        let mut out = Vec::new();
+        export([("k", "a,b")], Format::Csv, CsvOptions::default(), &mut out)?;
+        assert_eq!(out, b"key,value\r\nk,\"a,b\"\r\n");
+        let mut out = Vec::new();
+        export([("k", "v")], Format::Redis, CsvOptions::default(), &mut out)?;
+        assert_eq!(out, b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n");
+
+        // Hand-written CSV without a header, and bad input
+        let csv = CsvOptions::default();
+        let imported = import(&mut "a,1\nb,2".as_bytes(), Format::Csv, csv)?;
+        assert_eq!(imported.len(), 2);
+        assert!(import(&mut "a,1,extra\n".as_bytes(), Format::Csv, csv).is_err());
+        let del = b"*2\r\n$3\r\nDEL\r\n$1\r\nk\r\n";
+        assert!(import(&mut del.as_slice(), Format::Redis, csv).is_err());
+
+        // Counts and lengths no file could hold
+        assert!(read_commands(b"*4611686018427387903\r\n").is_err());
+        assert!(read_commands(b"*1\r\n$18446744073709551615\r\nx\r\n").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,323 @@
+// src/value.rs
+
+// How the store holds a value. Large values can be kept LZ4-compressed in
+// memory, and written base64-encoded to the database file so it stays JSON.
+// Very large values can be spilled to a file of their own, keeping only the
+// path in memory; the database file still holds them inline. The file is
+// removed once neither the store nor a snapshot of it holds the value.
+// With --engine mmap, values loaded from the database file are read in place
+// from a memory map of it, and paged in by the OS only when read. Readers
+// always get the original text back.
+
+use crate::error::{Result, StoreError};
+use crate::logging::log_error;
+use memmap2::Mmap;
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// A value as held by the store
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoredValue {
+    Plain(String),
+    // LZ4 block with the original length prepended
+    Compressed(Vec<u8>),
+    // Written to a file, along with the length of the text
+    Spilled { file: Arc<SpillFile>, len: usize },
+    // In the mapped database file, along with the length of the text
+    Mapped { value: MappedValue, len: usize },
+}
+
+// The file a spilled value lives in, removed when the last copy is dropped
+#[derive(Debug, PartialEq)]
+pub struct SpillFile(PathBuf);
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            log_error!("Could not remove spilled value {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+// Where a value sits in a mapped database file: its text, or a JSON string
+// literal if it has to be unescaped
+#[derive(Clone)]
+pub struct MappedValue {
+    file: Arc<Mmap>,
+    range: Range<usize>,
+    escaped: bool,
+}
+
+impl MappedValue {
+    fn read(&self) -> Result<String> {
+        let bytes = &self.file[self.range.clone()];
+        if self.escaped {
+            return serde_json::from_slice(bytes)
+                .map_err(|e| StoreError::SerializationError(e.to_string()));
+        }
+        String::from_utf8(bytes.to_vec()).map_err(|e| StoreError::SerializationError(e.to_string()))
+    }
+}
+
+impl PartialEq for MappedValue {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.file, &other.file) && self.range == other.range
+    }
+}
+
+impl fmt::Debug for MappedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("MappedValue").field(&self.range).finish()
+    }
+}
+
+impl StoredValue {
+    // Compress `value` if it is at least `threshold` bytes (0 = never) and
+    // compressing actually makes it smaller
+    pub fn new(value: String, threshold: usize) -> Self {
+        if threshold == 0 || value.len() < threshold {
+            return StoredValue::Plain(value);
+        }
+
+        let compressed = lz4_flex::compress_prepend_size(value.as_bytes());
+        if compressed.len() < value.len() {
+            StoredValue::Compressed(compressed)
+        } else {
+            StoredValue::Plain(value)
+        }
+    }
+
+    // Rebuild a value from its saved form, checking compressed data is intact
+    pub fn from_saved(value: String, compressed: bool) -> Result<Self> {
+        if !compressed {
+            return Ok(StoredValue::Plain(value));
+        }
+
+        let data = decode_base64(&value)?;
+        decompress(&data)?;
+        Ok(StoredValue::Compressed(data))
+    }
+
+    // Write `value` to `path` and keep only a reference to it
+    pub fn spill(value: &str, path: PathBuf) -> Result<Self> {
+        fs::write(&path, value)?;
+        Ok(StoredValue::Spilled {
+            file: Arc::new(SpillFile(path)),
+            len: value.len(),
+        })
+    }
+
+    // Refer to `text`, a slice of `file`, instead of copying it. An escaped
+    // JSON string literal is unescaped when read. None if `text` isn't in
+    // `file`.
+    pub fn mapped(file: &Arc<Mmap>, text: &[u8], escaped: bool) -> Option<Result<Self>> {
+        let start = (text.as_ptr() as usize).checked_sub(file.as_ptr() as usize)?;
+        let range = start..start + text.len();
+        if range.end > file.len() {
+            return None;
+        }
+        let value = MappedValue {
+            file: Arc::clone(file),
+            range,
+            escaped,
+        };
+        // Only escaped text has to be read to know its length
+        let len = if escaped {
+            match value.read() {
+                Ok(text) => text.len(),
+                Err(e) => return Some(Err(e)),
+            }
+        } else {
+            text.len()
+        };
+        Some(Ok(StoredValue::Mapped { value, len }))
+    }
+
+    // The value as written to the database file, and whether it is compressed
+    pub fn to_saved(&self) -> (String, bool) {
+        match self {
+            StoredValue::Compressed(data) => (encode_base64(data), true),
+            _ => (self.text(), false),
+        }
+    }
+
+    // The original text
+    pub fn text(&self) -> String {
+        match self {
+            StoredValue::Plain(value) => value.clone(),
+            // Compressed data is only ever built by us or checked on load
+            StoredValue::Compressed(data) => decompress(data).unwrap_or_default(),
+            StoredValue::Spilled { file, .. } => fs::read_to_string(&file.0).unwrap_or_else(|e| {
+                log_error!("Could not read spilled value {}: {}", file.0.display(), e);
+                String::new()
+            }),
+            // Checked when the file was loaded
+            StoredValue::Mapped { value, .. } => value.read().unwrap_or_default(),
+        }
+    }
+
+    // Length of the original text
+    pub fn text_len(&self) -> usize {
+        match self {
+            StoredValue::Plain(value) => value.len(),
+            StoredValue::Compressed(data) => data
+                .first_chunk()
+                .map_or(0, |len| u32::from_le_bytes(*len) as usize),
+            StoredValue::Spilled { len, .. } | StoredValue::Mapped { len, .. } => *len,
+        }
+    }
+
+    // Bytes held in memory
+    pub fn size(&self) -> usize {
+        match self {
+            StoredValue::Plain(value) => value.len(),
+            StoredValue::Compressed(data) => data.len(),
+            StoredValue::Spilled { file, .. } => file.0.as_os_str().len(),
+            StoredValue::Mapped { .. } => size_of::<Range<usize>>(),
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        matches!(self, StoredValue::Compressed(_))
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        matches!(self, StoredValue::Spilled { .. })
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, StoredValue::Mapped { .. })
+    }
+}
+
+fn decompress(data: &[u8]) -> Result<String> {
+    let bytes = lz4_flex::decompress_size_prepended(data)
+        .map_err(|e| StoreError::SerializationError(format!("Corrupt compressed value: {}", e)))?;
+    String::from_utf8(bytes)
+        .map_err(|e| StoreError::SerializationError(format!("Corrupt compressed value: {}", e)))
+}
+
+pub(crate) fn encode_base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+pub(crate) fn decode_base64(encoded: &str) -> Result<Vec<u8>> {
+    let invalid = || StoreError::SerializationError("Invalid base64".to_string());
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+
+    let mut data = Vec::with_capacity(encoded.len() * 3 / 4);
+    for chunk in encoded.chunks(4) {
+        if chunk.len() == 1 {
+            return Err(invalid());
+        }
+        let mut bits = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let index = BASE64_ALPHABET
+                .iter()
+                .position(|a| a == c)
+                .ok_or_else(invalid)?;
+            bits |= (index as u32) << (18 - 6 * i);
+        }
+        data.extend_from_slice(&bits.to_be_bytes()[1..chunk.len()]);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_compressed_values() -> Result<()> {
+        let large = "{\"field\": \"value\"}, ".repeat(100);
+        let value = StoredValue::new(large.clone(), 1024);
+        assert!(value.is_compressed());
+        assert!(value.size() < large.len());
+        assert_eq!(value.text(), large);
+        assert_eq!(value.text_len(), large.len());
+
+        // Small or incompressible values are left alone
+        assert!(!StoredValue::new("short".to_string(), 1024).is_compressed());
+        assert!(!StoredValue::new(large.clone(), 0).is_compressed());
+
+        let (saved, compressed) = value.to_saved();
+        assert!(compressed);
+        assert_eq!(StoredValue::from_saved(saved, true)?, value);
+        assert!(StoredValue::from_saved("not base64!".to_string(), true).is_err());
+        assert!(StoredValue::from_saved("AAAA".to_string(), true).is_err());
+
+        for data in [&b""[..], b"a", b"ab", b"abc", b"abcd"] {
+            assert_eq!(decode_base64(&encode_base64(data))?, data);
+        }
+        assert_eq!(encode_base64(b"ab"), "YWI=");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_spilled_values() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("1");
+        let value = StoredValue::spill("large value", path.clone())?;
+        assert!(value.is_spilled());
+        assert_eq!(value.text(), "large value");
+        assert_eq!(value.text_len(), 11);
+        assert_eq!(value.to_saved(), ("large value".to_string(), false));
+
+        // The file goes with the last copy of the value
+        let copy = value.clone();
+        drop(value);
+        assert!(path.exists());
+        drop(copy);
+        assert!(!path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mapped_values() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("mapped");
+        fs::write(&path, r#"plain "say \"hi\"""#)?;
+        let file = Arc::new(unsafe { Mmap::map(&File::open(&path)?)? });
+
+        let plain = StoredValue::mapped(&file, &file[..5], false).unwrap()?;
+        assert!(plain.is_mapped());
+        assert_eq!(plain.text(), "plain");
+        assert_eq!(plain.text_len(), 5);
+        assert_eq!(plain.to_saved(), ("plain".to_string(), false));
+
+        // JSON escapes are undone on read
+        let escaped = StoredValue::mapped(&file, &file[6..], true).unwrap()?;
+        assert_eq!(escaped.text(), r#"say "hi""#);
+        assert_eq!(escaped.text_len(), 8);
+
+        // Text from anywhere else can't be mapped
+        assert!(StoredValue::mapped(&file, b"elsewhere", false).is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+// src/vclock.rs
+
+// Vector clocks: a write counter per node that has written a key. Comparing
+// two clocks tells whether one write saw the other or whether they were made
+// independently, in which case neither should silently win.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+// Written as `node=count,node=count`, empty for a clock with no writes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+impl VectorClock {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Count a write made on `node`
+    pub fn increment(&mut self, node: &str) {
+        *self.0.entry(node.to_string()).or_default() += 1;
+    }
+
+    // Take in every write `other` has seen
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, &count) in &other.0 {
+            let ours = self.0.entry(node.clone()).or_default();
+            *ours = (*ours).max(count);
+        }
+    }
+
+    // Less if we happened before `other`, Greater if after, None if the
+    // two are concurrent
+    pub fn compare(&self, other: &VectorClock) -> Option<Ordering> {
+        let count = |clock: &VectorClock, node: &str| clock.0.get(node).copied().unwrap_or(0);
+        let (mut behind, mut ahead) = (false, false);
+        for node in self.0.keys().chain(other.0.keys()) {
+            match count(self, node).cmp(&count(other, node)) {
+                Ordering::Less => behind = true,
+                Ordering::Greater => ahead = true,
+                Ordering::Equal => {}
+            }
+        }
+        match (behind, ahead) {
+            (false, false) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (true, true) => None,
+        }
+    }
+}
+
+impl fmt::Display for VectorClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries: Vec<String> = self
+            .0
+            .iter()
+            .map(|(node, count)| format!("{}={}", node, count))
+            .collect();
+        f.write_str(&entries.join(","))
+    }
+}
+
+impl FromStr for VectorClock {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut clock = VectorClock::default();
+        for entry in s.split(',').filter(|entry| !entry.is_empty()) {
+            let count = entry
+                .split_once('=')
+                .and_then(|(node, count)| Some((node, count.parse().ok()?)))
+                .filter(|(node, _)| !node.is_empty());
+            let Some((node, count)) = count else {
+                return Err(format!("Invalid vector clock entry '{}'", entry));
+            };
+            clock.0.insert(node.to_string(), count);
+        }
+        Ok(clock)
+    }
+}
+
+impl Serialize for VectorClock {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for VectorClock {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vector_clocks() {
+        let mut a = VectorClock::default();
+        a.increment("a");
+        let mut later = a.clone();
+        later.increment("b");
+        assert_eq!(a.compare(&later), Some(Ordering::Less));
+        assert_eq!(later.compare(&a), Some(Ordering::Greater));
+        assert_eq!(a.compare(&a.clone()), Some(Ordering::Equal));
+
+        // Writes that didn't see each other are concurrent until merged
+        let mut other = a.clone();
+        other.increment("c");
+        assert_eq!(later.compare(&other), None);
+        let mut merged = later.clone();
+        merged.merge(&other);
+        assert_eq!(merged.to_string(), "a=1,b=1,c=1");
+        assert_eq!(merged.compare(&other), Some(Ordering::Greater));
+
+        assert_eq!(merged.to_string().parse(), Ok(merged));
+        assert_eq!("".parse(), Ok(VectorClock::default()));
+        assert!("a=x".parse::<VectorClock>().is_err());
+    }
+}
@@ -0,0 +1,657 @@
+// src/wal.rs
+
+// Write-ahead log. Every committed write is appended to a file next to the
+// database before it is applied, and synced to disk before it is
+// acknowledged, so a crash between saves loses nothing. Loading the database
+// replays the writes it doesn't cover yet. Records are changelog entries, one
+// JSON line each, sealed one by one when encryption at rest is on. A last
+// line cut short by a crash is ignored.
+//
+// The log is split into segments. Writes go to `<db>.wal`, which is sealed
+// as `<db>.wal.<first seq>` once it reaches `segment_size`, and on every
+// save. Sealed segments a save covers are removed, apart from the newest
+// `segment_retention` of them, which are kept as an archive of past writes,
+// and those with writes a backup hasn't acknowledged yet, which it can
+// still catch up from. A log that has grown too large is compacted by
+// saving.
+//
+// Writers that sync at the same time share one fsync (group commit), and
+// `commit_delay` makes the writer doing the fsync wait a little first so
+// more of them can join.
+
+use crate::changelog::{Change, ChangeOp};
+use crate::encryption;
+use crate::error::{Result, StoreError};
+use crate::faults;
+use crate::hlc::Timestamp;
+use crate::logging::log_warn;
+use crate::store;
+use crate::value::{decode_base64, encode_base64};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Size at which the segment being written is sealed
+pub const DEFAULT_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+pub struct Wal {
+    path: PathBuf,
+    active: Mutex<Active>,
+
+    // Records appended, only changed with `active` locked
+    written: AtomicU64,
+
+    // How far the log is known to be on disk, and whether a writer is
+    // syncing it right now
+    synced: Mutex<SyncState>,
+    sync_done: Condvar,
+
+    commit_delay_us: AtomicU64,
+    segment_size: AtomicU64,
+    segment_retention: AtomicUsize,
+    // Writes up to here every backup has applied, u64::MAX with no backups
+    replicas_acked: AtomicU64,
+    syncs: AtomicU64,
+}
+
+// The segment being written
+struct Active {
+    file: File,
+    size: u64,
+    // Sequence of its first write, None while it is empty
+    first_seq: Option<u64>,
+    // Sequence of the last write in any segment
+    last_seq: u64,
+    // Writes up to here are in the database file
+    covered: u64,
+}
+
+#[derive(Default)]
+struct SyncState {
+    through: u64,
+    syncing: bool,
+}
+
+// The log kept next to a database file
+pub fn wal_path(db_path: &Path) -> PathBuf {
+    let mut name = db_path.as_os_str().to_owned();
+    name.push(".wal");
+    PathBuf::from(name)
+}
+
+// Where the segment of the log at `path` starting at `first_seq` is sealed.
+// Padded so the names sort in order.
+fn segment_path(path: &Path, first_seq: u64) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{:020}", first_seq));
+    PathBuf::from(name)
+}
+
+// Sealed segments of the log at `path`, oldest first, with their first sequence
+fn sealed_segments(path: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let Some(prefix) = path.file_name().map(|name| name.to_string_lossy() + ".") else {
+        return Ok(Vec::new());
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut segments = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let first_seq = name
+            .strip_prefix(prefix.as_ref())
+            .filter(|seq| !seq.is_empty() && seq.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|seq| seq.parse().ok());
+        if let Some(first_seq) = first_seq {
+            segments.push((first_seq, entry.path()));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+impl Wal {
+    // Open the log for appending, creating it if needed. A last record cut
+    // short is cut off, so the next one starts on a line of its own.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = append_to(path)?;
+        let contents = fs::read(path)?;
+        let complete = contents
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |end| end + 1);
+        if complete < contents.len() {
+            log_warn!(
+                "Dropping {} bytes of an incomplete record from {}",
+                contents.len() - complete,
+                path.display()
+            );
+            file.set_len(complete as u64)?;
+        }
+
+        let active = read_file(path, true)?;
+        let last_seq = match active.last() {
+            Some(change) => change.seq,
+            None => read(path)?.last().map_or(0, |change| change.seq),
+        };
+        Ok(Wal {
+            path: path.to_path_buf(),
+            active: Mutex::new(Active {
+                file,
+                size: complete as u64,
+                first_seq: active.first().map(|change| change.seq),
+                last_seq,
+                covered: 0,
+            }),
+            written: AtomicU64::new(0),
+            synced: Mutex::new(SyncState::default()),
+            sync_done: Condvar::new(),
+            commit_delay_us: AtomicU64::new(0),
+            segment_size: AtomicU64::new(DEFAULT_SEGMENT_SIZE),
+            segment_retention: AtomicUsize::new(0),
+            replicas_acked: AtomicU64::new(u64::MAX),
+            syncs: AtomicU64::new(0),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Bytes of the log a save doesn't cover yet
+    pub fn size(&self) -> u64 {
+        let active = self.active.lock().unwrap();
+        let sealed = self.sealed(&active).unwrap_or_default();
+        active.size
+            + sealed
+                .iter()
+                .filter(|(_, last_seq)| *last_seq > active.covered)
+                .filter_map(|(path, _)| fs::metadata(path).ok())
+                .map(|meta| meta.len())
+                .sum::<u64>()
+    }
+
+    // How long the writer doing an fsync waits for others to join it
+    pub fn commit_delay(&self) -> Duration {
+        Duration::from_micros(self.commit_delay_us.load(Ordering::Relaxed))
+    }
+
+    pub fn set_commit_delay(&self, delay: Duration) {
+        self.commit_delay_us
+            .store(delay.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    // Bytes at which a segment is sealed (0 = only on saves)
+    pub fn segment_size(&self) -> u64 {
+        self.segment_size.load(Ordering::Relaxed)
+    }
+
+    pub fn set_segment_size(&self, bytes: u64) {
+        self.segment_size.store(bytes, Ordering::Relaxed);
+    }
+
+    // Sealed segments to keep once a save covers them
+    pub fn segment_retention(&self) -> usize {
+        self.segment_retention.load(Ordering::Relaxed)
+    }
+
+    pub fn set_segment_retention(&self, count: usize) {
+        self.segment_retention.store(count, Ordering::Relaxed);
+    }
+
+    // The sequence up to which the slowest backup has applied writes, None
+    // if no backup needs the log
+    pub fn replicas_acked(&self) -> Option<u64> {
+        Some(self.replicas_acked.load(Ordering::Relaxed)).filter(|&seq| seq != u64::MAX)
+    }
+
+    pub fn set_replicas_acked(&self, seq: Option<u64>) {
+        self.replicas_acked
+            .store(seq.unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    // Number of fsyncs so far, each covering one or more writes
+    pub fn syncs(&self) -> u64 {
+        self.syncs.load(Ordering::Relaxed)
+    }
+
+    // Append the write numbered `seq`. It is on disk once `sync` returns.
+    pub fn append(&self, seq: u64, hlc: Timestamp, op: &ChangeOp) -> Result<()> {
+        let change = Change {
+            seq,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            hlc,
+            op: op.clone(),
+        };
+        let line = encode(&change)?;
+
+        let mut active = self.active.lock().unwrap();
+        active.file.write_all(line.as_bytes())?;
+        active.size += line.len() as u64;
+        active.first_seq.get_or_insert(seq);
+        active.last_seq = seq;
+        self.written.fetch_add(1, Ordering::SeqCst);
+
+        let segment_size = self.segment_size();
+        // The write itself made it, so a failure here only delays sealing
+        if segment_size > 0
+            && active.size >= segment_size
+            && let Err(e) = self.seal(&mut active)
+        {
+            log_warn!("Could not seal a segment of {}: {}", self.path.display(), e);
+        }
+        Ok(())
+    }
+
+    // Get every record appended so far onto disk. If another writer is
+    // syncing, wait for it and then sync whatever it didn't cover, together
+    // with anyone else who was waiting. This blocks for the commit delay and
+    // the disk, so it must not be called on the runtime's threads; servers
+    // write from the writer threads and blocking tasks.
+    pub fn sync(&self) -> Result<()> {
+        let target = self.written.load(Ordering::SeqCst);
+        let mut state = self.synced.lock().unwrap();
+        loop {
+            if state.through >= target {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            state = self.sync_done.wait(state).unwrap();
+        }
+        state.syncing = true;
+        drop(state);
+
+        let delay = self.commit_delay();
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+        let result = self.sync_file();
+
+        let mut state = self.synced.lock().unwrap();
+        state.syncing = false;
+        if let Ok(through) = result {
+            state.through = state.through.max(through);
+        }
+        self.sync_done.notify_all();
+        result.map(|_| ())
+    }
+
+    // Sync the file, returning how many records that covers
+    fn sync_file(&self) -> Result<u64> {
+        let active = self.active.lock().unwrap();
+        let through = self.written.load(Ordering::SeqCst);
+        // Appends can carry on while we wait for the disk
+        let handle = active.file.try_clone()?;
+        drop(active);
+        faults::sync(&self.path, &handle)?;
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        Ok(through)
+    }
+
+    // Sync the segment being written, rename it after its first write and
+    // start a new one
+    fn seal(&self, active: &mut Active) -> Result<()> {
+        let Some(first_seq) = active.first_seq else {
+            return Ok(());
+        };
+        faults::sync(&self.path, &active.file)?;
+        fs::rename(&self.path, segment_path(&self.path, first_seq))?;
+        store::sync_parent(&self.path)?;
+        active.file = append_to(&self.path)?;
+        active.size = 0;
+        active.first_seq = None;
+
+        let mut state = self.synced.lock().unwrap();
+        state.through = state.through.max(self.written.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    // Sealed segments, oldest first, with the sequence of their last write
+    fn sealed(&self, active: &Active) -> Result<Vec<(PathBuf, u64)>> {
+        let segments = sealed_segments(&self.path)?;
+        let next_firsts: Vec<Option<u64>> = segments
+            .iter()
+            .skip(1)
+            .map(|(first_seq, _)| Some(*first_seq))
+            .chain([active.first_seq])
+            .collect();
+        Ok(segments
+            .into_iter()
+            .zip(next_firsts)
+            .map(|((_, path), next)| (path, next.map_or(active.last_seq, |next| next - 1)))
+            .collect())
+    }
+
+    // A save now covers the writes up to `seq`. Seal the segment being
+    // written, and remove the sealed segments the save covers and every
+    // backup has applied, bar the newest `segment_retention` of them.
+    pub fn truncate_through(&self, seq: u64) -> Result<()> {
+        let mut active = self.active.lock().unwrap();
+        active.covered = active.covered.max(seq);
+        self.seal(&mut active)?;
+
+        let removable = seq.min(self.replicas_acked.load(Ordering::Relaxed));
+        let covered: Vec<PathBuf> = self
+            .sealed(&active)?
+            .into_iter()
+            .filter(|(_, last_seq)| *last_seq <= removable)
+            .map(|(path, _)| path)
+            .collect();
+        let retention = self.segment_retention();
+        for path in &covered[..covered.len().saturating_sub(retention)] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+// Every write in the log at `path`, oldest first, none if it doesn't exist.
+// Archived segments are included.
+pub fn read(path: &Path) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    for (_, segment) in sealed_segments(path)? {
+        changes.extend(read_file(&segment, false)?);
+    }
+    changes.extend(read_file(path, true)?);
+    Ok(changes)
+}
+
+// The writes in the log at `path` from `from_seq` onwards, skipping sealed
+// segments that end before it. The segment being written is read first, so
+// if it is sealed meanwhile its writes are still found among the others.
+// Segments removed meanwhile leave a gap, which callers must check for.
+pub fn read_from(path: &Path, from_seq: u64) -> Result<Vec<Change>> {
+    let active = read_file(path, true)?;
+    let segments = sealed_segments(path)?;
+    let mut changes = Vec::new();
+    for (i, (_, segment)) in segments.iter().enumerate() {
+        let ends_before = segments
+            .get(i + 1)
+            .is_some_and(|&(next_first, _)| next_first <= from_seq);
+        if !ends_before {
+            changes.extend(read_file(segment, false)?);
+        }
+    }
+    changes.extend(active);
+    changes.retain(|change| change.seq >= from_seq);
+    changes.sort_by_key(|change| change.seq);
+    changes.dedup_by_key(|change| change.seq);
+    Ok(changes)
+}
+
+// Bytes on disk for the log at `path`, sealed segments included
+pub fn disk_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for (_, segment) in sealed_segments(path)? {
+        size += fs::metadata(segment)?.len();
+    }
+    Ok(size + fs::metadata(path).map_or(0, |meta| meta.len()))
+}
+
+// Rename the log at `path` and its sealed segments, adding `.<suffix>`, so
+// they are no longer read. Returns how many files were moved.
+pub fn set_aside(path: &Path, suffix: &str) -> Result<usize> {
+    let mut files: Vec<PathBuf> = sealed_segments(path)?
+        .into_iter()
+        .map(|(_, segment)| segment)
+        .collect();
+    if path.exists() {
+        files.push(path.to_path_buf());
+    }
+    for file in &files {
+        let mut name = file.as_os_str().to_owned();
+        name.push(format!(".{}", suffix));
+        fs::rename(file, name)?;
+    }
+    Ok(files.len())
+}
+
+fn append_to(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+// The writes in one segment. Only the one being written can end in a
+// record cut short.
+fn read_file(path: &Path, active: bool) -> Result<Vec<Change>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut records = Vec::new();
+    let mut lines = BufReader::new(file).lines().peekable();
+    while let Some(line) = lines.next() {
+        match decode(&line?) {
+            Ok(change) => records.push(change),
+            // The write being appended when we crashed never took effect
+            Err(e) if active && lines.peek().is_none() => {
+                log_warn!(
+                    "Ignoring incomplete last record in {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+            Err(e) => {
+                return Err(StoreError::PersistenceError(format!(
+                    "Corrupt record in {}: {}",
+                    path.display(),
+                    e
+                )));
+            }
+        }
+    }
+    Ok(records)
+}
+
+fn encode(change: &Change) -> Result<String> {
+    let json =
+        serde_json::to_string(change).map_err(|e| StoreError::SerializationError(e.to_string()))?;
+    if encryption::current().is_none() {
+        return Ok(json + "\n");
+    }
+
+    let mut writer = encryption::Writer::new(Vec::new())?;
+    writer.write_all(json.as_bytes())?;
+    Ok(encode_base64(&writer.finish()?) + "\n")
+}
+
+fn decode(line: &str) -> Result<Change> {
+    let line = line.trim_end();
+    let json = if line.starts_with('{') {
+        line.as_bytes().to_vec()
+    } else {
+        let mut json = Vec::new();
+        encryption::Reader::new(io::Cursor::new(decode_base64(line)?))?.read_to_end(&mut json)?;
+        json
+    };
+    serde_json::from_slice(&json).map_err(|e| StoreError::SerializationError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_write_ahead_log() -> Result<()> {
+        let dir = tempdir()?;
+        let path = wal_path(&dir.path().join("wal-db.json"));
+        let wal = Wal::open(&path)?;
+        for seq in 1..=3 {
+            let op = ChangeOp::Put {
+                key: format!("key{}", seq),
+                value: "value".to_string(),
+            };
+            wal.append(seq, Timestamp::default(), &op)?;
+        }
+        assert_eq!(read(&path)?.len(), 3);
+
+        // A save seals the segment, which stays until a save covers all of it
+        let seqs =
+            || -> Result<Vec<u64>> { Ok(read(&path)?.iter().map(|change| change.seq).collect()) };
+        wal.truncate_through(2)?;
+        let delete = ChangeOp::Delete {
+            key: "key1".to_string(),
+        };
+        wal.append(4, Timestamp::default(), &delete)?;
+        assert_eq!(seqs()?, vec![1, 2, 3, 4]);
+        wal.truncate_through(3)?;
+        assert_eq!(seqs()?, vec![4]);
+
+        // A torn last line is skipped, but damage before it is not
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(b"{\"seq\":5,\"ti")?;
+        assert_eq!(read(&path)?.len(), 1);
+        drop(wal);
+        let wal = Wal::open(&path)?;
+        wal.append(5, Timestamp::default(), &delete)?;
+        assert_eq!(seqs()?, vec![4, 5]);
+        let contents = fs::read_to_string(&path)?;
+        fs::write(&path, format!("garbage\n{}", contents))?;
+        assert!(read(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_segments() -> Result<()> {
+        let dir = tempdir()?;
+        let path = wal_path(&dir.path().join("segment-db.json"));
+        let wal = Wal::open(&path)?;
+        wal.set_segment_size(200);
+        let put = |seq: u64| {
+            let op = ChangeOp::Put {
+                key: format!("key{}", seq),
+                value: "value".to_string(),
+            };
+            wal.append(seq, Timestamp::default(), &op)
+        };
+
+        // Segments are sealed as they fill up and read back in order
+        for seq in 1..=10 {
+            put(seq)?;
+        }
+        let sealed = sealed_segments(&path)?;
+        assert!(sealed.len() > 1, "{:?}", sealed);
+        assert_eq!(sealed[0].0, 1);
+        let seqs: Vec<u64> = read(&path)?.iter().map(|change| change.seq).collect();
+        assert_eq!(seqs, (1..=10).collect::<Vec<_>>());
+
+        // A save covering them all keeps only the retained ones
+        wal.set_segment_retention(1);
+        wal.truncate_through(10)?;
+        assert_eq!(sealed_segments(&path)?.len(), 1);
+        assert_eq!(read(&path)?.last().map(|change| change.seq), Some(10));
+        assert_eq!(wal.size(), 0);
+
+        // A reopened log carries on after the archive
+        drop(wal);
+        let wal = Wal::open(&path)?;
+        wal.set_segment_retention(1);
+        wal.append(
+            11,
+            Timestamp::default(),
+            &ChangeOp::Delete {
+                key: "key1".to_string(),
+            },
+        )?;
+        wal.truncate_through(11)?;
+        let newest = sealed_segments(&path)?;
+        assert_eq!(newest.len(), 1);
+        assert_eq!(newest[0].0, 11);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replica_retention() -> Result<()> {
+        let dir = tempdir()?;
+        let path = wal_path(&dir.path().join("replica-db.json"));
+        let wal = Wal::open(&path)?;
+        wal.set_segment_size(200);
+        for seq in 1..=10 {
+            let op = ChangeOp::Put {
+                key: format!("key{}", seq),
+                value: "value".to_string(),
+            };
+            wal.append(seq, Timestamp::default(), &op)?;
+        }
+        let seqs = |from_seq| -> Result<Vec<u64>> {
+            Ok(read_from(&path, from_seq)?
+                .iter()
+                .map(|change| change.seq)
+                .collect())
+        };
+        assert_eq!(seqs(4)?, (4..=10).collect::<Vec<_>>());
+
+        // A save keeps what a backup hasn't applied yet
+        wal.set_replicas_acked(Some(3));
+        assert_eq!(wal.replicas_acked(), Some(3));
+        wal.truncate_through(10)?;
+        assert_eq!(seqs(4)?, (4..=10).collect::<Vec<_>>());
+        assert!(read(&path)?.len() < 10);
+
+        // And drops it once the backup has caught up
+        wal.set_replicas_acked(None);
+        wal.truncate_through(10)?;
+        assert_eq!(seqs(4)?, Vec::<u64>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_commit() -> Result<()> {
+        let dir = tempdir()?;
+        let path = wal_path(&dir.path().join("group-db.json"));
+        let wal = Wal::open(&path)?;
+        wal.set_commit_delay(Duration::from_millis(1));
+
+        // Writers syncing at the same time share fsyncs
+        thread::scope(|scope| {
+            let writers: Vec<_> = (0..8)
+                .map(|writer| {
+                    let wal = &wal;
+                    scope.spawn(move || {
+                        for i in 0..25 {
+                            let op = ChangeOp::Put {
+                                key: format!("key{}-{}", writer, i),
+                                value: "value".to_string(),
+                            };
+                            wal.append(writer * 25 + i + 1, Timestamp::default(), &op)?;
+                            wal.sync()?;
+                        }
+                        Ok::<_, StoreError>(())
+                    })
+                })
+                .collect();
+            writers
+                .into_iter()
+                .try_for_each(|writer| writer.join().unwrap())
+        })?;
+        assert_eq!(read(&path)?.len(), 200);
+        assert!(wal.syncs() < 200, "{} fsyncs", wal.syncs());
+
+        // Nothing new to sync costs nothing
+        let syncs = wal.syncs();
+        wal.sync()?;
+        assert_eq!(wal.syncs(), syncs);
+        Ok(())
+    }
+}
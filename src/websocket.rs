@@ -0,0 +1,259 @@
+// src/websocket.rs
+
+// WebSocket gateway and client transport. Browsers can't open plain TCP
+// connections, so a server given a WebSocket address also accepts WebSocket
+// connections there. Each text message carries one command line and each
+// line the server answers with comes back as a text message of its own, so
+// a connection behind the gateway is served exactly like a TCP one, SYNC
+// and SUBSCRIBE streams included.
+//
+// `WebSocketTransport` lets `Client` talk to the gateway. Natively it runs
+// on tokio; built for wasm32 it uses the browser's WebSocket, so dashboards
+// get the same typed client as native apps.
+
+use crate::client::{Connection, Transport, open_session};
+use crate::error::{Result, StoreError};
+use std::io;
+
+#[cfg(not(target_arch = "wasm32"))]
+use {
+    futures_util::{SinkExt, StreamExt},
+    tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream},
+    tokio::net::TcpStream,
+    tokio_tungstenite::tungstenite::{self, Message},
+    tokio_tungstenite::{MaybeTlsStream, WebSocketStream},
+};
+
+// Reaches the server's WebSocket gateway at a ws:// URL, over a new
+// connection per command
+pub struct WebSocketTransport {
+    url: String,
+    auth_token: Option<String>,
+    namespace: Option<String>,
+}
+
+impl WebSocketTransport {
+    pub fn new(url: String) -> Self {
+        WebSocketTransport {
+            url,
+            auth_token: None,
+            namespace: None,
+        }
+    }
+
+    // Authenticate every connection with the server's admin token
+    pub fn with_auth_token(mut self, token: String) -> Self {
+        self.auth_token = Some(token);
+        self
+    }
+
+    // Work inside a namespace, as if every connection started with SELECT
+    pub fn with_namespace(mut self, namespace: String) -> Self {
+        self.namespace = Some(namespace);
+        self
+    }
+}
+
+impl Transport for WebSocketTransport {
+    async fn send_command(&self, command: &str) -> Result<String> {
+        #[cfg(not(target_arch = "wasm32"))]
+        let mut connection = {
+            let (socket, _) = tokio_tungstenite::connect_async(self.url.as_str())
+                .await
+                .map_err(websocket_error)?;
+            NativeConnection(socket)
+        };
+        #[cfg(target_arch = "wasm32")]
+        let mut connection = browser::BrowserConnection::open(&self.url).await?;
+
+        open_session(
+            &mut connection,
+            self.auth_token.as_deref(),
+            self.namespace.as_deref(),
+        )
+        .await?;
+        connection.exchange(command).await
+    }
+}
+
+// How a connection that went away before answering is reported
+fn closed(reason: &str) -> StoreError {
+    StoreError::IoError(io::Error::new(io::ErrorKind::ConnectionAborted, reason))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn websocket_error(e: tungstenite::Error) -> StoreError {
+    match e {
+        tungstenite::Error::Io(e) => StoreError::IoError(e),
+        e => StoreError::IoError(io::Error::other(e)),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+struct NativeConnection(WebSocketStream<MaybeTlsStream<TcpStream>>);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Connection for NativeConnection {
+    async fn exchange(&mut self, command: &str) -> Result<String> {
+        self.0
+            .send(Message::text(command))
+            .await
+            .map_err(websocket_error)?;
+        loop {
+            match self.0.next().await {
+                Some(Ok(Message::Text(response))) => return Ok(response.trim().to_string()),
+                Some(Ok(Message::Close(_))) | None => return Err(closed("WebSocket closed")),
+                // Pings are answered for us
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(websocket_error(e)),
+            }
+        }
+    }
+}
+
+// Serve one WebSocket connection accepted by the gateway: complete the
+// handshake, then pass each message to `connection` as a command line and
+// send each line it answers with as a message, until either side closes
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    connection: DuplexStream,
+) -> Result<()> {
+    let websocket = tokio_tungstenite::accept_async(stream)
+        .await
+        .map_err(websocket_error)?;
+    let (mut outgoing, mut incoming) = websocket.split();
+    let (reader, mut writer) = tokio::io::split(connection);
+    let mut lines = BufReader::new(reader).lines();
+
+    loop {
+        tokio::select! {
+            message = incoming.next() => match message {
+                Some(Ok(Message::Text(command))) => {
+                    let line = format!("{}\n", command.trim_end_matches(['\r', '\n']));
+                    writer.write_all(line.as_bytes()).await?;
+                }
+                Some(Ok(Message::Binary(_))) => {
+                    let refusal = Message::text("ERROR: Commands must be sent as text messages");
+                    outgoing.send(refusal).await.map_err(websocket_error)?;
+                }
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(websocket_error(e)),
+            },
+            line = lines.next_line() => match line? {
+                Some(line) => outgoing.send(Message::text(line)).await.map_err(websocket_error)?,
+                // The server is done with the connection
+                None => {
+                    let _ = outgoing.close().await;
+                    return Ok(());
+                }
+            },
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod browser {
+    use super::closed;
+    use crate::client::Connection;
+    use crate::error::{Result, StoreError};
+    use futures_channel::mpsc;
+    use futures_util::StreamExt;
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen::prelude::*;
+    use web_sys::{CloseEvent, Event, MessageEvent, WebSocket};
+
+    enum SocketEvent {
+        Open,
+        Message(String),
+        Closed(String),
+    }
+
+    // A browser WebSocket, with the events it raises queued up for us. The
+    // callbacks are kept for as long as the socket can call them.
+    pub(super) struct BrowserConnection {
+        socket: WebSocket,
+        events: mpsc::UnboundedReceiver<SocketEvent>,
+        _on_open: Closure<dyn FnMut(Event)>,
+        _on_message: Closure<dyn FnMut(MessageEvent)>,
+        _on_error: Closure<dyn FnMut(Event)>,
+        _on_close: Closure<dyn FnMut(CloseEvent)>,
+    }
+
+    fn js_error(value: JsValue) -> StoreError {
+        closed(&format!("{:?}", value))
+    }
+
+    impl BrowserConnection {
+        // Open a WebSocket and wait until it is connected
+        pub(super) async fn open(url: &str) -> Result<Self> {
+            let socket = WebSocket::new(url).map_err(js_error)?;
+            let (sender, events) = mpsc::unbounded();
+
+            let on_open = {
+                let sender = sender.clone();
+                Closure::<dyn FnMut(Event)>::new(move |_: Event| {
+                    let _ = sender.unbounded_send(SocketEvent::Open);
+                })
+            };
+            let on_message = {
+                let sender = sender.clone();
+                Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+                    if let Some(text) = event.data().as_string() {
+                        let _ = sender.unbounded_send(SocketEvent::Message(text));
+                    }
+                })
+            };
+            let on_error = {
+                let sender = sender.clone();
+                Closure::<dyn FnMut(Event)>::new(move |_: Event| {
+                    let error = "WebSocket error".to_string();
+                    let _ = sender.unbounded_send(SocketEvent::Closed(error));
+                })
+            };
+            let on_close = Closure::<dyn FnMut(CloseEvent)>::new(move |event: CloseEvent| {
+                let reason = format!("WebSocket closed with code {}", event.code());
+                let _ = sender.unbounded_send(SocketEvent::Closed(reason));
+            });
+            socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+            socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+            socket.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+            socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+            let mut connection = BrowserConnection {
+                socket,
+                events,
+                _on_open: on_open,
+                _on_message: on_message,
+                _on_error: on_error,
+                _on_close: on_close,
+            };
+            match connection.events.next().await {
+                Some(SocketEvent::Open) => Ok(connection),
+                Some(SocketEvent::Closed(reason)) => Err(closed(&reason)),
+                _ => Err(closed("WebSocket closed")),
+            }
+        }
+    }
+
+    impl Connection for BrowserConnection {
+        async fn exchange(&mut self, command: &str) -> Result<String> {
+            self.socket.send_with_str(command).map_err(js_error)?;
+            loop {
+                match self.events.next().await {
+                    Some(SocketEvent::Message(response)) => return Ok(response.trim().to_string()),
+                    Some(SocketEvent::Open) => continue,
+                    Some(SocketEvent::Closed(reason)) => return Err(closed(&reason)),
+                    None => return Err(closed("WebSocket closed")),
+                }
+            }
+        }
+    }
+
+    impl Drop for BrowserConnection {
+        fn drop(&mut self) {
+            let _ = self.socket.close();
+        }
+    }
+}
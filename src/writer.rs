@@ -0,0 +1,551 @@
+// src/writer.rs
+
+// Client writes are carried out by a small pool of writers, each owning the
+// keys that hash to it. A writer applies a write and replicates it before
+// taking the next one, so writes to a key reach backups in the order they
+// were applied. Connection tasks queue their writes on a bounded channel
+// instead of calling into the store. Each writer runs on a thread of its
+// own, as applying a write blocks on the store's locks and on syncing the
+// write-ahead log; only replicating it waits on the runtime. The writers
+// still take turns at the store's write lock while a write is applied, so
+// what they buy is keeping that off the runtime's threads and letting
+// writes to different keys replicate at the same time.
+//
+// The writers don't own their keys' data, which stays in the store behind
+// its std RwLock. Reads (GET, KEYS, LIST, SCAN and the rest) still take
+// that lock on the runtime's threads, so a read can wait there while a
+// writer holds the write lock. Giving each writer its own shard of the
+// data would end that, but quotas, eviction, snapshots and the
+// write-ahead log all work on the store as a whole.
+//
+// A write to keys owned by several writers first has each of them stop and
+// wait, then runs on a blocking thread while they are all held.
+
+use crate::crdt::{self, Crdt, Update};
+use crate::error::{Result, StoreError};
+use crate::replication::{Operation, ReplicationManager, Role};
+use crate::store::{Condition, KeyValueStore};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+use std::thread;
+use tokio::runtime::Handle;
+use tokio::sync::{Mutex, mpsc, oneshot};
+
+// Number of writers
+pub const PARTITIONS: usize = 16;
+
+// Writes that may wait for each writer before senders are held up
+const QUEUE_DEPTH: usize = 256;
+
+// A client write, answered with the response line the client gets
+#[derive(Debug, Clone)]
+pub enum Write {
+    Put(String, String, Condition),
+    // A put that also makes the key expire at a unix time in milliseconds
+    PutExpiring(String, String, Condition, u64),
+    PutMany(Vec<(String, String)>),
+    GetOrSet(String, String),
+    GetDel(String),
+    // A put that returns the value it replaced
+    GetSet(String, String),
+    Delete(String),
+    DeleteMany(Vec<String>),
+    Rename(String, String),
+    // Replace the second key only if the flag is set
+    Copy(String, String, bool),
+    Expire(String, u64),
+    Persist(String),
+    // Count a key as used, moving its expiry time if it has one
+    Touch(String, Option<u64>),
+    // Add to the integer a key holds
+    Increment(String, i64),
+    Append(String, String),
+    // Remove a key whose time has come, if it still has. Replicated as a
+    // delete, which is how backups learn of it.
+    RemoveExpired(String),
+    // Change the CRDT a key holds
+    Crdt(String, Update),
+    // Merge CRDT state a backup took writes into
+    Merge(String, String),
+}
+
+impl Write {
+    fn keys(&self) -> Vec<&str> {
+        let key = match self {
+            Write::PutMany(entries) => {
+                return entries.iter().map(|(key, _)| key.as_str()).collect();
+            }
+            Write::DeleteMany(keys) => return keys.iter().map(String::as_str).collect(),
+            Write::Rename(src, dst) | Write::Copy(src, dst, _) => return vec![src, dst],
+            Write::Put(key, ..) | Write::PutExpiring(key, ..) | Write::GetOrSet(key, _) => key,
+            Write::GetDel(key) | Write::Delete(key) | Write::Expire(key, _) => key,
+            Write::Persist(key) | Write::Touch(key, _) | Write::Increment(key, _) => key,
+            Write::Append(key, _) | Write::GetSet(key, _) | Write::RemoveExpired(key) => key,
+            Write::Crdt(key, _) | Write::Merge(key, _) => key,
+        };
+        vec![key]
+    }
+}
+
+enum Task {
+    Write(Job),
+    // Take no more writes until released, for a write to keys owned by
+    // several writers. `held` is answered once this writer has stopped.
+    Hold {
+        held: oneshot::Sender<()>,
+        release: oneshot::Receiver<()>,
+    },
+}
+
+struct Job {
+    write: Write,
+    request_id: String,
+    reply: oneshot::Sender<Result<String>>,
+}
+
+pub struct Writers {
+    partitions: Vec<mpsc::Sender<Task>>,
+    store: Arc<KeyValueStore>,
+    replication_manager: Option<Arc<ReplicationManager>>,
+    // Held while queueing holds, so every writer sees those of different
+    // writes in the same order and two can't each wait on the other
+    holding: Mutex<()>,
+}
+
+impl Writers {
+    // Start the writer threads, which replicate on the current runtime.
+    // Each one stops once the Writers are dropped.
+    pub fn start(
+        store: Arc<KeyValueStore>,
+        replication_manager: Option<Arc<ReplicationManager>>,
+    ) -> Self {
+        let runtime = Handle::current();
+        let mut partitions = Vec::with_capacity(PARTITIONS);
+        for _ in 0..PARTITIONS {
+            let (sender, mut tasks) = mpsc::channel::<Task>(QUEUE_DEPTH);
+            let store = Arc::clone(&store);
+            let replication_manager = replication_manager.clone();
+            let runtime = runtime.clone();
+            thread::spawn(move || {
+                while let Some(task) = tasks.blocking_recv() {
+                    match task {
+                        Task::Write(job) => {
+                            let (response, ops) = commit(job.write, &store);
+                            let replicated = runtime.block_on(replicate(
+                                ops,
+                                &job.request_id,
+                                &store,
+                                &replication_manager,
+                            ));
+                            // The connection may have gone away meanwhile
+                            let _ = job.reply.send(replicated.map(|()| response));
+                        }
+                        Task::Hold { held, release } => {
+                            let _ = held.send(());
+                            let _ = release.blocking_recv();
+                        }
+                    }
+                }
+            });
+            partitions.push(sender);
+        }
+        Writers {
+            partitions,
+            store,
+            replication_manager,
+            holding: Mutex::new(()),
+        }
+    }
+
+    // Hand a write to the writer that owns its key and wait for the
+    // response. A write to keys owned by several writers holds them all
+    // and is applied here.
+    pub async fn submit(&self, write: Write, request_id: &str) -> Result<String> {
+        let mut owners: Vec<usize> = write
+            .keys()
+            .into_iter()
+            .map(|key| self.owner(key))
+            .collect();
+        owners.sort_unstable();
+        owners.dedup();
+        let stopped = || StoreError::UnavailableError("Writer stopped".to_string());
+
+        if let [owner] = owners[..] {
+            let (reply, response) = oneshot::channel();
+            let job = Job {
+                write,
+                request_id: request_id.to_string(),
+                reply,
+            };
+            self.partitions[owner]
+                .send(Task::Write(job))
+                .await
+                .map_err(|_| stopped())?;
+            return response.await.map_err(|_| stopped())?;
+        }
+
+        let mut held = Vec::with_capacity(owners.len());
+        // Dropped once the write is done, which lets the writers go on
+        let mut releases = Vec::with_capacity(owners.len());
+        let queueing = self.holding.lock().await;
+        for owner in owners {
+            let (held_tx, held_rx) = oneshot::channel();
+            let (release_tx, release_rx) = oneshot::channel();
+            let hold = Task::Hold {
+                held: held_tx,
+                release: release_rx,
+            };
+            self.partitions[owner]
+                .send(hold)
+                .await
+                .map_err(|_| stopped())?;
+            held.push(held_rx);
+            releases.push(release_tx);
+        }
+        drop(queueing);
+        for held in held {
+            held.await.map_err(|_| stopped())?;
+        }
+        let store = Arc::clone(&self.store);
+        let (response, ops) = tokio::task::spawn_blocking(move || commit(write, &store))
+            .await
+            .map_err(|_| stopped())?;
+        replicate(ops, request_id, &self.store, &self.replication_manager).await?;
+        Ok(response)
+    }
+
+    // The writer that owns `key`
+    fn owner(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % self.partitions.len()
+    }
+}
+
+// Apply a write locally and, on a primary, replicate it. A write that
+// changes nothing is not replicated. This runs the write on the calling
+// thread; servers hand writes to their writers instead.
+pub async fn apply(
+    write: Write,
+    request_id: &str,
+    store: &KeyValueStore,
+    replication_manager: &Option<Arc<ReplicationManager>>,
+) -> Result<String> {
+    let (response, ops) = commit(write, store);
+    replicate(ops, request_id, store, replication_manager).await?;
+    Ok(response)
+}
+
+// Carry out a write on the store. Returns the response with the operations
+// that replicate it, each with the sequence number it was committed under.
+fn commit(write: Write, store: &KeyValueStore) -> (String, Vec<(Operation, u64)>) {
+    let ((response, ops), seqs) = store.recording(|| execute(write, store));
+    let seqs = op_seqs(&seqs, ops.len());
+    (response, ops.into_iter().zip(seqs).collect())
+}
+
+// Send the operations a write made to the backups, if we're the primary.
+// Backups keep their writes to themselves, except for CRDT state, which
+// they pass on for the primary to merge and send to every backup.
+async fn replicate(
+    ops: Vec<(Operation, u64)>,
+    request_id: &str,
+    store: &KeyValueStore,
+    replication_manager: &Option<Arc<ReplicationManager>>,
+) -> Result<()> {
+    let Some(rm) = replication_manager.as_ref().filter(|_| !ops.is_empty()) else {
+        return Ok(());
+    };
+    match rm.get_role().await {
+        Role::Primary => {}
+        Role::Backup(primary) => {
+            for (op, _) in ops {
+                if let Operation::Put(key, state) = op
+                    && Crdt::parse(&state).is_some()
+                {
+                    rm.forward_crdt(&primary, &key, &state, request_id).await;
+                }
+            }
+            return Ok(());
+        }
+        Role::Standalone => return Ok(()),
+    }
+    for (op, seq) in ops {
+        // No other write to the key can have landed since, as we own it
+        let meta = match &op {
+            Operation::Put(key, _) => store.metadata(key),
+            Operation::Delete(_)
+            | Operation::DeleteMany(_)
+            | Operation::Rename(..)
+            | Operation::Copy(..)
+            | Operation::Expire(..) => None,
+        };
+        let hlc = meta
+            .as_ref()
+            .map_or_else(|| store.hlc().current(), |meta| meta.hlc);
+        let vclock = meta.as_ref().map(|meta| &meta.vclock);
+        rm.replicate_operation(&op, seq, hlc, vclock, request_id)
+            .await?;
+    }
+    Ok(())
+}
+
+// The sequence number each of `count` operations is sent with, given those
+// of the changes the write made. Operations map to changes one to one when
+// there are as many of each; otherwise, as for a rename or an eviction made
+// room for the write, they all take the write's last one.
+fn op_seqs(seqs: &[u64], count: usize) -> Vec<u64> {
+    if seqs.len() == count {
+        seqs.to_vec()
+    } else {
+        vec![seqs.last().copied().unwrap_or_default(); count]
+    }
+}
+
+// Carry out a write on the store, returning the response and the operations
+// that replicate it
+fn execute(write: Write, store: &KeyValueStore) -> (String, Vec<Operation>) {
+    match write {
+        Write::Put(key, value, condition) => {
+            match store.try_put_if(key.clone(), value.clone(), &condition) {
+                Ok(true) => ("OK".to_string(), vec![Operation::Put(key, value)]),
+                Ok(false) => ("NULL".to_string(), vec![]),
+                Err(e) => (format!("ERROR: {}", e), vec![]),
+            }
+        }
+        Write::PutExpiring(key, value, condition, at) => {
+            match store.try_put_expiring(key.clone(), value.clone(), &condition, at) {
+                // A put with a TTL reaches backups as the put and then its
+                // expiry
+                Ok(true) => (
+                    "OK".to_string(),
+                    vec![
+                        Operation::Put(key.clone(), value),
+                        Operation::Expire(key, at),
+                    ],
+                ),
+                Ok(false) => ("NULL".to_string(), vec![]),
+                Err(e) => (format!("ERROR: {}", e), vec![]),
+            }
+        }
+        Write::PutMany(entries) => {
+            let (written, result) = store.put_many(&entries);
+            // Limits are checked for the whole batch up front, but whatever
+            // was written is replicated even if logging stopped the rest
+            let ops = entries
+                .into_iter()
+                .take(written)
+                .map(|(key, value)| Operation::Put(key, value))
+                .collect();
+            match result {
+                Ok(()) => ("OK".to_string(), ops),
+                Err(e) => (format!("ERROR: {}", e), ops),
+            }
+        }
+        Write::GetOrSet(key, default) => match store.get_or_set(key.clone(), default) {
+            Ok((value, true)) => (value.clone(), vec![Operation::Put(key, value)]),
+            Ok((value, false)) => (value, vec![]),
+            Err(e) => (format!("ERROR: {}", e), vec![]),
+        },
+        Write::GetDel(key) => match store.try_take(&key) {
+            Ok(Some(value)) => (value, vec![Operation::Delete(key)]),
+            Ok(None) => ("Key not found".to_string(), vec![]),
+            Err(e) => (format!("ERROR: {}", e), vec![]),
+        },
+        Write::GetSet(key, value) => match store.get_set(key.clone(), value.clone()) {
+            Ok(old) => (
+                old.unwrap_or_else(|| "Key not found".to_string()),
+                vec![Operation::Put(key, value)],
+            ),
+            Err(e) => (format!("ERROR: {}", e), vec![]),
+        },
+        Write::Delete(key) => match store.try_delete(&key) {
+            Ok(true) => ("OK".to_string(), vec![Operation::Delete(key)]),
+            Ok(false) => ("NULL".to_string(), vec![]),
+            Err(e) => (format!("ERROR: {}", e), vec![]),
+        },
+        Write::DeleteMany(mut keys) => {
+            let (done, result) =
+                store.try_delete_many(&keys.iter().map(String::as_str).collect::<Vec<_>>());
+            // Sent as one operation, so backups remove them together too.
+            // Keys gone through before an error are still replicated.
+            keys.truncate(done);
+            let ops = if result.as_ref().map_or(done > 0, |&removed| removed > 0) {
+                vec![Operation::DeleteMany(keys)]
+            } else {
+                vec![]
+            };
+            match result {
+                Ok(removed) => (removed.to_string(), ops),
+                Err(e) => (format!("ERROR: {}", e), ops),
+            }
+        }
+        Write::Rename(src, dst) => match store.rename(&src, &dst) {
+            Ok(true) => ("OK".to_string(), vec![Operation::Rename(src, dst)]),
+            Ok(false) => ("NULL".to_string(), vec![]),
+            Err(e) => (format!("ERROR: {}", e), vec![]),
+        },
+        Write::Copy(src, dst, replace) => match store.copy(&src, &dst, replace) {
+            Ok(true) => ("OK".to_string(), vec![Operation::Copy(src, dst)]),
+            Ok(false) => ("NULL".to_string(), vec![]),
+            Err(e) => (format!("ERROR: {}", e), vec![]),
+        },
+        Write::Expire(key, at) => match store.expire(&key, at) {
+            Ok(true) => ("OK".to_string(), vec![Operation::Expire(key, at)]),
+            Ok(false) => ("NULL".to_string(), vec![]),
+            Err(e) => (format!("ERROR: {}", e), vec![]),
+        },
+        Write::Persist(key) => match store.persist(&key) {
+            Ok(true) => ("OK".to_string(), vec![Operation::Expire(key, 0)]),
+            Ok(false) => ("NULL".to_string(), vec![]),
+            Err(e) => (format!("ERROR: {}", e), vec![]),
+        },
+        Write::Touch(key, expires) => match store.touch_key(&key, expires) {
+            // Only a moved expiry time is worth replicating
+            Ok(Some(at)) if at != 0 && expires.is_some() => {
+                ("OK".to_string(), vec![Operation::Expire(key, at)])
+            }
+            Ok(Some(_)) => ("OK".to_string(), vec![]),
+            Ok(None) => ("NULL".to_string(), vec![]),
+            Err(e) => (format!("ERROR: {}", e), vec![]),
+        },
+        Write::Increment(key, delta) => match store.increment(&key, delta) {
+            Ok(value) => (
+                value.to_string(),
+                kept_expiry(store, key, value.to_string()),
+            ),
+            Err(e) => (format!("ERROR: {}", e), vec![]),
+        },
+        Write::Append(key, suffix) => match store.append(&key, &suffix) {
+            Ok(value) => (value.len().to_string(), kept_expiry(store, key, value)),
+            Err(e) => (format!("ERROR: {}", e), vec![]),
+        },
+        Write::Crdt(key, update) => match store.update_crdt(&key, update) {
+            Ok(state) => (crdt::shown(state.clone()), kept_expiry(store, key, state)),
+            Err(e) => (format!("ERROR: {}", e), vec![]),
+        },
+        Write::Merge(key, state) => match store.merge_crdt(&key, &state) {
+            Ok(state) => ("OK".to_string(), kept_expiry(store, key, state)),
+            Err(e) => (format!("ERROR: {}", e), vec![]),
+        },
+        Write::RemoveExpired(key) => {
+            if store.remove_if_expired(&key) {
+                ("OK".to_string(), vec![Operation::Delete(key)])
+            } else {
+                ("NULL".to_string(), vec![])
+            }
+        }
+    }
+}
+
+// Replicate a new value for a key that kept its expiry time. Backups clear
+// the time when the put reaches them, so it is sent again after it.
+fn kept_expiry(store: &KeyValueStore, key: String, value: String) -> Vec<Operation> {
+    let at = store.metadata(&key).map_or(0, |meta| meta.expires);
+    let mut ops = vec![Operation::Put(key.clone(), value)];
+    if at != 0 {
+        ops.push(Operation::Expire(key, at));
+    }
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_writers() -> Result<()> {
+        let store = Arc::new(KeyValueStore::new());
+        let writers = Writers::start(Arc::clone(&store), None);
+        let writers = Arc::new(writers);
+
+        // Concurrent writers to one key are applied one at a time
+        let mut clients = Vec::new();
+        for i in 0..50 {
+            let writers = Arc::clone(&writers);
+            clients.push(tokio::spawn(async move {
+                let put = Write::Put("counter".to_string(), i.to_string(), Condition::Always);
+                writers.submit(put, "test").await
+            }));
+        }
+        for client in clients {
+            assert_eq!(client.await.unwrap()?, "OK");
+        }
+        assert_eq!(store.version("counter"), 50);
+        let store_value = store.get("counter");
+
+        let absent = Write::Put("counter".to_string(), "x".to_string(), Condition::Absent);
+        assert_eq!(writers.submit(absent, "test").await?, "NULL");
+        let getdel = Write::GetDel("counter".to_string());
+        assert_eq!(Some(writers.submit(getdel, "test").await?), store_value);
+        let delete = Write::Delete("counter".to_string());
+        assert_eq!(writers.submit(delete, "test").await?, "NULL");
+        let getorset = Write::GetOrSet("k".to_string(), "v".to_string());
+        assert_eq!(writers.submit(getorset, "test").await?, "v");
+
+        // Increments from many clients all land
+        let mut clients = Vec::new();
+        for _ in 0..20 {
+            let writers = Arc::clone(&writers);
+            clients.push(tokio::spawn(async move {
+                let incr = Write::Increment("hits".to_string(), 2);
+                writers.submit(incr, "test").await
+            }));
+        }
+        for client in clients {
+            client.await.unwrap()?;
+        }
+        assert_eq!(store.get("hits").as_deref(), Some("40"));
+        let incr = Write::Increment("k".to_string(), 1);
+        assert!(writers.submit(incr, "test").await?.starts_with("ERROR"));
+
+        // Batches spanning several writers don't deadlock on each other or
+        // on single-key writes
+        let mut clients = Vec::new();
+        for i in 0..20 {
+            let writers = Arc::clone(&writers);
+            clients.push(tokio::spawn(async move {
+                let entries = (0..10)
+                    .map(|n| (format!("batch{}", (n + i) % 10), i.to_string()))
+                    .collect();
+                writers.submit(Write::PutMany(entries), "test").await?;
+                let incr = Write::Increment("hits".to_string(), 1);
+                writers.submit(incr, "test").await
+            }));
+        }
+        for client in clients {
+            client.await.unwrap()?;
+        }
+        assert_eq!(store.get("hits").as_deref(), Some("60"));
+        let batch: Vec<Option<String>> =
+            (0..10).map(|n| store.get(&format!("batch{}", n))).collect();
+        assert!(batch.iter().all(|value| *value == batch[0]), "{:?}", batch);
+        Ok(())
+    }
+
+    #[test]
+    fn test_operations_carry_their_own_seq() {
+        let store = KeyValueStore::new();
+        store.put("a".to_string(), "1".to_string());
+
+        // A put with a TTL is two changes, each sent under its own number
+        let write = Write::PutExpiring(
+            "b".to_string(),
+            "2".to_string(),
+            Condition::Always,
+            u64::MAX,
+        );
+        let ((_, ops), seqs) = store.recording(|| execute(write, &store));
+        assert_eq!(seqs, vec![2, 3]);
+        assert_eq!(op_seqs(&seqs, ops.len()), vec![2, 3]);
+
+        // Changes made outside the write don't count
+        store.put("c".to_string(), "3".to_string());
+        let write = Write::Rename("a".to_string(), "d".to_string());
+        let ((_, ops), seqs) = store.recording(|| execute(write, &store));
+        assert_eq!(ops.len(), 1);
+        assert_eq!(op_seqs(&seqs, ops.len()), vec![*seqs.last().unwrap()]);
+        assert!(seqs.iter().all(|&seq| seq > 4), "{:?}", seqs);
+        assert_eq!(store.changelog().last_seq(), *seqs.last().unwrap());
+    }
+}